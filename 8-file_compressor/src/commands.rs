@@ -1,5 +1,7 @@
 mod compress;
+mod dispatch;
 mod extract;
 
 pub use compress::*;
+pub use dispatch::*;
 pub use extract::*;
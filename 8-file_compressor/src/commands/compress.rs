@@ -1,11 +1,32 @@
 use std::fs::File;
-use std::io::{BufWriter, copy};
+use std::io::{BufWriter, Seek, Write, copy};
 use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
+use bzip2::write::BzEncoder;
+use flate2::write::GzEncoder;
+use liblzma::stream::{Check, MtStreamBuilder, Stream};
+use liblzma::write::XzEncoder;
 use walkdir::WalkDir;
 use zip::{ZipWriter, write::SimpleFileOptions};
 
+/// Window size zstd's long-distance matching looks back over, as a power of two. 2^27 is 128 MiB, like `zstd --long`
+pub(crate) const LONG_WINDOW_LOG: u32 = 27;
+
+/// Preset xz uses when no level is given
+const DEFAULT_XZ_LEVEL: i32 = 6;
+
+/// Tuning for the high-ratio tar formats
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CompressionOptions {
+    /// Compression level, or None for the format's default
+    pub level: Option<i32>,
+    /// Whether zstd also finds matches far back in the input, which suits large archives with repeated content
+    pub long_distance: bool,
+    /// Number of threads compressing in parallel, with 0 and 1 both compressing on the calling thread
+    pub threads: u32,
+}
+
 /// Compresses a single input file into a zip archive at the specified output path
 ///
 /// `input_file` is the Path of the archive target. `output_zip` is the Path of the resulting archive. `permissions` is the unix file permissions
@@ -62,46 +83,16 @@ pub fn compress_directory_to_zip_directory(in_dir: &Path, out_dir: &Path) -> Res
         .compression_method(zip::CompressionMethod::Deflated)
         .unix_permissions(0o755);
 
-    let base_path = in_dir
-        .canonicalize()
-        .context("Error: Failed to canonicalize base input path.")?;
-
-    for entry in WalkDir::new(in_dir) {
-        let entry = entry?;
-        let path = entry.path();
-
-        // Skip the root directory
-        if path == in_dir {
-            continue;
-        }
-
-        let rel_path = path
-            .strip_prefix(&base_path)
-            .context("Error: Failed to compute relative path.")?;
-
-        if path.is_file() {
-            zip.start_file(rel_path.to_string_lossy(), options)
-                .with_context(|| format!("Error: Failed to start entry file: {rel_path:?}"))?;
-            let mut f = File::open(path)
-                .with_context(|| format!("Error: Failed to open file: {path:?}"))?;
-
-            copy(&mut f, &mut zip)
-                .with_context(|| format!("Error: Failed to copy file into archive: {path:?}"))?;
-        } else if path.is_dir() {
-            // Append slash to signal directory in zip
-            let dir_name = format!("{}/", rel_path.to_string_lossy());
-            zip.add_directory(dir_name, options)
-                .with_context(|| format!("Error: Failed to add directory: {rel_path:?}"))?;
-        }
-    }
+    add_directory_to_zip(&mut zip, in_dir, Path::new(""), options)?;
     zip.finish()
         .context("Error: Failed to finalize zip archive.")?;
     Ok(())
 }
 
-/// Compresses multiple input files into a zip archive
+/// Compresses multiple input files and directories into a zip archive
 ///
-/// `input_files` is a list of file paths to archive and `out_dir` is the output archive Path
+/// `input_files` is a list of file or directory paths to archive and `out_dir` is the output archive Path.
+/// Directories are archived recursively under their own name
 ///
 /// Returns `()` on success and an error with context on Error
 pub fn compress_files_to_zip_directory(input_files: &[PathBuf], out_dir: &Path) -> Result<()> {
@@ -119,6 +110,14 @@ pub fn compress_files_to_zip_directory(input_files: &[PathBuf], out_dir: &Path)
             .file_name()
             .context("Missing input file name.")?
             .to_string_lossy();
+
+        if input_file.is_dir() {
+            let dir_name = format!("{input_name}/");
+            zip.add_directory(dir_name, options)
+                .with_context(|| format!("Error: Failed to add directory: {input_file:?}"))?;
+            add_directory_to_zip(&mut zip, input_file, Path::new(input_name.as_ref()), options)?;
+            continue;
+        }
         
         zip.start_file(input_name.as_ref(), options)
             .with_context(|| format!("Error: Failed to start file entry for {input_file:?}"))?;
@@ -134,12 +133,268 @@ pub fn compress_files_to_zip_directory(input_files: &[PathBuf], out_dir: &Path)
     Ok(())
 }
 
+/// Compresses a single input file into a gzip file
+///
+/// `input_file` is the Path of the file to compress and `output_gz` is the Path of the resulting `.gz` file
+///
+/// Returns `()` on success and an error with context on Error
+pub fn compress_file_to_gzip(input_file: &Path, output_gz: &Path) -> Result<()> {
+    let output = create_output_file(output_gz)?;
+    let mut encoder = GzEncoder::new(output, flate2::Compression::default());
+    copy_input_file(input_file, &mut encoder)?;
+    encoder
+        .finish()
+        .and_then(|mut output| output.flush())
+        .with_context(|| format!("Failed to finish writing gzip file: {output_gz:?}"))?;
+    Ok(())
+}
+
+/// Compresses a single input file into a bzip2 file
+///
+/// `input_file` is the Path of the file to compress and `output_bz2` is the Path of the resulting `.bz2` file
+///
+/// Returns `()` on success and an error with context on Error
+pub fn compress_file_to_bzip2(input_file: &Path, output_bz2: &Path) -> Result<()> {
+    let output = create_output_file(output_bz2)?;
+    let mut encoder = BzEncoder::new(output, bzip2::Compression::default());
+    copy_input_file(input_file, &mut encoder)?;
+    encoder
+        .finish()
+        .and_then(|mut output| output.flush())
+        .with_context(|| format!("Failed to finish writing bzip2 file: {output_bz2:?}"))?;
+    Ok(())
+}
+
+/// Archives multiple input files and directories into a tar archive
+///
+/// `input_files` is a list of file or directory paths to archive and `output_tar` is the output archive Path.
+/// Directories are archived recursively under their own name
+///
+/// Returns `()` on success and an error with context on Error
+pub fn compress_files_to_tar(input_files: &[PathBuf], output_tar: &Path) -> Result<()> {
+    let output = create_output_file(output_tar)?;
+    write_tar(input_files, output)?
+        .flush()
+        .with_context(|| format!("Failed to finish writing tar archive: {output_tar:?}"))?;
+    Ok(())
+}
+
+/// Archives multiple input files and directories into a gzip-compressed tar archive
+///
+/// `input_files` is a list of file or directory paths to archive and `output_tgz` is the output archive Path
+///
+/// Returns `()` on success and an error with context on Error
+pub fn compress_files_to_tar_gzip(input_files: &[PathBuf], output_tgz: &Path) -> Result<()> {
+    let output = create_output_file(output_tgz)?;
+    let encoder = GzEncoder::new(output, flate2::Compression::default());
+    write_tar(input_files, encoder)?
+        .finish()
+        .and_then(|mut output| output.flush())
+        .with_context(|| format!("Failed to finish writing tar.gz archive: {output_tgz:?}"))?;
+    Ok(())
+}
+
+/// Archives multiple input files and directories into a bzip2-compressed tar archive
+///
+/// `input_files` is a list of file or directory paths to archive and `output_tbz` is the output archive Path
+///
+/// Returns `()` on success and an error with context on Error
+pub fn compress_files_to_tar_bzip2(input_files: &[PathBuf], output_tbz: &Path) -> Result<()> {
+    let output = create_output_file(output_tbz)?;
+    let encoder = BzEncoder::new(output, bzip2::Compression::default());
+    write_tar(input_files, encoder)?
+        .finish()
+        .and_then(|mut output| output.flush())
+        .with_context(|| format!("Failed to finish writing tar.bz2 archive: {output_tbz:?}"))?;
+    Ok(())
+}
+
+/// Archives multiple input files and directories into a zstd-compressed tar archive
+///
+/// `input_files` is a list of file or directory paths to archive, `output_tzst` is the output archive Path, and `options`
+/// set the level, long-distance matching, and threads
+///
+/// Returns `()` on success and an error with context on Error
+pub fn compress_files_to_tar_zstd(
+    input_files: &[PathBuf],
+    output_tzst: &Path,
+    options: &CompressionOptions,
+) -> Result<()> {
+    let level = options.level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL);
+    let levels = zstd::compression_level_range();
+    if !levels.contains(&level) {
+        bail!(
+            "zstd levels range from {} to {}, not {level}",
+            levels.start(),
+            levels.end()
+        );
+    }
+
+    let output = create_output_file(output_tzst)?;
+    let mut encoder =
+        zstd::Encoder::new(output, level).context("Failed to set up zstd compression")?;
+    if options.long_distance {
+        encoder
+            .long_distance_matching(true)
+            .and_then(|_| encoder.window_log(LONG_WINDOW_LOG))
+            .context("Failed to enable long-distance matching")?;
+    }
+    if options.threads > 1 {
+        encoder
+            .multithread(options.threads)
+            .context("Failed to enable multithreaded compression")?;
+    }
+
+    write_tar(input_files, encoder)?
+        .finish()
+        .and_then(|mut output| output.flush())
+        .with_context(|| format!("Failed to finish writing tar.zst archive: {output_tzst:?}"))?;
+    Ok(())
+}
+
+/// Archives multiple input files and directories into an xz-compressed tar archive
+///
+/// `input_files` is a list of file or directory paths to archive, `output_txz` is the output archive Path, and `options`
+/// set the level and threads
+///
+/// Returns `()` on success and an error with context on Error
+pub fn compress_files_to_tar_xz(
+    input_files: &[PathBuf],
+    output_txz: &Path,
+    options: &CompressionOptions,
+) -> Result<()> {
+    let level = options.level.unwrap_or(DEFAULT_XZ_LEVEL);
+    if !(0..=9).contains(&level) {
+        bail!("xz levels range from 0 to 9, not {level}");
+    }
+    if options.long_distance {
+        bail!("Long-distance matching is only available for zstd");
+    }
+
+    // Parallel xz splits the input into blocks that are compressed independently
+    let stream = if options.threads > 1 {
+        MtStreamBuilder::new()
+            .preset(level as u32)
+            .threads(options.threads)
+            .check(Check::Crc64)
+            .encoder()
+    } else {
+        Stream::new_easy_encoder(level as u32, Check::Crc64)
+    }
+    .context("Failed to set up xz compression")?;
+
+    let output = create_output_file(output_txz)?;
+    write_tar(input_files, XzEncoder::new_stream(output, stream))?
+        .finish()
+        .and_then(|mut output| output.flush())
+        .with_context(|| format!("Failed to finish writing tar.xz archive: {output_txz:?}"))?;
+    Ok(())
+}
+
+/// Writes a tar archive of the input files and directories
+///
+/// `input_files` is a list of file or directory paths to archive and `writer` receives the archive
+///
+/// Returns the writer after the archive's end marker, so compressed streams can be finished
+fn write_tar<W: Write>(input_files: &[PathBuf], writer: W) -> Result<W> {
+    let mut tar = tar::Builder::new(writer);
+
+    for input_file in input_files {
+        let input_name = input_file
+            .file_name()
+            .context("Missing input file name.")?;
+        tar.append_path_with_name(input_file, input_name)
+            .with_context(|| format!("Failed to add to tar archive: {input_file:?}"))?;
+
+        if input_file.is_dir() {
+            walk_directory(input_file, Path::new(input_name), |path, rel_path| {
+                tar.append_path_with_name(path, rel_path)
+                    .with_context(|| format!("Failed to add to tar archive: {path:?}"))
+            })?;
+        }
+    }
+    tar.into_inner()
+        .context("Failed to finalize tar archive.")
+}
+
+/// Adds the contents of a directory to a zip archive
+///
+/// `in_dir` is the directory to add, `prefix` is the path its contents get inside the archive, and `options` apply to every entry
+fn add_directory_to_zip<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    in_dir: &Path,
+    prefix: &Path,
+    options: SimpleFileOptions,
+) -> Result<()> {
+    walk_directory(in_dir, prefix, |path, rel_path| {
+        if path.is_file() {
+            zip.start_file(rel_path.to_string_lossy(), options)
+                .with_context(|| format!("Error: Failed to start entry file: {rel_path:?}"))?;
+            let mut f = File::open(path)
+                .with_context(|| format!("Error: Failed to open file: {path:?}"))?;
+
+            copy(&mut f, zip)
+                .with_context(|| format!("Error: Failed to copy file into archive: {path:?}"))?;
+        } else if path.is_dir() {
+            // Append slash to signal directory in zip
+            let dir_name = format!("{}/", rel_path.to_string_lossy());
+            zip.add_directory(dir_name, options)
+                .with_context(|| format!("Error: Failed to add directory: {rel_path:?}"))?;
+        }
+        Ok(())
+    })
+}
+
+/// Visits everything below a directory, recursively and in file name order
+///
+/// `in_dir` is the directory to walk, `prefix` is prepended to the relative paths, and `visit` is called with each
+/// entry's Path and its relative path
+///
+/// Returns `()` on success and the first error from the walk or `visit` on Error
+fn walk_directory(
+    in_dir: &Path,
+    prefix: &Path,
+    mut visit: impl FnMut(&Path, &Path) -> Result<()>,
+) -> Result<()> {
+    for entry in WalkDir::new(in_dir).sort_by_file_name() {
+        let entry = entry?;
+        let path = entry.path();
+
+        // Skip the root directory
+        if path == in_dir {
+            continue;
+        }
+
+        let rel_path = prefix.join(
+            path.strip_prefix(in_dir)
+                .context("Error: Failed to compute relative path.")?,
+        );
+        visit(path, &rel_path)?;
+    }
+    Ok(())
+}
+
+/// Creates an output file for a compressed stream
+fn create_output_file(output: &Path) -> Result<BufWriter<File>> {
+    let file = File::create(output)
+        .with_context(|| format!("Failed to create output file: {output:?}"))?;
+    Ok(BufWriter::new(file))
+}
+
+/// Copies an input file into a compressing writer
+fn copy_input_file(input_file: &Path, writer: &mut impl Write) -> Result<()> {
+    let mut input = File::open(input_file)
+        .with_context(|| format!("Failed to open input file: {input_file:?}"))?;
+    copy(&mut input, writer)
+        .with_context(|| format!("Failed to compress input file: {input_file:?}"))?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use anyhow::Result;
     use std::fs;
-    use std::io::Write;
     use tempfile::tempdir;
     use zip::ZipArchive;
 
@@ -252,4 +507,56 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_high_ratio_tar_options() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let input_dir = temp_dir.path().join("cache");
+        fs::create_dir_all(&input_dir)?;
+        let contents = "build output that repeats\n".repeat(10_000);
+        fs::write(input_dir.join("object.o"), &contents)?;
+        let inputs = [input_dir];
+
+        // Long-distance matching and threads still produce a standard zstd stream
+        let options = CompressionOptions {
+            level: Some(19),
+            long_distance: true,
+            threads: 2,
+        };
+        let output_zst = temp_dir.path().join("cache.tar.zst");
+        compress_files_to_tar_zstd(&inputs, &output_zst, &options)?;
+        let mut decoder = zstd::Decoder::new(File::open(&output_zst)?)?;
+        decoder.window_log_max(LONG_WINDOW_LOG)?;
+        let mut archive = tar::Archive::new(decoder);
+        let names: Vec<String> = archive
+            .entries()?
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+            .collect();
+        assert_eq!(names, vec!["cache", "cache/object.o"]);
+
+        // Parallel xz output is much smaller than its input
+        let options = CompressionOptions {
+            level: Some(9),
+            threads: 2,
+            ..Default::default()
+        };
+        let output_xz = temp_dir.path().join("cache.tar.xz");
+        compress_files_to_tar_xz(&inputs, &output_xz, &options)?;
+        assert!(fs::metadata(&output_xz)?.len() < contents.len() as u64 / 100);
+
+        // Levels outside each format's range and options a format lacks are rejected
+        let level_23 = CompressionOptions {
+            level: Some(23),
+            ..Default::default()
+        };
+        assert!(compress_files_to_tar_zstd(&inputs, &output_zst, &level_23).is_err());
+        assert!(compress_files_to_tar_xz(&inputs, &output_xz, &level_23).is_err());
+        let long = CompressionOptions {
+            long_distance: true,
+            ..Default::default()
+        };
+        assert!(compress_files_to_tar_xz(&inputs, &output_xz, &long).is_err());
+
+        Ok(())
+    }
 }
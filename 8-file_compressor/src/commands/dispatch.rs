@@ -0,0 +1,340 @@
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+
+use super::{
+    CompressionOptions, compress_directory_to_zip_directory, compress_file_to_bzip2,
+    compress_file_to_gzip, compress_files_to_tar, compress_files_to_tar_bzip2,
+    compress_files_to_tar_gzip, compress_files_to_tar_xz, compress_files_to_tar_zstd,
+    compress_files_to_zip_directory, compress_single_file_to_zip, extract_tar_bzip2_to_directory,
+    extract_tar_gzip_to_directory, extract_tar_to_directory, extract_tar_xz_to_directory,
+    extract_tar_zstd_to_directory, extract_zip_to_directory,
+};
+
+/// Archive formats the CLI can create
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Zip,
+    Gzip,
+    Bzip2,
+    Tar,
+    TarGzip,
+    TarBzip2,
+    TarZstd,
+    TarXz,
+}
+
+/// A backend handling one file, given its input Path and output Path
+type Backend = fn(&Path, &Path) -> Result<()>;
+
+/// File name suffixes of each format, with compound suffixes before the ones they end with
+const FORMAT_SUFFIXES: &[(&str, Format)] = &[
+    (".tar.gz", Format::TarGzip),
+    (".tgz", Format::TarGzip),
+    (".tar.bz2", Format::TarBzip2),
+    (".tbz2", Format::TarBzip2),
+    (".tar.zst", Format::TarZstd),
+    (".tzst", Format::TarZstd),
+    (".tar.xz", Format::TarXz),
+    (".txz", Format::TarXz),
+    (".tar", Format::Tar),
+    (".zip", Format::Zip),
+    (".gz", Format::Gzip),
+    (".bz2", Format::Bzip2),
+];
+
+impl Format {
+    /// Returns the file extension of files in this format
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Zip => "zip",
+            Format::Gzip => "gz",
+            Format::Bzip2 => "bz2",
+            Format::Tar => "tar",
+            Format::TarGzip => "tar.gz",
+            Format::TarBzip2 => "tar.bz2",
+            Format::TarZstd => "tar.zst",
+            Format::TarXz => "tar.xz",
+        }
+    }
+
+    /// Recognizes the format of a file from its name, like `release.tgz`
+    ///
+    /// Returns the Format, or None if the name doesn't end with a known suffix
+    pub fn from_path(path: &Path) -> Option<Format> {
+        split_format_suffix(path).map(|(_, format)| format)
+    }
+}
+
+/// Compresses the input files and directories in the selected format
+///
+/// `input_files` are the validated inputs and `output` is the output argument. ZIP and tar formats put every input
+/// into one archive, while GZIP and BZIP2 compress each file on its own, into a directory at `output` when there are
+/// several. Output names get the format's extension unless they already end with one of its suffixes. The `options`
+/// tune the zstd and xz tar formats, and are rejected for the others rather than silently ignored
+///
+/// Returns `()` on success and an error with context on Error
+pub fn compress(
+    input_files: &[PathBuf],
+    output: &Path,
+    format: Format,
+    options: &CompressionOptions,
+) -> Result<()> {
+    let output_file = with_extension(output, format);
+
+    if !matches!(format, Format::TarZstd | Format::TarXz)
+        && *options != CompressionOptions::default()
+    {
+        bail!("{format:?} doesn't take a level, long-distance matching, or threads");
+    }
+
+    match (format, input_files) {
+        // ZIP archives hold any mix of files and directories
+        (Format::Zip, [input]) if input.is_dir() => {
+            return compress_directory_to_zip_directory(input, &output_file);
+        }
+        (Format::Zip, [input]) => return compress_single_file_to_zip(input, &output_file),
+        (Format::Zip, _) => return compress_files_to_zip_directory(input_files, &output_file),
+
+        // So do tar archives, which keep directories under their own name
+        (Format::Tar, _) => return compress_files_to_tar(input_files, &output_file),
+        (Format::TarGzip, _) => return compress_files_to_tar_gzip(input_files, &output_file),
+        (Format::TarBzip2, _) => return compress_files_to_tar_bzip2(input_files, &output_file),
+        (Format::TarZstd, _) => {
+            return compress_files_to_tar_zstd(input_files, &output_file, options);
+        }
+        (Format::TarXz, _) => return compress_files_to_tar_xz(input_files, &output_file, options),
+        (Format::Gzip | Format::Bzip2, _) => {}
+    }
+
+    // Other formats compress a single stream, so they can't hold directories
+    if let Some(dir) = input_files.iter().find(|input| input.is_dir()) {
+        bail!(
+            "{format:?} compresses single files, so it can't archive the directory {}",
+            dir.display()
+        );
+    }
+    let compress_file: Backend = match format {
+        Format::Gzip => compress_file_to_gzip,
+        _ => compress_file_to_bzip2,
+    };
+
+    if let [input] = input_files {
+        return compress_file(input, &output_file);
+    }
+    fs::create_dir_all(output)
+        .with_context(|| format!("Failed to create output directory: {}", output.display()))?;
+    for input in input_files {
+        let name = input.file_name().context("Missing input file name.")?;
+        compress_file(input, &with_extension(&output.join(name), format))?;
+    }
+    Ok(())
+}
+
+/// Extracts the input archives into the output directory, recognizing their format by name
+///
+/// `archives` are the validated archives and `out_dir` is the output argument. A single archive is extracted
+/// straight into `out_dir`, while multiple archives each get a directory named after them
+///
+/// Returns `()` on success and an error with context on Error
+pub fn extract(archives: &[PathBuf], out_dir: &Path) -> Result<()> {
+    // Check every archive before extracting any of them
+    let mut jobs: Vec<(&PathBuf, PathBuf, Backend)> = vec![];
+    for archive in archives {
+        let extract_archive: Backend = match Format::from_path(archive) {
+            Some(Format::Zip) => extract_zip_to_directory,
+            Some(Format::Tar) => extract_tar_to_directory,
+            Some(Format::TarGzip) => extract_tar_gzip_to_directory,
+            Some(Format::TarBzip2) => extract_tar_bzip2_to_directory,
+            Some(Format::TarZstd) => extract_tar_zstd_to_directory,
+            Some(Format::TarXz) => extract_tar_xz_to_directory,
+            _ => bail!(
+                "Extracting this archive format isn't supported yet: {}",
+                archive.display()
+            ),
+        };
+
+        // Multiple archives each get a directory named after them, which no two may share
+        let destination = if archives.len() == 1 {
+            out_dir.to_path_buf()
+        } else {
+            let (stem, _) = split_format_suffix(archive).context("Missing archive file name.")?;
+            out_dir.join(stem)
+        };
+        if let Some((other, ..)) = jobs.iter().find(|(_, dir, _)| *dir == destination) {
+            bail!(
+                "{} and {} would both extract into {}",
+                other.display(),
+                archive.display(),
+                destination.display()
+            );
+        }
+        jobs.push((archive, destination, extract_archive));
+    }
+
+    for (archive, destination, extract_archive) in jobs {
+        extract_archive(archive, &destination)?;
+    }
+    Ok(())
+}
+
+/// Splits a file name into its stem and the format its suffix names
+///
+/// Returns the stem and Format, or None if the name doesn't end with a known suffix
+fn split_format_suffix(path: &Path) -> Option<(&str, Format)> {
+    let name = path.file_name()?.to_str()?;
+    FORMAT_SUFFIXES.iter().find_map(|&(suffix, format)| {
+        name.strip_suffix(suffix)
+            .filter(|stem| !stem.is_empty())
+            .map(|stem| (stem, format))
+    })
+}
+
+/// Appends the format's extension to a path unless its file name already names the format
+///
+/// `path` is the output path and `format` is the format being written
+///
+/// Returns the path with the extension
+fn with_extension(path: &Path, format: Format) -> PathBuf {
+    if Format::from_path(path) == Some(format) {
+        return path.to_path_buf();
+    }
+    let mut name = OsString::from(path);
+    name.push(format!(".{}", format.extension()));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_compress_dispatches_by_format() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let input_1 = temp_dir.path().join("file1.txt");
+        let input_2 = temp_dir.path().join("file2.log");
+        fs::write(&input_1, "Hello world!")?;
+        fs::write(&input_2, "I will be back...")?;
+
+        // Output names get the format's extension once
+        assert_eq!(
+            with_extension(Path::new("out"), Format::Zip),
+            Path::new("out.zip")
+        );
+        assert_eq!(
+            with_extension(Path::new("out.zip"), Format::Zip),
+            Path::new("out.zip")
+        );
+        assert_eq!(
+            with_extension(Path::new("release.tgz"), Format::TarGzip),
+            Path::new("release.tgz")
+        );
+        assert_eq!(
+            with_extension(Path::new("notes.gz"), Format::TarGzip),
+            Path::new("notes.gz.tar.gz")
+        );
+        compress(
+            std::slice::from_ref(&input_1),
+            &temp_dir.path().join("single"),
+            Format::Zip,
+            &CompressionOptions::default(),
+        )?;
+        assert!(temp_dir.path().join("single.zip").exists());
+
+        // Several files compress one by one into a directory
+        let out_dir = temp_dir.path().join("gzipped");
+        compress(
+            &[input_1.clone(), input_2.clone()],
+            &out_dir,
+            Format::Gzip,
+            &CompressionOptions::default(),
+        )?;
+        let mut contents = String::new();
+        flate2::read::GzDecoder::new(fs::File::open(out_dir.join("file2.log.gz"))?)
+            .read_to_string(&mut contents)?;
+        assert_eq!(contents, "I will be back...");
+
+        compress(
+            &[input_1],
+            &temp_dir.path().join("file1.txt"),
+            Format::Bzip2,
+            &CompressionOptions::default(),
+        )?;
+        let mut contents = String::new();
+        bzip2::read::BzDecoder::new(fs::File::open(temp_dir.path().join("file1.txt.bz2"))?)
+            .read_to_string(&mut contents)?;
+        assert_eq!(contents, "Hello world!");
+
+        // Stream formats can't hold directories
+        let err = compress(
+            &[out_dir],
+            &temp_dir.path().join("dir"),
+            Format::Gzip,
+            &CompressionOptions::default(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("can't archive the directory"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_recognizes_archive_names() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let input_dir = temp_dir.path().join("project");
+        fs::create_dir_all(input_dir.join("src"))?;
+        fs::write(input_dir.join("src/main.rs"), "fn main() {}")?;
+
+        // Each archive format extracts into a directory named after its archive
+        let archives = [
+            temp_dir.path().join("a.zip"),
+            temp_dir.path().join("b.tar"),
+            temp_dir.path().join("c.tgz"),
+            temp_dir.path().join("d.tar.bz2"),
+            temp_dir.path().join("e.tar.zst"),
+            temp_dir.path().join("f.txz"),
+        ];
+        for archive in &archives {
+            let format = Format::from_path(archive).unwrap();
+            compress(
+                std::slice::from_ref(&input_dir),
+                archive,
+                format,
+                &CompressionOptions::default(),
+            )?;
+        }
+        let out_dir = temp_dir.path().join("out");
+        extract(&archives, &out_dir)?;
+        assert!(out_dir.join("a/src/main.rs").exists());
+        for stem in ["b", "c", "d", "e", "f"] {
+            let extracted = out_dir.join(stem).join("project/src/main.rs");
+            assert_eq!(fs::read_to_string(extracted)?, "fn main() {}");
+        }
+
+        // Nothing is extracted when one of the archives isn't supported
+        let err = extract(
+            &[archives[0].clone(), temp_dir.path().join("g.gz")],
+            &temp_dir.path().join("partial"),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("isn't supported yet"));
+        assert!(!temp_dir.path().join("partial").exists());
+
+        // Nor when two archives share a name apart from their format
+        let same_stem = temp_dir.path().join("c.tar");
+        fs::copy(&archives[1], &same_stem)?;
+        let err = extract(
+            &[archives[2].clone(), same_stem],
+            &temp_dir.path().join("partial"),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("would both extract into"));
+        assert!(!temp_dir.path().join("partial").exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,578 @@
+use std::fs::{self, File};
+use std::io::{BufReader, Read, copy};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use bzip2::read::BzDecoder;
+use flate2::read::GzDecoder;
+use liblzma::read::XzDecoder;
+use tar::EntryType;
+use zip::ZipArchive;
+
+use super::LONG_WINDOW_LOG;
+
+/// Extracts every entry of a zip archive into the specified output directory
+///
+/// `archive_path` is the Path of the archive to extract and `out_dir` is the destination directory, created if missing.
+/// Entries whose paths or symlink targets escape `out_dir` are rejected
+///
+/// Returns `()` on success and an error naming the failing entry on Error
+pub fn extract_zip_to_directory(archive_path: &Path, out_dir: &Path) -> Result<()> {
+    let file = File::open(archive_path)
+        .with_context(|| format!("Failed to open archive: {archive_path:?}"))?;
+    let mut archive = ZipArchive::new(BufReader::new(file))
+        .with_context(|| format!("Failed to read zip archive: {archive_path:?}"))?;
+    let mut destination = Destination::create(out_dir)?;
+
+    for i in 0..archive.len() {
+        let mut entry = archive
+            .by_index(i)
+            .with_context(|| format!("Failed to read entry {i} of {archive_path:?}"))?;
+        let name = PathBuf::from(entry.name());
+        let context = || format!("Failed to extract {name:?} from {archive_path:?}");
+
+        if entry.is_dir() {
+            destination.add_dir(&name).with_context(context)?;
+        } else if entry.is_symlink() {
+            let mut target = String::new();
+            entry.read_to_string(&mut target).with_context(context)?;
+            destination
+                .add_symlink(&name, Path::new(&target))
+                .with_context(context)?;
+        } else {
+            let mode = entry.unix_mode();
+            destination
+                .add_file(&name, &mut entry, mode)
+                .with_context(context)?;
+        }
+    }
+
+    destination
+        .finish()
+        .with_context(|| format!("Failed to extract links from {archive_path:?}"))
+}
+
+/// Extracts every entry of a tar archive into the specified output directory
+///
+/// `archive_path` is the Path of the `.tar` archive and `out_dir` is the destination directory, created if missing
+///
+/// Returns `()` on success and an error naming the failing entry on Error
+pub fn extract_tar_to_directory(archive_path: &Path, out_dir: &Path) -> Result<()> {
+    let file = open_archive(archive_path)?;
+    unpack_tar(file, archive_path, out_dir)
+}
+
+/// Extracts every entry of a gzip-compressed tar archive into the specified output directory
+///
+/// `archive_path` is the Path of the `.tar.gz` or `.tgz` archive and `out_dir` is the destination directory, created if missing
+///
+/// Returns `()` on success and an error naming the failing entry on Error
+pub fn extract_tar_gzip_to_directory(archive_path: &Path, out_dir: &Path) -> Result<()> {
+    let file = open_archive(archive_path)?;
+    unpack_tar(GzDecoder::new(file), archive_path, out_dir)
+}
+
+/// Extracts every entry of a bzip2-compressed tar archive into the specified output directory
+///
+/// `archive_path` is the Path of the `.tar.bz2` archive and `out_dir` is the destination directory, created if missing
+///
+/// Returns `()` on success and an error naming the failing entry on Error
+pub fn extract_tar_bzip2_to_directory(archive_path: &Path, out_dir: &Path) -> Result<()> {
+    let file = open_archive(archive_path)?;
+    unpack_tar(BzDecoder::new(file), archive_path, out_dir)
+}
+
+/// Extracts every entry of a zstd-compressed tar archive into the specified output directory
+///
+/// `archive_path` is the Path of the `.tar.zst` archive and `out_dir` is the destination directory, created if missing.
+/// Archives made with long-distance matching are accepted, but larger windows are refused so a crafted archive can't
+/// make extraction allocate gigabytes
+///
+/// Returns `()` on success and an error naming the failing entry on Error
+pub fn extract_tar_zstd_to_directory(archive_path: &Path, out_dir: &Path) -> Result<()> {
+    let file = open_archive(archive_path)?;
+    let mut decoder = zstd::Decoder::with_buffer(file)
+        .with_context(|| format!("Failed to read zstd archive: {archive_path:?}"))?;
+    decoder
+        .window_log_max(LONG_WINDOW_LOG)
+        .context("Failed to set up zstd decompression")?;
+    unpack_tar(decoder, archive_path, out_dir)
+}
+
+/// Extracts every entry of an xz-compressed tar archive into the specified output directory
+///
+/// `archive_path` is the Path of the `.tar.xz` archive and `out_dir` is the destination directory, created if missing
+///
+/// Returns `()` on success and an error naming the failing entry on Error
+pub fn extract_tar_xz_to_directory(archive_path: &Path, out_dir: &Path) -> Result<()> {
+    let file = open_archive(archive_path)?;
+    unpack_tar(XzDecoder::new_multi_decoder(file), archive_path, out_dir)
+}
+
+/// Opens an archive for reading
+fn open_archive(archive_path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(archive_path)
+        .with_context(|| format!("Failed to open archive: {archive_path:?}"))?;
+    Ok(BufReader::new(file))
+}
+
+/// Extracts the entries of a decompressed tar stream
+///
+/// `reader` yields the tar stream, `archive_path` names the archive in errors, and `out_dir` is the destination directory.
+/// Devices, FIFOs and other special entries are skipped
+fn unpack_tar(reader: impl Read, archive_path: &Path, out_dir: &Path) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    let mut destination = Destination::create(out_dir)?;
+
+    let entries = archive
+        .entries()
+        .with_context(|| format!("Failed to read tar archive: {archive_path:?}"))?;
+    for entry in entries {
+        let mut entry =
+            entry.with_context(|| format!("Failed to read entry of {archive_path:?}"))?;
+        let name = entry
+            .path()
+            .with_context(|| format!("Failed to read entry path in {archive_path:?}"))?
+            .into_owned();
+        let context = || format!("Failed to extract {name:?} from {archive_path:?}");
+
+        let entry_type = entry.header().entry_type();
+        let link_name = entry.link_name().with_context(context)?;
+        match (entry_type, link_name) {
+            (EntryType::Directory, _) => destination.add_dir(&name).with_context(context)?,
+            (EntryType::Symlink, Some(target)) => destination
+                .add_symlink(&name, &target)
+                .with_context(context)?,
+            (EntryType::Link, Some(target)) => destination
+                .add_hard_link(&name, &target)
+                .with_context(context)?,
+            (EntryType::Regular | EntryType::Continuous, _) => {
+                let mode = entry.header().mode().ok();
+                destination
+                    .add_file(&name, &mut entry, mode)
+                    .with_context(context)?
+            }
+            _ => {}
+        }
+    }
+
+    destination
+        .finish()
+        .with_context(|| format!("Failed to extract links from {archive_path:?}"))
+}
+
+/// A directory archive entries are extracted into, which no entry may write outside of
+struct Destination {
+    base_path: PathBuf,
+    // Links are created after everything else, so no entry can be written through one
+    links: Vec<(PathBuf, Link)>,
+}
+
+/// A link entry waiting to be created, with its target
+enum Link {
+    Symbolic(PathBuf),
+    Hard(PathBuf),
+}
+
+impl Destination {
+    /// Creates the output directory if missing
+    fn create(out_dir: &Path) -> Result<Destination> {
+        fs::create_dir_all(out_dir)
+            .with_context(|| format!("Failed to create output directory: {out_dir:?}"))?;
+        let base_path = out_dir
+            .canonicalize()
+            .context("Failed to canonicalize output directory.")?;
+        Ok(Destination {
+            base_path,
+            links: vec![],
+        })
+    }
+
+    /// Creates a directory entry, and any missing parents
+    fn add_dir(&self, name: &Path) -> Result<()> {
+        let rel_path = enclosed_path(name)?;
+        create_enclosed_dirs(&self.base_path, &rel_path)
+    }
+
+    /// Writes a regular file entry
+    ///
+    /// `name` is the entry path, `contents` the entry data, and `mode` its unix permissions if stored
+    fn add_file(&self, name: &Path, contents: &mut impl Read, mode: Option<u32>) -> Result<()> {
+        let rel_path = enclosed_path(name)?;
+        if let Some(parent) = rel_path.parent() {
+            create_enclosed_dirs(&self.base_path, parent)?;
+        }
+        let out_path = self.base_path.join(&rel_path);
+
+        // Replace an existing symlink rather than writing to wherever it points
+        if fs::symlink_metadata(&out_path).is_ok_and(|meta| meta.file_type().is_symlink()) {
+            fs::remove_file(&out_path)
+                .with_context(|| format!("Failed to replace symlink: {out_path:?}"))?;
+        }
+
+        let mut output = File::create(&out_path)
+            .with_context(|| format!("Failed to create file: {out_path:?}"))?;
+        copy(contents, &mut output)
+            .with_context(|| format!("Failed to write file: {out_path:?}"))?;
+
+        #[cfg(unix)]
+        if let Some(mode) = mode {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&out_path, fs::Permissions::from_mode(mode & 0o777))
+                .with_context(|| format!("Failed to set permissions: {out_path:?}"))?;
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+
+        Ok(())
+    }
+
+    /// Checks a symlink entry, which is created by `finish`
+    fn add_symlink(&mut self, name: &Path, target: &Path) -> Result<()> {
+        let rel_path = enclosed_path(name)?;
+        check_link_target(&rel_path, target)?;
+        self.links
+            .push((rel_path, Link::Symbolic(target.to_path_buf())));
+        Ok(())
+    }
+
+    /// Checks a hard link entry, whose target is another entry path, and which is created by `finish`
+    fn add_hard_link(&mut self, name: &Path, target: &Path) -> Result<()> {
+        let rel_path = enclosed_path(name)?;
+        let rel_target = enclosed_path(target)?;
+        self.links.push((rel_path, Link::Hard(rel_target)));
+        Ok(())
+    }
+
+    /// Creates the link entries, hard links first so they never go through the symlinks
+    ///
+    /// Returns `()` on success and an error naming the failing link on Error
+    fn finish(mut self) -> Result<()> {
+        self.links
+            .sort_by_key(|(_, link)| matches!(link, Link::Symbolic(_)));
+        for (rel_path, link) in &self.links {
+            let context = || format!("Failed to create link: {rel_path:?}");
+            match link {
+                Link::Hard(rel_target) => {
+                    create_hard_link(&self.base_path, rel_path, rel_target).with_context(context)?
+                }
+                Link::Symbolic(target) => {
+                    create_symlink(&self.base_path, rel_path, target).with_context(context)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Turns an archive entry name into a path relative to the destination
+///
+/// `name` is the entry name as stored in the archive
+///
+/// Returns the relative path, or an error if the name is absolute or climbs out with `..`
+fn enclosed_path(name: &Path) -> Result<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => bail!("Entry path escapes the destination: {name:?}"),
+        }
+    }
+    if path.as_os_str().is_empty() {
+        bail!("Entry has an empty path: {name:?}");
+    }
+    Ok(path)
+}
+
+/// Creates the directories of a relative path under the destination, one level at a time
+///
+/// `base_path` is the canonical destination and `rel_dir` is the directory path inside it.
+/// Existing symlinks along the way must resolve inside `base_path`, so nothing is created elsewhere
+fn create_enclosed_dirs(base_path: &Path, rel_dir: &Path) -> Result<()> {
+    let mut dir = base_path.to_path_buf();
+    for part in rel_dir.components() {
+        dir.push(part);
+        match fs::symlink_metadata(&dir) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                let resolved = dir
+                    .canonicalize()
+                    .with_context(|| format!("Failed to resolve symlink: {dir:?}"))?;
+                if !resolved.starts_with(base_path) || !resolved.is_dir() {
+                    bail!("Symlink leads outside the destination: {dir:?}");
+                }
+            }
+            Ok(meta) if !meta.is_dir() => bail!("Not a directory: {dir:?}"),
+            Ok(_) => {}
+            Err(_) => fs::create_dir(&dir)
+                .with_context(|| format!("Failed to create directory: {dir:?}"))?,
+        }
+    }
+    Ok(())
+}
+
+/// Checks that a symlink target stays inside the destination, judging by the link's own path
+///
+/// `rel_path` is the link's path inside the destination and `target` is what it points to
+fn check_link_target(rel_path: &Path, target: &Path) -> Result<()> {
+    let mut depth = rel_path.components().count() - 1;
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            _ => bail!("Symlink target escapes the destination: {target:?}"),
+        }
+    }
+    Ok(())
+}
+
+/// Creates a hard link to an already extracted file
+///
+/// `base_path` is the canonical destination, and `rel_path` and `rel_target` are the link and its target inside it
+fn create_hard_link(base_path: &Path, rel_path: &Path, rel_target: &Path) -> Result<()> {
+    let source = base_path
+        .join(rel_target)
+        .canonicalize()
+        .with_context(|| format!("Missing hard link target: {rel_target:?}"))?;
+    if !source.starts_with(base_path) {
+        bail!("Hard link target leads outside the destination: {rel_target:?}");
+    }
+    if let Some(parent) = rel_path.parent() {
+        create_enclosed_dirs(base_path, parent)?;
+    }
+    let out_path = base_path.join(rel_path);
+    fs::hard_link(&source, &out_path)
+        .with_context(|| format!("Failed to create hard link: {out_path:?}"))?;
+    Ok(())
+}
+
+/// Creates a symlink, then checks where it actually resolves
+///
+/// `base_path` is the canonical destination, `rel_path` the link's path inside it, and `target` what it points to.
+/// Links resolving outside `base_path` through other links are removed again
+fn create_symlink(base_path: &Path, rel_path: &Path, target: &Path) -> Result<()> {
+    if let Some(parent) = rel_path.parent() {
+        create_enclosed_dirs(base_path, parent)?;
+    }
+    let out_path = base_path.join(rel_path);
+
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(target, &out_path)
+        .with_context(|| format!("Failed to create symlink: {out_path:?}"))?;
+    #[cfg(not(unix))]
+    bail!("Symlinks can only be extracted on unix: {out_path:?}");
+
+    // Dangling links are left alone, since their target path was already checked
+    if let Ok(resolved) = out_path.canonicalize()
+        && !resolved.starts_with(base_path)
+    {
+        fs::remove_file(&out_path)?;
+        bail!("Symlink leads outside the destination: {out_path:?}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::compress_directory_to_zip_directory;
+    use std::io::Write;
+    use tar::Header;
+    use tempfile::tempdir;
+    use zip::{ZipWriter, write::SimpleFileOptions};
+
+    /// Helper function to write a zip archive from raw entries
+    ///
+    /// `path` is the archive to create. `files` are entry names and contents, and `links` are symlink names and targets
+    fn create_zip(path: &Path, files: &[(&str, &str)], links: &[(&str, &str)]) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        let options = SimpleFileOptions::default();
+        for (name, contents) in files {
+            zip.start_file(*name, options).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        for (name, target) in links {
+            zip.add_symlink(*name, *target, options).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    /// Helper function to write a tar archive from raw entries, without the checks `tar::Builder` makes on names
+    ///
+    /// `path` is the archive to create. `entries` are entry names, types, and contents or link targets
+    fn create_tar(path: &Path, entries: &[(&str, EntryType, &str)]) {
+        let mut tar = tar::Builder::new(File::create(path).unwrap());
+        for (name, entry_type, data) in entries {
+            let mut header = Header::new_old();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_entry_type(*entry_type);
+            header.set_mode(0o644);
+            let contents = if entry_type.is_file() {
+                data.as_bytes()
+            } else {
+                header.as_old_mut().linkname[..data.len()].copy_from_slice(data.as_bytes());
+                b""
+            };
+            header.set_size(contents.len() as u64);
+            header.set_cksum();
+            tar.append(&header, contents).unwrap();
+        }
+        tar.finish().unwrap();
+    }
+
+    #[test]
+    fn test_directory_archive_round_trip() -> Result<()> {
+        // Create nested input directories and archive them
+        let temp_dir = tempdir()?;
+        let input_dir = temp_dir.path().join("input");
+        fs::create_dir_all(input_dir.join("sub/deeper"))?;
+        fs::write(input_dir.join("file1.txt"), "Hello world!")?;
+        fs::write(input_dir.join("sub/deeper/file2.log"), "I will be back...")?;
+        let archive = temp_dir.path().join("input.zip");
+        compress_directory_to_zip_directory(&input_dir, &archive)?;
+
+        // Extract into a directory that doesn't exist yet
+        let output_dir = temp_dir.path().join("output");
+        extract_zip_to_directory(&archive, &output_dir)?;
+        assert_eq!(
+            fs::read_to_string(output_dir.join("file1.txt"))?,
+            "Hello world!"
+        );
+        assert_eq!(
+            fs::read_to_string(output_dir.join("sub/deeper/file2.log"))?,
+            "I will be back..."
+        );
+
+        // Symlinks to extracted files are kept
+        let second = temp_dir.path().join("second.zip");
+        create_zip(&second, &[("notes/a.txt", "A")], &[("notes/link", "a.txt")]);
+        extract_zip_to_directory(&second, &output_dir)?;
+        assert_eq!(fs::read_to_string(output_dir.join("notes/link"))?, "A");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_entries_escaping_destination() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let output_dir = temp_dir.path().join("output");
+
+        // Parent components and absolute paths
+        for name in ["../evil.txt", "sub/../../evil.txt", "/tmp/evil.txt"] {
+            let archive = temp_dir.path().join("slip.zip");
+            create_zip(&archive, &[(name, "pwned")], &[]);
+            let err = extract_zip_to_directory(&archive, &output_dir).unwrap_err();
+            assert!(
+                format!("{err:#}").contains("escapes the destination"),
+                "{err:#}"
+            );
+        }
+        assert!(!temp_dir.path().join("evil.txt").exists());
+
+        // Symlinks pointing outside, directly or through another link
+        let archive = temp_dir.path().join("links.zip");
+        create_zip(&archive, &[], &[("link", "../../outside")]);
+        assert!(extract_zip_to_directory(&archive, &output_dir).is_err());
+        create_zip(&archive, &[], &[("up", "."), ("sub/link", "../up/..")]);
+        let err = extract_zip_to_directory(&archive, &output_dir).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("outside the destination"),
+            "{err:#}"
+        );
+        assert!(!output_dir.join("sub/link").exists());
+
+        // Existing links in the destination aren't followed out of it
+        #[cfg(unix)]
+        {
+            let outside = temp_dir.path().join("outside");
+            fs::create_dir_all(&outside)?;
+            std::os::unix::fs::symlink(&outside, output_dir.join("escape"))?;
+            create_zip(&archive, &[("escape/evil.txt", "pwned")], &[]);
+            assert!(extract_zip_to_directory(&archive, &output_dir).is_err());
+            assert!(!outside.join("evil.txt").exists());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tar_entries_stay_in_destination() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let output_dir = temp_dir.path().join("output");
+
+        // Links are created once the files they point to exist, whatever the entry order
+        let archive = temp_dir.path().join("links.tar");
+        create_tar(
+            &archive,
+            &[
+                ("docs/", EntryType::Directory, ""),
+                ("docs/current", EntryType::Symlink, "v2/guide.txt"),
+                ("docs/v2/guide.txt", EntryType::Regular, "Read me"),
+                ("docs/copy.txt", EntryType::Link, "docs/v2/guide.txt"),
+            ],
+        );
+        extract_tar_to_directory(&archive, &output_dir)?;
+        assert_eq!(
+            fs::read_to_string(output_dir.join("docs/current"))?,
+            "Read me"
+        );
+        assert_eq!(
+            fs::read_to_string(output_dir.join("docs/copy.txt"))?,
+            "Read me"
+        );
+
+        // Paths, symlinks, and hard links escaping the destination
+        let escapes = [
+            ("../evil.txt", EntryType::Regular, "pwned"),
+            ("/tmp/evil.txt", EntryType::Regular, "pwned"),
+            ("link", EntryType::Symlink, "/etc"),
+            ("docs/link", EntryType::Symlink, "../../etc"),
+            ("copy", EntryType::Link, "../outside.txt"),
+        ];
+        for entry in escapes {
+            create_tar(&archive, &[entry]);
+            let err = extract_tar_to_directory(&archive, &output_dir).unwrap_err();
+            assert!(
+                format!("{err:#}").contains("escapes the destination"),
+                "{err:#}"
+            );
+        }
+        assert!(!temp_dir.path().join("evil.txt").exists());
+        assert!(!output_dir.join("link").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_refuses_oversized_zstd_windows() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let archive = temp_dir.path().join("big-window.tar.zst");
+
+        // A window larger than `--long` uses would need a matching allocation to decode
+        let tar_bytes = {
+            let mut tar = tar::Builder::new(vec![]);
+            let mut header = Header::new_gnu();
+            header.set_size(5);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, "a.txt", &b"Hello"[..])?;
+            tar.into_inner()?
+        };
+        let mut encoder = zstd::Encoder::new(File::create(&archive)?, 3)?;
+        encoder.long_distance_matching(true)?;
+        encoder.window_log(LONG_WINDOW_LOG + 1)?;
+        encoder.write_all(&tar_bytes)?;
+        encoder.finish()?;
+
+        let output_dir = temp_dir.path().join("output");
+        let err = extract_tar_zstd_to_directory(&archive, &output_dir).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("Frame requires too much memory"),
+            "{err:#}"
+        );
+        assert!(!output_dir.join("a.txt").exists());
+
+        Ok(())
+    }
+}
@@ -3,11 +3,11 @@ use clap::{Args, Parser};
 
 use std::path::PathBuf;
 use walkdir::WalkDir;
-use crate::commands::{compress_directory_to_zip_directory, compress_files_to_zip_directory, compress_single_file_to_zip};
+use crate::commands::{CompressionOptions, Format, compress, extract};
 
 mod commands;
 
-const SUPPORTED_ARCHIVE_FORMATS: &[&str] = &["tar.gz", "tar.xz", "zip", "zip64", "bz2", "gz", "xz"];
+const SUPPORTED_ARCHIVE_FORMATS: &[&str] = &["tar", "tar.gz", "tgz", "tar.bz2", "tbz2", "tar.zst", "tzst", "tar.xz", "txz", "zip", "zip64", "bz2", "gz", "xz"];
 
 #[derive(Parser)]
 #[command(
@@ -30,12 +30,24 @@ struct Cli {
 
     #[command(flatten)]
     schema: Option<Schema>,
+
+    /// Compression level for `--tar-zst` (1 to 22, or negative for faster) and `--tar-xz` (0 to 9)
+    #[arg(long, allow_negative_numbers = true)]
+    level: Option<i32>,
+
+    /// Use zstd long-distance matching, which finds repeats across a 128 MiB window
+    #[arg(long)]
+    long: bool,
+
+    /// Number of threads for `--tar-zst` and `--tar-xz` compression, with 0 using one per CPU
+    #[arg(long, default_value_t = 1)]
+    threads: u32,
 }
 
 #[derive(Args)]
 #[group(required = false, multiple = false)]
 struct Schema {
-    /// Use ZIP compression (Default)
+    /// Use ZIP compression (Default, unless the output name ends with another format's extension)
     #[arg(long)]
     zip: bool,
 
@@ -46,54 +58,64 @@ struct Schema {
     /// Use GZIP compression
     #[arg(long)]
     gzip: bool,
+
+    /// Use an uncompressed TAR archive
+    #[arg(long)]
+    tar: bool,
+
+    /// Use a TAR archive with GZIP compression
+    #[arg(long)]
+    tar_gzip: bool,
+
+    /// Use a TAR archive with BZIP2 compression
+    #[arg(long)]
+    tar_bzip2: bool,
+
+    /// Use a TAR archive with ZSTD compression
+    #[arg(long)]
+    tar_zst: bool,
+
+    /// Use a TAR archive with XZ compression
+    #[arg(long, alias = "tar-xz2")]
+    tar_xz: bool,
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
-    // Get flag to determine extraction or compression
-    let _extract = cli.extract;
-
     // Get and validate input files
     let input_files = validate_input_files(cli.input, cli.extract);
 
-    // Calculate compression scheme
-    let _schema = if let Some(s) = cli.schema {
-        match s {
-            Schema { zip: true, .. } => "zip",
-            Schema { bzip2: true, .. } => "bzip2",
-            Schema { gzip: true, .. } => "gzip",
-            _ => "zip",
-        }
-    } else {
-        "zip"
+    // Extract archives into the output directory
+    if cli.extract {
+        return extract(&input_files, &cli.output);
+    }
+
+    // Calculate compression scheme from the flags, or else the output name
+    let format = match cli.schema {
+        Some(Schema { zip: true, .. }) => Format::Zip,
+        Some(Schema { bzip2: true, .. }) => Format::Bzip2,
+        Some(Schema { gzip: true, .. }) => Format::Gzip,
+        Some(Schema { tar: true, .. }) => Format::Tar,
+        Some(Schema { tar_gzip: true, .. }) => Format::TarGzip,
+        Some(Schema { tar_bzip2: true, .. }) => Format::TarBzip2,
+        Some(Schema { tar_zst: true, .. }) => Format::TarZstd,
+        Some(Schema { tar_xz: true, .. }) => Format::TarXz,
+        _ => Format::from_path(&cli.output).unwrap_or(Format::Zip),
     };
-    
-    // TODO: Remove placeholder
-    println!("Input files: {input_files:?}");
-
-    // NOTE: Rework as needed
-    // if input_files.len() == 1 {
-    //     if input_files[0].is_dir() {
-    //         compress_directory_to_zip_directory(input_files[0].as_path(), cli.output.as_path())?;
-    //     } else {
-    //         compress_single_file_to_zip(input_files[0].as_path(), cli.output.as_path())?;
-    //     }
-    // } else {
-    //     compress_files_to_zip_directory(&input_files, cli.output.as_path())?;
-    // }
-    
-    // OR
-    
-    // for file in input_files {
-    //     if file.is_dir() {
-    //         compress_directory_to_zip_directory(file.as_path(), cli.output.as_path())?;
-    //     } else if file.is_file() {
-    //         compress_single_file_to_zip(file.as_path(), cli.output.as_path())?;
-    //     }
-    // }
-    
-    Ok(())
+
+    // Tune the high-ratio formats
+    let threads = match cli.threads {
+        0 => std::thread::available_parallelism().map_or(1, |threads| threads.get() as u32),
+        threads => threads,
+    };
+    let options = CompressionOptions {
+        level: cli.level,
+        long_distance: cli.long,
+        threads: if threads > 1 { threads } else { 0 },
+    };
+
+    compress(&input_files, &cli.output, format, &options)
 }
 
 fn validate_input_files(input: Vec<PathBuf>, extract: bool) -> Vec<PathBuf> {
@@ -125,12 +147,9 @@ fn validate_input_files(input: Vec<PathBuf>, extract: bool) -> Vec<PathBuf> {
             // Create a recursive directory iterator using WalkDir
             for entry in WalkDir::new(file) {
                 if let Ok(entry) = entry {
-                    // Check file extension to ensure it's an archive format
-                    if let Some(ext) = entry.path().extension() {
-                        if SUPPORTED_ARCHIVE_FORMATS.contains(&ext.to_str().unwrap_or("")) {
-                            continue;
-                        }
-                    } else {
+                    // Check file extension to ensure it's an archive format, including compound ones like `.tar.gz`
+                    let name = entry.file_name().to_string_lossy();
+                    if !SUPPORTED_ARCHIVE_FORMATS.iter().any(|format| name.ends_with(&format!(".{format}"))) {
                         eprintln!("Error: File must be an archive of a supported format: {}", file.display());
                         std::process::exit(1);
                     }
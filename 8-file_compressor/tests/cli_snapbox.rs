@@ -3,8 +3,9 @@ use std::io::Write;
 use std::path::{Path, PathBuf};
 
 use anyhow::Result;
-use snapbox::cmd::{Command, cargo_bin};
+use snapbox::{cargo_bin, cmd::Command};
 use tempfile::{tempdir, TempDir};
+use zip::{ZipWriter, write::SimpleFileOptions};
 
 /// Helper function to create a temp file for testing
 ///
@@ -19,6 +20,23 @@ fn create_temp_file(dir: &Path, name: &str, contents: &str) -> PathBuf {
     file_path
 }
 
+/// Helper function to create a zip archive for testing
+///
+/// `dir` is the directory where the archive is to be created. `name` is the archive name. `entries` are the entry names and contents
+///
+/// Returns a PathBuf containing the path to the created archive
+fn create_temp_zip(dir: &Path, name: &str, entries: &[(&str, &str)]) -> PathBuf {
+    let zip_path = dir.join(name);
+    let mut zip = ZipWriter::new(File::create(&zip_path).unwrap());
+
+    for (entry, contents) in entries {
+        zip.start_file(*entry, SimpleFileOptions::default()).unwrap();
+        zip.write_all(contents.as_bytes()).unwrap();
+    }
+    zip.finish().unwrap();
+    zip_path
+}
+
 #[test]
 /// Tests that the program succeeds when provided multiple input files
 fn success_with_multiple_files() -> Result<()> {
@@ -29,7 +47,7 @@ fn success_with_multiple_files() -> Result<()> {
     let input_2 = create_temp_file(input_dir, "test_input_2.txt", "World!");
 
     Command::new(cargo_bin!("ezarc"))
-        .arg("out")
+        .arg(input_dir.join("out"))
         .args([
             input_1.canonicalize()?.display().to_string(),
             input_2.canonicalize()?.display().to_string(),
@@ -37,6 +55,7 @@ fn success_with_multiple_files() -> Result<()> {
         .assert()
         .success();
 
+    assert!(input_dir.join("out.zip").exists());
     Ok(())
 }
 
@@ -45,9 +64,10 @@ fn success_with_multiple_files() -> Result<()> {
 fn success_with_input_directories() -> Result<()> {
     let tmp_dir_1 = TempDir::with_prefix("test_input_dir_1")?;
     let tmp_dir_2 = TempDir::with_prefix("test_input_dir_2")?;
+    let out_dir = tempdir()?;
     
     Command::new(cargo_bin!("ezarc"))
-        .arg("out")
+        .arg(out_dir.path().join("out"))
         .args([tmp_dir_1.path().canonicalize()?.display().to_string(), tmp_dir_2.path().canonicalize()?.display().to_string()])
         .assert()
         .success();
@@ -65,7 +85,7 @@ fn success_with_multiple_args() -> Result<()> {
     let input_1 = create_temp_file(input_dir, "test_input_1.txt", "Hello!");
     
     Command::new(cargo_bin!("ezarc"))
-        .arg("out")
+        .arg(input_dir.join("out"))
         .args([input_1.canonicalize()?.display().to_string(), tmp_dir_1.path().canonicalize()?.display().to_string()])
         .assert()
         .success();
@@ -73,6 +93,108 @@ fn success_with_multiple_args() -> Result<()> {
     Ok(())
 }
 
+#[test]
+/// Tests that the program compresses each input file separately when provided the `--gzip` flag
+fn success_with_gzip_flag() -> Result<()> {
+    let tmp_dir = tempdir()?;
+    let input_dir = tmp_dir.path();
+
+    let input_1 = create_temp_file(input_dir, "test_input_1.txt", "Hello!");
+    let input_2 = create_temp_file(input_dir, "test_input_2.txt", "World!");
+
+    Command::new(cargo_bin!("ezarc"))
+        .arg("--gzip")
+        .arg(input_dir.join("out"))
+        .args([input_1.canonicalize()?.display().to_string(), input_2.canonicalize()?.display().to_string()])
+        .assert()
+        .success();
+
+    assert!(input_dir.join("out/test_input_1.txt.gz").exists());
+    assert!(input_dir.join("out/test_input_2.txt.gz").exists());
+    Ok(())
+}
+
+#[test]
+/// Tests that the program fails with a clear error when a schema can't archive the input
+fn failure_with_gzip_directory() -> Result<()> {
+    let tmp_dir = TempDir::with_prefix("test_input_dir")?;
+
+    Command::new(cargo_bin!("ezarc"))
+        .arg("--gzip")
+        .arg(tmp_dir.path().join("out"))
+        .args([tmp_dir.path().canonicalize()?.display().to_string()])
+        .assert()
+        .failure()
+        .code(1)
+        .stderr_eq("Error: Gzip compresses single files, so it can't archive the directory [..]\n...");
+
+    Ok(())
+}
+
+#[test]
+/// Tests that the program picks the tarball format from the output name and extracts it again
+fn success_with_tarball_round_trip() -> Result<()> {
+    let tmp_dir = tempdir()?;
+    let input_dir = tmp_dir.path().join("release");
+    std::fs::create_dir_all(input_dir.join("bin"))?;
+    create_temp_file(&input_dir.join("bin"), "tool", "#!/bin/sh");
+    let archive = tmp_dir.path().join("release.tgz");
+
+    Command::new(cargo_bin!("ezarc"))
+        .arg(&archive)
+        .arg(&input_dir)
+        .assert()
+        .success();
+
+    Command::new(cargo_bin!("ezarc"))
+        .arg("-x")
+        .arg(tmp_dir.path().join("out"))
+        .arg(&archive)
+        .assert()
+        .success();
+
+    let extracted = tmp_dir.path().join("out/release/bin/tool");
+    assert_eq!(std::fs::read_to_string(extracted)?, "#!/bin/sh\n");
+    Ok(())
+}
+
+#[test]
+/// Tests that the program tunes zstd tarballs with the level, long-distance, and thread options
+fn success_with_tuned_tar_zst() -> Result<()> {
+    let tmp_dir = tempdir()?;
+    let input_dir = tmp_dir.path().join("cache");
+    std::fs::create_dir_all(&input_dir)?;
+    create_temp_file(&input_dir, "object.o", "Hello!");
+
+    Command::new(cargo_bin!("ezarc"))
+        .args(["--tar-zst", "--level", "19", "--long", "--threads", "2"])
+        .arg(tmp_dir.path().join("cache"))
+        .arg(&input_dir)
+        .assert()
+        .success();
+
+    Command::new(cargo_bin!("ezarc"))
+        .arg("-x")
+        .arg(tmp_dir.path().join("out"))
+        .arg(tmp_dir.path().join("cache.tar.zst"))
+        .assert()
+        .success();
+
+    let extracted = tmp_dir.path().join("out/cache/object.o");
+    assert_eq!(std::fs::read_to_string(extracted)?, "Hello!\n");
+
+    // Options the selected format can't use are refused
+    Command::new(cargo_bin!("ezarc"))
+        .args(["--zip", "--level", "9"])
+        .arg(tmp_dir.path().join("cache"))
+        .arg(&input_dir)
+        .assert()
+        .failure()
+        .code(1)
+        .stderr_eq("Error: Zip doesn't take a level, long-distance matching, or threads\n...");
+    Ok(())
+}
+
 #[test]
 /// Tests that the program fails when provided invalid input arguments, such as non-existent files or directories
 fn failure_with_invalid_args() {
@@ -86,20 +208,43 @@ fn failure_with_invalid_args() {
 }
 
 #[test]
-/// Tests that the program succeeds when provided the `-x` flag to indicate file extraction
+/// Tests that the program extracts archives into the output directory when provided the `-x` flag
 fn success_with_extract_flag() -> Result<()> {
     let tmp_dir_2 = tempdir()?;
     let input_dir = tmp_dir_2.path();
+    let output_dir = input_dir.join("out");
 
-    let input_1 = create_temp_file(input_dir, "test_input_1.zip", "Hello!");
+    let input_1 = create_temp_zip(input_dir, "test_input_1.zip", &[("sub/hello.txt", "Hello!")]);
 
     Command::new(cargo_bin!("ezarc"))
         .arg("-x")
-        .arg("out")
+        .arg(&output_dir)
         .args([input_1.canonicalize()?.display().to_string()])
         .assert()
         .success();
-    
+
+    assert_eq!(std::fs::read_to_string(output_dir.join("sub/hello.txt"))?, "Hello!");
+    Ok(())
+}
+
+#[test]
+/// Tests that the program refuses to extract archive entries that escape the output directory
+fn failure_with_zip_slip_entry() -> Result<()> {
+    let tmp_dir = tempdir()?;
+    let input_dir = tmp_dir.path();
+
+    let input_1 = create_temp_zip(input_dir, "test_input_1.zip", &[("../../evil.txt", "Hello!")]);
+
+    Command::new(cargo_bin!("ezarc"))
+        .arg("-x")
+        .arg(input_dir.join("out"))
+        .args([input_1.canonicalize()?.display().to_string()])
+        .assert()
+        .failure()
+        .code(1)
+        .stderr_eq("Error: Failed to extract [..]\n\nCaused by:\n    Entry path escapes the destination: [..]\n...");
+
+    assert!(!input_dir.parent().unwrap().join("evil.txt").exists());
     Ok(())
 }
 
@@ -0,0 +1,5 @@
+mod http_client;
+mod test_server;
+
+pub use http_client::*;
+pub use test_server::*;
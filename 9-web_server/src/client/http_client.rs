@@ -0,0 +1,472 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::{
+    models::{HttpMethod, Request, Response},
+    utils::ChunkedReader,
+};
+
+/// Most idle connections kept open per server
+const MAX_IDLE_PER_AUTHORITY: usize = 4;
+
+/// Headers only sent to the server they were given for, dropped when a redirect leaves it
+const ORIGIN_HEADERS: &[&str] = &["authorization", "cookie", "host"];
+
+/// HTTP/1.1 client over plain TCP
+///
+/// Connections are kept open for later requests to the same server when it allows it, chunked bodies are decoded, and redirects are followed by `request`
+#[derive(Debug)]
+pub struct Client {
+    timeout: Option<Duration>,
+    max_redirects: usize,
+    idle: Mutex<HashMap<String, Vec<BufReader<TcpStream>>>>,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client {
+            timeout: Some(Duration::from_secs(30)),
+            max_redirects: 5,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Client {
+    /// Creates a client with a 30 second timeout that follows up to 5 redirects
+    pub fn new() -> Client {
+        Client::default()
+    }
+
+    /// Sets the timeout for connecting and for each read and write. `None` waits forever
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Sets the most redirects followed by one call to `request` before it fails
+    pub fn set_max_redirects(&mut self, max_redirects: usize) {
+        self.max_redirects = max_redirects;
+    }
+
+    /// Sends a `GET` request, following redirects
+    ///
+    /// The `url` is an `http://host[:port]/path` URL
+    pub fn get(&self, url: &str) -> io::Result<Response> {
+        self.request(HttpMethod::Get, url, &[], b"")
+    }
+
+    /// Sends a request, following redirects
+    ///
+    /// The `method` and `url` start the request, `headers` are sent with it and any request it is redirected to on the same server, and `body` is sent with a `Content-Length`. `303` redirects, and `301`/`302` redirects of a `POST`, are followed with a `GET` without the body
+    ///
+    /// Returns the final Response with its body read into memory. Its header names are lowercase
+    pub fn request(
+        &self,
+        method: HttpMethod,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<Response> {
+        let (origin, target) = parse_url(url)?;
+        let (mut authority, mut target, mut method, mut body) =
+            (origin.clone(), target, method, body);
+
+        for _ in 0..=self.max_redirects {
+            let mut req = Request::default();
+            req.parse_status_line(format!("{} {target} HTTP/1.1", method.as_str()));
+            for (key, value) in headers {
+                if authority == origin || !ORIGIN_HEADERS.contains(&key.to_lowercase().as_str()) {
+                    req.append_header(format!("{key}: {value}"));
+                }
+            }
+            req.set_body(body);
+
+            let res = self.send(&authority, &req)?;
+            let code = res.get_status().unwrap_or_default();
+            let Some(location) = res
+                .get_headers()
+                .get("location")
+                .filter(|_| matches!(code, 301 | 302 | 303 | 307 | 308))
+            else {
+                return Ok(res);
+            };
+
+            (authority, target) = resolve_location(&authority, &target, location)?;
+            if code == 303 || (matches!(code, 301 | 302) && matches!(method, HttpMethod::Post)) {
+                method = HttpMethod::Get;
+                body = b"";
+            }
+        }
+
+        Err(io::Error::other(format!(
+            "Gave up after {} redirects",
+            self.max_redirects
+        )))
+    }
+
+    /// Sends a single request without following redirects
+    ///
+    /// The `authority` is the `host:port` to connect to and `req` is the Request to send. A `Host` header is added if `req` has none
+    ///
+    /// Returns the Response with its body read into memory. Its header names are lowercase
+    pub fn send(&self, authority: &str, req: &Request) -> io::Result<Response> {
+        // The server may have closed an idle connection meanwhile, so retry those once on a new one
+        if let Some(mut connection) = self.take_idle(authority) {
+            match exchange(&mut connection, authority, req) {
+                Ok((res, reusable)) => {
+                    if reusable {
+                        self.put_idle(authority, connection);
+                    }
+                    return Ok(res);
+                }
+                Err(err) if !is_stale(&err) => return Err(timeout_error(err)),
+                Err(_) => {}
+            }
+        }
+
+        let mut connection = self.connect(authority)?;
+        let (res, reusable) = exchange(&mut connection, authority, req).map_err(timeout_error)?;
+        if reusable {
+            self.put_idle(authority, connection);
+        }
+        Ok(res)
+    }
+
+    /// Opens a new connection with the client's timeouts
+    fn connect(&self, authority: &str) -> io::Result<BufReader<TcpStream>> {
+        let mut last_err = io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{authority} did not resolve to any address"),
+        );
+        for addr in authority.to_socket_addrs()? {
+            let stream = match self.timeout {
+                Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+                None => TcpStream::connect(addr),
+            };
+            match stream {
+                Ok(stream) => {
+                    stream.set_read_timeout(self.timeout)?;
+                    stream.set_write_timeout(self.timeout)?;
+                    stream.set_nodelay(true)?;
+                    return Ok(BufReader::new(stream));
+                }
+                Err(err) => last_err = err,
+            }
+        }
+        Err(timeout_error(last_err))
+    }
+
+    /// Takes an idle connection to a server, if any
+    fn take_idle(&self, authority: &str) -> Option<BufReader<TcpStream>> {
+        self.idle.lock().unwrap().get_mut(authority)?.pop()
+    }
+
+    /// Keeps a connection open for a later request to the same server
+    fn put_idle(&self, authority: &str, connection: BufReader<TcpStream>) {
+        let mut idle = self.idle.lock().unwrap();
+        let connections = idle.entry(authority.to_owned()).or_default();
+        if connections.len() < MAX_IDLE_PER_AUTHORITY {
+            connections.push(connection);
+        }
+    }
+}
+
+/// Writes a request to a connection and reads the response
+///
+/// Returns the Response and whether the connection can be reused
+fn exchange(
+    connection: &mut BufReader<TcpStream>,
+    authority: &str,
+    req: &Request,
+) -> io::Result<(Response, bool)> {
+    let mut head = format!(
+        "{} {} {}\r\n",
+        req.get_method().as_str(),
+        req.get_target(),
+        req.get_protocol()
+    );
+    if !req.get_headers().contains_key("host") {
+        head.push_str(&format!("Host: {authority}\r\n"));
+    }
+    for (key, value) in req.get_headers() {
+        if key != "content-length" && key != "transfer-encoding" {
+            head.push_str(&format!("{key}: {value}\r\n"));
+        }
+    }
+    let body = req.get_body();
+    if !body.is_empty() || matches!(req.get_method(), HttpMethod::Post | HttpMethod::Put) {
+        head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    head.push_str("\r\n");
+
+    let stream = connection.get_mut();
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    read_response(connection)
+}
+
+/// Reads a response from a connection, skipping interim `1xx` responses
+///
+/// Returns the Response with its body read into memory and whether the connection can be reused
+fn read_response(connection: &mut BufReader<TcpStream>) -> io::Result<(Response, bool)> {
+    loop {
+        let mut res = Response::default();
+
+        // Parse status line
+        let mut status_line = String::new();
+        if connection.read_line(&mut status_line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Server closed connection without a response",
+            ));
+        }
+        let mut parts = status_line.trim_end().splitn(3, ' ');
+        let (Some(protocol), Some(code)) = (parts.next(), parts.next()) else {
+            return Err(invalid_data("Malformed status line"));
+        };
+        let code = code
+            .parse::<usize>()
+            .map_err(|_| invalid_data("Malformed status code"))?;
+        res.set_protocol(protocol.to_owned());
+        res.set_custom_status(code, parts.next().unwrap_or(""));
+
+        // Parse headers, joining repeated ones
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if connection.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Server closed connection in headers",
+                ));
+            }
+            let trimmed = line.trim_end();
+            if trimmed.is_empty() {
+                break;
+            }
+            if let Some((key, value)) = trimmed.split_once(':') {
+                let key = key.trim().to_lowercase();
+                let value = match res.get_headers().get(&key) {
+                    Some(existing) => format!("{existing}, {}", value.trim()),
+                    None => value.trim().to_owned(),
+                };
+                res.add_header((key, value));
+            }
+        }
+        if (100..200).contains(&code) {
+            continue;
+        }
+
+        let headers = res.get_headers();
+        let connection_header = headers
+            .get("connection")
+            .map(|value| value.to_lowercase())
+            .unwrap_or_default();
+        let keep_alive = if protocol == "HTTP/1.0" {
+            connection_header.contains("keep-alive")
+        } else {
+            !connection_header.contains("close")
+        };
+        let chunked = headers
+            .get("transfer-encoding")
+            .is_some_and(|te| te.to_lowercase().contains("chunked"));
+        let content_length = headers
+            .get("content-length")
+            .map(|cl| {
+                cl.parse::<u64>()
+                    .map_err(|_| invalid_data("Malformed Content-Length"))
+            })
+            .transpose()?;
+
+        // Read body using the response framing
+        let mut body = vec![];
+        let framed = if code == 204 || code == 304 {
+            true
+        } else if chunked {
+            ChunkedReader::new(&mut *connection).read_to_end(&mut body)?;
+            true
+        } else if let Some(length) = content_length {
+            (&mut *connection).take(length).read_to_end(&mut body)?;
+            if (body.len() as u64) < length {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Server closed connection in body",
+                ));
+            }
+            true
+        } else {
+            // Without framing the body ends when the server closes the connection
+            connection.read_to_end(&mut body)?;
+            false
+        };
+        if !body.is_empty() {
+            res.set_body_bytes(body);
+        }
+
+        return Ok((res, framed && keep_alive));
+    }
+}
+
+/// Splits an `http://` URL into the `host:port` to connect to and the request target
+fn parse_url(url: &str) -> io::Result<(String, String)> {
+    let Some(rest) = url.strip_prefix("http://") else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Only http:// URLs are supported: {url}"),
+        ));
+    };
+    let rest = rest.split('#').next().unwrap_or_default();
+    let (authority, target) = match rest.find(['/', '?']) {
+        Some(index) if rest[index..].starts_with('?') => {
+            (&rest[..index], format!("/{}", &rest[index..]))
+        }
+        Some(index) => (&rest[..index], rest[index..].to_owned()),
+        None => (rest, "/".to_owned()),
+    };
+    if authority.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("URL has no host: {url}"),
+        ));
+    }
+
+    // Default to port 80, minding the brackets of IPv6 addresses
+    let has_port = authority
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| !port.contains(']'));
+    let authority = if has_port {
+        authority.to_owned()
+    } else {
+        format!("{authority}:80")
+    };
+    Ok((authority, target))
+}
+
+/// Resolves a `Location` header against the request it redirected
+///
+/// Returns the `host:port` and target to request next
+fn resolve_location(authority: &str, target: &str, location: &str) -> io::Result<(String, String)> {
+    if location.starts_with("http://") {
+        parse_url(location)
+    } else if let Some(rest) = location.strip_prefix("//") {
+        parse_url(&format!("http://{rest}"))
+    } else if location.starts_with('/') {
+        Ok((authority.to_owned(), location.to_owned()))
+    } else {
+        // Relative to the directory of the current path
+        let path = target.split('?').next().unwrap_or_default();
+        let dir = &path[..path.rfind('/').map_or(0, |index| index + 1)];
+        Ok((authority.to_owned(), format!("{dir}{location}")))
+    }
+}
+
+/// Checks whether an error means a reused connection had been closed by the server
+fn is_stale(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+/// Reports socket timeouts, which some platforms signal as `WouldBlock`, as `TimedOut`
+fn timeout_error(err: io::Error) -> io::Error {
+    if err.kind() == io::ErrorKind::WouldBlock {
+        io::Error::new(io::ErrorKind::TimedOut, "Request timed out")
+    } else {
+        err
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    /// Helper function to start a stub server answering requests on one connection
+    ///
+    /// `responses` are the raw HTTP responses sent back in turn, each after reading a request head. Bodies sent with requests are not read
+    ///
+    /// Returns the stub's address
+    fn stub_server(responses: Vec<&'static str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            for response in responses {
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 && !line.ends_with("\r\n\r\n") {}
+                (&stream).write_all(response.as_bytes()).unwrap();
+            }
+
+            // Hold the connection open until the client closes it
+            let _ = reader.read_line(&mut String::new());
+        });
+        addr
+    }
+
+    #[test]
+    fn test_reuses_connections_and_decodes_chunks() {
+        // The stub accepts a single connection, so the second request must reuse it
+        let addr = stub_server(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nwor\r\n2\r\nld\r\n0\r\n\r\n",
+        ]);
+        let client = Client::new();
+
+        let res = client.get(&format!("http://{addr}/a")).unwrap();
+        assert_eq!(res.get_body(), Some(&b"hello"[..]));
+        let res = client.get(&format!("http://{addr}/b")).unwrap();
+        assert_eq!(res.get_body(), Some(&b"world"[..]));
+    }
+
+    #[test]
+    fn test_follows_redirects_and_times_out() {
+        let addr = stub_server(vec![
+            "HTTP/1.1 302 Found\r\nLocation: next\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+            "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\ncut",
+        ]);
+        let mut client = Client::new();
+        client.set_timeout(Some(Duration::from_millis(200)));
+
+        let res = client.get(&format!("http://{addr}/dir/start")).unwrap();
+        assert_eq!(res.get_status(), Some(200));
+        assert_eq!(res.get_body(), Some(&b"ok"[..]));
+
+        let err = client.get(&format!("http://{addr}/slow")).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_parse_urls() {
+        assert_eq!(
+            parse_url("http://example.com").unwrap(),
+            ("example.com:80".to_owned(), "/".to_owned())
+        );
+        assert_eq!(
+            parse_url("http://[::1]:8080/a?b=c#top").unwrap(),
+            ("[::1]:8080".to_owned(), "/a?b=c".to_owned())
+        );
+        assert_eq!(
+            resolve_location("h:80", "/dir/page?x", "other").unwrap(),
+            ("h:80".to_owned(), "/dir/other".to_owned())
+        );
+        assert!(parse_url("https://example.com/").is_err());
+    }
+}
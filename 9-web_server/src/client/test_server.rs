@@ -0,0 +1,272 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    models::{ServerConfig, ServerState, ThreadPool},
+    server::handle_connection,
+};
+
+/// A server running in-process on an ephemeral port, for end-to-end tests
+///
+/// The server stops when the TestServer is dropped
+pub struct TestServer {
+    addr: SocketAddr,
+    state: Arc<ServerState>,
+    shutdown: Arc<AtomicBool>,
+    accept_thread: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    /// Starts serving a configuration on `127.0.0.1` with a port picked by the operating system
+    ///
+    /// The `config` is used as loaded except for its `address`
+    pub fn start(config: ServerConfig) -> io::Result<TestServer> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let pool = ThreadPool::new(config.threads);
+        let state = Arc::new(ServerState::new(config, pool.load()));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let accept_thread = {
+            let (state, shutdown) = (Arc::clone(&state), Arc::clone(&shutdown));
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if shutdown.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let Ok(stream) = stream else {
+                        continue;
+                    };
+                    let state = Arc::clone(&state);
+                    pool.execute(move || handle_connection(stream.into(), &state));
+                }
+                // Dropping the pool waits for open connections to finish
+            })
+        };
+
+        Ok(TestServer {
+            addr,
+            state,
+            shutdown,
+            accept_thread: Some(accept_thread),
+        })
+    }
+
+    /// Returns the address the server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the URL of a path on the server
+    ///
+    /// The `path` starts with `/`
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{path}", self.addr)
+    }
+
+    /// Returns the shared state of the server, e.g. to check its metrics
+    pub fn state(&self) -> &ServerState {
+        &self.state
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        // Connect once so the accept loop notices the shutdown
+        self.shutdown.store(true, Ordering::SeqCst);
+        let _ = TcpStream::connect(self.addr);
+        if let Some(accept_thread) = self.accept_thread.take() {
+            let _ = accept_thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::Client,
+        http2::{Decoder, END_STREAM, Frame, FrameKind, PREFACE},
+        models::{HttpMethod, SiteConfig},
+    };
+    use std::{
+        fs,
+        io::{BufRead, BufReader, Read, Write},
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_serves_requests_end_to_end() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("hello.txt"), "Hello, world!").unwrap();
+        let server = TestServer::start(ServerConfig {
+            threads: 2,
+            default_site: SiteConfig {
+                root: temp_dir.path().to_path_buf(),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .unwrap();
+        let client = Client::new();
+
+        let res = client.get(&server.url("/hello.txt")).unwrap();
+        assert_eq!(res.get_status(), Some(200));
+        assert_eq!(res.get_body(), Some(&b"Hello, world!"[..]));
+        assert_eq!(
+            res.get_headers()["content-type"],
+            "text/plain; charset=utf-8"
+        );
+
+        let res = client.get(&server.url("/missing.txt")).unwrap();
+        assert_eq!(res.get_status(), Some(404));
+
+        // Form posts are redirected to the page showing what was written
+        let res = client
+            .request(
+                HttpMethod::Post,
+                &server.url("/form"),
+                &[("Content-Type", "application/x-www-form-urlencoded")],
+                b"name=alice",
+            )
+            .unwrap();
+        assert_eq!(res.get_status(), Some(200));
+        assert_eq!(res.get_body(), Some(&b"name: alice"[..]));
+    }
+
+    #[test]
+    fn test_echoes_request_id_and_trace() {
+        let temp_dir = tempdir().unwrap();
+        let server = TestServer::start(ServerConfig {
+            threads: 2,
+            default_site: SiteConfig {
+                root: temp_dir.path().to_path_buf(),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .unwrap();
+        let client = Client::new();
+
+        // Incoming ids are kept and the trace continues with a new span
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let res = client
+            .request(
+                HttpMethod::Get,
+                &server.url("/missing.txt"),
+                &[
+                    ("Accept", "text/plain"),
+                    ("X-Request-Id", "checkout-42"),
+                    ("traceparent", traceparent),
+                    ("tracestate", "vendor=abc"),
+                ],
+                b"",
+            )
+            .unwrap();
+        let headers = res.get_headers();
+        assert_eq!(headers["x-request-id"], "checkout-42");
+        assert!(headers["traceparent"].starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert_ne!(headers["traceparent"], traceparent);
+        assert_eq!(headers["tracestate"], "vendor=abc");
+        let body = String::from_utf8(res.get_body().unwrap().to_vec()).unwrap();
+        assert!(body.contains("Request ID: checkout-42\n"));
+        assert!(body.contains("Trace ID: 4bf92f3577b34da6a3ce929d0e0e4736\n"));
+
+        // Unusable ids are replaced by generated ones
+        let res = client
+            .request(
+                HttpMethod::Get,
+                &server.url("/missing.txt"),
+                &[("X-Request-Id", "has spaces")],
+                b"",
+            )
+            .unwrap();
+        assert_eq!(res.get_headers()["x-request-id"].len(), 16);
+        assert_eq!(res.get_headers()["traceparent"].len(), 55);
+
+        // Including ones that would inject markup into the error page
+        let res = client
+            .request(
+                HttpMethod::Get,
+                &server.url("/missing.txt"),
+                &[("X-Request-Id", "<script>alert(document.cookie)</script>")],
+                b"",
+            )
+            .unwrap();
+        assert_eq!(res.get_headers()["x-request-id"].len(), 16);
+        let body = String::from_utf8(res.get_body().unwrap().to_vec()).unwrap();
+        assert!(!body.contains("<script>"));
+    }
+
+    #[test]
+    fn test_upgrades_to_http2() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("hello.txt"), "Hello over h2c").unwrap();
+        let server = TestServer::start(ServerConfig {
+            threads: 2,
+            default_site: SiteConfig {
+                root: temp_dir.path().to_path_buf(),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .unwrap();
+
+        // Ask to upgrade the way curl does, then switch to frames after the 101
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        write!(
+            stream,
+            "GET /hello.txt HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+             Upgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAAP__\r\n\r\n"
+        )
+        .unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            reader.read_line(&mut head).unwrap();
+        }
+        assert!(head.starts_with("HTTP/1.1 101"));
+        assert!(head.contains("Upgrade: h2c"));
+
+        let mut out = PREFACE.to_vec();
+        Frame::new(FrameKind::Settings, 0, 0, vec![])
+            .write(&mut out)
+            .unwrap();
+        stream.write_all(&out).unwrap();
+
+        // The upgraded request is answered on stream 1
+        let mut decoder = Decoder::default();
+        let (mut status, mut body) = (None, vec![]);
+        loop {
+            let frame = Frame::read(&mut reader, 1 << 24).unwrap();
+            if frame.stream_id != 1 {
+                continue;
+            }
+            match frame.kind {
+                FrameKind::Headers => {
+                    let fields = decoder.decode(&frame.payload).unwrap();
+                    status = fields.into_iter().find(|(name, _)| name == ":status");
+                }
+                FrameKind::Data => body.extend_from_slice(&frame.payload),
+                _ => {}
+            }
+            if frame.has_flag(END_STREAM) {
+                break;
+            }
+        }
+        assert_eq!(status.unwrap().1, "200");
+        assert_eq!(body, b"Hello over h2c");
+
+        // The connection stays open for more streams until the client leaves
+        let mut rest = vec![];
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        reader.read_to_end(&mut rest).unwrap();
+    }
+}
@@ -0,0 +1,9 @@
+mod connection;
+mod frame;
+mod hpack;
+mod huffman;
+
+pub use connection::*;
+pub use frame::*;
+pub use hpack::*;
+pub use huffman::*;
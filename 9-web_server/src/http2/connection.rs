@@ -0,0 +1,1051 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, BufWriter, Read, Write},
+    sync::{Condvar, Mutex},
+    thread::{self, Scope},
+};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+
+use super::{
+    ACK, DEFAULT_MAX_FRAME_SIZE, DEFAULT_WINDOW_SIZE, Decoder, END_HEADERS, END_STREAM, ErrorCode,
+    Frame, FrameKind, H2Error, MAX_WINDOW_SIZE, PREFACE, PRIORITY, Settings, encode_headers,
+};
+use crate::models::{Body, HttpMethod, Request, Response};
+
+/// Streams a client may have open at once
+pub const MAX_CONCURRENT_STREAMS: u32 = 100;
+
+/// Largest header block accepted, across a HEADERS frame and its CONTINUATION frames
+const MAX_HEADER_BLOCK: usize = 65_536;
+
+/// Largest request body accepted on a stream before it is reset
+const MAX_BODY_SIZE: usize = 8 << 20;
+
+/// Request body bytes a client may have sent but not yet had handled, across all streams of a connection
+const RECEIVE_WINDOW: i64 = 16 << 20;
+
+/// Handlers a connection runs at once, with further complete requests waiting for one to finish
+const MAX_CONCURRENT_HANDLERS: usize = 8;
+
+/// Headers that only apply to a single HTTP/1.1 connection, which HTTP/2 messages must not carry
+const CONNECTION_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Checks whether a request head is the start of the HTTP/2 connection preface
+///
+/// The preface reads as a `PRI * HTTP/2.0` request line followed by a blank line, leaving `SM\r\n\r\n` unread
+pub fn is_preface_start(req: &Request) -> bool {
+    matches!(req.get_method(), HttpMethod::None)
+        && req.get_target() == "*"
+        && req.get_protocol() == "HTTP/2.0"
+        && req.get_headers().is_empty()
+}
+
+/// Reads the rest of the connection preface after its start was parsed as a request head
+///
+/// Returns whether the bytes read complete the preface
+pub fn read_preface_end(reader: &mut impl Read) -> bool {
+    let mut end = [0; 6];
+    reader.read_exact(&mut end).is_ok() && end == PREFACE[PREFACE.len() - 6..]
+}
+
+/// Checks whether an HTTP/1.1 request asks to switch to HTTP/2 over cleartext
+///
+/// Requests with a body are answered over HTTP/1.1 instead, so the body doesn't need to be read before switching
+///
+/// Returns the SETTINGS payload decoded from the `HTTP2-Settings` header, or None if the request doesn't ask to upgrade
+pub fn upgrade_settings(req: &Request) -> Option<Vec<u8>> {
+    let headers = req.get_headers();
+    let upgrade = headers.get("upgrade")?;
+    if !upgrade
+        .split(',')
+        .any(|protocol| protocol.trim().eq_ignore_ascii_case("h2c"))
+    {
+        return None;
+    }
+    let has_body = headers.contains_key("transfer-encoding")
+        || headers
+            .get("content-length")
+            .is_some_and(|len| len.trim() != "0");
+    if has_body {
+        return None;
+    }
+
+    let settings = headers.get("http2-settings")?;
+    URL_SAFE_NO_PAD
+        .decode(settings.trim().trim_end_matches('='))
+        .ok()
+}
+
+/// Builds the `101 Switching Protocols` response accepting an upgrade to HTTP/2
+pub fn switching_protocols() -> Response {
+    let mut res = Response::default();
+    res.set_custom_status(101, "Switching Protocols");
+    res.add_header(("Connection".to_owned(), "Upgrade".to_owned()));
+    res.add_header(("Upgrade".to_owned(), "h2c".to_owned()));
+    res
+}
+
+/// Serves an HTTP/2 connection until the client closes it or breaks the protocol
+///
+/// The `reader` and `writer` are the two directions of the connection, and `handler` answers each complete request and its body through a Responder, on threads of their own so slow responses don't hold up other streams. Up to `MAX_CONCURRENT_HANDLERS` run at once, and the body bytes a handler holds are only returned to the client's connection window once it finishes. Without an `upgrade` the client's preface was already read. An `upgrade` is the HTTP/1.1 Request that asked to switch, answered on stream 1, with the SETTINGS payload of its `HTTP2-Settings` header, and the preface is still to be read
+///
+/// Returns once the client closed the connection, or an error after sending GOAWAY
+pub fn serve_http2<R, W, H>(
+    reader: &mut R,
+    writer: W,
+    upgrade: Option<(Request, Vec<u8>)>,
+    handler: H,
+) -> Result<(), H2Error>
+where
+    R: Read,
+    W: Write + Send,
+    H: Fn(Request, Vec<u8>, Responder<'_, W>) + Sync,
+{
+    let shared = Shared {
+        writer: Mutex::new(BufWriter::new(writer)),
+        flow: Mutex::new(Flow {
+            settings: Settings::default(),
+            connection_window: DEFAULT_WINDOW_SIZE,
+            streams: HashMap::new(),
+            receive_window: DEFAULT_WINDOW_SIZE,
+            closed: false,
+        }),
+        window_opened: Condvar::new(),
+        handlers: Mutex::new(Handlers::default()),
+    };
+
+    thread::scope(|scope| {
+        let mut connection = Connection {
+            shared: &shared,
+            handler: &handler,
+            scope,
+            decoder: Decoder::default(),
+            receiving: HashMap::new(),
+            last_stream_id: 0,
+        };
+        let result = connection.run(reader, upgrade);
+
+        // Tell the client why the connection ends, and stop responders waiting for flow control
+        if let Err(H2Error::Connection(code, _)) = &result {
+            let _ = shared.write_frames(&[Frame::go_away(connection.last_stream_id, *code)]);
+        }
+        shared.handlers.lock().unwrap().pending.clear();
+        shared.flow.lock().unwrap().closed = true;
+        shared.window_opened.notify_all();
+        result
+    })
+}
+
+/// Sends the response to one stream of an HTTP/2 connection
+pub struct Responder<'a, W: Write> {
+    shared: &'a Shared<W>,
+    stream_id: u32,
+}
+
+impl<W: Write> Responder<'_, W> {
+    /// Sends a response on the stream, its body as fast as the client's flow-control windows allow
+    ///
+    /// Connection-specific headers are dropped, and cookies are sent as separate `set-cookie` fields
+    ///
+    /// Returns an error if the connection failed or the client reset the stream
+    pub fn send(self, res: Response) -> io::Result<()> {
+        let result = self.send_response(res);
+
+        // The stream is closed on both sides once the response ends
+        self.shared
+            .flow
+            .lock()
+            .unwrap()
+            .streams
+            .remove(&self.stream_id);
+        result
+    }
+
+    /// Sends the header block and body of a response
+    fn send_response(&self, mut res: Response) -> io::Result<()> {
+        let status = res.get_status().unwrap_or(500);
+        let mut fields = vec![(":status".to_owned(), status.to_string())];
+        for (name, value) in res.get_headers() {
+            let name = name.to_lowercase();
+            if !CONNECTION_HEADERS.contains(&name.as_str()) {
+                fields.push((name, value.clone()));
+            }
+        }
+        for cookie in res.get_set_cookie_headers() {
+            fields.push(("set-cookie".to_owned(), cookie));
+        }
+
+        let body = res.take_body();
+        let bodyless = match &body {
+            Some(Body::Bytes(bytes)) => bytes.is_empty(),
+            Some(Body::Shared(bytes)) => bytes.is_empty(),
+            Some(Body::Stream(_)) => false,
+            None => true,
+        };
+        self.send_headers(&fields, bodyless)?;
+
+        match body {
+            Some(Body::Bytes(bytes)) if !bodyless => self.send_data(&bytes, true),
+            Some(Body::Shared(bytes)) if !bodyless => self.send_data(&bytes, true),
+            Some(Body::Stream(mut reader)) => {
+                let mut buf = vec![0; DEFAULT_MAX_FRAME_SIZE];
+                loop {
+                    let n = reader.read(&mut buf)?;
+                    self.send_data(&buf[..n], n == 0)?;
+                    if n == 0 {
+                        return Ok(());
+                    }
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Sends a header block in a HEADERS frame, continued in CONTINUATION frames if it is larger than a frame
+    ///
+    /// The `end_stream` flag marks responses without a body
+    fn send_headers(&self, fields: &[(String, String)], end_stream: bool) -> io::Result<()> {
+        let max_frame_size = {
+            let flow = self.shared.flow.lock().unwrap();
+            if !flow.streams.contains_key(&self.stream_id) {
+                return Err(stream_reset());
+            }
+            flow.settings.max_frame_size
+        };
+
+        let block = encode_headers(fields);
+        let mut chunks = block.chunks(max_frame_size).peekable();
+        let mut frames = vec![];
+        let mut kind = FrameKind::Headers;
+        let mut flags = if end_stream { END_STREAM } else { 0 };
+        while let Some(chunk) = chunks.next() {
+            if chunks.peek().is_none() {
+                flags |= END_HEADERS;
+            }
+            frames.push(Frame::new(kind, flags, self.stream_id, chunk.to_vec()));
+            kind = FrameKind::Continuation;
+            flags = 0;
+        }
+        if frames.is_empty() {
+            frames.push(Frame::new(
+                kind,
+                flags | END_HEADERS,
+                self.stream_id,
+                vec![],
+            ));
+        }
+
+        // The frames of a header block may not be interleaved with other frames
+        self.shared.write_frames(&frames)
+    }
+
+    /// Sends body bytes in DATA frames, waiting for the client to open its flow-control windows where needed
+    ///
+    /// The `end_stream` flag marks the last bytes of the body
+    fn send_data(&self, mut data: &[u8], end_stream: bool) -> io::Result<()> {
+        loop {
+            let len = self.reserve(data.len())?;
+            let (chunk, rest) = data.split_at(len);
+            let flags = if end_stream && rest.is_empty() {
+                END_STREAM
+            } else {
+                0
+            };
+            self.shared.write_frames(&[Frame::new(
+                FrameKind::Data,
+                flags,
+                self.stream_id,
+                chunk.to_vec(),
+            )])?;
+
+            data = rest;
+            if data.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Takes room for up to `wanted` bytes from the stream's and the connection's flow-control windows
+    ///
+    /// Returns the number of bytes that may be sent now, at least one unless `wanted` is 0
+    fn reserve(&self, wanted: usize) -> io::Result<usize> {
+        let mut flow = self.shared.flow.lock().unwrap();
+        loop {
+            if flow.closed {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "HTTP/2 connection closed",
+                ));
+            }
+            let Some(&stream_window) = flow.streams.get(&self.stream_id) else {
+                return Err(stream_reset());
+            };
+            if wanted == 0 {
+                return Ok(0);
+            }
+
+            let available = stream_window
+                .min(flow.connection_window)
+                .min(flow.settings.max_frame_size as i64);
+            if available > 0 {
+                let len = (available as usize).min(wanted);
+                flow.connection_window -= len as i64;
+                if let Some(window) = flow.streams.get_mut(&self.stream_id) {
+                    *window -= len as i64;
+                }
+                return Ok(len);
+            }
+            flow = self.shared.window_opened.wait(flow).unwrap();
+        }
+    }
+}
+
+/// State shared by the thread reading a connection and the threads answering its streams
+struct Shared<W: Write> {
+    writer: Mutex<BufWriter<W>>,
+    flow: Mutex<Flow>,
+    /// Signalled whenever a flow-control window grows, a stream is reset, or the connection closes
+    window_opened: Condvar,
+    handlers: Mutex<Handlers>,
+}
+
+impl<W: Write> Shared<W> {
+    /// Writes frames back to back and flushes them
+    fn write_frames(&self, frames: &[Frame]) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        for frame in frames {
+            frame.write(&mut *writer)?;
+        }
+        writer.flush()
+    }
+
+    /// Takes `len` bytes of a DATA frame from the connection's receive window
+    ///
+    /// Returns an error if the client sent more than the window allowed
+    fn consume(&self, len: usize) -> Result<(), H2Error> {
+        let mut flow = self.flow.lock().unwrap();
+        flow.receive_window -= len as i64;
+        if flow.receive_window < 0 {
+            return Err(H2Error::Connection(
+                ErrorCode::FlowControlError,
+                "DATA frames exceeded the connection window",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Gives `len` bytes back to the connection's receive window once nothing holds them any more
+    fn release(&self, len: usize) -> io::Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        self.flow.lock().unwrap().receive_window += len as i64;
+        self.write_frames(&[Frame::window_update(0, len as u32)])
+    }
+
+    /// Takes the next request waiting for a handler, or counts the calling handler as finished if there is none
+    fn next_job(&self) -> Option<Job> {
+        let mut handlers = self.handlers.lock().unwrap();
+        let job = handlers.pending.pop_front();
+        if job.is_none() {
+            handlers.running -= 1;
+        }
+        job
+    }
+}
+
+/// Flow-control state of the frames sent to and received from the client
+struct Flow {
+    /// Settings the client sent
+    settings: Settings,
+    connection_window: i64,
+    /// Send windows of the streams that are open, by stream id
+    streams: HashMap<u32, i64>,
+    /// Bytes the client may still send before body bytes are handled and the window returned
+    receive_window: i64,
+    closed: bool,
+}
+
+/// A complete request waiting for a handler: its stream id, head, and body
+type Job = (u32, Request, Vec<u8>);
+
+/// Handler threads of a connection and the requests waiting for one
+#[derive(Default)]
+struct Handlers {
+    running: usize,
+    pending: VecDeque<Job>,
+}
+
+/// Reads the frames of a connection and starts a handler for each complete request
+struct Connection<'scope, 'env: 'scope, W: Write, H> {
+    shared: &'env Shared<W>,
+    handler: &'env H,
+    scope: &'scope Scope<'scope, 'env>,
+    decoder: Decoder,
+    /// Requests whose body is still arriving, by stream id
+    receiving: HashMap<u32, (Request, Vec<u8>)>,
+    /// Highest stream id the client opened
+    last_stream_id: u32,
+}
+
+impl<'scope, 'env, W, H> Connection<'scope, 'env, W, H>
+where
+    W: Write + Send,
+    H: Fn(Request, Vec<u8>, Responder<'_, W>) + Sync,
+{
+    /// Exchanges the connection prefaces and handles frames until the client closes the connection
+    fn run(
+        &mut self,
+        reader: &mut impl Read,
+        upgrade: Option<(Request, Vec<u8>)>,
+    ) -> Result<(), H2Error> {
+        // The server's preface is its settings
+        let settings = Settings {
+            max_concurrent_streams: Some(MAX_CONCURRENT_STREAMS),
+            ..Default::default()
+        };
+        self.shared.flow.lock().unwrap().receive_window = RECEIVE_WINDOW;
+        self.shared.write_frames(&[
+            Frame::new(FrameKind::Settings, 0, 0, settings.encode()),
+            Frame::window_update(0, (RECEIVE_WINDOW - DEFAULT_WINDOW_SIZE) as u32),
+        ])?;
+
+        // The request that asked to upgrade becomes stream 1, half-closed since its body was empty
+        if let Some((req, client_settings)) = upgrade {
+            self.shared
+                .flow
+                .lock()
+                .unwrap()
+                .settings
+                .apply(&client_settings)?;
+            let mut preface = [0; PREFACE.len()];
+            reader.read_exact(&mut preface)?;
+            if preface != PREFACE {
+                return Err(H2Error::Connection(
+                    ErrorCode::ProtocolError,
+                    "Invalid connection preface",
+                ));
+            }
+            self.open_stream(1);
+            self.dispatch(1, req, vec![])?;
+        }
+
+        // The client's preface ends with its settings
+        let first = Frame::read(reader, DEFAULT_MAX_FRAME_SIZE)?;
+        if first.kind != FrameKind::Settings || first.has_flag(ACK) {
+            return Err(H2Error::Connection(
+                ErrorCode::ProtocolError,
+                "Connection preface must end with SETTINGS",
+            ));
+        }
+        self.handle_frame(first, reader)?;
+
+        loop {
+            let frame = match Frame::read(reader, DEFAULT_MAX_FRAME_SIZE) {
+                Ok(frame) => frame,
+                Err(H2Error::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            };
+
+            // Errors confined to a stream reset it and leave the connection open
+            match self.handle_frame(frame, reader) {
+                Err(H2Error::Stream(stream_id, code)) => {
+                    self.close_stream(stream_id)?;
+                    self.shared
+                        .write_frames(&[Frame::rst_stream(stream_id, code)])?;
+                }
+                result => result?,
+            }
+        }
+    }
+
+    /// Handles a frame received from the client
+    ///
+    /// The `reader` is used to read the CONTINUATION frames of a header block
+    fn handle_frame(&mut self, frame: Frame, reader: &mut impl Read) -> Result<(), H2Error> {
+        let stream_id = frame.stream_id;
+        let on_connection = stream_id == 0;
+        let protocol_error = |reason| Err(H2Error::Connection(ErrorCode::ProtocolError, reason));
+        let size_error = |reason| Err(H2Error::Connection(ErrorCode::FrameSizeError, reason));
+
+        match frame.kind {
+            FrameKind::Data if on_connection => protocol_error("DATA frame on stream 0"),
+            FrameKind::Data => self.handle_data(frame),
+            FrameKind::Headers if on_connection || stream_id.is_multiple_of(2) => {
+                protocol_error("HEADERS frame on a stream the client cannot open")
+            }
+            FrameKind::Headers => self.handle_headers(frame, reader),
+            FrameKind::Priority if on_connection => protocol_error("PRIORITY frame on stream 0"),
+            FrameKind::Priority if frame.payload.len() != 5 => {
+                Err(H2Error::Stream(stream_id, ErrorCode::FrameSizeError))
+            }
+            FrameKind::RstStream if on_connection || stream_id > self.last_stream_id => {
+                protocol_error("RST_STREAM frame on an idle stream")
+            }
+            FrameKind::RstStream if frame.payload.len() != 4 => {
+                size_error("RST_STREAM frame must be 4 bytes")
+            }
+            FrameKind::RstStream => Ok(self.close_stream(stream_id)?),
+            FrameKind::Settings if !on_connection => protocol_error("SETTINGS frame on a stream"),
+            FrameKind::Settings if frame.has_flag(ACK) && !frame.payload.is_empty() => {
+                size_error("SETTINGS acknowledgement must be empty")
+            }
+            FrameKind::Settings if frame.has_flag(ACK) => Ok(()),
+            FrameKind::Settings => self.handle_settings(&frame.payload),
+            FrameKind::PushPromise => protocol_error("Clients cannot push streams"),
+            FrameKind::Ping if !on_connection => protocol_error("PING frame on a stream"),
+            FrameKind::Ping if frame.payload.len() != 8 => size_error("PING frame must be 8 bytes"),
+            FrameKind::Ping if frame.has_flag(ACK) => Ok(()),
+            FrameKind::Ping => Ok(self.shared.write_frames(&[Frame::new(
+                FrameKind::Ping,
+                ACK,
+                0,
+                frame.payload,
+            )])?),
+            FrameKind::GoAway if !on_connection => protocol_error("GOAWAY frame on a stream"),
+            FrameKind::WindowUpdate if frame.payload.len() != 4 => {
+                size_error("WINDOW_UPDATE frame must be 4 bytes")
+            }
+            FrameKind::WindowUpdate => self.handle_window_update(stream_id, &frame.payload),
+            FrameKind::Continuation => protocol_error("CONTINUATION frame without HEADERS"),
+            // The client opens no new streams after GOAWAY, but still reads the open ones to their end
+            FrameKind::GoAway | FrameKind::Priority | FrameKind::Unknown(_) => Ok(()),
+        }
+    }
+
+    /// Handles a DATA frame, adding its bytes to the body of the stream's request
+    ///
+    /// Streams get their window back as bytes arrive, up to `MAX_BODY_SIZE`, while the connection only gets it back once the body was handled
+    fn handle_data(&mut self, frame: Frame) -> Result<(), H2Error> {
+        let stream_id = frame.stream_id;
+        let Some(data) = frame.unpadded_payload() else {
+            return Err(H2Error::Connection(
+                ErrorCode::ProtocolError,
+                "Padding longer than the frame",
+            ));
+        };
+        let end_stream = frame.has_flag(END_STREAM);
+        let len = frame.payload.len();
+        self.shared.consume(len)?;
+
+        let Some((_, body)) = self.receiving.get_mut(&stream_id) else {
+            self.shared.release(len)?;
+            if stream_id > self.last_stream_id {
+                return Err(H2Error::Connection(
+                    ErrorCode::ProtocolError,
+                    "DATA frame on an idle stream",
+                ));
+            }
+            return Err(H2Error::Stream(stream_id, ErrorCode::StreamClosed));
+        };
+        if body.len() + data.len() > MAX_BODY_SIZE {
+            self.shared.release(len)?;
+            return Err(H2Error::Stream(stream_id, ErrorCode::Cancel));
+        }
+        body.extend_from_slice(data);
+
+        // Padding isn't kept, so its share of the window is returned at once
+        self.shared.release(len - data.len())?;
+        if !end_stream && len > 0 {
+            self.shared
+                .write_frames(&[Frame::window_update(stream_id, len as u32)])?;
+        }
+
+        if end_stream && let Some((req, body)) = self.receiving.remove(&stream_id) {
+            self.dispatch(stream_id, req, body)?;
+        }
+        Ok(())
+    }
+
+    /// Handles a HEADERS frame and its CONTINUATION frames, opening a stream or ending one with trailers
+    fn handle_headers(&mut self, frame: Frame, reader: &mut impl Read) -> Result<(), H2Error> {
+        let stream_id = frame.stream_id;
+        let end_stream = frame.has_flag(END_STREAM);
+        let mut block = frame.unpadded_payload().map(<[u8]>::to_vec);
+        if frame.has_flag(PRIORITY) {
+            block = block
+                .filter(|block| block.len() >= 5)
+                .map(|block| block[5..].to_vec());
+        }
+        let Some(mut block) = block else {
+            return Err(H2Error::Connection(
+                ErrorCode::ProtocolError,
+                "HEADERS frame too short for its padding and priority",
+            ));
+        };
+
+        // Collect the rest of the header block, which no other frame may interrupt
+        let mut end_headers = frame.has_flag(END_HEADERS);
+        while !end_headers {
+            let next = Frame::read(reader, DEFAULT_MAX_FRAME_SIZE)?;
+            if next.kind != FrameKind::Continuation || next.stream_id != stream_id {
+                return Err(H2Error::Connection(
+                    ErrorCode::ProtocolError,
+                    "Header block interrupted by another frame",
+                ));
+            }
+            if block.len() + next.payload.len() > MAX_HEADER_BLOCK {
+                return Err(H2Error::Connection(
+                    ErrorCode::ProtocolError,
+                    "Header block too large",
+                ));
+            }
+            block.extend_from_slice(&next.payload);
+            end_headers = next.has_flag(END_HEADERS);
+        }
+
+        // Every block is decoded, even for refused streams, to keep the compression state in sync
+        let Some(fields) = self.decoder.decode(&block) else {
+            return Err(H2Error::Connection(
+                ErrorCode::CompressionError,
+                "Malformed header block",
+            ));
+        };
+
+        // Trailers end a request whose body was still arriving, and are dropped
+        if let Some((req, body)) = self.receiving.remove(&stream_id) {
+            if !end_stream {
+                return Err(H2Error::Stream(stream_id, ErrorCode::ProtocolError));
+            }
+            return self.dispatch(stream_id, req, body);
+        }
+        if stream_id <= self.last_stream_id {
+            return Err(H2Error::Connection(
+                ErrorCode::StreamClosed,
+                "HEADERS frame on a closed stream",
+            ));
+        }
+
+        self.last_stream_id = stream_id;
+        if self.shared.flow.lock().unwrap().streams.len() >= MAX_CONCURRENT_STREAMS as usize {
+            return Err(H2Error::Stream(stream_id, ErrorCode::RefusedStream));
+        }
+        let Some(req) = build_request(fields) else {
+            return Err(H2Error::Stream(stream_id, ErrorCode::ProtocolError));
+        };
+
+        self.open_stream(stream_id);
+        if end_stream {
+            self.dispatch(stream_id, req, vec![])
+        } else {
+            self.receiving.insert(stream_id, (req, vec![]));
+            Ok(())
+        }
+    }
+
+    /// Applies the client's settings, resizing the send windows of open streams, and acknowledges them
+    fn handle_settings(&mut self, payload: &[u8]) -> Result<(), H2Error> {
+        {
+            let mut flow = self.shared.flow.lock().unwrap();
+            let old_window = flow.settings.initial_window_size;
+            flow.settings.apply(payload)?;
+            let delta = flow.settings.initial_window_size - old_window;
+            for window in flow.streams.values_mut() {
+                *window += delta;
+                if *window > MAX_WINDOW_SIZE {
+                    return Err(H2Error::Connection(
+                        ErrorCode::FlowControlError,
+                        "Stream window grew too large",
+                    ));
+                }
+            }
+        }
+        self.shared.window_opened.notify_all();
+
+        Ok(self
+            .shared
+            .write_frames(&[Frame::new(FrameKind::Settings, ACK, 0, vec![])])?)
+    }
+
+    /// Grows the send window of the connection or one of its streams
+    fn handle_window_update(&mut self, stream_id: u32, payload: &[u8]) -> Result<(), H2Error> {
+        let increment =
+            u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) & 0x7fff_ffff;
+        if increment == 0 && stream_id == 0 {
+            return Err(H2Error::Connection(
+                ErrorCode::ProtocolError,
+                "WINDOW_UPDATE must not be 0",
+            ));
+        }
+        if increment == 0 {
+            return Err(H2Error::Stream(stream_id, ErrorCode::ProtocolError));
+        }
+
+        {
+            let mut flow = self.shared.flow.lock().unwrap();
+            if stream_id == 0 {
+                flow.connection_window += increment as i64;
+                if flow.connection_window > MAX_WINDOW_SIZE {
+                    return Err(H2Error::Connection(
+                        ErrorCode::FlowControlError,
+                        "Connection window grew too large",
+                    ));
+                }
+            } else if let Some(window) = flow.streams.get_mut(&stream_id) {
+                // Updates for streams that already closed are ignored
+                *window += increment as i64;
+                if *window > MAX_WINDOW_SIZE {
+                    return Err(H2Error::Stream(stream_id, ErrorCode::FlowControlError));
+                }
+            }
+        }
+        self.shared.window_opened.notify_all();
+        Ok(())
+    }
+
+    /// Starts tracking the send window of a new stream
+    fn open_stream(&mut self, stream_id: u32) {
+        let mut flow = self.shared.flow.lock().unwrap();
+        let window = flow.settings.initial_window_size;
+        flow.streams.insert(stream_id, window);
+    }
+
+    /// Forgets a stream that was reset, stopping its responder if one is running
+    ///
+    /// Returns an error if the window held by its unfinished body couldn't be returned
+    fn close_stream(&mut self, stream_id: u32) -> io::Result<()> {
+        let received = self.receiving.remove(&stream_id);
+        self.shared.flow.lock().unwrap().streams.remove(&stream_id);
+        self.shared.window_opened.notify_all();
+        self.shared
+            .release(received.map_or(0, |(_, body)| body.len()))
+    }
+
+    /// Hands a complete request to a handler thread, starting one unless `MAX_CONCURRENT_HANDLERS` are running
+    ///
+    /// Returns an error resetting the stream if the body doesn't match its `content-length`
+    fn dispatch(&mut self, stream_id: u32, mut req: Request, body: Vec<u8>) -> Result<(), H2Error> {
+        match req.get_headers().get("content-length") {
+            Some(len) if len.trim().parse() != Ok(body.len()) => {
+                self.shared.release(body.len())?;
+                return Err(H2Error::Stream(stream_id, ErrorCode::ProtocolError));
+            }
+            None if !body.is_empty() => {
+                // Handlers read bodies as HTTP/1.1 would frame them
+                req.append_header(format!("content-length: {}", body.len()));
+            }
+            _ => {}
+        }
+
+        // Busy connections queue their requests for the running handlers
+        {
+            let mut handlers = self.shared.handlers.lock().unwrap();
+            if handlers.running >= MAX_CONCURRENT_HANDLERS {
+                handlers.pending.push_back((stream_id, req, body));
+                return Ok(());
+            }
+            handlers.running += 1;
+        }
+
+        let (shared, handler) = (self.shared, self.handler);
+        self.scope.spawn(move || {
+            let mut job = Some((stream_id, req, body));
+            while let Some((stream_id, req, body)) = job {
+                let len = body.len();
+                handler(req, body, Responder { shared, stream_id });
+
+                // The body was handled, so the client may send as much again
+                let _ = shared.release(len);
+                job = shared.next_job();
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Builds a Request from the header fields of a stream
+///
+/// Returns None if the fields are malformed, e.g. missing pseudo-headers or carrying connection-specific headers
+fn build_request(fields: Vec<(String, String)>) -> Option<Request> {
+    let (mut method, mut path, mut scheme, mut authority) = (None, None, None, None);
+    let mut headers: Vec<(String, String)> = vec![];
+
+    for (name, value) in fields {
+        // Pseudo-headers come first, once each
+        if let Some(pseudo) = name.strip_prefix(':') {
+            let slot = match pseudo {
+                "method" => &mut method,
+                "path" => &mut path,
+                "scheme" => &mut scheme,
+                "authority" => &mut authority,
+                _ => return None,
+            };
+            if !headers.is_empty() || slot.replace(value).is_some() {
+                return None;
+            }
+            continue;
+        }
+
+        if name.bytes().any(|byte| byte.is_ascii_uppercase())
+            || CONNECTION_HEADERS.contains(&name.as_str())
+            || (name == "te" && value != "trailers")
+        {
+            return None;
+        }
+
+        // Join repeated fields as HTTP/1.1 would, cookies with `; `
+        match headers.iter_mut().find(|(existing, _)| *existing == name) {
+            Some((_, existing)) => {
+                existing.push_str(if name == "cookie" { "; " } else { ", " });
+                existing.push_str(&value);
+            }
+            None => headers.push((name, value)),
+        }
+    }
+
+    let (Some(method), Some(path), Some(_)) = (method, path, scheme) else {
+        return None;
+    };
+    if path.is_empty() {
+        return None;
+    }
+
+    let mut req = Request::default();
+    req.parse_status_line(format!("{method} {path} HTTP/2.0"));
+    if let Some(authority) = authority.filter(|_| !headers.iter().any(|(name, _)| name == "host")) {
+        req.append_header(format!("host: {authority}"));
+    }
+    for (name, value) in headers {
+        req.append_header(format!("{name}: {value}"));
+    }
+    req.read_trace_headers();
+
+    Some(req)
+}
+
+/// Returns the error a responder gets when the client reset its stream
+fn stream_reset() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "HTTP/2 stream reset by the client",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+
+    /// Helper function to encode a request header block
+    fn request_block(method: &str, path: &str, extra: &[(&str, &str)]) -> Vec<u8> {
+        let mut fields = vec![
+            (":method".to_owned(), method.to_owned()),
+            (":scheme".to_owned(), "http".to_owned()),
+            (":path".to_owned(), path.to_owned()),
+            (":authority".to_owned(), "localhost".to_owned()),
+        ];
+        fields.extend(
+            extra
+                .iter()
+                .map(|&(name, value)| (name.to_owned(), value.to_owned())),
+        );
+        encode_headers(&fields)
+    }
+
+    #[test]
+    fn test_multiplexes_streams_with_flow_control() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = &stream;
+            let mut preface = [0; PREFACE.len()];
+            reader.read_exact(&mut preface).unwrap();
+            serve_http2(&mut reader, &stream, None, |req, body, responder| {
+                let mut res = Response::default();
+                res.set_status(200);
+                if req.get_path() == "/big" {
+                    res.set_body_bytes(vec![b'x'; 100_000]);
+                } else {
+                    res.set_body_bytes([req.get_path().as_bytes(), b" ", &body].concat());
+                }
+                responder.send(res).unwrap();
+            })
+        });
+
+        // A large download, an upload, and a malformed request share the connection
+        let mut client = TcpStream::connect(addr).unwrap();
+        let mut out = PREFACE.to_vec();
+        let frames = [
+            Frame::new(FrameKind::Settings, 0, 0, vec![]),
+            Frame::new(
+                FrameKind::Headers,
+                END_HEADERS | END_STREAM,
+                1,
+                request_block("GET", "/big", &[]),
+            ),
+            Frame::new(
+                FrameKind::Headers,
+                END_HEADERS,
+                3,
+                request_block("POST", "/echo", &[]),
+            ),
+            Frame::new(FrameKind::Data, END_STREAM, 3, b"ping".to_vec()),
+            Frame::new(
+                FrameKind::Headers,
+                END_HEADERS | END_STREAM,
+                5,
+                request_block("GET", "/", &[("Bad-Name", "x")]),
+            ),
+        ];
+        for frame in &frames {
+            frame.write(&mut out).unwrap();
+        }
+        client.write_all(&out).unwrap();
+
+        let mut decoder = Decoder::default();
+        let mut bodies: HashMap<u32, Vec<u8>> = HashMap::new();
+        let (mut ended, mut reset) = (vec![], vec![]);
+        while ended.len() < 2 || reset.is_empty() {
+            let frame = Frame::read(&mut client, 1 << 24).unwrap();
+            match frame.kind {
+                FrameKind::Headers => {
+                    let fields = decoder.decode(&frame.payload).unwrap();
+                    assert_eq!(fields[0], (":status".to_owned(), "200".to_owned()));
+                }
+                FrameKind::Data => {
+                    bodies
+                        .entry(frame.stream_id)
+                        .or_default()
+                        .extend_from_slice(&frame.payload);
+
+                    // Open the windows again so the large body can finish
+                    if !frame.payload.is_empty() {
+                        let len = frame.payload.len() as u32;
+                        let mut update = vec![];
+                        Frame::window_update(0, len).write(&mut update).unwrap();
+                        Frame::window_update(frame.stream_id, len)
+                            .write(&mut update)
+                            .unwrap();
+                        client.write_all(&update).unwrap();
+                    }
+                }
+                FrameKind::RstStream => {
+                    assert_eq!(
+                        frame.payload,
+                        (ErrorCode::ProtocolError as u32).to_be_bytes()
+                    );
+                    reset.push(frame.stream_id);
+                }
+                _ => {}
+            }
+            if frame.kind == FrameKind::Data && frame.has_flag(END_STREAM) {
+                ended.push(frame.stream_id);
+            }
+        }
+
+        assert_eq!(reset, [5]);
+        assert_eq!(bodies[&1], vec![b'x'; 100_000]);
+        assert_eq!(bodies[&3], b"/echo ping");
+
+        // The server returns once the client closes the connection
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        assert!(server.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_bounds_request_bodies_and_handlers() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = &stream;
+            let mut preface = [0; PREFACE.len()];
+            reader.read_exact(&mut preface).unwrap();
+            let (running, most) = (AtomicUsize::new(0), AtomicUsize::new(0));
+            serve_http2(&mut reader, &stream, None, |_, body, responder| {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(20));
+                running.fetch_sub(1, Ordering::SeqCst);
+
+                let mut res = Response::default();
+                res.set_status(200);
+                res.set_body_bytes(body.len().to_string().into_bytes());
+                responder.send(res).unwrap();
+            })
+            .unwrap();
+            most.into_inner()
+        });
+
+        // An upload over the body limit, then more small requests than there are handlers
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut out = PREFACE.to_vec();
+        Frame::new(FrameKind::Settings, 0, 0, vec![])
+            .write(&mut out)
+            .unwrap();
+        Frame::new(
+            FrameKind::Headers,
+            END_HEADERS,
+            1,
+            request_block("POST", "/upload", &[]),
+        )
+        .write(&mut out)
+        .unwrap();
+        let chunk = vec![0; DEFAULT_MAX_FRAME_SIZE];
+        let mut sent = 0;
+        while sent <= MAX_BODY_SIZE {
+            Frame::new(FrameKind::Data, 0, 1, chunk.clone())
+                .write(&mut out)
+                .unwrap();
+            sent += chunk.len();
+        }
+        let small_streams: Vec<u32> = (0..20).map(|i| 3 + 2 * i).collect();
+        for &stream_id in &small_streams {
+            Frame::new(
+                FrameKind::Headers,
+                END_HEADERS,
+                stream_id,
+                request_block("POST", "/echo", &[]),
+            )
+            .write(&mut out)
+            .unwrap();
+            Frame::new(FrameKind::Data, END_STREAM, stream_id, b"ping".to_vec())
+                .write(&mut out)
+                .unwrap();
+            sent += 4;
+        }
+        client.write_all(&out).unwrap();
+
+        // Every byte sent is eventually given back to the connection window, once handled or dropped
+        let expected = (RECEIVE_WINDOW - DEFAULT_WINDOW_SIZE) as usize + sent;
+        let (mut returned, mut reset, mut ended) = (0, vec![], vec![]);
+        while returned < expected || ended.len() < small_streams.len() {
+            let frame = Frame::read(&mut client, 1 << 24).unwrap();
+            match frame.kind {
+                FrameKind::WindowUpdate if frame.stream_id == 0 => {
+                    returned += u32::from_be_bytes(frame.payload[..4].try_into().unwrap()) as usize;
+                }
+                FrameKind::RstStream => {
+                    assert_eq!(frame.payload, (ErrorCode::Cancel as u32).to_be_bytes());
+                    reset.push(frame.stream_id);
+                }
+                FrameKind::Data if frame.has_flag(END_STREAM) => {
+                    assert_eq!(frame.payload, b"4");
+                    ended.push(frame.stream_id);
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(returned, expected);
+        assert_eq!(reset, [1]);
+
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        assert!(server.join().unwrap() <= MAX_CONCURRENT_HANDLERS);
+    }
+}
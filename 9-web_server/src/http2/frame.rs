@@ -0,0 +1,365 @@
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+/// Bytes every HTTP/2 client sends before its first frame
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Flag ending the stream on DATA and HEADERS frames
+pub const END_STREAM: u8 = 0x1;
+/// Flag acknowledging SETTINGS and PING frames
+pub const ACK: u8 = 0x1;
+/// Flag ending the header block on HEADERS and CONTINUATION frames
+pub const END_HEADERS: u8 = 0x4;
+/// Flag marking padded DATA and HEADERS frames
+pub const PADDED: u8 = 0x8;
+/// Flag marking HEADERS frames carrying a stream priority
+pub const PRIORITY: u8 = 0x20;
+
+/// Largest frame payload peers may send before raising it with their settings
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+/// Flow-control window of new streams and connections before the settings change it
+pub const DEFAULT_WINDOW_SIZE: i64 = 65_535;
+/// Largest flow-control window allowed
+pub const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+
+/// Types of frames, identified by the byte in the frame header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Data,
+    Headers,
+    Priority,
+    RstStream,
+    Settings,
+    PushPromise,
+    Ping,
+    GoAway,
+    WindowUpdate,
+    Continuation,
+    /// Extension frames, which are ignored
+    Unknown(u8),
+}
+
+impl FrameKind {
+    /// Returns the byte identifying the frame type
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            FrameKind::Data => 0x0,
+            FrameKind::Headers => 0x1,
+            FrameKind::Priority => 0x2,
+            FrameKind::RstStream => 0x3,
+            FrameKind::Settings => 0x4,
+            FrameKind::PushPromise => 0x5,
+            FrameKind::Ping => 0x6,
+            FrameKind::GoAway => 0x7,
+            FrameKind::WindowUpdate => 0x8,
+            FrameKind::Continuation => 0x9,
+            FrameKind::Unknown(kind) => *kind,
+        }
+    }
+
+    /// Parses the byte identifying a frame type
+    pub fn from_u8(kind: u8) -> FrameKind {
+        match kind {
+            0x0 => FrameKind::Data,
+            0x1 => FrameKind::Headers,
+            0x2 => FrameKind::Priority,
+            0x3 => FrameKind::RstStream,
+            0x4 => FrameKind::Settings,
+            0x5 => FrameKind::PushPromise,
+            0x6 => FrameKind::Ping,
+            0x7 => FrameKind::GoAway,
+            0x8 => FrameKind::WindowUpdate,
+            0x9 => FrameKind::Continuation,
+            _ => FrameKind::Unknown(kind),
+        }
+    }
+}
+
+/// Error codes sent in RST_STREAM and GOAWAY frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    NoError = 0x0,
+    ProtocolError = 0x1,
+    InternalError = 0x2,
+    FlowControlError = 0x3,
+    StreamClosed = 0x5,
+    FrameSizeError = 0x6,
+    RefusedStream = 0x7,
+    Cancel = 0x8,
+    CompressionError = 0x9,
+}
+
+/// Error ending an HTTP/2 connection or one of its streams
+#[derive(Debug)]
+pub enum H2Error {
+    /// The connection failed or was closed
+    Io(io::Error),
+    /// The peer broke the protocol and the connection is closed with GOAWAY
+    Connection(ErrorCode, &'static str),
+    /// A single stream is reset with RST_STREAM
+    Stream(u32, ErrorCode),
+}
+
+impl From<io::Error> for H2Error {
+    fn from(err: io::Error) -> Self {
+        H2Error::Io(err)
+    }
+}
+
+impl fmt::Display for H2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            H2Error::Io(err) => write!(f, "{err}"),
+            H2Error::Connection(code, reason) => write!(f, "{reason} ({code:?})"),
+            H2Error::Stream(id, code) => write!(f, "stream {id} reset ({code:?})"),
+        }
+    }
+}
+
+/// A single HTTP/2 frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub flags: u8,
+    pub stream_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Creates a frame
+    ///
+    /// The `stream_id` is 0 for frames concerning the whole connection
+    pub fn new(kind: FrameKind, flags: u8, stream_id: u32, payload: Vec<u8>) -> Frame {
+        Frame {
+            kind,
+            flags,
+            stream_id,
+            payload,
+        }
+    }
+
+    /// Reads the next frame from a connection
+    ///
+    /// The `max_size` is the largest payload this side accepts, as advertised in its settings
+    ///
+    /// Returns the frame, or an error if the connection failed or the frame is too large
+    pub fn read(reader: &mut impl Read, max_size: usize) -> Result<Frame, H2Error> {
+        let mut header = [0; 9];
+        reader.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        if len > max_size {
+            return Err(H2Error::Connection(
+                ErrorCode::FrameSizeError,
+                "Frame larger than the maximum frame size",
+            ));
+        }
+
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload)?;
+        Ok(Frame {
+            kind: FrameKind::from_u8(header[3]),
+            flags: header[4],
+            stream_id: u32::from_be_bytes([header[5], header[6], header[7], header[8]])
+                & 0x7fff_ffff,
+            payload,
+        })
+    }
+
+    /// Writes the frame to a connection
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let len = (self.payload.len() as u32).to_be_bytes();
+        let id = self.stream_id.to_be_bytes();
+        writer.write_all(&[
+            len[1],
+            len[2],
+            len[3],
+            self.kind.as_u8(),
+            self.flags,
+            id[0],
+            id[1],
+            id[2],
+            id[3],
+        ])?;
+        writer.write_all(&self.payload)
+    }
+
+    /// Checks whether a flag is set on the frame
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Returns the payload of a DATA or HEADERS frame without its padding
+    ///
+    /// Returns None if the padding is longer than the payload
+    pub fn unpadded_payload(&self) -> Option<&[u8]> {
+        if !self.has_flag(PADDED) {
+            return Some(&self.payload);
+        }
+        let (&pad_len, rest) = self.payload.split_first()?;
+        rest.len()
+            .checked_sub(pad_len as usize)
+            .map(|len| &rest[..len])
+    }
+
+    /// Creates a RST_STREAM frame resetting a stream
+    pub fn rst_stream(stream_id: u32, code: ErrorCode) -> Frame {
+        Frame::new(
+            FrameKind::RstStream,
+            0,
+            stream_id,
+            (code as u32).to_be_bytes().to_vec(),
+        )
+    }
+
+    /// Creates a GOAWAY frame closing the connection
+    ///
+    /// The `last_stream_id` is the highest stream the server processed or may still process
+    pub fn go_away(last_stream_id: u32, code: ErrorCode) -> Frame {
+        let mut payload = last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&(code as u32).to_be_bytes());
+        Frame::new(FrameKind::GoAway, 0, 0, payload)
+    }
+
+    /// Creates a WINDOW_UPDATE frame letting the peer send `increment` more bytes
+    pub fn window_update(stream_id: u32, increment: u32) -> Frame {
+        Frame::new(
+            FrameKind::WindowUpdate,
+            0,
+            stream_id,
+            increment.to_be_bytes().to_vec(),
+        )
+    }
+}
+
+/// Settings a peer sent about what it accepts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Settings {
+    pub max_concurrent_streams: Option<u32>,
+    pub initial_window_size: i64,
+    pub max_frame_size: usize,
+    pub max_header_list_size: Option<u32>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            max_concurrent_streams: None,
+            initial_window_size: DEFAULT_WINDOW_SIZE,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_header_list_size: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Applies the parameters of a SETTINGS frame payload
+    ///
+    /// Unknown parameters, and those of no concern to a server that never pushes or indexes its headers, are ignored
+    ///
+    /// Returns an error if the payload is malformed or a value is out of range
+    pub fn apply(&mut self, payload: &[u8]) -> Result<(), H2Error> {
+        if !payload.len().is_multiple_of(6) {
+            return Err(H2Error::Connection(
+                ErrorCode::FrameSizeError,
+                "SETTINGS payload is not a multiple of 6 bytes",
+            ));
+        }
+
+        for param in payload.chunks_exact(6) {
+            let id = u16::from_be_bytes([param[0], param[1]]);
+            let value = u32::from_be_bytes([param[2], param[3], param[4], param[5]]);
+            match id {
+                0x2 if value > 1 => {
+                    return Err(H2Error::Connection(
+                        ErrorCode::ProtocolError,
+                        "SETTINGS_ENABLE_PUSH must be 0 or 1",
+                    ));
+                }
+                0x3 => self.max_concurrent_streams = Some(value),
+                0x4 if value as i64 > MAX_WINDOW_SIZE => {
+                    return Err(H2Error::Connection(
+                        ErrorCode::FlowControlError,
+                        "SETTINGS_INITIAL_WINDOW_SIZE is too large",
+                    ));
+                }
+                0x4 => self.initial_window_size = value as i64,
+                0x5 if !(DEFAULT_MAX_FRAME_SIZE as u32..=0xff_ffff).contains(&value) => {
+                    return Err(H2Error::Connection(
+                        ErrorCode::ProtocolError,
+                        "SETTINGS_MAX_FRAME_SIZE is out of range",
+                    ));
+                }
+                0x5 => self.max_frame_size = value as usize,
+                0x6 => self.max_header_list_size = Some(value),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encodes settings as a SETTINGS frame payload
+    ///
+    /// Only values differing from the protocol defaults are included
+    pub fn encode(&self) -> Vec<u8> {
+        let defaults = Settings::default();
+        let mut params: Vec<(u16, u32)> = vec![];
+        if let Some(streams) = self.max_concurrent_streams {
+            params.push((0x3, streams));
+        }
+        if self.initial_window_size != defaults.initial_window_size {
+            params.push((0x4, self.initial_window_size as u32));
+        }
+        if self.max_frame_size != defaults.max_frame_size {
+            params.push((0x5, self.max_frame_size as u32));
+        }
+        if let Some(size) = self.max_header_list_size {
+            params.push((0x6, size));
+        }
+
+        params
+            .into_iter()
+            .flat_map(|(id, value)| {
+                let mut param = id.to_be_bytes().to_vec();
+                param.extend_from_slice(&value.to_be_bytes());
+                param
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_and_settings_round_trip() {
+        let frame = Frame::new(FrameKind::Headers, END_HEADERS, 3, b"block".to_vec());
+        let mut bytes = vec![];
+        frame.write(&mut bytes).unwrap();
+        assert_eq!(&bytes[..9], &[0, 0, 5, 1, 4, 0, 0, 0, 3]);
+        assert_eq!(Frame::read(&mut &bytes[..], 16_384).unwrap(), frame);
+        assert!(matches!(
+            Frame::read(&mut &bytes[..], 4),
+            Err(H2Error::Connection(ErrorCode::FrameSizeError, _))
+        ));
+
+        let padded = Frame::new(FrameKind::Data, PADDED, 1, b"\x02hi\0\0".to_vec());
+        assert_eq!(padded.unpadded_payload(), Some(&b"hi"[..]));
+        let overpadded = Frame::new(FrameKind::Data, PADDED, 1, b"\x05hi".to_vec());
+        assert_eq!(overpadded.unpadded_payload(), None);
+
+        let sent = Settings {
+            max_concurrent_streams: Some(100),
+            initial_window_size: 1 << 20,
+            ..Default::default()
+        };
+        let mut received = Settings::default();
+        received.apply(&sent.encode()).unwrap();
+        assert_eq!(received, sent);
+        assert!(received.apply(&[0, 5, 0, 0, 0, 1]).is_err());
+        assert!(received.apply(&[0, 4, 0x80, 0, 0, 0]).is_err());
+    }
+}
@@ -0,0 +1,358 @@
+use std::collections::VecDeque;
+
+use super::{huffman_decode, huffman_encode, huffman_encoded_len};
+
+/// Header fields every HPACK table starts with, from RFC 7541 Appendix A. Index 1 is the first entry
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Size of the dynamic table, which the server never asks peers to change
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+
+/// Decodes header blocks, keeping the dynamic table shared by every block of a connection
+pub struct Decoder {
+    table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: DEFAULT_TABLE_SIZE,
+        }
+    }
+}
+
+impl Decoder {
+    /// Decodes a complete header block
+    ///
+    /// The `block` is the concatenated fragments of a HEADERS frame and its CONTINUATION frames
+    ///
+    /// Returns the header fields in order, or None if the block is malformed, which breaks the connection's compression state
+    pub fn decode(&mut self, block: &[u8]) -> Option<Vec<(String, String)>> {
+        let mut fields = vec![];
+        let mut input = block;
+
+        while let Some(&first) = input.first() {
+            if first & 0x80 != 0 {
+                // Indexed field
+                let index = decode_int(&mut input, 7)?;
+                fields.push(self.entry(index)?);
+            } else if first & 0x40 != 0 {
+                // Literal field added to the dynamic table
+                let field = self.decode_literal(&mut input, 6)?;
+                self.insert(field.clone());
+                fields.push(field);
+            } else if first & 0x20 != 0 {
+                // Table size updates may only start a block, and not exceed the size this side advertised
+                if !fields.is_empty() {
+                    return None;
+                }
+                let max_size = decode_int(&mut input, 5)?;
+                if max_size > DEFAULT_TABLE_SIZE {
+                    return None;
+                }
+                self.max_size = max_size;
+                self.evict();
+            } else {
+                // Literal field not added to the table, possibly never to be indexed
+                fields.push(self.decode_literal(&mut input, 4)?);
+            }
+        }
+
+        Some(fields)
+    }
+
+    /// Decodes a literal field whose name is indexed or given as a string
+    ///
+    /// The `prefix` is the number of bits holding the name index in the first byte
+    fn decode_literal(&self, input: &mut &[u8], prefix: u8) -> Option<(String, String)> {
+        let index = decode_int(input, prefix)?;
+        let name = if index == 0 {
+            decode_string(input)?
+        } else {
+            self.entry(index)?.0
+        };
+        Some((name, decode_string(input)?))
+    }
+
+    /// Looks up a field in the static table, or the dynamic table past its end
+    fn entry(&self, index: usize) -> Option<(String, String)> {
+        match index {
+            0 => None,
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Some((name.to_owned(), value.to_owned()))
+            }
+            _ => self.table.get(index - 62).cloned(),
+        }
+    }
+
+    /// Adds a field to the front of the dynamic table, evicting the oldest ones to make room
+    fn insert(&mut self, field: (String, String)) {
+        let size = entry_size(&field);
+        if size > self.max_size {
+            // A field larger than the whole table empties it
+            self.table.clear();
+            self.size = 0;
+            return;
+        }
+        self.size += size;
+        self.table.push_front(field);
+        self.evict();
+    }
+
+    /// Drops the oldest fields until the table fits its maximum size
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            let Some(field) = self.table.pop_back() else {
+                break;
+            };
+            self.size -= entry_size(&field);
+        }
+    }
+}
+
+/// Encodes header blocks without a dynamic table, so blocks can be encoded independently of each other
+///
+/// Fields found in the static table are indexed, and strings are Huffman-encoded when that makes them shorter
+pub fn encode_headers(fields: &[(String, String)]) -> Vec<u8> {
+    let mut block = vec![];
+
+    for (name, value) in fields {
+        let full_match = STATIC_TABLE
+            .iter()
+            .position(|&(n, v)| n == name && v == value && !v.is_empty());
+        if let Some(index) = full_match {
+            encode_int(&mut block, 0x80, 7, index + 1);
+            continue;
+        }
+
+        // Literal field without indexing, with an indexed name where possible
+        match STATIC_TABLE.iter().position(|&(n, _)| n == name) {
+            Some(index) => encode_int(&mut block, 0x00, 4, index + 1),
+            None => {
+                block.push(0x00);
+                encode_string(&mut block, name.as_bytes());
+            }
+        }
+        encode_string(&mut block, value.as_bytes());
+    }
+
+    block
+}
+
+/// Returns the size a field takes up in the dynamic table
+fn entry_size((name, value): &(String, String)) -> usize {
+    name.len() + value.len() + 32
+}
+
+/// Decodes an integer from its prefix bits in the first byte and any continuation bytes
+///
+/// The `prefix` is the number of low bits of the first byte holding the integer
+///
+/// Returns the integer, or None if the input ends early or the integer is implausibly large
+fn decode_int(input: &mut &[u8], prefix: u8) -> Option<usize> {
+    let (&first, rest) = input.split_first()?;
+    *input = rest;
+    let max_prefix = (1 << prefix) - 1;
+    let mut value = (first & max_prefix) as usize;
+    if value < max_prefix as usize {
+        return Some(value);
+    }
+
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = input.split_first()?;
+        *input = rest;
+        if shift > 28 {
+            return None;
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+}
+
+/// Decodes a length-prefixed string literal, Huffman-encoded or not
+fn decode_string(input: &mut &[u8]) -> Option<String> {
+    let huffman = input.first()? & 0x80 != 0;
+    let len = decode_int(input, 7)?;
+    if input.len() < len {
+        return None;
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+
+    let bytes = if huffman {
+        huffman_decode(bytes)?
+    } else {
+        bytes.to_vec()
+    };
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Encodes an integer into the low `prefix` bits of a first byte carrying `flags`, and continuation bytes
+fn encode_int(block: &mut Vec<u8>, flags: u8, prefix: u8, mut value: usize) {
+    let max_prefix = (1usize << prefix) - 1;
+    if value < max_prefix {
+        block.push(flags | value as u8);
+        return;
+    }
+
+    block.push(flags | max_prefix as u8);
+    value -= max_prefix;
+    while value >= 0x80 {
+        block.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+/// Encodes a length-prefixed string literal, Huffman-encoded if that is shorter
+fn encode_string(block: &mut Vec<u8>, bytes: &[u8]) {
+    if huffman_encoded_len(bytes) < bytes.len() {
+        let encoded = huffman_encode(bytes);
+        encode_int(block, 0x80, 7, encoded.len());
+        block.extend_from_slice(&encoded);
+    } else {
+        encode_int(block, 0x00, 7, bytes.len());
+        block.extend_from_slice(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Helper function to compare decoded fields with expected pairs
+    fn fields(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn test_decodes_requests_sharing_a_dynamic_table() {
+        // Requests from RFC 7541 Appendix C.4, with Huffman-encoded strings
+        let mut decoder = Decoder::default();
+        let first = [
+            0x82, 0x86, 0x84, 0x41, 0x8c, 0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab,
+            0x90, 0xf4, 0xff,
+        ];
+        assert_eq!(
+            decoder.decode(&first).unwrap(),
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ])
+        );
+        let second = [
+            0x82, 0x86, 0x84, 0xbe, 0x58, 0x86, 0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbf,
+        ];
+        assert_eq!(
+            decoder.decode(&second).unwrap(),
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+                ("cache-control", "no-cache"),
+            ])
+        );
+        assert_eq!(decoder.size, 110);
+
+        // Unknown indexes and truncated blocks are rejected
+        assert_eq!(decoder.decode(&[0xc0]), None);
+        assert_eq!(decoder.decode(&[0x41, 0x8c, 0xf1]), None);
+        assert_eq!(decoder.decode(&[0x82, 0x3f, 0xe1, 0x1f]), None);
+    }
+
+    #[test]
+    fn test_encoded_headers_decode() {
+        let headers = fields(&[
+            (":status", "200"),
+            (":status", "302"),
+            ("content-type", "text/html; charset=utf-8"),
+            ("x-request-id", "abc"),
+            ("set-cookie", &"a".repeat(300)),
+        ]);
+        let block = encode_headers(&headers);
+        assert_eq!(block[0], 0x88);
+        assert_eq!(Decoder::default().decode(&block).unwrap(), headers);
+
+        let mut block = vec![];
+        encode_int(&mut block, 0x00, 5, 1337);
+        assert_eq!(block, [0x1f, 0x9a, 0x0a]);
+        assert_eq!(decode_int(&mut &block[..], 5), Some(1337));
+    }
+}
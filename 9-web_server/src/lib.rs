@@ -1,2 +1,5 @@
+pub mod client;
+pub mod http2;
 pub mod models;
+pub mod server;
 pub mod utils;
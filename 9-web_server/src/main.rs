@@ -1,41 +1,73 @@
-use std::{
-    io::{BufReader, Write},
-    net::{TcpListener, TcpStream},
-};
+use std::{env, path::Path, process, sync::Arc, thread};
 
 use web_server::{
-    models::ThreadPool,
-    utils::{parse_request, route},
+    models::{ListenerRole, ServerConfig, ServerState, ThreadPool, inherited_listeners},
+    server::{bind_listeners, serve, serve_admin, serve_metrics},
 };
 
 fn main() {
-    // Create TCP listener bound to localhost on port 7878
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap(); // TODO: Handle possible error case
-    let pool = ThreadPool::new(50);
+    // Load configuration from the path given as the first argument, or `server.toml`
+    let config_path = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "server.toml".to_owned());
+    let config = match ServerConfig::load(Path::new(&config_path)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Error loading {config_path}: {err}");
+            process::exit(1);
+        }
+    };
+
+    // Use the sockets passed by socket activation, or bind the configured addresses
+    let listeners = match inherited_listeners() {
+        Some(inherited) => {
+            inherited.map_err(|err| format!("Error taking inherited sockets: {err}"))
+        }
+        None => bind_listeners(&config),
+    };
+    let listeners = listeners.unwrap_or_else(|err| {
+        eprintln!("{err}");
+        process::exit(1);
+    });
+    let pool = ThreadPool::new(config.threads);
+
+    // Share configuration, file cache, and metrics between workers
+    let state = Arc::new(ServerState::new(config, pool.load()));
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
+    let (main_listeners, others): (Vec<_>, Vec<_>) = listeners
+        .into_iter()
+        .partition(|(_, role)| *role == ListenerRole::Main);
+    let wake_addrs: Vec<_> = main_listeners
+        .iter()
+        .filter_map(|(listener, _)| listener.local_addr().ok())
+        .collect();
 
-        pool.execute(|| {
-            handle_connection(stream);
-        });
+    // Serve the admin API and metrics on their own listeners
+    for (listener, role) in others {
+        let state = Arc::clone(&state);
+        match role {
+            ListenerRole::Metrics => {
+                thread::spawn(move || serve_metrics(listener, &state));
+            }
+            _ => {
+                let wake_addrs = wake_addrs.clone();
+                thread::spawn(move || serve_admin(listener, &state, &wake_addrs));
+            }
+        }
     }
-}
 
-/// Handles each request from client
-///
-/// The `stream` is the TcpStream containing the HTTP request
-fn handle_connection(mut stream: TcpStream) {
-    // Get all lines from stream
-    let mut buf_reader = BufReader::new(&stream);
-    let req = parse_request(&mut buf_reader);
-
-    // Construct response based on request
-    let res = route(req);
-
-    // Send response
-    stream
-        .write_all(res.stringify().as_bytes())
-        .unwrap_or_else(|err| eprintln!("Error sending response: {err}"));
-    // NOTE: Flush stream after?
+    // Accept on every main listener until a drain is requested
+    thread::scope(|scope| {
+        for (listener, _) in main_listeners {
+            let (state, pool) = (&state, &pool);
+            scope.spawn(move || serve(listener, state, pool));
+        }
+    });
+
+    // Dropping the pool waits for open connections to finish
+    eprintln!(
+        "Draining {} open connections before exiting",
+        state.connections.len()
+    );
+    drop(pool);
 }
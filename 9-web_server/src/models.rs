@@ -1,9 +1,37 @@
+mod config;
+mod connections;
+mod cookie;
+mod doc_store;
+mod file_cache;
 mod http;
+mod listener;
+mod log_level;
+mod metrics;
+mod mime_types;
+mod path_locks;
+mod rate_limit;
 mod request;
 mod response;
+mod session;
+mod state;
 mod thread_pool;
+mod trace;
 
+pub use config::*;
+pub use connections::*;
+pub use cookie::*;
+pub use doc_store::*;
+pub use file_cache::*;
 pub use http::*;
+pub use listener::*;
+pub use log_level::*;
+pub use metrics::*;
+pub use mime_types::*;
+pub use path_locks::*;
+pub use rate_limit::*;
 pub use request::*;
 pub use response::*;
+pub use session::*;
+pub use state::*;
 pub use thread_pool::*;
+pub use trace::*;
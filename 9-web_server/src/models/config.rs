@@ -0,0 +1,1210 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use regex::Regex;
+use serde::{Deserialize, Deserializer, de::Error as _};
+
+use super::{
+    DocumentStore, FileStore, LogLevel, MemoryStore, MimeTypes, RateDecision, RateLimiter, Request,
+    SameSite, SessionManager, SessionStore,
+};
+use crate::{log_error, utils::WEBDAV_METHODS};
+
+/// Server configuration loaded from a TOML file
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Address the main listener binds to, `host:port` or `unix:` followed by a socket path
+    pub address: String,
+    /// Further listeners, e.g. for IPv6 or a Unix domain socket. Ignored along with `address` when the server is
+    /// started through socket activation
+    pub listen: Vec<ListenerConfig>,
+    /// Takes the client address of requests on Unix domain sockets from the last `X-Forwarded-For` entry, as added
+    /// by a front proxy. Only processes allowed to open the socket file can set it
+    pub unix_forwarded_for: bool,
+    /// Number of worker threads in the ThreadPool
+    pub threads: usize,
+    /// Verbosity of the logs, which the admin API can change at runtime
+    pub log_level: LogLevel,
+    /// Serves `/healthz` and `/readyz` probes on the main listener
+    pub health_checks: bool,
+    /// Speaks HTTP/2 over cleartext to clients that start with its preface or ask to upgrade
+    pub http2: bool,
+    /// In-memory cache of static files
+    pub cache: CacheConfig,
+    /// Media types of served files
+    pub mime: MimeConfig,
+    /// Prometheus metrics endpoint
+    pub metrics: MetricsConfig,
+    /// Authenticated admin API on a separate listener
+    pub admin: Option<AdminConfig>,
+    /// Site used when no virtual host matches the request's `Host`
+    #[serde(flatten)]
+    pub default_site: SiteConfig,
+    /// Name-based virtual hosts
+    #[serde(rename = "site")]
+    pub sites: Vec<SiteConfig>,
+    /// File the configuration was loaded from, used to reload it
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: String::from("127.0.0.1:7878"),
+            listen: vec![],
+            unix_forwarded_for: false,
+            threads: 50,
+            log_level: LogLevel::Info,
+            health_checks: true,
+            http2: true,
+            cache: CacheConfig::default(),
+            mime: MimeConfig::default(),
+            metrics: MetricsConfig::default(),
+            admin: None,
+            default_site: SiteConfig::default(),
+            sites: vec![],
+            path: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Loads the configuration from a TOML file
+    ///
+    /// The `path` is the location of the configuration file. A missing file yields the default configuration
+    ///
+    /// Returns the parsed ServerConfig or a message describing why it is invalid
+    pub fn load(path: &Path) -> Result<ServerConfig, String> {
+        if !path.exists() {
+            return Ok(ServerConfig {
+                path: Some(path.to_path_buf()),
+                ..ServerConfig::default()
+            });
+        }
+
+        let contents = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let mut config: ServerConfig = toml::from_str(&contents).map_err(|err| err.to_string())?;
+        config.path = Some(path.to_path_buf());
+
+        // Validate settings that can't be expressed through serde
+        if config.threads == 0 {
+            return Err("threads must be greater than zero".to_owned());
+        }
+        if let Some(admin) = &config.admin
+            && admin.htpasswd.is_none()
+            && admin.tokens.is_none()
+        {
+            return Err("admin needs an htpasswd or tokens file".to_owned());
+        }
+        for listener in &config.listen {
+            if listener.role == ListenerRole::Admin && config.admin.is_none() {
+                return Err(format!(
+                    "admin listener {:?} needs an [admin] table",
+                    listener.address
+                ));
+            }
+            if listener.role == ListenerRole::Metrics && !config.metrics.enabled {
+                return Err(format!(
+                    "metrics listener {:?} needs metrics to be enabled",
+                    listener.address
+                ));
+            }
+        }
+        if let Some(types_file) = &config.mime.types_file
+            && fs::metadata(types_file).is_err()
+        {
+            return Err(format!(
+                "mime types_file {} is not readable",
+                types_file.display()
+            ));
+        }
+        config.default_site.validate()?;
+        for site in &config.sites {
+            if site.hosts.is_empty() {
+                return Err(format!("site {:?} has no hosts", site.root));
+            }
+            site.validate()?;
+        }
+
+        Ok(config)
+    }
+
+    /// Keeps the sessions and rate-limit buckets of a previous configuration, e.g. across a reload
+    ///
+    /// The `previous` configuration's sites are matched to these by their hosts
+    pub fn carry_over_state(&mut self, previous: &ServerConfig) {
+        self.default_site.carry_over_state(&previous.default_site);
+        for site in &mut self.sites {
+            if let Some(old) = previous.sites.iter().find(|old| old.hosts == site.hosts) {
+                site.carry_over_state(old);
+            }
+        }
+    }
+
+    /// Checks whether metrics are served on the main listeners rather than a dedicated one
+    pub fn metrics_on_main(&self) -> bool {
+        self.metrics.enabled
+            && self.metrics.address.is_none()
+            && !self
+                .listen
+                .iter()
+                .any(|listener| listener.role == ListenerRole::Metrics)
+    }
+
+    /// Finds the site responsible for a request's `Host` header
+    ///
+    /// The `host` is the raw header value, possibly including a port. Falls back to the default site
+    pub fn find_site(&self, host: Option<&str>) -> &SiteConfig {
+        let Some(name) = host.map(host_name) else {
+            return &self.default_site;
+        };
+
+        self.sites
+            .iter()
+            .find(|site| {
+                site.hosts
+                    .iter()
+                    .any(|pattern| host_matches(pattern, &name))
+            })
+            .unwrap_or(&self.default_site)
+    }
+}
+
+/// An additional socket the server listens on
+#[derive(Debug, Deserialize)]
+pub struct ListenerConfig {
+    /// Address to bind, `host:port` such as `[::]:7878`, or `unix:` followed by a socket path
+    pub address: String,
+    /// What the listener serves
+    #[serde(default)]
+    pub role: ListenerRole,
+}
+
+/// What a listener serves
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerRole {
+    /// Requests to the sites
+    #[default]
+    Main,
+    /// The admin API, authenticated as configured in `[admin]`
+    Admin,
+    /// Prometheus metrics
+    Metrics,
+}
+
+/// Limits of the in-memory static file cache
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Serves unchanged files from memory
+    pub enabled: bool,
+    /// Total size of cached files in bytes
+    pub max_bytes: usize,
+    /// Largest file that is cached, in bytes
+    pub max_file_bytes: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            enabled: true,
+            max_bytes: 64 * 1024 * 1024,
+            max_file_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// Media types sent in `Content-Type` for static files
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct MimeConfig {
+    /// `mime.types` file adding to and replacing the built-in mappings, e.g. `/etc/mime.types`
+    pub types_file: Option<PathBuf>,
+    /// Media types by extension, taking precedence over `types_file`
+    pub overrides: HashMap<String, String>,
+    /// Charset parameter added to `text/*` types. An empty string sends them without one
+    pub charset: String,
+    /// Identify files with missing or unknown extensions by their first bytes
+    pub sniff: bool,
+    #[serde(skip)]
+    types: OnceLock<MimeTypes>,
+}
+
+impl Default for MimeConfig {
+    fn default() -> Self {
+        MimeConfig {
+            types_file: None,
+            overrides: HashMap::new(),
+            charset: String::from("utf-8"),
+            sniff: true,
+            types: OnceLock::new(),
+        }
+    }
+}
+
+impl MimeConfig {
+    /// Returns the media types of the built-in table, `types_file`, and overrides
+    pub fn types(&self) -> &MimeTypes {
+        self.types.get_or_init(|| {
+            let mut types = MimeTypes::default();
+            if let Some(types_file) = &self.types_file
+                && let Err(err) = types.load(types_file)
+            {
+                log_error!("Error reading {}: {err}", types_file.display());
+            }
+            for (extension, media_type) in &self.overrides {
+                types.insert(extension, media_type);
+            }
+            types.set_charset(Some(self.charset.clone()).filter(|charset| !charset.is_empty()));
+            types.set_sniff(self.sniff);
+            types
+        })
+    }
+}
+
+/// Listener and credentials of the admin API
+#[derive(Debug, Deserialize)]
+pub struct AdminConfig {
+    /// Address the admin listener binds to, e.g. `127.0.0.1:9091`
+    pub address: String,
+    /// Realm sent in `WWW-Authenticate` challenges
+    #[serde(default = "default_admin_realm")]
+    pub realm: String,
+    /// htpasswd-style file of `user:hash` lines with bcrypt or argon2 hashes, enabling Basic auth
+    pub htpasswd: Option<PathBuf>,
+    /// File of `identity:token` lines, enabling Bearer auth
+    pub tokens: Option<PathBuf>,
+}
+
+fn default_admin_realm() -> String {
+    String::from("admin")
+}
+
+/// Where the Prometheus metrics endpoint is served
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Serves metrics at all
+    pub enabled: bool,
+    /// Path metrics are served at
+    pub path: String,
+    /// Separate address to serve metrics on, e.g. `127.0.0.1:9090`. Without one they are served on the main listener
+    pub address: Option<String>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enabled: false,
+            path: String::from("/metrics"),
+            address: None,
+        }
+    }
+}
+
+/// Document root, error pages, and handlers for one site
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct SiteConfig {
+    /// Host names served by this site. A leading `*.` matches any subdomain
+    pub hosts: Vec<String>,
+    /// Directory files are served from
+    pub root: PathBuf,
+    /// Directory containing `<code>.html` error pages. Defaults to `<root>/error`
+    pub error_root: Option<PathBuf>,
+    /// Pages replacing the one in `error_root` for specific status codes, e.g. `404 = "public/missing.tmpl.html"`
+    pub error_pages: HashMap<String, PathBuf>,
+    /// File served for directory requests such as `/`
+    pub index: String,
+    /// HTTP methods handled by this site. Others are answered with `405`
+    pub methods: Vec<String>,
+    /// Create missing parent directories of files written with `PUT`. Otherwise such writes get `409`
+    pub create_dirs: bool,
+    /// Serve the document root over WebDAV, adding `PROPFIND`, `MKCOL`, `COPY`, and `MOVE` to `methods`
+    pub webdav: bool,
+    /// Redirects and internal rewrites, tried in order before the request is routed
+    #[serde(rename = "rewrite")]
+    pub rewrites: Vec<RewriteRule>,
+    /// Path prefixes forwarded to upstream servers instead of served from disk
+    #[serde(rename = "proxy")]
+    pub proxies: Vec<ProxyRoute>,
+    /// CGI scripts served under a path prefix
+    pub cgi: Option<CgiConfig>,
+    /// JSON document store served under a path prefix
+    pub api: Option<ApiConfig>,
+    /// Cookie sessions started by logging in with a form
+    pub sessions: Option<SessionConfig>,
+    /// Access control rules by path prefix and method
+    #[serde(rename = "access")]
+    pub access_rules: Vec<AccessRule>,
+    /// Request rate limits by path prefix and method
+    #[serde(rename = "rate_limit")]
+    pub rate_limits: Vec<RateLimitRule>,
+    /// Cross-origin resource sharing policy. Without one, no `Access-Control-*` headers are sent
+    pub cors: Option<CorsConfig>,
+}
+
+impl Default for SiteConfig {
+    fn default() -> Self {
+        SiteConfig {
+            hosts: vec![],
+            root: PathBuf::from("public"),
+            error_root: None,
+            error_pages: HashMap::new(),
+            index: String::from("index.html"),
+            methods: ["GET", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"]
+                .iter()
+                .map(|m| m.to_string())
+                .collect(),
+            create_dirs: false,
+            webdav: false,
+            rewrites: vec![],
+            proxies: vec![],
+            cgi: None,
+            api: None,
+            sessions: None,
+            access_rules: vec![],
+            rate_limits: vec![],
+            cors: None,
+        }
+    }
+}
+
+impl SiteConfig {
+    /// Keeps the sessions of a previous version of the site and the buckets of rate limits with the same prefix and methods
+    fn carry_over_state(&mut self, previous: &SiteConfig) {
+        if let (Some(sessions), Some(old)) = (&mut self.sessions, &previous.sessions) {
+            sessions.carry_over(old);
+        }
+        for rule in &mut self.rate_limits {
+            if let Some(old) = previous
+                .rate_limits
+                .iter()
+                .find(|old| old.prefix == rule.prefix && old.methods == rule.methods)
+            {
+                rule.limiter = Arc::clone(&old.limiter);
+            }
+        }
+    }
+
+    /// Checks the site settings for mistakes serde can't catch
+    fn validate(&self) -> Result<(), String> {
+        for code in self.error_pages.keys() {
+            if !code
+                .parse()
+                .is_ok_and(|code: usize| (400..600).contains(&code))
+            {
+                return Err(format!(
+                    "error page {code:?} must be keyed by a 4xx or 5xx status code"
+                ));
+            }
+        }
+        for route in &self.proxies {
+            if !route.prefix.starts_with('/') {
+                return Err(format!(
+                    "proxy prefix {:?} must start with '/'",
+                    route.prefix
+                ));
+            }
+            if route.upstreams.is_empty() {
+                return Err(format!("proxy {:?} has no upstreams", route.prefix));
+            }
+        }
+        for rule in &self.rewrites {
+            match (&rule.prefix, &rule.pattern) {
+                (Some(prefix), None) if prefix.starts_with('/') => {}
+                (None, Some(_)) => {}
+                _ => {
+                    return Err(format!(
+                        "rewrite to {:?} needs either a prefix starting with '/' or a pattern",
+                        rule.to
+                    ));
+                }
+            }
+            match rule.redirect {
+                Some(301 | 302 | 307 | 308) => {}
+                Some(code) => {
+                    return Err(format!(
+                        "rewrite to {:?} has redirect {code}, expected 301, 302, 307, or 308",
+                        rule.to
+                    ));
+                }
+                None if !rule.to.starts_with('/') => {
+                    return Err(format!(
+                        "internal rewrite to {:?} must start with '/'",
+                        rule.to
+                    ));
+                }
+                None => {}
+            }
+        }
+        for rule in &self.access_rules {
+            if rule.policy == AccessPolicy::Session && self.sessions.is_none() {
+                return Err(format!(
+                    "access rule {:?} needs a [sessions] table",
+                    rule.prefix
+                ));
+            }
+            if rule.policy == AccessPolicy::Authenticate
+                && rule.htpasswd.is_none()
+                && rule.tokens.is_none()
+            {
+                return Err(format!(
+                    "access rule {:?} needs an htpasswd or tokens file",
+                    rule.prefix
+                ));
+            }
+        }
+        for limit in &self.rate_limits {
+            if limit.requests == 0 || limit.per_secs == 0 || limit.max_clients == 0 {
+                return Err(format!(
+                    "rate limit {:?} needs non-zero requests, per_secs, and max_clients",
+                    limit.prefix
+                ));
+            }
+        }
+        if let Some(cgi) = &self.cgi
+            && !(cgi.prefix.starts_with('/') && cgi.prefix.ends_with('/'))
+        {
+            return Err(format!(
+                "cgi prefix {:?} must start and end with '/'",
+                cgi.prefix
+            ));
+        }
+        if let Some(sessions) = &self.sessions {
+            if sessions.htpasswd.is_none() {
+                return Err("sessions need an htpasswd file to check logins against".to_owned());
+            }
+            if sessions.ttl_secs == 0 {
+                return Err("sessions need a non-zero ttl_secs".to_owned());
+            }
+            if let Some(secret_file) = &sessions.secret_file
+                && fs::read(secret_file).is_err()
+            {
+                return Err(format!(
+                    "session secret_file {} is not readable",
+                    secret_file.display()
+                ));
+            }
+        }
+        if let Some(api) = &self.api
+            && !(api.prefix.starts_with('/') && api.prefix.ends_with('/'))
+        {
+            return Err(format!(
+                "api prefix {:?} must start and end with '/'",
+                api.prefix
+            ));
+        }
+        Ok(())
+    }
+
+    /// Finds the CGI configuration responsible for a request path, if any
+    ///
+    /// The `path` is the request path without its query string
+    pub fn find_cgi(&self, path: &str) -> Option<&CgiConfig> {
+        self.cgi
+            .as_ref()
+            .filter(|cgi| path.starts_with(&cgi.prefix))
+    }
+
+    /// Finds the document store responsible for a request path, if any
+    ///
+    /// The `path` is the request path without its query string
+    pub fn find_api(&self, path: &str) -> Option<&ApiConfig> {
+        self.api
+            .as_ref()
+            .filter(|api| path.starts_with(&api.prefix))
+    }
+
+    /// Finds the proxy route responsible for a request path
+    ///
+    /// The `path` is the request path without its query string. The longest matching prefix wins
+    pub fn find_proxy(&self, path: &str) -> Option<&ProxyRoute> {
+        self.proxies
+            .iter()
+            .filter(|route| route.matches(path))
+            .max_by_key(|route| route.prefix.len())
+    }
+
+    /// Finds the access rule governing a request
+    ///
+    /// The `path` is the request path without its query string and `method` is the method name. The longest matching prefix wins
+    pub fn find_access_rule(&self, path: &str, method: &str) -> Option<&AccessRule> {
+        self.access_rules
+            .iter()
+            .filter(|rule| rule_matches(&rule.prefix, &rule.methods, path, method))
+            .max_by_key(|rule| rule.prefix.len())
+    }
+
+    /// Finds the rate limit governing a request
+    ///
+    /// The `path` is the request path without its query string and `method` is the method name. The longest matching prefix wins
+    pub fn find_rate_limit(&self, path: &str, method: &str) -> Option<&RateLimitRule> {
+        self.rate_limits
+            .iter()
+            .filter(|limit| rule_matches(&limit.prefix, &limit.methods, path, method))
+            .max_by_key(|limit| limit.prefix.len())
+    }
+
+    /// Checks whether the site handles an HTTP method
+    ///
+    /// The `method` is the method name as it appears in the request line
+    pub fn allows_method(&self, method: &str) -> bool {
+        self.methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+            || (self.webdav
+                && WEBDAV_METHODS
+                    .iter()
+                    .any(|m| m.eq_ignore_ascii_case(method)))
+    }
+
+    /// Returns the path of the error page for a status code
+    ///
+    /// The `code` is the HTTP status code of the error. Pages configured in `error_pages` take precedence over `error_root`
+    pub fn error_page(&self, code: usize) -> PathBuf {
+        if let Some(page) = self.error_pages.get(&code.to_string()) {
+            return page.clone();
+        }
+        self.error_root
+            .clone()
+            .unwrap_or_else(|| self.root.join("error"))
+            .join(format!("{code}.html"))
+    }
+}
+
+/// Redirect or internal rewrite of requests whose path matches a prefix or regular expression
+#[derive(Debug, Deserialize)]
+pub struct RewriteRule {
+    /// Path prefix the rule applies to, replaced by `to`
+    pub prefix: Option<String>,
+    /// Regular expression the rule applies to, whose match is replaced by `to` with `$1` or `${name}` substituted by
+    /// the captures
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub pattern: Option<Regex>,
+    /// Replacement path, or for redirects a path or URL
+    pub to: String,
+    /// Redirect status: `301`, `302`, `307`, or `308`. Without one the request is rewritten internally before routing
+    pub redirect: Option<usize>,
+    /// Methods the rule applies to. An empty list applies to every method
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Host patterns the rule applies to, like a site's `hosts`. An empty list applies to every host
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Regular expressions that request headers, by name, must match. Missing headers don't match
+    #[serde(default, deserialize_with = "deserialize_regex_map")]
+    pub headers: HashMap<String, Regex>,
+}
+
+impl RewriteRule {
+    /// Checks whether the rule's method, host, and header conditions hold for a request
+    ///
+    /// The `req` is the Request to check
+    pub fn applies_to(&self, req: &Request) -> bool {
+        let method = req.get_method().as_str();
+        let host = req.get_headers().get("host").map(|host| host_name(host));
+        (self.methods.is_empty() || self.methods.iter().any(|m| m.eq_ignore_ascii_case(method)))
+            && (self.hosts.is_empty()
+                || host.is_some_and(|name| self.hosts.iter().any(|p| host_matches(p, &name))))
+            && self.headers.iter().all(|(name, pattern)| {
+                req.get_headers()
+                    .get(&name.to_lowercase())
+                    .is_some_and(|value| pattern.is_match(value))
+            })
+    }
+
+    /// Rewrites a request path if it matches the rule's prefix or pattern
+    ///
+    /// The `path` is the request path without the query string
+    ///
+    /// Returns the replacement path, or None if the path doesn't match
+    pub fn rewrite(&self, path: &str) -> Option<String> {
+        if let Some(prefix) = &self.prefix {
+            return path
+                .strip_prefix(prefix.as_str())
+                .map(|rest| format!("{}{rest}", self.to));
+        }
+        let pattern = self.pattern.as_ref()?;
+        pattern
+            .is_match(path)
+            .then(|| pattern.replace(path, self.to.as_str()).into_owned())
+    }
+}
+
+/// Parses a regular expression from a config string
+fn deserialize_regex<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Regex>, D::Error> {
+    let Some(pattern) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    Regex::new(&pattern).map(Some).map_err(D::Error::custom)
+}
+
+/// Parses a table of regular expressions from config strings
+fn deserialize_regex_map<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, Regex>, D::Error> {
+    HashMap::<String, String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, pattern)| Ok((name, Regex::new(&pattern).map_err(D::Error::custom)?)))
+        .collect()
+}
+
+/// Who may access requests matching a path prefix and method
+#[derive(Debug, Deserialize)]
+pub struct AccessRule {
+    /// Path prefix the rule applies to, e.g. `/` or `/uploads/`
+    pub prefix: String,
+    /// Methods the rule applies to. An empty list applies to every method
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Whether matching requests are public, denied, or need credentials
+    pub policy: AccessPolicy,
+    /// Realm sent in `WWW-Authenticate` challenges
+    #[serde(default = "default_realm")]
+    pub realm: String,
+    /// htpasswd-style file of `user:hash` lines with bcrypt or argon2 hashes, enabling Basic auth
+    pub htpasswd: Option<PathBuf>,
+    /// File of `identity:token` lines, enabling Bearer auth
+    pub tokens: Option<PathBuf>,
+    /// Identities allowed through. An empty list allows any valid credentials
+    #[serde(default)]
+    pub users: Vec<String>,
+}
+
+fn default_realm() -> String {
+    String::from("web_server")
+}
+
+/// Checks whether a rule's path prefix and methods cover a request
+fn rule_matches(prefix: &str, methods: &[String], path: &str, method: &str) -> bool {
+    path.starts_with(prefix)
+        && (methods.is_empty() || methods.iter().any(|m| m.eq_ignore_ascii_case(method)))
+}
+
+/// Access policy of an AccessRule
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessPolicy {
+    Public,
+    Deny,
+    Authenticate,
+    /// Requires a session started on the site's login page
+    Session,
+}
+
+/// Token-bucket rate limit for requests matching a path prefix and method
+///
+/// Clients are limited separately, by authenticated identity or else by IP address
+#[derive(Debug, Deserialize)]
+pub struct RateLimitRule {
+    /// Path prefix the limit applies to, e.g. `/` or `/api/`
+    pub prefix: String,
+    /// Methods the limit applies to. An empty list applies to every method
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Requests allowed per window, which is also the largest burst
+    pub requests: u32,
+    /// Length of the window in seconds
+    #[serde(default = "default_rate_window")]
+    pub per_secs: u64,
+    /// Most clients tracked at once. The least recently seen are forgotten first
+    #[serde(default = "default_rate_clients")]
+    pub max_clients: usize,
+    #[serde(skip)]
+    limiter: Arc<RateLimiter>,
+}
+
+fn default_rate_window() -> u64 {
+    60
+}
+
+fn default_rate_clients() -> usize {
+    10_000
+}
+
+impl RateLimitRule {
+    /// Takes a token from a client's bucket
+    ///
+    /// The `client` is the key the client is limited by
+    ///
+    /// Returns whether the request is allowed along with the state of the client's bucket
+    pub fn check(&self, client: &str) -> RateDecision {
+        let capacity = f64::from(self.requests);
+        let rate = capacity / self.per_secs as f64;
+        self.limiter.take(client, capacity, rate, self.max_clients)
+    }
+}
+
+/// Cross-origin resource sharing policy of a site
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// Origins allowed to read responses, e.g. `https://app.example.com`. A `*` matches any run of characters, so
+    /// `http://localhost:*` allows any port and `*` alone allows every origin
+    pub origins: Vec<String>,
+    /// Methods allowed in cross-origin requests. Defaults to the methods the target supports
+    pub methods: Vec<String>,
+    /// Request headers allowed in cross-origin requests. Defaults to whatever the preflight asks for
+    pub headers: Vec<String>,
+    /// Response headers exposed to scripts beyond the CORS-safelisted ones
+    pub expose_headers: Vec<String>,
+    /// Allows cookies and `Authorization` headers on cross-origin requests
+    pub credentials: bool,
+    /// Seconds a browser may cache a preflight response
+    pub max_age_secs: Option<u64>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            origins: vec![],
+            methods: vec![],
+            headers: vec![],
+            expose_headers: vec![],
+            credentials: false,
+            max_age_secs: Some(600),
+        }
+    }
+}
+
+impl CorsConfig {
+    /// Checks whether an origin may read responses
+    ///
+    /// The `origin` is the value of the request's `Origin` header
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.origins
+            .iter()
+            .any(|pattern| wildcard_matches(pattern, origin))
+    }
+
+    /// Checks whether every origin may read responses
+    pub fn allows_any_origin(&self) -> bool {
+        self.origins.iter().any(|pattern| pattern == "*")
+    }
+}
+
+/// Matches a string against a pattern where `*` stands for any run of characters
+fn wildcard_matches(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard, so the whole value must have matched
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Executables run through the Common Gateway Interface
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct CgiConfig {
+    /// Path prefix scripts are requested under, e.g. `/cgi-bin/`
+    pub prefix: String,
+    /// Directory containing the scripts
+    pub dir: PathBuf,
+    /// Seconds a script may run before it is killed
+    pub timeout_secs: u64,
+    /// Interpreters for script extensions, e.g. `py = "python3"`. Other scripts are executed directly
+    pub interpreters: HashMap<String, String>,
+}
+
+impl Default for CgiConfig {
+    fn default() -> Self {
+        CgiConfig {
+            prefix: String::from("/cgi-bin/"),
+            dir: PathBuf::from("cgi-bin"),
+            timeout_secs: 30,
+            interpreters: HashMap::new(),
+        }
+    }
+}
+
+impl CgiConfig {
+    /// Returns the script timeout as a Duration
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+/// JSON documents stored on disk and served as a REST API under a path prefix
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ApiConfig {
+    /// Path prefix collections are served under, e.g. `/api/`
+    pub prefix: String,
+    /// Directory holding one subdirectory of documents per collection
+    pub dir: PathBuf,
+    /// Documents listed per page when the request has no `limit`
+    pub page_size: usize,
+    /// Largest `limit` a request may ask for
+    pub max_page_size: usize,
+    #[serde(skip)]
+    store: OnceLock<DocumentStore>,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            prefix: String::from("/api/"),
+            dir: PathBuf::from("data"),
+            page_size: 20,
+            max_page_size: 100,
+            store: OnceLock::new(),
+        }
+    }
+}
+
+impl ApiConfig {
+    /// Returns the store of the configured directory
+    pub fn store(&self) -> &DocumentStore {
+        self.store.get_or_init(|| DocumentStore::new(&self.dir))
+    }
+}
+
+/// Cookie sessions of a site and the login form that starts them
+///
+/// Posting `user` and `password` to `login_path` checks them against `htpasswd` and starts a session. Posting to `logout_path` ends it
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// Name of the cookie holding the signed session id
+    pub cookie_name: String,
+    /// Seconds a session lasts after it was last saved, e.g. by logging in
+    pub ttl_secs: u64,
+    /// Where sessions are kept, in memory or as files in `dir`
+    pub store: SessionStoreKind,
+    /// Directory of the file store
+    pub dir: PathBuf,
+    /// File containing the key that signs session ids. Without one a random key is used and sessions end when the server restarts
+    pub secret_file: Option<PathBuf>,
+    /// Only send the session cookie over HTTPS
+    pub secure: bool,
+    pub same_site: SameSite,
+    /// Page with the login form. `GET` serves it, `POST` logs in
+    pub login_path: String,
+    /// Path that ends the session when posted to
+    pub logout_path: String,
+    /// htpasswd-style file of `user:hash` lines logins are checked against
+    pub htpasswd: Option<PathBuf>,
+    #[serde(skip)]
+    manager: OnceLock<SessionManager>,
+}
+
+/// Storage backend of sessions
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionStoreKind {
+    #[default]
+    Memory,
+    File,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            cookie_name: String::from("session"),
+            ttl_secs: 86400,
+            store: SessionStoreKind::Memory,
+            dir: PathBuf::from("sessions"),
+            secret_file: None,
+            secure: false,
+            same_site: SameSite::Lax,
+            login_path: String::from("/login.html"),
+            logout_path: String::from("/logout"),
+            htpasswd: None,
+            manager: OnceLock::new(),
+        }
+    }
+}
+
+impl SessionConfig {
+    /// Returns the manager loading and saving sessions with the configured store and key
+    pub fn manager(&self) -> &SessionManager {
+        self.manager.get_or_init(|| {
+            let store: Box<dyn SessionStore> = match self.store {
+                SessionStoreKind::Memory => Box::new(MemoryStore::default()),
+                SessionStoreKind::File => Box::new(FileStore::new(&self.dir)),
+            };
+            let key = match &self.secret_file {
+                Some(path) => fs::read(path).unwrap_or_else(|err| {
+                    log_error!("Error reading {}: {err}", path.display());
+                    random_key()
+                }),
+                None => random_key(),
+            };
+
+            let mut manager = SessionManager::new(
+                store,
+                key.trim_ascii(),
+                &self.cookie_name,
+                Duration::from_secs(self.ttl_secs),
+            );
+            manager.set_cookie_attributes(self.secure, self.same_site);
+            manager
+        })
+    }
+
+    /// Keeps the sessions of a previous configuration, e.g. across a reload
+    ///
+    /// The `previous` sessions are kept if they were stored in the same place and signed with the same key file, so memory stores and random keys survive
+    fn carry_over(&mut self, previous: &SessionConfig) {
+        let Some(manager) = previous.manager.get() else {
+            return;
+        };
+        if self.store != previous.store
+            || self.dir != previous.dir
+            || self.secret_file != previous.secret_file
+        {
+            return;
+        }
+
+        let mut manager =
+            manager.share_store(&self.cookie_name, Duration::from_secs(self.ttl_secs));
+        manager.set_cookie_attributes(self.secure, self.same_site);
+        self.manager = OnceLock::from(manager);
+    }
+}
+
+/// Generates a key for signing session ids
+fn random_key() -> Vec<u8> {
+    let mut key = vec![0u8; 32];
+    getrandom::fill(&mut key).expect("operating system random number generator failed");
+    key
+}
+
+/// Strips the port from a `Host` header value and lowercases it
+fn host_name(host: &str) -> String {
+    let host = host.trim();
+    let name = if let Some(rest) = host.strip_prefix('[') {
+        // IPv6 literal, e.g. `[::1]:7878`
+        rest.split(']').next().unwrap_or(rest)
+    } else {
+        host.split(':').next().unwrap_or(host)
+    };
+    name.to_lowercase()
+}
+
+/// Checks whether a host name matches a site's host pattern
+fn host_matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(domain) => name.ends_with(&format!(".{domain}")),
+        None => pattern == name,
+    }
+}
+
+/// A path prefix forwarded to one or more upstream HTTP servers
+#[derive(Debug, Deserialize)]
+pub struct ProxyRoute {
+    /// Path prefix handled by this route, e.g. `/api/`
+    pub prefix: String,
+    /// Upstream `host:port` authorities, used in round-robin order
+    pub upstreams: Vec<String>,
+    /// Seconds to wait when connecting to, writing to or reading from an upstream
+    #[serde(default = "default_proxy_timeout")]
+    pub timeout_secs: u64,
+    /// Removes the prefix from the path before forwarding
+    #[serde(default)]
+    pub strip_prefix: bool,
+    /// Forwards the client's `Host` header instead of the upstream authority
+    #[serde(default)]
+    pub preserve_host: bool,
+    #[serde(skip)]
+    next: AtomicUsize,
+}
+
+fn default_proxy_timeout() -> u64 {
+    30
+}
+
+impl ProxyRoute {
+    /// Creates a new ProxyRoute with the default options
+    ///
+    /// The `prefix` is the path prefix to match and `upstreams` are the `host:port` authorities to forward to
+    pub fn new(prefix: &str, upstreams: Vec<String>) -> ProxyRoute {
+        ProxyRoute {
+            prefix: prefix.to_owned(),
+            upstreams,
+            timeout_secs: default_proxy_timeout(),
+            strip_prefix: false,
+            preserve_host: false,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Checks whether a request path falls under this route
+    ///
+    /// The `path` is the request path without its query string
+    pub fn matches(&self, path: &str) -> bool {
+        path.starts_with(&self.prefix)
+            || (self.prefix.ends_with('/') && path == &self.prefix[..self.prefix.len() - 1])
+    }
+
+    /// Returns all upstreams, starting from the next one in round-robin order
+    pub fn upstream_order(&self) -> Vec<&str> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = self.upstreams.len();
+
+        (0..count)
+            .map(|i| self.upstreams[(start + i) % count].as_str())
+            .collect()
+    }
+
+    /// Returns the upstream timeout as a Duration
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Response;
+
+    /// Helper function to parse a configuration from a TOML string
+    fn parse(contents: &str) -> ServerConfig {
+        toml::from_str(contents).unwrap()
+    }
+
+    #[test]
+    fn test_default_site_settings_are_top_level() {
+        let config = parse(
+            r#"
+            root = "www"
+
+            [[proxy]]
+            prefix = "/api/"
+            upstreams = ["127.0.0.1:9000"]
+            "#,
+        );
+
+        assert_eq!(config.default_site.root, PathBuf::from("www"));
+        assert_eq!(
+            config.default_site.error_page(404),
+            PathBuf::from("www/error/404.html")
+        );
+        assert!(config.default_site.find_proxy("/api/users").is_some());
+        assert!(config.default_site.find_proxy("/index.html").is_none());
+    }
+
+    #[test]
+    fn test_carry_over_keeps_sessions_and_rate_limits() {
+        let contents = r#"
+            [sessions]
+
+            [[rate_limit]]
+            prefix = "/"
+            requests = 1
+        "#;
+        let previous = parse(contents);
+        let site = &previous.default_site;
+
+        // Log in and use up the rate limit before the reload
+        let manager = site.sessions.as_ref().unwrap().manager();
+        let mut session = manager.load(&Request::default());
+        session.insert("user", "alice");
+        let mut res = Response::default();
+        manager.save(&session, &mut res).unwrap();
+        let cookie = &res.get_cookies()[0];
+        assert!(site.rate_limits[0].check("10.0.0.1").allowed);
+
+        let mut config = parse(&format!(
+            "{contents}\n[[rate_limit]]\nprefix = \"/api/\"\nrequests = 1\n"
+        ));
+        config.carry_over_state(&previous);
+        let site = &config.default_site;
+
+        let mut req = Request::default();
+        req.append_header(format!("Cookie: {}={}", cookie.name, cookie.value));
+        let session = site.sessions.as_ref().unwrap().manager().load(&req);
+        assert_eq!(session.get("user"), Some("alice"));
+        assert!(!site.rate_limits[0].check("10.0.0.1").allowed);
+        assert!(site.rate_limits[1].check("10.0.0.1").allowed);
+    }
+
+    #[test]
+    fn test_find_site_by_host() {
+        let config = parse(
+            r#"
+            [[site]]
+            hosts = ["docs.internal", "*.wiki.internal"]
+            root = "sites/docs"
+            methods = ["GET"]
+            "#,
+        );
+
+        assert_eq!(
+            config.find_site(Some("Docs.Internal:7878")).root,
+            PathBuf::from("sites/docs")
+        );
+        assert_eq!(
+            config.find_site(Some("team.wiki.internal")).root,
+            PathBuf::from("sites/docs")
+        );
+        assert_eq!(
+            config.find_site(Some("other.internal")).root,
+            PathBuf::from("public")
+        );
+        assert_eq!(config.find_site(None).root, PathBuf::from("public"));
+        assert!(!config.find_site(Some("docs.internal")).allows_method("PUT"));
+    }
+
+    #[test]
+    fn test_sample_config_loads() {
+        let config = ServerConfig::load(Path::new("server.toml")).unwrap();
+        assert_eq!(config.default_site.root, PathBuf::from("public"));
+        assert!(config.cache.enabled);
+    }
+
+    #[test]
+    fn test_cors_origin_patterns() {
+        let cors = CorsConfig {
+            origins: vec![
+                "https://app.example.com".to_owned(),
+                "http://localhost:*".to_owned(),
+            ],
+            ..CorsConfig::default()
+        };
+
+        assert!(cors.allows_origin("https://app.example.com"));
+        assert!(cors.allows_origin("http://localhost:5173"));
+        assert!(!cors.allows_origin("https://app.example.com.evil.net"));
+        assert!(!cors.allows_origin("http://127.0.0.1:5173"));
+        assert!(!cors.allows_any_origin());
+        assert!(wildcard_matches(
+            "https://*.example.com",
+            "https://a.b.example.com"
+        ));
+        assert!(wildcard_matches("*", "null"));
+    }
+
+    #[test]
+    fn test_host_name_strips_port() {
+        assert_eq!(host_name("example.com:8080"), "example.com");
+        assert_eq!(host_name("[::1]:7878"), "::1");
+        assert_eq!(host_name("EXAMPLE.com"), "example.com");
+    }
+}
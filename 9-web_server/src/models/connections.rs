@@ -0,0 +1,108 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::SystemTime,
+};
+
+/// A client connection currently being handled
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub client: Option<SocketAddr>,
+    pub opened: SystemTime,
+    /// Method and target of the request being answered, once its head was read
+    pub request: Option<String>,
+}
+
+/// Registry of the connections currently open
+#[derive(Debug, Default)]
+pub struct Connections {
+    next_id: AtomicU64,
+    live: Mutex<BTreeMap<u64, ConnectionInfo>>,
+}
+
+impl Connections {
+    /// Registers a new connection, which stays listed until the returned guard is dropped
+    ///
+    /// The `client` is the address of the connected client
+    pub fn open(&self, client: Option<SocketAddr>) -> OpenConnection<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.live.lock().unwrap().insert(
+            id,
+            ConnectionInfo {
+                id,
+                client,
+                opened: SystemTime::now(),
+                request: None,
+            },
+        );
+        OpenConnection {
+            connections: self,
+            id,
+        }
+    }
+
+    /// Returns the connections currently open, oldest first
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        self.live.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Returns the number of connections currently open
+    pub fn len(&self) -> usize {
+        self.live.lock().unwrap().len()
+    }
+
+    /// Checks whether no connections are open
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Keeps a connection listed in Connections, see `Connections::open`
+pub struct OpenConnection<'a> {
+    connections: &'a Connections,
+    id: u64,
+}
+
+impl OpenConnection<'_> {
+    /// Records the request being answered on this connection
+    ///
+    /// The `request` describes it, e.g. `GET /index.html`
+    pub fn set_request(&self, request: String) {
+        if let Some(info) = self.connections.live.lock().unwrap().get_mut(&self.id) {
+            info.request = Some(request);
+        }
+    }
+}
+
+impl Drop for OpenConnection<'_> {
+    fn drop(&mut self) {
+        self.connections.live.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connections_are_listed_while_open() {
+        let connections = Connections::default();
+        let first = connections.open(None);
+        let second = connections.open("127.0.0.1:5000".parse().ok());
+        second.set_request("GET /index.html".to_owned());
+
+        let list = connections.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[1].request.as_deref(), Some("GET /index.html"));
+
+        drop(first);
+        assert_eq!(connections.len(), 1);
+        drop(second);
+        assert!(connections.is_empty());
+    }
+}
@@ -0,0 +1,162 @@
+use std::{collections::HashMap, time::SystemTime};
+
+use httpdate::fmt_http_date;
+use serde::Deserialize;
+
+/// `SameSite` attribute of a cookie, restricting when browsers send it cross-site
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSite {
+    Strict,
+    #[default]
+    Lax,
+    None,
+}
+
+impl SameSite {
+    /// Returns the attribute value as sent in `Set-Cookie`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+/// A cookie to be set on the client with a `Set-Cookie` header
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    /// Path prefix the cookie is sent for
+    pub path: Option<String>,
+    /// Host the cookie is sent to, including its subdomains
+    pub domain: Option<String>,
+    /// Time the cookie expires at. Without it or `max_age` the cookie lasts until the browser closes
+    pub expires: Option<SystemTime>,
+    /// Seconds until the cookie expires, taking precedence over `expires`
+    pub max_age: Option<u64>,
+    /// Only send the cookie over HTTPS
+    pub secure: bool,
+    /// Hide the cookie from scripts
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    /// Creates a session cookie without attributes
+    ///
+    /// The `name` and `value` must not contain `;`, `,`, or whitespace
+    pub fn new(name: &str, value: &str) -> Cookie {
+        Cookie {
+            name: name.to_owned(),
+            value: value.to_owned(),
+            path: None,
+            domain: None,
+            expires: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// Creates a cookie that makes the client delete a previously set one
+    ///
+    /// The `name` and `path` must match those of the cookie to delete
+    pub fn removal(name: &str, path: &str) -> Cookie {
+        Cookie {
+            path: Some(path.to_owned()),
+            expires: Some(SystemTime::UNIX_EPOCH),
+            max_age: Some(0),
+            ..Cookie::new(name, "")
+        }
+    }
+
+    /// Formats the cookie as the value of a `Set-Cookie` header
+    pub fn to_header(&self) -> String {
+        let mut header = format!("{}={}", self.name, self.value);
+        if let Some(path) = &self.path {
+            header.push_str(&format!("; Path={path}"));
+        }
+        if let Some(domain) = &self.domain {
+            header.push_str(&format!("; Domain={domain}"));
+        }
+        if let Some(expires) = self.expires {
+            header.push_str(&format!("; Expires={}", fmt_http_date(expires)));
+        }
+        if let Some(max_age) = self.max_age {
+            header.push_str(&format!("; Max-Age={max_age}"));
+        }
+        if self.secure {
+            header.push_str("; Secure");
+        }
+        if self.http_only {
+            header.push_str("; HttpOnly");
+        }
+        if let Some(same_site) = self.same_site {
+            header.push_str(&format!("; SameSite={}", same_site.as_str()));
+        }
+        header
+    }
+}
+
+/// Parses the value of a `Cookie` request header
+///
+/// The `header` is a list of `name=value` pairs separated by `;`. Values may be quoted
+///
+/// Returns the cookies by name. The first of several cookies with the same name wins, since browsers send the most specific one first
+pub fn parse_cookies(header: &str) -> HashMap<String, String> {
+    let mut cookies = HashMap::new();
+    for pair in header.split(';') {
+        let Some((name, value)) = pair.split_once('=') else {
+            continue;
+        };
+        let name = name.trim();
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+        if !name.is_empty() {
+            cookies
+                .entry(name.to_owned())
+                .or_insert_with(|| value.to_owned());
+        }
+    }
+    cookies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_and_format_cookies() {
+        let cookies = parse_cookies("session=abc.def; theme=\"dark\"; session=older; junk");
+        assert_eq!(cookies.len(), 2);
+        assert_eq!(cookies["session"], "abc.def");
+        assert_eq!(cookies["theme"], "dark");
+
+        let cookie = Cookie {
+            path: Some("/".to_owned()),
+            domain: Some("example.com".to_owned()),
+            expires: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(784111777)),
+            max_age: Some(3600),
+            secure: true,
+            http_only: true,
+            same_site: Some(SameSite::Strict),
+            ..Cookie::new("id", "42")
+        };
+        assert_eq!(
+            cookie.to_header(),
+            "id=42; Path=/; Domain=example.com; Expires=Sun, 06 Nov 1994 08:49:37 GMT; Max-Age=3600; Secure; HttpOnly; SameSite=Strict"
+        );
+        assert_eq!(
+            Cookie::removal("id", "/").to_header(),
+            "id=; Path=/; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Max-Age=0"
+        );
+    }
+}
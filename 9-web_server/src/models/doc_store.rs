@@ -0,0 +1,510 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// A JSON document along with its identity and version
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document {
+    pub id: String,
+    /// When the document was created, in hex nanoseconds. Versions restart at 1 when a deleted document is created again, so this keeps its ETags from matching the old document's
+    pub generation: String,
+    pub version: u64,
+    pub data: Map<String, Value>,
+}
+
+impl Document {
+    /// Returns the strong ETag of this version of the document
+    pub fn etag(&self) -> String {
+        if self.generation.is_empty() {
+            format!("\"v{}\"", self.version)
+        } else {
+            format!("\"{}-v{}\"", self.generation, self.version)
+        }
+    }
+
+    /// Returns the document as sent to clients, with its `id` field set
+    pub fn to_json(&self) -> Value {
+        let mut data = self.data.clone();
+        data.insert("id".to_owned(), Value::String(self.id.clone()));
+        Value::Object(data)
+    }
+}
+
+/// Why a document store operation failed
+#[derive(Debug)]
+pub enum StoreError {
+    /// The document doesn't exist
+    NotFound,
+    /// The document's version doesn't match the client's precondition
+    PreconditionFailed,
+    /// The change would not leave a JSON object
+    NotAnObject,
+    /// The document file couldn't be read or written
+    Io(io::Error),
+}
+
+impl From<io::Error> for StoreError {
+    fn from(err: io::Error) -> Self {
+        StoreError::Io(err)
+    }
+}
+
+/// A client's precondition on the current version of a document
+#[derive(Debug, Clone, Copy)]
+pub enum Precondition<'a> {
+    /// No precondition
+    None,
+    /// `If-Match`: the document must exist with one of the listed ETags, or at all for `*`
+    Match(&'a str),
+    /// `If-None-Match: *`: the document must not exist
+    Absent,
+}
+
+/// Document contents as persisted on disk
+#[derive(Serialize, Deserialize)]
+struct StoredDocument {
+    /// Missing from documents stored before generations were recorded
+    #[serde(default)]
+    generation: String,
+    version: u64,
+    data: Map<String, Value>,
+}
+
+/// JSON documents persisted as `<dir>/<collection>/<id>.json`
+///
+/// Writes are serialized through a lock so versions increase without gaps or lost updates
+#[derive(Debug)]
+pub struct DocumentStore {
+    dir: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl DocumentStore {
+    /// Creates a new DocumentStore
+    ///
+    /// The `dir` is the directory holding one subdirectory per collection. It is created on the first write
+    pub fn new(dir: &Path) -> DocumentStore {
+        DocumentStore {
+            dir: dir.to_path_buf(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Lists the documents of a collection, ordered by id
+    ///
+    /// The `collection` is the collection name, `filters` are `(field, value)` pairs a document's top-level fields must equal, and `offset`/`limit` select the page
+    ///
+    /// Returns the documents on the page and the number of matching documents
+    pub fn list(
+        &self,
+        collection: &str,
+        filters: &[(String, String)],
+        offset: usize,
+        limit: usize,
+    ) -> io::Result<(Vec<Document>, usize)> {
+        let dir = self.dir.join(collection);
+        let mut ids: Vec<String> = match fs::read_dir(&dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let name = entry.file_name().into_string().ok()?;
+                    name.strip_suffix(".json").map(str::to_owned)
+                })
+                .collect(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err),
+        };
+        ids.sort();
+
+        let mut matching = vec![];
+        for id in ids {
+            if let Some(doc) = self.read(collection, &id)?
+                && filters
+                    .iter()
+                    .all(|(field, value)| field_matches(&doc, field, value))
+            {
+                matching.push(doc);
+            }
+        }
+
+        let total = matching.len();
+        let page = matching.into_iter().skip(offset).take(limit).collect();
+        Ok((page, total))
+    }
+
+    /// Returns a document, if it exists
+    ///
+    /// The `collection` and `id` name the document
+    pub fn get(&self, collection: &str, id: &str) -> io::Result<Option<Document>> {
+        self.read(collection, id)
+    }
+
+    /// Stores a new document under a server-assigned id
+    ///
+    /// The `collection` is the collection name and `data` is the document's contents
+    ///
+    /// Returns the stored Document
+    pub fn create(&self, collection: &str, data: Map<String, Value>) -> io::Result<Document> {
+        let _guard = self.lock();
+
+        let mut id = new_id();
+        while self.path(collection, &id).exists() {
+            id = new_id();
+        }
+        let doc = Document {
+            id,
+            generation: new_id(),
+            version: 1,
+            data: without_id(data),
+        };
+        self.write(collection, &doc)?;
+        Ok(doc)
+    }
+
+    /// Replaces a document, creating it if it doesn't exist
+    ///
+    /// The `collection` and `id` name the document, `data` is its new contents, and `precondition` is checked against the current version
+    ///
+    /// Returns the stored Document and whether it was created
+    pub fn replace(
+        &self,
+        collection: &str,
+        id: &str,
+        data: Map<String, Value>,
+        precondition: Precondition,
+    ) -> Result<(Document, bool), StoreError> {
+        let _guard = self.lock();
+
+        let current = self.read(collection, id)?;
+        check_precondition(current.as_ref(), precondition)?;
+
+        let doc = Document {
+            id: id.to_owned(),
+            generation: current
+                .as_ref()
+                .map_or_else(new_id, |doc| doc.generation.clone()),
+            version: current.as_ref().map_or(1, |doc| doc.version + 1),
+            data: without_id(data),
+        };
+        self.write(collection, &doc)?;
+        Ok((doc, current.is_none()))
+    }
+
+    /// Applies a JSON Merge Patch (RFC 7396) to a document
+    ///
+    /// The `collection` and `id` name the document, `patch` is the merge patch, and `precondition` is checked against the current version
+    ///
+    /// Returns the patched Document
+    pub fn patch(
+        &self,
+        collection: &str,
+        id: &str,
+        patch: &Value,
+        precondition: Precondition,
+    ) -> Result<Document, StoreError> {
+        let _guard = self.lock();
+
+        let current = self.read(collection, id)?.ok_or(StoreError::NotFound)?;
+        check_precondition(Some(&current), precondition)?;
+
+        let mut data = Value::Object(current.data);
+        merge_patch(&mut data, patch);
+        let Value::Object(data) = data else {
+            return Err(StoreError::NotAnObject);
+        };
+
+        let doc = Document {
+            id: current.id,
+            generation: current.generation,
+            version: current.version + 1,
+            data: without_id(data),
+        };
+        self.write(collection, &doc)?;
+        Ok(doc)
+    }
+
+    /// Deletes a document
+    ///
+    /// The `collection` and `id` name the document and `precondition` is checked against the current version
+    pub fn delete(
+        &self,
+        collection: &str,
+        id: &str,
+        precondition: Precondition,
+    ) -> Result<(), StoreError> {
+        let _guard = self.lock();
+
+        let current = self.read(collection, id)?.ok_or(StoreError::NotFound)?;
+        check_precondition(Some(&current), precondition)?;
+        fs::remove_file(self.path(collection, id))?;
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        self.lock
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn path(&self, collection: &str, id: &str) -> PathBuf {
+        self.dir.join(collection).join(format!("{id}.json"))
+    }
+
+    fn read(&self, collection: &str, id: &str) -> io::Result<Option<Document>> {
+        let contents = match fs::read(self.path(collection, id)) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let stored: StoredDocument = serde_json::from_slice(&contents)?;
+
+        Ok(Some(Document {
+            id: id.to_owned(),
+            generation: stored.generation,
+            version: stored.version,
+            data: stored.data,
+        }))
+    }
+
+    /// Writes a document to a temporary file and renames it into place, so readers never see a partial write
+    fn write(&self, collection: &str, doc: &Document) -> io::Result<()> {
+        let path = self.path(collection, &doc.id);
+        fs::create_dir_all(self.dir.join(collection))?;
+
+        let stored = StoredDocument {
+            generation: doc.generation.clone(),
+            version: doc.version,
+            data: doc.data.clone(),
+        };
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_vec_pretty(&stored)?)?;
+        fs::rename(&temp_path, &path)
+    }
+}
+
+/// Checks whether a collection name or document id is safe to use as a file name
+///
+/// Names are 1 to 64 ASCII letters, digits, `-`, or `_`
+pub fn is_valid_name(name: &str) -> bool {
+    (1..=64).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Applies a JSON Merge Patch (RFC 7396) to a value in place
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+
+    if let Value::Object(target) = target {
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+fn check_precondition(
+    current: Option<&Document>,
+    precondition: Precondition,
+) -> Result<(), StoreError> {
+    let satisfied = match (precondition, current) {
+        (Precondition::None, _) => true,
+        (Precondition::Match(_), None) => false,
+        (Precondition::Match(tags), Some(doc)) => tags
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag == doc.etag()),
+        (Precondition::Absent, current) => current.is_none(),
+    };
+
+    if satisfied {
+        Ok(())
+    } else {
+        Err(StoreError::PreconditionFailed)
+    }
+}
+
+/// Checks whether a document's top-level field equals a filter value
+///
+/// Strings are compared as-is and other values are parsed as JSON, e.g. `true` or `42`
+fn field_matches(doc: &Document, field: &str, value: &str) -> bool {
+    match doc.to_json().get(field) {
+        Some(Value::String(s)) => s == value,
+        Some(other) => serde_json::from_str::<Value>(value).is_ok_and(|value| value == *other),
+        None => false,
+    }
+}
+
+/// Removes the `id` field, which is stored as the file name instead
+fn without_id(mut data: Map<String, Value>) -> Map<String, Value> {
+    data.remove("id");
+    data
+}
+
+/// Generates a document id or generation that sorts by creation time
+fn new_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("{nanos:x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    /// Helper function to convert a `json!` object into a document body
+    fn object(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            _ => panic!("not an object"),
+        }
+    }
+
+    #[test]
+    fn test_versions_and_preconditions() {
+        let temp_dir = tempdir().unwrap();
+        let store = DocumentStore::new(temp_dir.path());
+
+        let doc = store
+            .create("notes", object(json!({"title": "a", "id": "ignored"})))
+            .unwrap();
+        assert_eq!(doc.version, 1);
+        assert_eq!(doc.to_json()["id"], json!(doc.id));
+        assert_eq!(store.get("notes", &doc.id).unwrap(), Some(doc.clone()));
+
+        // A stale ETag loses the race
+        let (replaced, created) = store
+            .replace(
+                "notes",
+                &doc.id,
+                object(json!({"title": "b"})),
+                Precondition::Match(&doc.etag()),
+            )
+            .unwrap();
+        assert_eq!((replaced.version, created), (2, false));
+        assert!(matches!(
+            store.delete("notes", &doc.id, Precondition::Match(&doc.etag())),
+            Err(StoreError::PreconditionFailed)
+        ));
+        assert!(matches!(
+            store.replace("notes", &doc.id, Map::new(), Precondition::Absent),
+            Err(StoreError::PreconditionFailed)
+        ));
+
+        store
+            .delete("notes", &doc.id, Precondition::Match(&replaced.etag()))
+            .unwrap();
+        assert_eq!(store.get("notes", &doc.id).unwrap(), None);
+    }
+
+    #[test]
+    fn test_recreated_documents_get_new_etags() {
+        let temp_dir = tempdir().unwrap();
+        let store = DocumentStore::new(temp_dir.path());
+
+        let (old, _) = store
+            .replace(
+                "notes",
+                "a",
+                object(json!({"title": "old"})),
+                Precondition::None,
+            )
+            .unwrap();
+        store.delete("notes", "a", Precondition::None).unwrap();
+        let (new, created) = store
+            .replace(
+                "notes",
+                "a",
+                object(json!({"title": "new"})),
+                Precondition::None,
+            )
+            .unwrap();
+        assert_eq!((new.version, created), (1, true));
+        assert_ne!(new.etag(), old.etag());
+
+        // A client still holding the old document's ETag can't overwrite the new one
+        assert!(matches!(
+            store.replace("notes", "a", Map::new(), Precondition::Match(&old.etag())),
+            Err(StoreError::PreconditionFailed)
+        ));
+        assert_eq!(store.get("notes", "a").unwrap(), Some(new));
+    }
+
+    #[test]
+    fn test_merge_patch() {
+        let temp_dir = tempdir().unwrap();
+        let store = DocumentStore::new(temp_dir.path());
+        store
+            .replace(
+                "users",
+                "ada",
+                object(json!({"name": "Ada", "tags": ["x"], "address": {"city": "London", "zip": "N1"}})),
+                Precondition::None,
+            )
+            .unwrap();
+
+        let doc = store
+            .patch(
+                "users",
+                "ada",
+                &json!({"tags": null, "address": {"zip": null, "country": "UK"}}),
+                Precondition::None,
+            )
+            .unwrap();
+        assert_eq!(
+            doc.to_json(),
+            json!({"id": "ada", "name": "Ada", "address": {"city": "London", "country": "UK"}})
+        );
+        assert!(matches!(
+            store.patch("users", "ada", &json!([1]), Precondition::None),
+            Err(StoreError::NotAnObject)
+        ));
+        assert!(matches!(
+            store.patch("users", "bob", &json!({}), Precondition::None),
+            Err(StoreError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn test_list_filters_and_pages() {
+        let temp_dir = tempdir().unwrap();
+        let store = DocumentStore::new(temp_dir.path());
+        for (id, done) in [("a", true), ("b", false), ("c", true), ("d", true)] {
+            store
+                .replace(
+                    "todos",
+                    id,
+                    object(json!({"done": done})),
+                    Precondition::None,
+                )
+                .unwrap();
+        }
+
+        let filters = vec![("done".to_owned(), "true".to_owned())];
+        let (page, total) = store.list("todos", &filters, 1, 1).unwrap();
+        assert_eq!(total, 3);
+        assert_eq!(page[0].id, "c");
+        assert_eq!(store.list("missing", &[], 0, 10).unwrap().1, 0);
+        assert!(!is_valid_name("../etc"));
+    }
+}
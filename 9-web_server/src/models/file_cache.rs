@@ -0,0 +1,282 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::MimeTypes;
+
+/// A file's contents along with its precomputed response headers
+#[derive(Clone)]
+pub struct CachedFile {
+    pub contents: Arc<Vec<u8>>,
+    pub content_type: String,
+    pub etag: String,
+    pub modified: SystemTime,
+}
+
+/// Size-bounded LRU cache of static files shared by all workers
+///
+/// Entries are revalidated against the file's modification time and size on every lookup
+pub struct FileCache {
+    inner: Mutex<CacheInner>,
+    max_bytes: usize,
+    max_file_bytes: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct CacheInner {
+    entries: HashMap<PathBuf, CacheEntry>,
+    /// Last-use tick of each entry, oldest first
+    recency: BTreeMap<u64, PathBuf>,
+    tick: u64,
+    used_bytes: usize,
+}
+
+struct CacheEntry {
+    file: CachedFile,
+    len: u64,
+    last_used: u64,
+}
+
+impl FileCache {
+    /// Creates a new FileCache
+    ///
+    /// The `max_bytes` is the total size of cached contents and `max_file_bytes` is the largest file that is cached. A `max_bytes` of zero disables caching
+    pub fn new(max_bytes: usize, max_file_bytes: usize) -> FileCache {
+        FileCache {
+            inner: Mutex::new(CacheInner {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                tick: 0,
+                used_bytes: 0,
+            }),
+            max_bytes,
+            max_file_bytes,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns a file's contents and headers, reading it from disk on a miss
+    ///
+    /// The `path` is the file path to be read and `mime` determines its `Content-Type`
+    ///
+    /// Returns the CachedFile or the error from reading the file
+    pub fn get(&self, path: &Path, mime: &MimeTypes) -> io::Result<CachedFile> {
+        let metadata = fs::metadata(path)?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Not a file"));
+        }
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        let len = metadata.len();
+
+        // Serve from memory while the file is unchanged
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let fresh = inner
+                .entries
+                .get(path)
+                .map(|entry| entry.file.modified == modified && entry.len == len);
+
+            match fresh {
+                Some(true) => {
+                    inner.touch(path);
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(inner.entries[path].file.clone());
+                }
+                Some(false) => inner.remove(path),
+                None => {}
+            }
+        }
+
+        // Read from disk
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let contents = fs::read(path)?;
+        let file = CachedFile {
+            etag: make_etag(contents.len() as u64, modified),
+            content_type: mime.content_type(path, &contents),
+            contents: Arc::new(contents),
+            modified,
+        };
+
+        // Only cache files that fit
+        let size = file.contents.len();
+        if size <= self.max_file_bytes && size <= self.max_bytes {
+            let mut inner = self.inner.lock().unwrap();
+            inner.remove(path);
+            while inner.used_bytes + size > self.max_bytes {
+                if !inner.evict_oldest() {
+                    break;
+                }
+            }
+            inner.insert(path.to_path_buf(), file.clone(), len);
+        }
+
+        Ok(file)
+    }
+
+    /// Drops a file from the cache, e.g. after it was written or deleted
+    ///
+    /// The `path` is the file path to be invalidated
+    pub fn invalidate(&self, path: &Path) {
+        self.inner.lock().unwrap().remove(path);
+    }
+
+    /// Drops every cached file under a directory, e.g. after it was moved or deleted
+    ///
+    /// The `dir` is the directory path to be invalidated
+    pub fn invalidate_dir(&self, dir: &Path) {
+        let mut inner = self.inner.lock().unwrap();
+        let stale: Vec<PathBuf> = inner
+            .entries
+            .keys()
+            .filter(|path| path.starts_with(dir))
+            .cloned()
+            .collect();
+        for path in stale {
+            inner.remove(&path);
+        }
+    }
+
+    /// Returns the number of lookups served from memory
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of lookups that read from disk
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of cached files and their total size in bytes
+    pub fn usage(&self) -> (usize, usize) {
+        let inner = self.inner.lock().unwrap();
+        (inner.entries.len(), inner.used_bytes)
+    }
+}
+
+impl CacheInner {
+    /// Marks an entry as most recently used
+    fn touch(&mut self, path: &Path) {
+        self.tick += 1;
+        let tick = self.tick;
+
+        if let Some(entry) = self.entries.get_mut(path) {
+            self.recency.remove(&entry.last_used);
+            entry.last_used = tick;
+            self.recency.insert(tick, path.to_path_buf());
+        }
+    }
+
+    fn insert(&mut self, path: PathBuf, file: CachedFile, len: u64) {
+        self.tick += 1;
+        self.used_bytes += file.contents.len();
+        self.recency.insert(self.tick, path.clone());
+        self.entries.insert(
+            path,
+            CacheEntry {
+                file,
+                len,
+                last_used: self.tick,
+            },
+        );
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.remove(path) {
+            self.recency.remove(&entry.last_used);
+            self.used_bytes -= entry.file.contents.len();
+        }
+    }
+
+    /// Removes the least recently used entry
+    ///
+    /// Returns false if the cache was already empty
+    fn evict_oldest(&mut self) -> bool {
+        match self.recency.pop_first() {
+            Some((_, path)) => {
+                if let Some(entry) = self.entries.remove(&path) {
+                    self.used_bytes -= entry.file.contents.len();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Builds a strong ETag from a file's size and modification time
+pub(crate) fn make_etag(len: u64, modified: SystemTime) -> String {
+    let nanos = modified
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("\"{len:x}-{nanos:x}\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_hits_misses_and_revalidation() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("page.html");
+        fs::write(&path, "one").unwrap();
+        let cache = FileCache::new(1024, 1024);
+        let mime = MimeTypes::default();
+
+        let first = cache.get(&path, &mime).unwrap();
+        let second = cache.get(&path, &mime).unwrap();
+        assert_eq!(first.contents.as_slice(), b"one");
+        assert_eq!(first.content_type, "text/html; charset=utf-8");
+        assert_eq!(first.etag, second.etag);
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+
+        // A change in size is picked up without invalidation
+        fs::write(&path, "three").unwrap();
+        assert_eq!(
+            cache.get(&path, &mime).unwrap().contents.as_slice(),
+            b"three"
+        );
+        assert_eq!(cache.misses(), 2);
+
+        cache.invalidate(&path);
+        assert_eq!(cache.usage(), (0, 0));
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let temp_dir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = ["a.txt", "b.txt", "c.txt"]
+            .iter()
+            .map(|name| {
+                let path = temp_dir.path().join(name);
+                fs::write(&path, "1234").unwrap();
+                path
+            })
+            .collect();
+        let cache = FileCache::new(8, 8);
+        let mime = MimeTypes::default();
+
+        cache.get(&paths[0], &mime).unwrap();
+        cache.get(&paths[1], &mime).unwrap();
+        cache.get(&paths[0], &mime).unwrap();
+        cache.get(&paths[2], &mime).unwrap();
+
+        // `b.txt` was least recently used when `c.txt` needed room
+        assert_eq!(cache.usage(), (2, 8));
+        cache.get(&paths[0], &mime).unwrap();
+        assert_eq!(cache.hits(), 2);
+        cache.get(&paths[1], &mime).unwrap();
+        assert_eq!(cache.misses(), 4);
+    }
+}
@@ -3,11 +3,35 @@ pub enum HttpMethod {
     Get,
     Post,
     Put,
+    Patch,
     Delete,
     Options,
+    Propfind,
+    Mkcol,
+    Copy,
+    Move,
     None,
 }
 
+impl HttpMethod {
+    /// Returns the method name as it appears in a request line
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpMethod::Get => "GET",
+            HttpMethod::Post => "POST",
+            HttpMethod::Put => "PUT",
+            HttpMethod::Patch => "PATCH",
+            HttpMethod::Delete => "DELETE",
+            HttpMethod::Options => "OPTIONS",
+            HttpMethod::Propfind => "PROPFIND",
+            HttpMethod::Mkcol => "MKCOL",
+            HttpMethod::Copy => "COPY",
+            HttpMethod::Move => "MOVE",
+            HttpMethod::None => "",
+        }
+    }
+}
+
 pub enum HttpProtocol {
     Default(String),
 }
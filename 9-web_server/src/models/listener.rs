@@ -0,0 +1,268 @@
+use std::{
+    env, fmt, fs,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    os::{
+        fd::{FromRawFd, IntoRawFd, RawFd},
+        unix::{
+            fs::FileTypeExt,
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::PathBuf,
+    process,
+    time::Duration,
+};
+
+use super::ListenerRole;
+
+/// File descriptor of the first socket passed by socket activation
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A listening socket, either TCP over IPv4 or IPv6, or a Unix domain socket
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Binds a listening socket
+    ///
+    /// The `address` is a `host:port` address such as `127.0.0.1:7878` or `[::]:7878`, or `unix:` followed by a socket path. A stale socket file left at the path is replaced
+    pub fn bind(address: &str) -> io::Result<Listener> {
+        let Some(path) = address.strip_prefix("unix:") else {
+            return TcpListener::bind(address).map(Listener::Tcp);
+        };
+
+        // A socket file still present after a previous run would make binding fail
+        if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        UnixListener::bind(path).map(Listener::Unix)
+    }
+
+    /// Waits for and accepts the next connection
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+            Listener::Unix(listener) => listener.accept().map(|(stream, _)| Stream::Unix(stream)),
+        }
+    }
+
+    /// Returns an iterator accepting connections, like `TcpListener::incoming`
+    pub fn incoming(&self) -> impl Iterator<Item = io::Result<Stream>> + '_ {
+        std::iter::repeat_with(|| self.accept())
+    }
+
+    /// Returns the address the socket is bound to
+    pub fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(ListenAddr::Tcp),
+            Listener::Unix(listener) => listener
+                .local_addr()?
+                .as_pathname()
+                .map(|path| ListenAddr::Unix(path.to_path_buf()))
+                .ok_or_else(|| io::Error::other("Unix socket has no path")),
+        }
+    }
+}
+
+/// Address of a Listener, which can be connected to
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    /// Opens a connection to the listener, e.g. to wake its accept loop
+    pub fn connect(&self) -> io::Result<Stream> {
+        match self {
+            ListenAddr::Tcp(addr) => TcpStream::connect(addr).map(Stream::Tcp),
+            ListenAddr::Unix(path) => UnixStream::connect(path).map(Stream::Unix),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{addr}"),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A connection accepted by a Listener
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Returns the address of the client, or None for Unix domain sockets
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.peer_addr().ok(),
+            Stream::Unix(_) => None,
+        }
+    }
+
+    /// Returns the local address the connection was accepted on, or None for Unix domain sockets
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.local_addr().ok(),
+            Stream::Unix(_) => None,
+        }
+    }
+
+    /// Sends small writes right away instead of coalescing them, e.g. for the frames of multiplexed streams
+    ///
+    /// Unix domain sockets have no such delay, so this does nothing for them
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nodelay(nodelay),
+            Stream::Unix(_) => Ok(()),
+        }
+    }
+
+    /// Bounds how long a single read or write may block, after which it fails with a timeout error
+    ///
+    /// The `timeout` is the longest wait, or None to block indefinitely
+    pub fn set_timeouts(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+            Stream::Unix(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+        }
+    }
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Self {
+        Stream::Tcp(stream)
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).read(buf),
+            Stream::Unix(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).write(buf),
+            Stream::Unix(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).flush(),
+            Stream::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+/// Takes the listening sockets passed by a service manager such as systemd through socket activation
+///
+/// Sockets named `admin` or `metrics` in `LISTEN_FDNAMES` get that role, all others serve requests
+///
+/// Returns None unless `LISTEN_PID` names this process and `LISTEN_FDS` counts at least one socket
+pub fn inherited_listeners() -> Option<io::Result<Vec<(Listener, ListenerRole)>>> {
+    let pid: u32 = env::var("LISTEN_PID").ok()?.parse().ok()?;
+    let count: RawFd = env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    if pid != process::id() || count <= 0 {
+        return None;
+    }
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+
+    let listeners = (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            let role = match names.next() {
+                Some("admin") => ListenerRole::Admin,
+                Some("metrics") => ListenerRole::Metrics,
+                _ => ListenerRole::Main,
+            };
+            Ok((inherit(fd)?, role))
+        })
+        .collect();
+    Some(listeners)
+}
+
+/// Takes ownership of an inherited listening socket
+///
+/// The socket is duplicated so the copy is closed on exec, keeping it out of CGI scripts, and the original closed
+fn inherit(fd: RawFd) -> io::Result<Listener> {
+    // SAFETY: socket activation passes the sockets as open descriptors starting at 3, which nothing else in the
+    // process owns
+    let tcp = unsafe { TcpListener::from_raw_fd(fd) };
+
+    // TCP sockets have an IP address, Unix domain sockets fail the conversion
+    if tcp.local_addr().is_ok() {
+        return tcp.try_clone().map(Listener::Tcp);
+    }
+    // SAFETY: the descriptor was just released by `tcp`, so it has a single owner again
+    let unix = unsafe { UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+    unix.try_clone().map(Listener::Unix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_listen_on_tcp_and_unix_sockets() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("web_server.sock");
+        let address = format!("unix:{}", path.display());
+
+        // Binding the Unix socket twice replaces the stale socket file left by the first listener
+        for address in ["127.0.0.1:0", &address, &address] {
+            let listener = Listener::bind(address).unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = addr.connect().unwrap();
+            let server = listener.accept().unwrap();
+            (&client).write_all(b"ping").unwrap();
+            let mut buf = [0; 4];
+            (&server).read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"ping");
+            assert_eq!(
+                server.peer_addr().is_some(),
+                matches!(addr, ListenAddr::Tcp(_))
+            );
+
+            // Idle clients can't block reads forever
+            server
+                .set_timeouts(Some(Duration::from_millis(50)))
+                .unwrap();
+            let err = (&server).read(&mut buf).unwrap_err();
+            assert!(matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ));
+        }
+        assert_eq!(
+            Listener::bind(&address)
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .to_string(),
+            address
+        );
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    cell::RefCell,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use serde::Deserialize;
+
+use super::Request;
+
+/// Verbosity of the server's logs, from least to most verbose
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    /// Only errors
+    Error,
+    /// Errors and one access log line per request
+    #[default]
+    Info,
+    /// Everything, including worker activity
+    Debug,
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+impl LogLevel {
+    /// Returns the level's name as used in the configuration
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        }
+    }
+
+    /// Parses a level's name, ignoring case
+    pub fn parse(name: &str) -> Option<LogLevel> {
+        match name.trim().to_lowercase().as_str() {
+            "error" => Some(LogLevel::Error),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+
+    /// Returns the level the server currently logs at
+    pub fn current() -> LogLevel {
+        match LOG_LEVEL.load(Ordering::Relaxed) {
+            0 => LogLevel::Error,
+            1 => LogLevel::Info,
+            _ => LogLevel::Debug,
+        }
+    }
+
+    /// Changes the level the server logs at
+    pub fn set_current(level: LogLevel) {
+        LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+    }
+
+    /// Checks whether messages at this level are currently logged
+    pub fn enabled(self) -> bool {
+        self <= LogLevel::current()
+    }
+}
+
+thread_local! {
+    /// Request id and trace id of the request the current thread is handling
+    static REQUEST_IDS: RefCell<Option<(String, String)>> = const { RefCell::new(None) };
+}
+
+/// Marks the current thread as handling a request, so its log lines name the request
+///
+/// The request is forgotten again when the scope is dropped
+pub struct RequestLogScope(());
+
+impl RequestLogScope {
+    /// Starts logging on behalf of a request
+    ///
+    /// The `req` is the Request the current thread is about to handle
+    pub fn enter(req: &Request) -> RequestLogScope {
+        let ids = (
+            req.get_request_id().to_owned(),
+            req.get_trace().get_trace_id().to_owned(),
+        );
+        REQUEST_IDS.with(|current| *current.borrow_mut() = Some(ids));
+        RequestLogScope(())
+    }
+}
+
+impl Drop for RequestLogScope {
+    fn drop(&mut self) {
+        REQUEST_IDS.with(|current| *current.borrow_mut() = None);
+    }
+}
+
+/// Returns the suffix naming the request being handled in log lines
+///
+/// Returns ` request_id=<id> trace_id=<id>`, or an empty string outside of a RequestLogScope
+pub fn log_suffix() -> String {
+    REQUEST_IDS.with(|current| match &*current.borrow() {
+        Some((request_id, trace_id)) => format!(" request_id={request_id} trace_id={trace_id}"),
+        None => String::new(),
+    })
+}
+
+/// Writes an error line to stderr, naming the request being handled if any
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        eprintln!("{}{}", format_args!($($arg)*), $crate::models::log_suffix())
+    };
+}
@@ -0,0 +1,265 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, Read, Write},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use super::{FileCache, PoolLoad};
+
+/// Upper bounds in seconds of the request latency histogram buckets
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Server-wide counters reported in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    /// Requests by method and status code
+    requests: Mutex<BTreeMap<(String, usize), u64>>,
+    /// Requests per latency bucket, with one extra bucket for `+Inf`
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    latency_sum_micros: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+}
+
+impl Metrics {
+    /// Records a completed request
+    ///
+    /// The `method` is the request method, `status` is the response status code, and `elapsed` is the time taken to answer
+    pub fn record_request(&self, method: &str, status: usize, elapsed: Duration) {
+        let method = if method.is_empty() { "OTHER" } else { method };
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((method.to_owned(), status))
+            .or_insert(0) += 1;
+
+        let secs = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Wraps a stream so the bytes read from and written to it are counted
+    pub fn count<S>(&self, stream: S) -> Counted<'_, S> {
+        Counted {
+            inner: stream,
+            metrics: self,
+        }
+    }
+
+    /// Renders every metric in the Prometheus text exposition format
+    ///
+    /// The `open_connections` is the number of connections being handled, `pool` is the load of the ThreadPool, and `cache` is the static file cache, whose hit rate is included if it is enabled
+    pub fn render(
+        &self,
+        open_connections: usize,
+        pool: &PoolLoad,
+        cache: Option<&FileCache>,
+    ) -> String {
+        let mut out = String::new();
+
+        out.push_str(
+            "# HELP web_server_requests_total Requests answered, by method and status code.\n",
+        );
+        out.push_str("# TYPE web_server_requests_total counter\n");
+        for ((method, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "web_server_requests_total{{method=\"{method}\",status=\"{status}\"}} {count}"
+            );
+        }
+
+        out.push_str("# HELP web_server_request_duration_seconds Time taken to answer requests.\n");
+        out.push_str("# TYPE web_server_request_duration_seconds histogram\n");
+        let mut cumulative = 0;
+        for (i, bucket) in self.latency_buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let bound = LATENCY_BUCKETS
+                .get(i)
+                .map_or("+Inf".to_owned(), |bound| bound.to_string());
+            let _ = writeln!(
+                out,
+                "web_server_request_duration_seconds_bucket{{le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        let sum = self.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "web_server_request_duration_seconds_sum {sum}");
+        let _ = writeln!(
+            out,
+            "web_server_request_duration_seconds_count {cumulative}"
+        );
+
+        let counters = [
+            (
+                "web_server_received_bytes_total",
+                "Bytes read from clients.",
+                self.bytes_received.load(Ordering::Relaxed),
+            ),
+            (
+                "web_server_sent_bytes_total",
+                "Bytes written to clients.",
+                self.bytes_sent.load(Ordering::Relaxed),
+            ),
+        ];
+        for (name, help, value) in counters {
+            write_metric(&mut out, name, "counter", help, value as f64);
+        }
+
+        let gauges = [
+            (
+                "web_server_open_connections",
+                "Client connections currently open.",
+                open_connections,
+            ),
+            (
+                "web_server_pool_workers",
+                "Worker threads in the pool.",
+                pool.size(),
+            ),
+            (
+                "web_server_pool_busy_workers",
+                "Workers currently handling a connection.",
+                pool.busy(),
+            ),
+            (
+                "web_server_pool_queue_depth",
+                "Connections waiting for a free worker.",
+                pool.queued(),
+            ),
+            (
+                "web_server_pool_stream_handlers",
+                "HTTP/2 stream handlers running beside the workers.",
+                pool.stream_handlers(),
+            ),
+        ];
+        for (name, help, value) in gauges {
+            write_metric(&mut out, name, "gauge", help, value as f64);
+        }
+
+        if let Some(cache) = cache {
+            let (entries, bytes) = cache.usage();
+            let (hits, misses) = (cache.hits(), cache.misses());
+            let hit_ratio = if hits + misses == 0 {
+                0.0
+            } else {
+                hits as f64 / (hits + misses) as f64
+            };
+
+            write_metric(
+                &mut out,
+                "web_server_cache_hits_total",
+                "counter",
+                "File cache lookups served from memory.",
+                hits as f64,
+            );
+            write_metric(
+                &mut out,
+                "web_server_cache_misses_total",
+                "counter",
+                "File cache lookups read from disk.",
+                misses as f64,
+            );
+            write_metric(
+                &mut out,
+                "web_server_cache_hit_ratio",
+                "gauge",
+                "Share of file cache lookups served from memory.",
+                hit_ratio,
+            );
+            write_metric(
+                &mut out,
+                "web_server_cache_entries",
+                "gauge",
+                "Files held in the cache.",
+                entries as f64,
+            );
+            write_metric(
+                &mut out,
+                "web_server_cache_bytes",
+                "gauge",
+                "Bytes held in the cache.",
+                bytes as f64,
+            );
+        }
+
+        out
+    }
+}
+
+/// Writes a single unlabelled metric with its `HELP` and `TYPE` lines
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: f64) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    let _ = writeln!(out, "{name} {value}");
+}
+
+/// A stream whose traffic is added to the byte counters of Metrics
+pub struct Counted<'a, S> {
+    inner: S,
+    metrics: &'a Metrics,
+}
+
+impl<S: Read> Read for Counted<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.metrics
+            .bytes_received
+            .fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Counted<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.metrics
+            .bytes_sent
+            .fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_prometheus_text() {
+        let metrics = Metrics::default();
+        metrics.record_request("GET", 200, Duration::from_millis(3));
+        metrics.record_request("GET", 200, Duration::from_millis(30));
+        metrics.record_request("", 400, Duration::from_secs(20));
+
+        let mut sink = vec![];
+        metrics.count(&mut sink).write_all(b"hello").unwrap();
+        metrics.count(&b"hi"[..]).read_to_end(&mut vec![]).unwrap();
+
+        let out = metrics.render(1, &PoolLoad::default(), Some(&FileCache::new(0, 0)));
+        assert!(out.contains("web_server_requests_total{method=\"GET\",status=\"200\"} 2\n"));
+        assert!(out.contains("web_server_requests_total{method=\"OTHER\",status=\"400\"} 1\n"));
+        assert!(out.contains("web_server_request_duration_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(out.contains("web_server_request_duration_seconds_bucket{le=\"0.05\"} 2\n"));
+        assert!(out.contains("web_server_request_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("web_server_request_duration_seconds_count 3\n"));
+        assert!(out.contains("web_server_sent_bytes_total 5\n"));
+        assert!(out.contains("web_server_received_bytes_total 2\n"));
+        assert!(out.contains("web_server_open_connections 1\n"));
+        assert!(out.contains("web_server_cache_hit_ratio 0\n"));
+    }
+}
@@ -0,0 +1,291 @@
+use std::{collections::HashMap, fs, io, path::Path};
+
+/// Types known without a `mime.types` file, in the same format
+const BUILTIN_TYPES: &str = "
+application/gzip                gz
+application/json                json map
+application/ld+json             jsonld
+application/manifest+json       webmanifest
+application/octet-stream        bin exe
+application/pdf                 pdf
+application/rtf                 rtf
+application/vnd.ms-fontobject   eot
+application/wasm                wasm
+application/x-7z-compressed     7z
+application/x-bzip2             bz2
+application/x-tar               tar
+application/x-xz                xz
+application/xml                 xml xsl
+application/zip                 zip
+application/zstd                zst
+audio/aac                       aac
+audio/flac                      flac
+audio/midi                      mid midi
+audio/mpeg                      mp3
+audio/ogg                       oga ogg opus
+audio/wav                       wav
+audio/webm                      weba
+font/otf                        otf
+font/ttf                        ttf
+font/woff                       woff
+font/woff2                      woff2
+image/avif                      avif
+image/bmp                       bmp
+image/gif                       gif
+image/jpeg                      jpeg jpg
+image/png                       png
+image/svg+xml                   svg svgz
+image/tiff                      tif tiff
+image/vnd.microsoft.icon        ico
+image/webp                      webp
+text/calendar                   ics
+text/css                        css
+text/csv                        csv
+text/html                       html htm
+text/javascript                 js mjs
+text/markdown                   md markdown
+text/plain                      txt text log conf ini toml
+text/vtt                        vtt
+text/yaml                       yaml yml
+video/mp2t                      ts
+video/mp4                       mp4 m4v
+video/mpeg                      mpeg mpg
+video/ogg                       ogv
+video/quicktime                 mov
+video/webm                      webm
+video/x-msvideo                 avi
+";
+
+/// Type sent when nothing else is known about a file
+pub const DEFAULT_MIME_TYPE: &str = "application/octet-stream";
+
+/// Maps file extensions to media types
+#[derive(Debug, Clone)]
+pub struct MimeTypes {
+    by_extension: HashMap<String, String>,
+    /// Charset parameter added to `text/*` types, if any
+    charset: Option<String>,
+    /// Whether files with unknown extensions are identified by their contents
+    sniff: bool,
+}
+
+impl Default for MimeTypes {
+    fn default() -> Self {
+        let mut types = MimeTypes {
+            by_extension: HashMap::new(),
+            charset: Some("utf-8".to_owned()),
+            sniff: true,
+        };
+        types.parse(BUILTIN_TYPES);
+        types
+    }
+}
+
+impl MimeTypes {
+    /// Adds the mappings of a `mime.types` file, replacing built-in ones for the same extensions
+    ///
+    /// The `path` is a file of `type/subtype ext1 ext2 ...` lines, such as `/etc/mime.types`
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        let contents = fs::read_to_string(path)?;
+        self.parse(&contents);
+        Ok(())
+    }
+
+    /// Adds the mappings of `mime.types`-formatted text
+    fn parse(&mut self, contents: &str) {
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(media_type) = fields.next() else {
+                continue;
+            };
+            for extension in fields {
+                self.insert(extension, media_type);
+            }
+        }
+    }
+
+    /// Maps an extension to a media type, replacing any existing mapping
+    ///
+    /// The `extension` is matched case-insensitively, with or without a leading `.`
+    pub fn insert(&mut self, extension: &str, media_type: &str) {
+        self.by_extension.insert(
+            extension.trim_start_matches('.').to_lowercase(),
+            media_type.to_lowercase(),
+        );
+    }
+
+    /// Sets the charset parameter added to `text/*` types. `None` sends them without one
+    pub fn set_charset(&mut self, charset: Option<String>) {
+        self.charset = charset;
+    }
+
+    /// Sets whether files with unknown extensions are identified by their contents
+    pub fn set_sniff(&mut self, sniff: bool) {
+        self.sniff = sniff;
+    }
+
+    /// Looks up the media type of a path by its extension
+    ///
+    /// The `path` may have a non-UTF-8 name, whose extension is then unknown
+    pub fn lookup(&self, path: &Path) -> Option<&str> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        self.by_extension.get(&extension).map(String::as_str)
+    }
+
+    /// Determines the `Content-Type` of a file
+    ///
+    /// The `path` is the file's path and `contents` are its first bytes, sniffed if the extension is missing or unknown
+    ///
+    /// Returns the media type, with a charset parameter for text types
+    pub fn content_type(&self, path: &Path, contents: &[u8]) -> String {
+        let media_type = self
+            .lookup(path)
+            .or_else(|| self.sniff.then(|| sniff(contents)).flatten())
+            .unwrap_or(DEFAULT_MIME_TYPE);
+
+        match &self.charset {
+            Some(charset) if media_type.starts_with("text/") => {
+                format!("{media_type}; charset={charset}")
+            }
+            _ => media_type.to_owned(),
+        }
+    }
+}
+
+/// Identifies a file's type from its leading bytes
+///
+/// The `contents` are the file's first bytes. Text is only recognized if it is valid UTF-8 without control characters
+///
+/// Returns the media type, if recognized
+pub fn sniff(contents: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"BZh", "application/x-bzip2"),
+        (b"\xfd7zXZ\x00", "application/x-xz"),
+        (b"\x28\xb5\x2f\xfd", "application/zstd"),
+        (b"\x00asm", "application/wasm"),
+        (b"wOFF", "font/woff"),
+        (b"wOF2", "font/woff2"),
+        (b"OggS", "audio/ogg"),
+        (b"ID3", "audio/mpeg"),
+        (b"fLaC", "audio/flac"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+    ];
+    if let Some((_, media_type)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| contents.starts_with(signature))
+    {
+        return Some(media_type);
+    }
+
+    // Containers with their brand after a size field
+    if contents.get(4..8) == Some(b"ftyp") {
+        return Some("video/mp4");
+    }
+    if contents.starts_with(b"RIFF") {
+        match contents.get(8..12) {
+            Some(b"WEBP") => return Some("image/webp"),
+            Some(b"WAVE") => return Some("audio/wav"),
+            Some(b"AVI ") => return Some("video/x-msvideo"),
+            _ => {}
+        }
+    }
+
+    // Markup and plain text
+    let text = std::str::from_utf8(contents).ok()?;
+    let start = text.trim_start().get(..14).unwrap_or(text.trim_start());
+    let start = start.to_ascii_lowercase();
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        Some("text/html")
+    } else if start.starts_with("<?xml") {
+        Some("application/xml")
+    } else if text
+        .chars()
+        .all(|c| !c.is_control() || c.is_ascii_whitespace())
+    {
+        Some("text/plain")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_content_types() {
+        let mut types = MimeTypes::default();
+        let none = b"";
+        assert_eq!(
+            types.content_type(Path::new("app.WASM"), none),
+            "application/wasm"
+        );
+        assert_eq!(
+            types.content_type(Path::new("index.html"), none),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(
+            types.content_type(Path::new("logo.svg"), none),
+            "image/svg+xml"
+        );
+
+        // Names that aren't UTF-8 fall back to sniffing instead of panicking
+        let name = Path::new(OsStr::from_bytes(b"caf\xe9.png"));
+        assert_eq!(
+            types.content_type(name, b"\x89PNG\r\n\x1a\n...."),
+            "image/png"
+        );
+        assert_eq!(
+            types.content_type(Path::new("README"), b"hello\n"),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(
+            types.content_type(Path::new("blob"), b"\x00\x01\x02"),
+            DEFAULT_MIME_TYPE
+        );
+
+        // Files and overrides replace built-in mappings
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("mime.types");
+        fs::write(
+            &path,
+            "# comment\ntext/x-rust rs\napplication/x-lua lua # inline\n",
+        )
+        .unwrap();
+        types.load(&path).unwrap();
+        types.insert(".md", "text/x-markdown");
+        types.set_charset(None);
+        assert_eq!(
+            types.content_type(Path::new("main.rs"), none),
+            "text/x-rust"
+        );
+        assert_eq!(
+            types.content_type(Path::new("a.lua"), none),
+            "application/x-lua"
+        );
+        assert_eq!(
+            types.content_type(Path::new("a.md"), none),
+            "text/x-markdown"
+        );
+    }
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(sniff(b"\x00asm\x01\x00\x00\x00"), Some("application/wasm"));
+        assert_eq!(sniff(b"\x00\x00\x00\x18ftypmp42"), Some("video/mp4"));
+        assert_eq!(sniff(b"RIFF\x00\x00\x00\x00WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(b"  <!DOCTYPE html><html>"), Some("text/html"));
+        assert_eq!(sniff(b"<?xml version=\"1.0\"?>"), Some("application/xml"));
+        assert_eq!(sniff(b"\xff\xfe\x00"), None);
+    }
+}
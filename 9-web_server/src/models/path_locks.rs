@@ -0,0 +1,93 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex},
+};
+
+/// Locks serializing writes to the same file
+///
+/// Only paths currently being written are held, so memory use doesn't grow with the number of files
+#[derive(Debug, Default)]
+pub struct PathLocks {
+    held: Mutex<HashSet<PathBuf>>,
+    released: Condvar,
+}
+
+impl PathLocks {
+    /// Locks a path, waiting while another thread holds it
+    ///
+    /// The `path` is the file about to be written. It stays locked until the returned guard is dropped
+    pub fn lock(&self, path: &Path) -> PathLock<'_> {
+        let mut held = self.held.lock().unwrap();
+        while held.contains(path) {
+            held = self.released.wait(held).unwrap();
+        }
+        held.insert(path.to_path_buf());
+
+        PathLock {
+            locks: self,
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// Returns the number of paths currently locked
+    pub fn len(&self) -> usize {
+        self.held.lock().unwrap().len()
+    }
+
+    /// Checks whether no paths are locked
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Keeps a path locked, see `PathLocks::lock`
+pub struct PathLock<'a> {
+    locks: &'a PathLocks,
+    path: PathBuf,
+}
+
+impl Drop for PathLock<'_> {
+    fn drop(&mut self) {
+        self.locks.held.lock().unwrap().remove(&self.path);
+        self.locks.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn test_writers_to_a_path_take_turns() {
+        let locks = Arc::new(PathLocks::default());
+        let writing = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let (locks, writing) = (Arc::clone(&locks), Arc::clone(&writing));
+                thread::spawn(move || {
+                    let _lock = locks.lock(Path::new("public/a.txt"));
+                    assert_eq!(writing.fetch_add(1, Ordering::SeqCst), 0);
+                    thread::sleep(Duration::from_millis(10));
+                    writing.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+
+        // Other paths aren't blocked meanwhile
+        drop(locks.lock(Path::new("public/b.txt")));
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert!(locks.is_empty());
+    }
+}
@@ -0,0 +1,157 @@
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+/// Token buckets of every client limited by one rate limit rule
+///
+/// Each bucket holds up to `capacity` tokens and refills continuously at `rate` tokens per second. Each request takes a token
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Outcome of taking a token from a client's bucket
+#[derive(Debug, PartialEq)]
+pub struct RateDecision {
+    /// Whether the request may proceed
+    pub allowed: bool,
+    /// Whole tokens left in the bucket
+    pub remaining: u64,
+    /// Seconds until the bucket is full again
+    pub reset_secs: u64,
+    /// Seconds until the next token is available, if the request was refused
+    pub retry_after_secs: u64,
+}
+
+impl RateLimiter {
+    /// Takes a token from a client's bucket
+    ///
+    /// The `key` identifies the client, `capacity` is the bucket size, `rate` is the refill in tokens per second, and `max_clients` bounds the number of buckets kept
+    ///
+    /// Returns whether the request is allowed along with the state of the bucket
+    pub fn take(&self, key: &str, capacity: f64, rate: f64, max_clients: usize) -> RateDecision {
+        self.take_at(key, capacity, rate, max_clients, Instant::now())
+    }
+
+    fn take_at(
+        &self,
+        key: &str,
+        capacity: f64,
+        rate: f64,
+        max_clients: usize,
+        now: Instant,
+    ) -> RateDecision {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // Make room for a new client
+        if !buckets.contains_key(key) && buckets.len() >= max_clients {
+            evict(&mut buckets, capacity, rate, max_clients, now);
+        }
+
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = refilled(bucket, capacity, rate, now);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        RateDecision {
+            allowed,
+            remaining: bucket.tokens.floor() as u64,
+            reset_secs: ((capacity - bucket.tokens) / rate).ceil() as u64,
+            retry_after_secs: if allowed {
+                0
+            } else {
+                ((1.0 - bucket.tokens) / rate).ceil().max(1.0) as u64
+            },
+        }
+    }
+
+    /// Returns the number of clients currently tracked
+    pub fn clients(&self) -> usize {
+        self.buckets.lock().unwrap().len()
+    }
+}
+
+/// Returns the tokens in a bucket after refilling it up to `now`
+fn refilled(bucket: &Bucket, capacity: f64, rate: f64, now: Instant) -> f64 {
+    let elapsed = now.saturating_duration_since(bucket.updated);
+    (bucket.tokens + elapsed.as_secs_f64() * rate).min(capacity)
+}
+
+/// Drops buckets until there is room for one more client
+///
+/// Full buckets are dropped first since they behave like a missing bucket, then the least recently used
+fn evict(
+    buckets: &mut HashMap<String, Bucket>,
+    capacity: f64,
+    rate: f64,
+    max_clients: usize,
+    now: Instant,
+) {
+    buckets.retain(|_, bucket| refilled(bucket, capacity, rate, now) < capacity);
+
+    while buckets.len() >= max_clients {
+        let oldest = buckets
+            .iter()
+            .min_by_key(|(_, bucket)| bucket.updated)
+            .map(|(key, _)| key.clone());
+        match oldest {
+            Some(key) => buckets.remove(&key),
+            None => break,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_bucket_drains_and_refills() {
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+
+        // Two requests per second with a burst of two
+        assert!(limiter.take_at("a", 2.0, 2.0, 10, start).allowed);
+        assert!(limiter.take_at("a", 2.0, 2.0, 10, start).allowed);
+        let refused = limiter.take_at("a", 2.0, 2.0, 10, start);
+        assert!(!refused.allowed);
+        assert_eq!(refused.remaining, 0);
+        assert_eq!(refused.retry_after_secs, 1);
+
+        // Other clients have their own bucket
+        assert!(limiter.take_at("b", 2.0, 2.0, 10, start).allowed);
+
+        let later = start + Duration::from_millis(500);
+        let decision = limiter.take_at("a", 2.0, 2.0, 10, later);
+        assert!(decision.allowed);
+        assert_eq!(decision.reset_secs, 1);
+    }
+
+    #[test]
+    fn test_client_count_is_bounded() {
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+
+        for (i, key) in ["a", "b", "c"].iter().enumerate() {
+            limiter.take_at(key, 5.0, 1.0, 2, start + Duration::from_millis(i as u64));
+        }
+        assert_eq!(limiter.clients(), 2);
+
+        // `a` was evicted, so it starts over with a full bucket
+        let decision = limiter.take_at("a", 5.0, 1.0, 2, start + Duration::from_millis(3));
+        assert_eq!(decision.remaining, 4);
+        assert_eq!(limiter.clients(), 2);
+    }
+}
@@ -1,14 +1,32 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use super::http::*;
+use super::{TraceContext, http::*, parse_cookies};
+use crate::utils::{canonical_path, percent_decode};
+
+/// Longest `X-Request-Id` accepted from clients
+const MAX_REQUEST_ID_LEN: usize = 128;
 
 pub struct Request {
     protocol: String,
     method: HttpMethod,
+    method_name: String,
+    target: String,
+    path: Option<String>,
     resource: PathBuf,
     queries: HashMap<String, String>,
     headers: HashMap<String, String>,
     body: Vec<u8>,
+    client_addr: Option<SocketAddr>,
+    server_addr: Option<SocketAddr>,
+    identity: Option<String>,
+    id: String,
+    trace: TraceContext,
 }
 
 impl Default for Request {
@@ -16,10 +34,18 @@ impl Default for Request {
         Request {
             protocol: String::from("HTTP/1.1"),
             method: HttpMethod::None,
+            method_name: String::new(),
+            target: String::new(),
+            path: Some(String::from("/")),
             resource: PathBuf::new(),
             queries: HashMap::new(),
             headers: HashMap::new(),
             body: vec![],
+            client_addr: None,
+            server_addr: None,
+            identity: None,
+            id: new_request_id(),
+            trace: TraceContext::default(),
         }
     }
 }
@@ -31,21 +57,42 @@ impl Request {
     pub fn parse_status_line(&mut self, status_line: String) {
         let mut chunks = status_line.split_whitespace();
 
-        // Set method
-        self.method = match chunks.next() {
+        // Set method, keeping its name for methods without a variant
+        let method = chunks.next();
+        self.method_name = method.unwrap_or("").to_owned();
+        self.method = match method {
             Some(x) => match x.to_uppercase().as_str() {
                 "GET" => HttpMethod::Get,
                 "POST" => HttpMethod::Post,
                 "PUT" => HttpMethod::Put,
+                "PATCH" => HttpMethod::Patch,
                 "DELETE" => HttpMethod::Delete,
                 "OPTIONS" => HttpMethod::Options,
+                "PROPFIND" => HttpMethod::Propfind,
+                "MKCOL" => HttpMethod::Mkcol,
+                "COPY" => HttpMethod::Copy,
+                "MOVE" => HttpMethod::Move,
                 _ => HttpMethod::None,
             },
             None => HttpMethod::None,
         };
 
+        // Keep the raw request target for forwarding
+        self.set_target(chunks.next().unwrap_or(""));
+
+        // Set protocol
+        self.protocol = chunks.next().unwrap_or("").to_owned();
+    }
+
+    /// Replaces the request target, e.g. when a rewrite rule changes it, parsing its path and queries again
+    ///
+    /// The `target` is the raw request target, including any query string. The resource is resolved against the default document root until `set_document_root` is called
+    pub fn set_target(&mut self, target: &str) {
+        self.target = target.to_owned();
+
         // Parse resource for queries
-        let (path, query_string) = if let Some(target) = chunks.next() {
+        let (path, query_string) = if !self.target.is_empty() {
+            let target = self.target.as_str();
             // Check if resource has queries
             if let Some((p, q)) = target.split_once("?") {
                 (p, q)
@@ -56,12 +103,9 @@ impl Request {
             ("", "")
         };
 
-        // Set resource
-        self.resource = if path.is_empty() || path == "/" {
-            PathBuf::from("public/index.html")
-        } else {
-            PathBuf::from(format!("public{path}"))
-        };
+        // Set resource relative to the default document root
+        self.path = canonical_path(path);
+        self.resource = self.resolve_resource(Path::new("public"), "index.html");
 
         // Set queries
         self.queries.clear();
@@ -69,13 +113,11 @@ impl Request {
             for query in query_string.split("&") {
                 let mut kv = query.splitn(2, "=");
                 if let (Some(key), Some(value)) = (kv.next(), kv.next()) {
-                    self.queries.insert(key.to_owned(), value.to_owned());
+                    self.queries
+                        .insert(percent_decode(key), percent_decode(value));
                 }
             }
         }
-
-        // Set protocol
-        self.protocol = chunks.next().unwrap_or("").to_owned();
     }
 
     /// Processes and appends a given header into the headers HashMap
@@ -100,6 +142,51 @@ impl Request {
         &self.method
     }
 
+    /// Returns the method name exactly as the client sent it, including methods such as `HEAD` that have no HttpMethod variant
+    pub fn get_method_name(&self) -> &str {
+        &self.method_name
+    }
+
+    /// Returns the raw request target, including the query string
+    pub fn get_target(&self) -> &str {
+        &self.target
+    }
+
+    /// Returns the canonical path of the request target, decoded and without the query string
+    ///
+    /// Access rules, routes, and the served file are all looked up with this path, so differently spelled targets can't get around a rule. Targets with a `..` segment have an empty path
+    pub fn get_path(&self) -> &str {
+        self.path.as_deref().unwrap_or_default()
+    }
+
+    /// Returns whether the request target stays inside the document root, i.e. has no `..` segment
+    pub fn has_valid_path(&self) -> bool {
+        self.path.is_some()
+    }
+
+    /// Returns the protocol from the status line of the Request
+    pub fn get_protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    /// Resolves the target resource against a site's document root
+    ///
+    /// The `root` is the directory files are served from and `index` is the file served for directory requests
+    pub fn set_document_root(&mut self, root: &Path, index: &str) {
+        self.resource = self.resolve_resource(root, index);
+    }
+
+    /// Maps the canonical path onto a file under a document root
+    ///
+    /// The `root` is the document root and `index` is the file served for directory requests. Targets leaving the root resolve to its parent, which routing refuses
+    fn resolve_resource(&self, root: &Path, index: &str) -> PathBuf {
+        match &self.path {
+            Some(path) if path.ends_with('/') => root.join(&path[1..]).join(index),
+            Some(path) => root.join(&path[1..]),
+            None => root.join(".."),
+        }
+    }
+
     /// Returns a reference to the target resource of the Request
     pub fn get_resource(&self) -> &PathBuf {
         &self.resource
@@ -115,8 +202,128 @@ impl Request {
         &self.headers
     }
 
+    /// Returns the cookies sent in the `Cookie` header, by name
+    pub fn get_cookies(&self) -> HashMap<String, String> {
+        self.headers
+            .get("cookie")
+            .map(|header| parse_cookies(header))
+            .unwrap_or_default()
+    }
+
+    /// Returns the value of a cookie, if sent
+    ///
+    /// The `name` is the cookie's name
+    pub fn get_cookie(&self, name: &str) -> Option<String> {
+        self.get_cookies().remove(name)
+    }
+
     /// Returns a reference to the body, if any, of the Request
     pub fn get_body(&self) -> &[u8] {
         &self.body
     }
+
+    /// Sets the address of the client that sent the Request
+    ///
+    /// The `addr` is the peer address of the connection, if known
+    pub fn set_client_addr(&mut self, addr: Option<SocketAddr>) {
+        self.client_addr = addr;
+    }
+
+    /// Returns the address of the client that sent the Request, if known
+    pub fn get_client_addr(&self) -> Option<SocketAddr> {
+        self.client_addr
+    }
+
+    /// Takes the client address a trusted front proxy added as the last `X-Forwarded-For` entry
+    ///
+    /// The entry is removed, so the request is forwarded on as if it came from that client directly
+    ///
+    /// Returns the client's IP address, or None if the header is missing or its last entry isn't an IP address
+    pub fn take_forwarded_for(&mut self) -> Option<IpAddr> {
+        let forwarded_for = self.headers.get("x-forwarded-for")?;
+        let (rest, last) = forwarded_for
+            .rsplit_once(',')
+            .unwrap_or(("", forwarded_for.as_str()));
+        let client = last.trim().parse().ok()?;
+
+        let rest = rest.trim().to_owned();
+        if rest.is_empty() {
+            self.headers.remove("x-forwarded-for");
+        } else {
+            self.headers.insert("x-forwarded-for".to_owned(), rest);
+        }
+        Some(client)
+    }
+
+    /// Sets the local address the Request was received on
+    ///
+    /// The `addr` is the local address of the connection, if known
+    pub fn set_server_addr(&mut self, addr: Option<SocketAddr>) {
+        self.server_addr = addr;
+    }
+
+    /// Returns the local address the Request was received on, if known
+    pub fn get_server_addr(&self) -> Option<SocketAddr> {
+        self.server_addr
+    }
+
+    /// Sets the authenticated identity of the client
+    ///
+    /// The `identity` is the user or token name verified by access control
+    pub fn set_identity(&mut self, identity: Option<String>) {
+        self.identity = identity;
+    }
+
+    /// Returns the authenticated identity of the client, if any
+    pub fn get_identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+
+    /// Returns the id identifying this request in logs and error pages
+    pub fn get_request_id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns the trace context continued or started by this request
+    pub fn get_trace(&self) -> &TraceContext {
+        &self.trace
+    }
+
+    /// Adopts the request id and trace context sent by the client or an upstream proxy
+    ///
+    /// An `X-Request-Id` of up to 128 letters, digits, `.`, `_`, and `-` replaces the generated id. The `traceparent` and `tracestate` headers continue the caller's trace
+    pub fn read_trace_headers(&mut self) {
+        if let Some(id) = self.headers.get("x-request-id").map(|id| id.trim())
+            && is_valid_request_id(id)
+        {
+            self.id = id.to_owned();
+        }
+        self.trace = TraceContext::from_headers(
+            self.headers.get("traceparent").map(String::as_str),
+            self.headers.get("tracestate").map(String::as_str),
+        );
+    }
+}
+
+/// Generates a request id unique within this process
+///
+/// Returns 16 hex digits mixing the current time with a counter
+fn new_request_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:016x}", nanos ^ count.rotate_right(16))
+}
+
+/// Checks whether a request id sent by a client is safe to log and echo
+///
+/// Only letters, digits, `.`, `_`, and `-` are allowed, so ids can't carry markup or break log lines
+fn is_valid_request_id(id: &str) -> bool {
+    (1..=MAX_REQUEST_ID_LEN).contains(&id.len())
+        && id
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"._-".contains(&byte))
 }
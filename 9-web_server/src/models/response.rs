@@ -1,11 +1,28 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    sync::Arc,
+};
+
+use super::Cookie;
+use crate::{log_error, utils::write_chunked};
+
+/// Body of a Response, either held in memory or streamed from a reader
+pub enum Body {
+    Bytes(Vec<u8>),
+    Shared(Arc<Vec<u8>>),
+    Stream(Box<dyn Read + Send>),
+}
 
 pub struct Response {
     protocol: String,
     status_code: Option<usize>,
     description: Option<String>,
     headers: HashMap<String, String>,
-    body: Option<String>,
+    cookies: Vec<Cookie>,
+    /// `Set-Cookie` values passed through unparsed, e.g. from a proxied upstream
+    raw_cookies: Vec<String>,
+    body: Option<Body>,
 }
 
 impl Default for Response {
@@ -15,41 +32,73 @@ impl Default for Response {
             status_code: None,
             description: None,
             headers: HashMap::new(),
+            cookies: vec![],
+            raw_cookies: vec![],
             body: None,
         }
     }
 }
 
 impl Response {
-    /// Consumes calling Response and returns its data as a `String` in HTTP response format
-    pub fn stringify(self) -> String {
-        // Format headers
-        let headers = self
-            .headers
-            .iter()
-            .map(|(k, v)| format!("{k}: {v}"))
-            .collect::<Vec<_>>()
-            .join("\r\n");
-
-        // Construct response string
-        if self.body.is_some() {
-            format!(
-                "{} {} {}\r\n{}\r\n\r\n{}",
-                self.protocol,
-                self.status_code.unwrap(),
-                self.description.unwrap(),
-                headers,
-                self.body.unwrap_or("".to_owned())
-            )
-        } else {
-            format!(
-                "{} {} {}\r\n{}\r\n",
-                self.protocol,
-                self.status_code.unwrap(),
-                self.description.unwrap(),
-                headers,
-            )
+    /// Consumes calling Response and writes it to `stream` in HTTP response format
+    ///
+    /// Streamed bodies without a `Content-Length` header are sent with chunked transfer encoding
+    pub fn send(self, stream: &mut impl Write) -> io::Result<()> {
+        // Refuse to write anything that could end the head early
+        let cookies = self.get_set_cookie_headers();
+        let fields = self.headers.iter().flat_map(|(k, v)| [k, v]);
+        if let Some(field) = fields
+            .chain(&cookies)
+            .chain(&self.description)
+            .find(|field| !is_valid_field(field))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("header field contains a line break: {field:?}"),
+            ));
+        }
+
+        let chunked = matches!(self.body, Some(Body::Stream(_)))
+            && !self
+                .headers
+                .keys()
+                .any(|k| k.eq_ignore_ascii_case("content-length"));
+
+        // Format status line and headers
+        let mut head = format!(
+            "{} {} {}\r\n",
+            self.protocol,
+            self.status_code.unwrap_or(500),
+            self.description
+                .as_deref()
+                .unwrap_or("Internal Server Error")
+        );
+        for (k, v) in &self.headers {
+            head.push_str(&format!("{k}: {v}\r\n"));
+        }
+        for cookie in &cookies {
+            head.push_str(&format!("Set-Cookie: {cookie}\r\n"));
         }
+        if chunked {
+            head.push_str("Transfer-Encoding: chunked\r\n");
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes())?;
+
+        // Send body
+        match self.body {
+            Some(Body::Bytes(bytes)) => stream.write_all(&bytes)?,
+            Some(Body::Shared(bytes)) => stream.write_all(&bytes)?,
+            Some(Body::Stream(mut reader)) if chunked => {
+                write_chunked(&mut reader, stream)?;
+            }
+            Some(Body::Stream(mut reader)) => {
+                io::copy(&mut reader, stream)?;
+            }
+            None => {}
+        }
+
+        stream.flush()
     }
 
     /// Sets the `status_code` and `description` fields
@@ -73,14 +122,42 @@ impl Response {
                 self.description = Some("No Content".to_owned());
                 Some(204)
             }
+            207 => {
+                self.description = Some("Multi-Status".to_owned());
+                Some(207)
+            }
+            301 => {
+                self.description = Some("Moved Permanently".to_owned());
+                Some(301)
+            }
+            302 => {
+                self.description = Some("Found".to_owned());
+                Some(302)
+            }
             303 => {
                 self.description = Some("See Other".to_owned());
                 Some(303)
             }
+            304 => {
+                self.description = Some("Not Modified".to_owned());
+                Some(304)
+            }
+            307 => {
+                self.description = Some("Temporary Redirect".to_owned());
+                Some(307)
+            }
+            308 => {
+                self.description = Some("Permanent Redirect".to_owned());
+                Some(308)
+            }
             400 => {
                 self.description = Some("Bad Request".to_owned());
                 Some(400)
             }
+            401 => {
+                self.description = Some("Unauthorized".to_owned());
+                Some(401)
+            }
             403 => {
                 self.description = Some("Forbidden".to_owned());
                 Some(403)
@@ -89,14 +166,65 @@ impl Response {
                 self.description = Some("Not Found".to_owned());
                 Some(404)
             }
+            405 => {
+                self.description = Some("Method Not Allowed".to_owned());
+                Some(405)
+            }
+            409 => {
+                self.description = Some("Conflict".to_owned());
+                Some(409)
+            }
+            412 => {
+                self.description = Some("Precondition Failed".to_owned());
+                Some(412)
+            }
+            415 => {
+                self.description = Some("Unsupported Media Type".to_owned());
+                Some(415)
+            }
+            429 => {
+                self.description = Some("Too Many Requests".to_owned());
+                Some(429)
+            }
             500 => {
                 self.description = Some("Internal Server Error".to_owned());
                 Some(500)
             }
+            502 => {
+                self.description = Some("Bad Gateway".to_owned());
+                Some(502)
+            }
+            503 => {
+                self.description = Some("Service Unavailable".to_owned());
+                Some(503)
+            }
+            504 => {
+                self.description = Some("Gateway Timeout".to_owned());
+                Some(504)
+            }
             _ => None,
         }
     }
 
+    /// Sets a status code with a custom description, e.g. one relayed from an upstream server
+    ///
+    /// The `code` is the HTTP status code and `description` is its reason phrase
+    pub fn set_custom_status(&mut self, code: usize, description: &str) {
+        self.status_code = Some(code);
+        self.description = Some(description.to_owned());
+    }
+
+    /// Returns the status code of the Response, if set
+    pub fn get_status(&self) -> Option<usize> {
+        self.status_code
+    }
+
+    /// Returns the reason phrase of the Response, if set
+    pub fn get_description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    /// Returns a reference to the headers of the Response
     pub fn get_headers(&self) -> &HashMap<String, String> {
         &self.headers
     }
@@ -105,17 +233,92 @@ impl Response {
     ///
     /// The `contents` is the String to be sent to the client
     pub fn set_body(&mut self, contents: Option<String>) {
-        self.body = contents;
+        self.body = contents.map(|c| Body::Bytes(c.into_bytes()));
+    }
+
+    /// Loads raw bytes to be sent to client into the `body` field
+    ///
+    /// The `contents` is the byte payload to be sent to the client
+    pub fn set_body_bytes(&mut self, contents: Vec<u8>) {
+        self.body = Some(Body::Bytes(contents));
+    }
+
+    /// Loads bytes shared with other responses, e.g. from the file cache, into the `body` field
+    ///
+    /// The `contents` is the byte payload to be sent to the client
+    pub fn set_body_shared(&mut self, contents: Arc<Vec<u8>>) {
+        self.body = Some(Body::Shared(contents));
+    }
+
+    /// Streams the body to the client from a reader when the Response is sent
+    ///
+    /// The `reader` is read to its end. Without a `Content-Length` header the body is sent chunked
+    pub fn set_body_stream(&mut self, reader: Box<dyn Read + Send>) {
+        self.body = Some(Body::Stream(reader));
+    }
+
+    /// Removes the body from the Response, e.g. to send it in the frames of another protocol
+    pub fn take_body(&mut self) -> Option<Body> {
+        self.body.take()
+    }
+
+    /// Returns the body of the Response if it is held in memory
+    ///
+    /// Streamed bodies are only read when the Response is sent, so they return `None`
+    pub fn get_body(&self) -> Option<&[u8]> {
+        match &self.body {
+            Some(Body::Bytes(bytes)) => Some(bytes),
+            Some(Body::Shared(bytes)) => Some(bytes),
+            Some(Body::Stream(_)) | None => None,
+        }
     }
 
     /// Adds a header to the calling Response
     ///
-    /// The `header` is a tuple containing the key-value pair to be added
+    /// The `header` is a tuple containing the key-value pair to be added. Headers whose name or value contains a line break are dropped, since they would let the value inject headers of its own
     pub fn add_header(&mut self, header: (String, String)) {
         let (key, value) = header;
+        if !is_valid_field(&key) || !is_valid_field(&value) {
+            log_error!("Dropping header with a line break: {key:?}");
+            return;
+        }
         self.headers.insert(key, value);
     }
 
+    /// Adds a cookie, sent in its own `Set-Cookie` header
+    ///
+    /// The `cookie` replaces any cookie added earlier with the same name and path
+    pub fn add_cookie(&mut self, cookie: Cookie) {
+        self.cookies
+            .retain(|c| c.name != cookie.name || c.path != cookie.path);
+        self.cookies.push(cookie);
+    }
+
+    /// Returns the cookies to be set by the Response
+    pub fn get_cookies(&self) -> &[Cookie] {
+        &self.cookies
+    }
+
+    /// Adds a `Set-Cookie` header value as-is, sent in a header of its own
+    ///
+    /// The `value` is kept apart from other cookies and headers, since cookie attributes like `Expires` contain commas that would be ambiguous if joined. Values with a line break are dropped
+    pub fn add_set_cookie_header(&mut self, value: String) {
+        if !is_valid_field(&value) {
+            log_error!("Dropping Set-Cookie header with a line break");
+            return;
+        }
+        self.raw_cookies.push(value);
+    }
+
+    /// Returns the value of every `Set-Cookie` header to be sent, from added cookies and passed-through values
+    pub fn get_set_cookie_headers(&self) -> Vec<String> {
+        self.cookies
+            .iter()
+            .map(Cookie::to_header)
+            .chain(self.raw_cookies.iter().cloned())
+            .collect()
+    }
+
     /// Sets the protocol field of the calling Response
     ///
     /// The `proto` is the protocol to be set. The default is `HTTP/1.1`
@@ -123,3 +326,8 @@ impl Response {
         self.protocol = proto;
     }
 }
+
+/// Checks whether text can be written into a header field without ending it early
+fn is_valid_field(text: &str) -> bool {
+    !text.contains(['\r', '\n', '\0'])
+}
@@ -0,0 +1,406 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use hmac::{Hmac, KeyInit, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use super::{Cookie, Request, Response, SameSite};
+use crate::log_error;
+
+/// Data of a session as kept by a SessionStore
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub data: BTreeMap<String, String>,
+    /// Unix time in seconds the session expires at
+    pub expires: u64,
+}
+
+impl SessionRecord {
+    /// Checks whether the session has expired
+    pub fn is_expired(&self) -> bool {
+        self.expires <= unix_now()
+    }
+}
+
+/// Storage of sessions by id
+pub trait SessionStore: Send + Sync + Debug {
+    /// Returns the session with an id, if stored
+    fn load(&self, id: &str) -> io::Result<Option<SessionRecord>>;
+
+    /// Stores a session, replacing one with the same id
+    fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()>;
+
+    /// Removes a session. Removing a missing session is not an error
+    fn remove(&self, id: &str) -> io::Result<()>;
+}
+
+/// Sessions kept in memory, lost when the server restarts
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, SessionRecord>>,
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> io::Result<Option<SessionRecord>> {
+        Ok(self.sessions.lock().unwrap().get(id).cloned())
+    }
+
+    fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, record| !record.is_expired());
+        sessions.insert(id.to_owned(), record.clone());
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        self.sessions.lock().unwrap().remove(id);
+        Ok(())
+    }
+}
+
+/// Sessions kept as one JSON file per session in a directory
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    /// Creates a store in a directory, which is created when the first session is saved
+    pub fn new(dir: &Path) -> FileStore {
+        FileStore {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    /// Returns the path of a session's file
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+}
+
+impl SessionStore for FileStore {
+    fn load(&self, id: &str) -> io::Result<Option<SessionRecord>> {
+        match fs::read(self.path(id)) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(id);
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_vec(record)?)?;
+        fs::rename(&temp_path, &path)
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(id)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A client's session, loaded by `SessionManager::load` and written back by `SessionManager::save`
+#[derive(Debug)]
+pub struct Session {
+    id: String,
+    data: BTreeMap<String, String>,
+    /// Whether the session was loaded from the store rather than started by this request
+    stored: bool,
+    /// Ids the session had before being rotated, removed from the store when it is saved
+    previous_ids: Vec<String>,
+    destroyed: bool,
+}
+
+impl Session {
+    /// Starts an empty session with a new id
+    fn start() -> Session {
+        Session {
+            id: new_session_id(),
+            data: BTreeMap::new(),
+            stored: false,
+            previous_ids: vec![],
+            destroyed: false,
+        }
+    }
+
+    /// Returns the session's id
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns a value stored in the session
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.data.get(key).map(String::as_str)
+    }
+
+    /// Stores a value in the session
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.data.insert(key.to_owned(), value.to_owned());
+    }
+
+    /// Removes a value from the session
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.data.remove(key)
+    }
+
+    /// Gives the session a new id, keeping its data
+    ///
+    /// Rotate on login and privilege changes so an id known before can't be used to hijack the session
+    pub fn rotate(&mut self) {
+        let old_id = std::mem::replace(&mut self.id, new_session_id());
+        self.previous_ids.push(old_id);
+    }
+
+    /// Ends the session, clearing its data and the client's cookie when saved
+    pub fn destroy(&mut self) {
+        self.data.clear();
+        self.destroyed = true;
+    }
+}
+
+/// Loads and saves sessions identified by a signed cookie
+#[derive(Debug)]
+pub struct SessionManager {
+    store: Arc<dyn SessionStore>,
+    key: Vec<u8>,
+    cookie_name: String,
+    ttl: Duration,
+    secure: bool,
+    same_site: SameSite,
+}
+
+impl SessionManager {
+    /// Creates a manager for a store
+    ///
+    /// The `store` keeps the sessions, `key` signs session ids, `cookie_name` names the session cookie, and sessions expire `ttl` after they were last saved
+    pub fn new(
+        store: Box<dyn SessionStore>,
+        key: &[u8],
+        cookie_name: &str,
+        ttl: Duration,
+    ) -> SessionManager {
+        SessionManager {
+            store: store.into(),
+            key: key.to_vec(),
+            cookie_name: cookie_name.to_owned(),
+            ttl,
+            secure: false,
+            same_site: SameSite::Lax,
+        }
+    }
+
+    /// Creates a manager keeping its sessions in this manager's store and signing them with its key
+    ///
+    /// The `cookie_name` and `ttl` are as for `new`. Sessions started with this manager stay valid with the new one
+    pub fn share_store(&self, cookie_name: &str, ttl: Duration) -> SessionManager {
+        SessionManager {
+            store: Arc::clone(&self.store),
+            key: self.key.clone(),
+            cookie_name: cookie_name.to_owned(),
+            ttl,
+            secure: false,
+            same_site: SameSite::Lax,
+        }
+    }
+
+    /// Sets the `Secure` and `SameSite` attributes of the session cookie
+    pub fn set_cookie_attributes(&mut self, secure: bool, same_site: SameSite) {
+        self.secure = secure;
+        self.same_site = same_site;
+    }
+
+    /// Loads the session of a request
+    ///
+    /// The `req` may carry the session cookie. Missing, forged, and expired sessions are replaced by a new empty one
+    pub fn load(&self, req: &Request) -> Session {
+        let Some(id) = req
+            .get_cookie(&self.cookie_name)
+            .and_then(|value| self.verify(&value))
+        else {
+            return Session::start();
+        };
+
+        match self.store.load(&id) {
+            Ok(Some(record)) if !record.is_expired() => Session {
+                id,
+                data: record.data,
+                stored: true,
+                previous_ids: vec![],
+                destroyed: false,
+            },
+            Ok(Some(_)) => {
+                let _ = self.store.remove(&id);
+                Session::start()
+            }
+            Ok(None) => Session::start(),
+            Err(err) => {
+                log_error!("Error loading session: {err}");
+                Session::start()
+            }
+        }
+    }
+
+    /// Saves a session and sets or clears its cookie on the response
+    ///
+    /// The `session` is written with a renewed expiry unless it is new and empty, and `res` is the Response to the request it was loaded for
+    pub fn save(&self, session: &Session, res: &mut Response) -> io::Result<()> {
+        for id in &session.previous_ids {
+            self.store.remove(id)?;
+        }
+
+        if session.destroyed {
+            self.store.remove(&session.id)?;
+            res.add_cookie(Cookie::removal(&self.cookie_name, "/"));
+            return Ok(());
+        }
+        if !session.stored && session.data.is_empty() {
+            return Ok(());
+        }
+
+        let record = SessionRecord {
+            data: session.data.clone(),
+            expires: unix_now() + self.ttl.as_secs(),
+        };
+        self.store.save(&session.id, &record)?;
+        res.add_cookie(Cookie {
+            path: Some("/".to_owned()),
+            max_age: Some(self.ttl.as_secs()),
+            secure: self.secure,
+            http_only: true,
+            same_site: Some(self.same_site),
+            ..Cookie::new(&self.cookie_name, &self.sign(&session.id))
+        });
+        Ok(())
+    }
+
+    /// Signs a session id for its cookie, as `<id>.<signature>`
+    fn sign(&self, id: &str) -> String {
+        format!(
+            "{id}.{}",
+            URL_SAFE_NO_PAD.encode(self.mac(id).finalize().into_bytes())
+        )
+    }
+
+    /// Checks a session cookie's signature
+    ///
+    /// Returns the session id if the signature is valid
+    fn verify(&self, value: &str) -> Option<String> {
+        let (id, signature) = value.split_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        self.mac(id).verify_slice(&signature).ok()?;
+        Some(id.to_owned())
+    }
+
+    /// Returns the HMAC-SHA256 of a session id
+    fn mac(&self, id: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(id.as_bytes());
+        mac
+    }
+}
+
+/// Generates a random session id
+///
+/// Returns 32 hex digits from the operating system's random number generator
+fn new_session_id() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::fill(&mut bytes).expect("operating system random number generator failed");
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Returns the current Unix time in seconds
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    /// Helper function to build a request carrying the cookie set by a response
+    fn follow_cookie(res: &Response) -> Request {
+        let mut req = Request::default();
+        let cookie = &res.get_cookies()[0];
+        req.append_header(format!("Cookie: {}={}", cookie.name, cookie.value));
+        req
+    }
+
+    #[test]
+    fn test_sessions_round_trip_and_rotate() {
+        let temp_dir = tempdir().unwrap();
+        let manager = SessionManager::new(
+            Box::new(FileStore::new(temp_dir.path())),
+            b"secret",
+            "sid",
+            Duration::from_secs(60),
+        );
+
+        // Empty sessions aren't stored
+        let mut res = Response::default();
+        let mut session = manager.load(&Request::default());
+        manager.save(&session, &mut res).unwrap();
+        assert!(res.get_cookies().is_empty());
+
+        session.insert("user", "alice");
+        manager.save(&session, &mut res).unwrap();
+        let req = follow_cookie(&res);
+        let mut session = manager.load(&req);
+        assert_eq!(session.get("user"), Some("alice"));
+
+        // Rotation moves the data to a new id
+        let old_id = session.id().to_owned();
+        session.rotate();
+        let mut res = Response::default();
+        manager.save(&session, &mut res).unwrap();
+        assert_eq!(manager.load(&req).get("user"), None);
+        assert!(!temp_dir.path().join(format!("{old_id}.json")).exists());
+        let req = follow_cookie(&res);
+        let mut session = manager.load(&req);
+        assert_eq!(session.get("user"), Some("alice"));
+
+        session.destroy();
+        let mut res = Response::default();
+        manager.save(&session, &mut res).unwrap();
+        assert_eq!(res.get_cookies()[0].max_age, Some(0));
+        assert_eq!(manager.load(&req).get("user"), None);
+    }
+
+    #[test]
+    fn test_forged_and_expired_sessions_are_rejected() {
+        let manager = SessionManager::new(
+            Box::new(MemoryStore::default()),
+            b"secret",
+            "sid",
+            Duration::ZERO,
+        );
+        let mut session = manager.load(&Request::default());
+        session.insert("user", "alice");
+        let mut res = Response::default();
+        manager.save(&session, &mut res).unwrap();
+
+        // Sessions with a zero lifetime have already expired
+        assert_eq!(manager.load(&follow_cookie(&res)).get("user"), None);
+
+        let forged = format!("{}.{}", session.id(), "AAAA");
+        let mut req = Request::default();
+        req.append_header(format!("Cookie: sid={forged}"));
+        assert!(manager.verify(&forged).is_none());
+        assert_ne!(manager.load(&req).id(), session.id());
+    }
+}
@@ -0,0 +1,97 @@
+use std::sync::{
+    Arc, RwLock,
+    atomic::{AtomicBool, Ordering},
+};
+
+use super::{Connections, FileCache, LogLevel, Metrics, PathLocks, PoolLoad, ServerConfig};
+
+/// Runtime state shared by every worker
+pub struct ServerState {
+    config: RwLock<Arc<ServerConfig>>,
+    pub cache: FileCache,
+    pub metrics: Metrics,
+    pub connections: Connections,
+    /// Locks held while files are written, so concurrent writes to one file don't interleave
+    pub locks: PathLocks,
+    pub pool: Arc<PoolLoad>,
+    draining: AtomicBool,
+}
+
+impl ServerState {
+    /// Creates the shared state for a configuration
+    ///
+    /// The `config` is the loaded server configuration and `pool` is the load of the ThreadPool handling connections
+    pub fn new(config: ServerConfig, pool: Arc<PoolLoad>) -> ServerState {
+        let cache = if config.cache.enabled {
+            FileCache::new(config.cache.max_bytes, config.cache.max_file_bytes)
+        } else {
+            FileCache::new(0, 0)
+        };
+        LogLevel::set_current(config.log_level);
+
+        ServerState {
+            config: RwLock::new(Arc::new(config)),
+            cache,
+            metrics: Metrics::default(),
+            connections: Connections::default(),
+            locks: PathLocks::default(),
+            pool,
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the current configuration
+    ///
+    /// Requests keep the configuration they started with even if it is reloaded meanwhile
+    pub fn config(&self) -> Arc<ServerConfig> {
+        Arc::clone(&self.config.read().unwrap())
+    }
+
+    /// Reloads the configuration from the file it was loaded from
+    ///
+    /// Sites, handlers, access rules, and the log level take effect for new requests. Sessions and rate-limit buckets are kept. Listener addresses, threads, and cache limits need a restart
+    ///
+    /// Returns the names of changed settings that need a restart, or why the new configuration is invalid
+    pub fn reload_config(&self) -> Result<Vec<&'static str>, String> {
+        let current = self.config();
+        let path = current
+            .path
+            .as_deref()
+            .ok_or("configuration wasn't loaded from a file")?;
+        let mut config = ServerConfig::load(path)?;
+        config.carry_over_state(&current);
+
+        let mut restart_required = vec![];
+        if config.address != current.address {
+            restart_required.push("address");
+        }
+        if config.threads != current.threads {
+            restart_required.push("threads");
+        }
+        if config.cache != current.cache {
+            restart_required.push("cache");
+        }
+        if config.metrics.address != current.metrics.address {
+            restart_required.push("metrics.address");
+        }
+        if config.admin.as_ref().map(|admin| &admin.address)
+            != current.admin.as_ref().map(|admin| &admin.address)
+        {
+            restart_required.push("admin.address");
+        }
+
+        LogLevel::set_current(config.log_level);
+        *self.config.write().unwrap() = Arc::new(config);
+        Ok(restart_required)
+    }
+
+    /// Stops accepting new connections so the server can exit once open ones finish
+    pub fn start_drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    /// Checks whether the server is draining
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+}
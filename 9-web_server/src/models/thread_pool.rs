@@ -1,13 +1,58 @@
 use std::{
-    sync::{Arc, Mutex, mpsc},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
     thread,
 };
 
+use super::LogLevel;
+use crate::log_error;
+
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
 pub struct ThreadPool {
     workers: Vec<Worker>,
     sender: Option<mpsc::Sender<Job>>,
+    load: Arc<PoolLoad>,
+}
+
+/// Live counters of a ThreadPool's work, shared with whoever reports them
+#[derive(Debug, Default)]
+pub struct PoolLoad {
+    size: usize,
+    queued: AtomicUsize,
+    busy: AtomicUsize,
+    stream_handlers: AtomicUsize,
+}
+
+impl PoolLoad {
+    /// Returns the number of worker threads
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the number of jobs waiting for a free worker
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of workers currently executing a job
+    pub fn busy(&self) -> usize {
+        self.busy.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of HTTP/2 stream handlers running beside the workers
+    pub fn stream_handlers(&self) -> usize {
+        self.stream_handlers.load(Ordering::Relaxed)
+    }
+
+    /// Counts an HTTP/2 stream handler as running until the returned guard is dropped
+    pub fn enter_stream_handler(&self) -> LoadGuard<'_> {
+        LoadGuard::enter(&self.stream_handlers)
+    }
 }
 
 impl ThreadPool {
@@ -24,19 +69,29 @@ impl ThreadPool {
 
         let (sender, receiver) = mpsc::channel();
         let receiver = Arc::new(Mutex::new(receiver));
+        let load = Arc::new(PoolLoad {
+            size,
+            ..PoolLoad::default()
+        });
 
         let mut workers = Vec::with_capacity(size);
         for id in 0..size {
             // Create thread and store in threads
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            workers.push(Worker::new(id, Arc::clone(&receiver), Arc::clone(&load)));
         }
 
         ThreadPool {
             workers,
             sender: Some(sender),
+            load,
         }
     }
 
+    /// Returns the live counters of the pool's work
+    pub fn load(&self) -> Arc<PoolLoad> {
+        Arc::clone(&self.load)
+    }
+
     /// Sends the enclosed closure to an available thread. If no thread is available, closure is queued.
     ///
     /// Takes a closure `f`
@@ -46,6 +101,7 @@ impl ThreadPool {
     {
         let job = Box::new(f);
 
+        self.load.queued.fetch_add(1, Ordering::Relaxed);
         self.sender.as_ref().unwrap().send(job).unwrap();
     }
 }
@@ -59,7 +115,9 @@ impl Drop for ThreadPool {
         for worker in self.workers.drain(..) {
             println!("Shutting down worker {}", worker.id);
 
-            worker.thread.join().unwrap();
+            if worker.thread.join().is_err() {
+                log_error!("Worker {} exited with a panic", worker.id);
+            }
         }
     }
 }
@@ -70,7 +128,7 @@ struct Worker {
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
+    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>, load: Arc<PoolLoad>) -> Worker {
         let thread = thread::spawn(move || {
             loop {
                 // Retrieve closure from channel
@@ -78,10 +136,16 @@ impl Worker {
 
                 match message {
                     Ok(job) => {
-                        println!("Worker {id} got a job. Executing...");
+                        if LogLevel::Debug.enabled() {
+                            println!("Worker {id} got a job. Executing...");
+                        }
+                        load.queued.fetch_sub(1, Ordering::Relaxed);
+                        let _busy = LoadGuard::enter(&load.busy);
 
-                        // Execute closure
-                        job();
+                        // Execute closure, keeping the worker alive if it panics
+                        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                            log_error!("Worker {id} recovered from a panicking job");
+                        }
                     }
                     Err(_) => {
                         eprintln!("Worker {id} disconnected. Shutting down...");
@@ -93,3 +157,40 @@ impl Worker {
         Worker { id, thread }
     }
 }
+
+/// Counts work in one of a PoolLoad's counters until dropped, even if the work unwinds
+pub struct LoadGuard<'a>(&'a AtomicUsize);
+
+impl<'a> LoadGuard<'a> {
+    fn enter(counter: &'a AtomicUsize) -> LoadGuard<'a> {
+        counter.fetch_add(1, Ordering::Relaxed);
+        LoadGuard(counter)
+    }
+}
+
+impl Drop for LoadGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_workers_survive_panicking_jobs() {
+        let pool = ThreadPool::new(1);
+        let load = pool.load();
+        let (sender, receiver) = mpsc::channel();
+
+        pool.execute(|| panic!("handler bug"));
+        pool.execute(move || sender.send(()).unwrap());
+
+        // The only worker is still there to run the next job
+        receiver.recv().unwrap();
+        drop(pool);
+        assert_eq!(load.busy(), 0);
+        assert_eq!(load.queued(), 0);
+    }
+}
@@ -0,0 +1,174 @@
+use std::fmt::Write;
+
+/// Longest `tracestate` header passed on, as suggested by W3C Trace Context
+const MAX_TRACESTATE_LEN: usize = 512;
+
+/// W3C Trace Context of a request, tying it to the traces of the services it passed through
+///
+/// Each request gets its own span id. The trace id and flags come from an incoming `traceparent` header, or are
+/// generated for requests starting a new trace
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceContext {
+    trace_id: String,
+    parent_id: Option<String>,
+    span_id: String,
+    flags: u8,
+    state: Option<String>,
+}
+
+impl Default for TraceContext {
+    fn default() -> Self {
+        TraceContext {
+            trace_id: random_hex::<16>(),
+            parent_id: None,
+            span_id: random_hex::<8>(),
+            flags: 1,
+            state: None,
+        }
+    }
+}
+
+impl TraceContext {
+    /// Continues the trace described by a request's trace headers, or starts a new one
+    ///
+    /// The `traceparent` and `tracestate` are the header values, if sent. A malformed `traceparent` starts a new trace and drops `tracestate` along with it
+    pub fn from_headers(traceparent: Option<&str>, tracestate: Option<&str>) -> TraceContext {
+        let mut trace = TraceContext::default();
+        let Some((trace_id, parent_id, flags)) = traceparent.and_then(parse_traceparent) else {
+            return trace;
+        };
+
+        trace.trace_id = trace_id;
+        trace.parent_id = Some(parent_id);
+        trace.flags = flags;
+        trace.state = tracestate
+            .map(str::trim)
+            .filter(|state| !state.is_empty() && state.len() <= MAX_TRACESTATE_LEN)
+            .map(str::to_owned);
+        trace
+    }
+
+    /// Returns the 32 hex digit id shared by every span of the trace
+    pub fn get_trace_id(&self) -> &str {
+        &self.trace_id
+    }
+
+    /// Returns the span id of the caller, if the request continued a trace
+    pub fn get_parent_id(&self) -> Option<&str> {
+        self.parent_id.as_deref()
+    }
+
+    /// Returns the 16 hex digit id of this request's span
+    pub fn get_span_id(&self) -> &str {
+        &self.span_id
+    }
+
+    /// Returns the vendor-specific `tracestate` received with the trace, if any
+    pub fn get_state(&self) -> Option<&str> {
+        self.state.as_deref()
+    }
+
+    /// Formats the `traceparent` header naming this request's span as the parent
+    ///
+    /// Returns the value sent in responses and to upstream servers and scripts
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{:02x}", self.trace_id, self.span_id, self.flags)
+    }
+
+    /// Returns the headers propagating the trace, `traceparent` and `tracestate` if any
+    pub fn headers(&self) -> Vec<(String, String)> {
+        let mut headers = vec![("traceparent".to_owned(), self.traceparent())];
+        if let Some(state) = &self.state {
+            headers.push(("tracestate".to_owned(), state.clone()));
+        }
+        headers
+    }
+}
+
+/// Parses a `traceparent` header
+///
+/// Versions other than `00` are accepted as long as they start with its fields, as the specification asks
+///
+/// Returns the trace id, parent span id, and flags, or None if the header is malformed or an id is all zeros
+fn parse_traceparent(value: &str) -> Option<(String, String, u8)> {
+    let mut fields = value.trim().split('-');
+    let version = fields.next()?;
+    let trace_id = fields.next()?;
+    let parent_id = fields.next()?;
+    let flags = fields.next()?;
+
+    let is_hex = |field: &str, len: usize| {
+        field.len() == len
+            && field
+                .bytes()
+                .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+    };
+    let is_zero = |field: &str| field.bytes().all(|byte| byte == b'0');
+    if !is_hex(version, 2) || version == "ff" || (version == "00" && fields.next().is_some()) {
+        return None;
+    }
+    if !is_hex(trace_id, 32) || !is_hex(parent_id, 16) || !is_hex(flags, 2) {
+        return None;
+    }
+    if is_zero(trace_id) || is_zero(parent_id) {
+        return None;
+    }
+
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some((trace_id.to_owned(), parent_id.to_owned(), flags))
+}
+
+/// Generates a random id of `N` bytes as lowercase hex digits
+fn random_hex<const N: usize>() -> String {
+    let mut bytes = [0u8; N];
+    getrandom::fill(&mut bytes).expect("operating system random number generator failed");
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_continues_or_starts_traces() {
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let trace = TraceContext::from_headers(Some(traceparent), Some("vendor=abc"));
+        assert_eq!(trace.get_trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(trace.get_parent_id(), Some("00f067aa0ba902b7"));
+        assert_eq!(trace.get_state(), Some("vendor=abc"));
+        assert_ne!(trace.get_span_id(), "00f067aa0ba902b7");
+        assert_eq!(
+            trace.traceparent(),
+            format!(
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-{}-01",
+                trace.get_span_id()
+            )
+        );
+
+        // Malformed or all-zero headers start a new trace without the state
+        for traceparent in [
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "garbage",
+        ] {
+            let trace = TraceContext::from_headers(Some(traceparent), Some("vendor=abc"));
+            assert_eq!(trace.get_trace_id().len(), 32);
+            assert_ne!(trace.get_trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+            assert_eq!(trace.get_parent_id(), None);
+            assert_eq!(trace.get_state(), None);
+        }
+
+        // Later versions may append fields
+        let trace = TraceContext::from_headers(
+            Some("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-more"),
+            None,
+        );
+        assert_eq!(trace.get_parent_id(), Some("00f067aa0ba902b7"));
+        assert!(trace.traceparent().ends_with("-00"));
+    }
+}
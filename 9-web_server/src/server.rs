@@ -0,0 +1,371 @@
+use std::{
+    io::{self, BufRead, BufReader, Read},
+    net::SocketAddr,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    http2::{
+        is_preface_start, read_preface_end, serve_http2, switching_protocols, upgrade_settings,
+    },
+    log_error,
+    models::{
+        ListenAddr, Listener, ListenerRole, LogLevel, OpenConnection, Request, RequestLogScope,
+        Response, ServerConfig, ServerState, SiteConfig, Stream, ThreadPool, log_suffix,
+    },
+    utils::{
+        admin, api, apply_cors, apply_rewrites, cgi, check_access, check_rate_limit,
+        handle_bad_request, healthz, is_login_request, is_preflight, login, metrics_response,
+        parse_request_head, preflight, proxy, read_request_body, readyz, route,
+    },
+};
+
+/// Binds the listeners of a configuration
+///
+/// The `config` gives the main `address`, the additional `listen` sockets, and the dedicated admin and metrics addresses
+///
+/// Returns each Listener with its role, or a message naming the address that couldn't be bound
+pub fn bind_listeners(config: &ServerConfig) -> Result<Vec<(Listener, ListenerRole)>, String> {
+    let mut addresses = vec![(config.address.as_str(), ListenerRole::Main)];
+    addresses.extend(
+        config
+            .listen
+            .iter()
+            .map(|listener| (listener.address.as_str(), listener.role)),
+    );
+    if let Some(address) = config
+        .metrics
+        .address
+        .as_ref()
+        .filter(|_| config.metrics.enabled)
+    {
+        addresses.push((address, ListenerRole::Metrics));
+    }
+    if let Some(admin) = &config.admin {
+        addresses.push((&admin.address, ListenerRole::Admin));
+    }
+
+    addresses
+        .into_iter()
+        .map(|(address, role)| {
+            Listener::bind(address)
+                .map(|listener| (listener, role))
+                .map_err(|err| format!("Error binding {address}: {err}"))
+        })
+        .collect()
+}
+
+/// Accepts connections on a main listener until a drain starts
+///
+/// The `listener` is a main listener, `state` is the shared server state, and `pool` runs `handle_connection` for each connection
+pub fn serve(listener: Listener, state: &Arc<ServerState>, pool: &ThreadPool) {
+    for stream in listener.incoming() {
+        // Stop accepting once a drain was requested
+        if state.is_draining() {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log_error!("Error accepting connection: {err}");
+                continue;
+            }
+        };
+        let state = Arc::clone(state);
+
+        pool.execute(move || {
+            handle_connection(stream, &state);
+        });
+    }
+}
+
+/// Handles each request from client
+///
+/// The `stream` is the connection containing the HTTP request and `state` is the shared server state. Clients sending the HTTP/2 preface, or asking to upgrade to HTTP/2, are served over HTTP/2 instead
+pub fn handle_connection(stream: Stream, state: &ServerState) {
+    let connection = state.connections.open(stream.peer_addr());
+
+    // Get request line and headers from stream
+    let mut buf_reader = BufReader::new(state.metrics.count(&stream));
+    let req = parse_request_head(&mut buf_reader);
+
+    if state.config().http2 {
+        // Clients with prior knowledge of HTTP/2 start with its preface, which parses as a request head
+        if is_preface_start(&req) {
+            if read_preface_end(&mut buf_reader) {
+                serve_http2_connection(&mut buf_reader, &stream, None, &connection, state);
+            }
+            return;
+        }
+
+        // Others ask to upgrade an HTTP/1.1 request, which is then answered on the first stream
+        if let Some(settings) = upgrade_settings(&req) {
+            if let Err(err) = switching_protocols().send(&mut state.metrics.count(&stream)) {
+                log_error!("Error switching to HTTP/2: {err}");
+                return;
+            }
+            let upgrade = Some((req, settings));
+            serve_http2_connection(&mut buf_reader, &stream, upgrade, &connection, state);
+            return;
+        }
+    }
+
+    serve_request(
+        req,
+        &mut buf_reader,
+        &stream,
+        &connection,
+        state,
+        |mut res| {
+            // Connections carry a single request, so tell the client not to reuse them
+            res.add_header(("Connection".to_owned(), "close".to_owned()));
+            res.send(&mut state.metrics.count(&stream))
+        },
+    );
+}
+
+/// Serves the streams of an HTTP/2 connection like separate HTTP/1.1 requests
+///
+/// The `reader` holds the connection's unread frames, `upgrade` is the upgraded HTTP/1.1 request and its settings if any, `connection` lists the connection in the admin API, and `state` is the shared server state
+fn serve_http2_connection(
+    reader: &mut impl Read,
+    stream: &Stream,
+    upgrade: Option<(Request, Vec<u8>)>,
+    connection: &OpenConnection,
+    state: &ServerState,
+) {
+    // Frames of different streams are small and interleaved, so send them without waiting to fill packets
+    let _ = stream.set_nodelay(true);
+    let writer = state.metrics.count(stream);
+    let result = serve_http2(reader, writer, upgrade, |req, body, responder| {
+        // Streams are answered beside the connection's worker, so count them separately
+        let _handler = state.pool.enter_stream_handler();
+        serve_request(
+            req,
+            &mut body.as_slice(),
+            stream,
+            connection,
+            state,
+            |res| responder.send(res),
+        );
+    });
+    if let Err(err) = result {
+        log_error!("HTTP/2 connection error: {err}");
+    }
+}
+
+/// Answers a request and records it in the metrics and the access log
+///
+/// The `req` is the parsed request head, `body` holds its unread body, `stream` is the connection it arrived on, `connection` lists it in the admin API, and `send` writes the response in the connection's protocol
+fn serve_request(
+    mut req: Request,
+    body: &mut impl BufRead,
+    stream: &Stream,
+    connection: &OpenConnection,
+    state: &ServerState,
+    send: impl FnOnce(Response) -> io::Result<()>,
+) {
+    let client_addr = match stream {
+        // Unix sockets can only be reached through the front proxy, which names the client
+        Stream::Unix(_) if state.config().unix_forwarded_for => {
+            req.take_forwarded_for().map(|ip| SocketAddr::new(ip, 0))
+        }
+        _ => stream.peer_addr(),
+    };
+    req.set_client_addr(client_addr);
+    req.set_server_addr(stream.local_addr());
+    let started = Instant::now();
+    let method = req.get_method().as_str();
+    let target = req.get_target().to_owned();
+    connection.set_request(format!("{method} {target}"));
+
+    // Name the request in log lines and echo its ids, since the Request itself is consumed by dispatch
+    let _log_scope = RequestLogScope::enter(&req);
+    let request_id = req.get_request_id().to_owned();
+    let trace = req.get_trace().clone();
+
+    let mut res = respond(req, body, state);
+    res.add_header(("X-Request-Id".to_owned(), request_id));
+    for header in trace.headers() {
+        res.add_header(header);
+    }
+
+    // Send response
+    let status = res.get_status().unwrap_or(500);
+    send(res).unwrap_or_else(|err| log_error!("Error sending response: {err}"));
+    state
+        .metrics
+        .record_request(method, status, started.elapsed());
+
+    if LogLevel::Info.enabled() {
+        let client = client_addr.map_or("-".to_owned(), |addr| addr.ip().to_string());
+        println!(
+            "{client} \"{method} {target}\" {status} {}ms{}",
+            started.elapsed().as_millis(),
+            log_suffix()
+        );
+    }
+}
+
+/// Constructs the response to a request from the virtual host it was addressed to
+///
+/// The `req` is the parsed Request, `body` holds its unread body, and `state` is the shared server state
+fn respond(mut req: Request, body: &mut impl BufRead, state: &ServerState) -> Response {
+    // Requests keep the configuration they started with across reloads
+    let config = state.config();
+
+    // HTTP/1.1 requests must name the host they are addressed to
+    let host = req.get_headers().get("host").cloned();
+    if host.is_none() && req.get_protocol() == "HTTP/1.1" {
+        handle_bad_request("HTTP/1.1 requests must include a Host header")
+    } else if !req.has_valid_path() {
+        // Paths are only matched against rules once they can't climb out of the document root
+        handle_bad_request("Request target must not leave the document root")
+    } else if config.metrics_on_main() && req.get_path() == config.metrics.path {
+        metrics_response(state)
+    } else if config.health_checks && req.get_path() == "/healthz" {
+        healthz()
+    } else if config.health_checks && req.get_path() == "/readyz" {
+        readyz(state)
+    } else {
+        // Resolve the virtual host and its document root
+        let site = config.find_site(host.as_deref());
+        let redirect = apply_rewrites(&mut req, site);
+        req.set_document_root(&site.root, &site.index);
+
+        if let Some(redirect) = redirect {
+            // Redirect rules answer before anything else looks at the path
+            redirect
+        } else if is_preflight(&req, site) {
+            // Answer CORS preflights before access control, since browsers send them without credentials
+            preflight(&req, site)
+        } else {
+            let origin = req.get_headers().get("origin").cloned();
+
+            // Reject requests lacking credentials or over their rate limit before doing any work
+            let (mut res, rate_headers) = match check_access(&mut req, site) {
+                Some(denied) => (denied, vec![]),
+                None => match check_rate_limit(&req, site) {
+                    Err(limited) => (*limited, vec![]),
+                    Ok(headers) => (dispatch(req, body, site, state), headers),
+                },
+            };
+            for header in rate_headers {
+                res.add_header(header);
+            }
+            apply_cors(origin.as_deref(), site, &mut res);
+            res
+        }
+    }
+}
+
+/// Longest a client of the admin or metrics listener may stay silent or stop reading
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Answers Prometheus scrapes on the dedicated metrics listener
+///
+/// The `listener` is bound to a metrics address and `state` is the shared server state. Each connection is answered on its own thread, so an idle client can't hold up other scrapes
+pub fn serve_metrics(listener: Listener, state: &ServerState) {
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            scope.spawn(move || answer_scrape(stream, state));
+        }
+    });
+}
+
+/// Answers a single connection to the metrics listener
+fn answer_scrape(stream: Stream, state: &ServerState) {
+    if let Err(err) = stream.set_timeouts(Some(CONTROL_TIMEOUT)) {
+        log_error!("Error setting metrics connection timeouts: {err}");
+        return;
+    }
+
+    let req = parse_request_head(&mut BufReader::new(&stream));
+    let res = if req.get_path() == state.config().metrics.path {
+        metrics_response(state)
+    } else {
+        let mut res = Response::default();
+        res.set_status(404);
+        res.add_header(("Content-Length".to_owned(), "0".to_owned()));
+        res
+    };
+    res.send(&mut &stream)
+        .unwrap_or_else(|err| log_error!("Error sending metrics: {err}"));
+}
+
+/// Answers requests to the admin API on its dedicated listener
+///
+/// The `listener` is bound to an admin address, `state` is the shared server state, and `wake_addrs` are the main listeners' addresses, connected to once a drain starts so their accept loops notice. Each connection is answered on its own thread, so an idle client can't hold up a reload or shutdown
+pub fn serve_admin(listener: Listener, state: &ServerState, wake_addrs: &[ListenAddr]) {
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            scope.spawn(move || answer_admin(stream, state, wake_addrs));
+        }
+    });
+}
+
+/// Answers a single connection to the admin listener
+fn answer_admin(stream: Stream, state: &ServerState, wake_addrs: &[ListenAddr]) {
+    if let Err(err) = stream.set_timeouts(Some(CONTROL_TIMEOUT)) {
+        log_error!("Error setting admin connection timeouts: {err}");
+        return;
+    }
+
+    let mut buf_reader = BufReader::new(&stream);
+    let mut req = parse_request_head(&mut buf_reader);
+    read_request_body(&mut buf_reader, &mut req);
+    let res = match &state.config().admin {
+        Some(admin_config) => admin(&req, admin_config, state),
+        None => handle_bad_request("The admin API was removed from the configuration"),
+    };
+    res.send(&mut &stream)
+        .unwrap_or_else(|err| log_error!("Error sending admin response: {err}"));
+
+    if state.is_draining() {
+        for addr in wake_addrs {
+            let _ = addr.connect();
+        }
+    }
+}
+
+/// Constructs the response to a request that passed access control and rate limiting
+///
+/// The `req` is the parsed Request, `buf_reader` holds its unread body, `site` is the virtual host it was addressed to, and `state` is the shared server state
+fn dispatch(
+    mut req: Request,
+    buf_reader: &mut impl BufRead,
+    site: &SiteConfig,
+    state: &ServerState,
+) -> Response {
+    if let Some(sessions) = site
+        .sessions
+        .as_ref()
+        .filter(|sessions| is_login_request(&req, sessions))
+    {
+        // Log in or out with a form post
+        read_request_body(buf_reader, &mut req);
+        login(&req, sessions, site)
+    } else if let Some(proxy_route) = site.find_proxy(req.get_path()) {
+        // Forward to upstream, streaming the body straight from the client
+        proxy(&req, buf_reader, proxy_route, site)
+    } else if let Some(api_config) = site.find_api(req.get_path()) {
+        // Serve JSON documents from the document store
+        read_request_body(buf_reader, &mut req);
+        api(&req, api_config, site)
+    } else if let Some(cgi_config) = site.find_cgi(req.get_path()) {
+        // Run CGI script with the request body on its stdin
+        read_request_body(buf_reader, &mut req);
+        cgi(&req, cgi_config, site)
+    } else {
+        read_request_body(buf_reader, &mut req);
+        route(req, site, state)
+    }
+}
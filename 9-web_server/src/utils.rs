@@ -1,5 +1,35 @@
+mod access;
+mod admin;
+mod api;
+mod cgi;
+mod chunked;
+mod cors;
+mod error_pages;
+mod health;
+mod login;
+mod metrics;
 mod parsing;
+mod proxy;
+mod rate_limit;
+mod rewrite;
 mod routing;
+mod templates;
+mod webdav;
 
+pub use access::*;
+pub use admin::*;
+pub use api::*;
+pub use cgi::*;
+pub use chunked::*;
+pub use cors::*;
+pub use error_pages::*;
+pub use health::*;
+pub use login::*;
+pub use metrics::*;
 pub use parsing::*;
+pub use proxy::*;
+pub use rate_limit::*;
+pub use rewrite::*;
 pub use routing::*;
+pub use templates::*;
+pub use webdav::*;
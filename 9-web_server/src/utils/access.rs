@@ -0,0 +1,453 @@
+use std::{fs, io, path::Path, ptr};
+
+use argon2::{Argon2, PasswordVerifier};
+use base64::{Engine, engine::general_purpose::STANDARD};
+
+use crate::{
+    log_error,
+    models::{AccessPolicy, AccessRule, HttpMethod, Request, Response, SiteConfig},
+    utils::{error_response, implied_writes, percent_encode, redirect},
+};
+
+/// Outcome of checking the credentials sent with a request
+enum Credentials {
+    /// No usable `Authorization` header was sent
+    Missing,
+    /// Credentials were sent but did not verify
+    Invalid { bearer: bool },
+    /// Credentials verified as this identity
+    Valid(String),
+}
+
+/// Applies a site's access rules to a request
+///
+/// The `req` is the Request to check and `site` is the virtual host it was addressed to. On success the authenticated identity, if any, is stored on the Request
+///
+/// Returns None if the request may proceed, or a `401`/`403` Response denying it. `GET` requests without a session are redirected to the login page instead
+pub fn check_access(req: &mut Request, site: &SiteConfig) -> Option<Response> {
+    // WebDAV requests must also pass the rules guarding the writes they make
+    let mut checks = vec![(req.get_path().to_owned(), req.get_method().as_str())];
+    checks.extend(implied_writes(req, site));
+
+    let mut rules: Vec<&AccessRule> = vec![];
+    for (path, method) in &checks {
+        if let Some(rule) = site.find_access_rule(path, method)
+            && !rules.iter().any(|seen| ptr::eq(*seen, rule))
+        {
+            rules.push(rule);
+        }
+    }
+    rules
+        .into_iter()
+        .find_map(|rule| apply_access_rule(req, site, rule))
+}
+
+/// Applies one access rule to a request
+///
+/// Returns None if the rule lets the request proceed, or the Response denying it
+fn apply_access_rule(req: &mut Request, site: &SiteConfig, rule: &AccessRule) -> Option<Response> {
+    match rule.policy {
+        AccessPolicy::Public => None,
+        AccessPolicy::Deny => Some(error_response(403, req, site)),
+        AccessPolicy::Authenticate => {
+            let authentication = authenticate(
+                req,
+                &rule.realm,
+                rule.htpasswd.as_deref(),
+                rule.tokens.as_deref(),
+            );
+
+            match authentication {
+                Ok(Authentication::Valid(identity)) => {
+                    // Valid credentials that aren't on the allow list are forbidden
+                    if !rule.users.is_empty() && !rule.users.contains(&identity) {
+                        return Some(error_response(403, req, site));
+                    }
+                    req.set_identity(Some(identity));
+                    None
+                }
+                Ok(Authentication::Challenge(challenge)) => {
+                    let mut res = error_response(401, req, site);
+                    res.add_header(("WWW-Authenticate".to_owned(), challenge));
+                    Some(res)
+                }
+                Err(err) => {
+                    log_error!("Error reading credentials for {:?}: {err}", rule.prefix);
+                    Some(error_response(500, req, site))
+                }
+            }
+        }
+        AccessPolicy::Session => {
+            let Some(sessions) = &site.sessions else {
+                return Some(error_response(403, req, site));
+            };
+
+            let session = sessions.manager().load(req);
+            match session.get("user") {
+                Some(user) if rule.users.is_empty() || rule.users.iter().any(|u| u == user) => {
+                    req.set_identity(Some(user.to_owned()));
+                    None
+                }
+                Some(_) => Some(error_response(403, req, site)),
+                // Send browsers to the login form, which returns them here afterwards
+                None if matches!(req.get_method(), HttpMethod::Get) => Some(redirect(
+                    303,
+                    &format!(
+                        "{}?next={}",
+                        sessions.login_path,
+                        percent_encode(req.get_target())
+                    ),
+                )),
+                None => Some(error_response(403, req, site)),
+            }
+        }
+    }
+}
+
+/// Outcome of authenticating a request
+pub enum Authentication {
+    /// Credentials verified as this identity
+    Valid(String),
+    /// Credentials are missing or invalid. Holds the `WWW-Authenticate` value to send with a `401`
+    Challenge(String),
+}
+
+/// Authenticates a request against credential files
+///
+/// The `req` is the Request to check, `realm` is sent in challenges, `htpasswd` enables Basic auth, and `tokens` enables Bearer auth
+///
+/// Returns the outcome, or an error if a credential file can't be read
+pub fn authenticate(
+    req: &Request,
+    realm: &str,
+    htpasswd: Option<&Path>,
+    tokens: Option<&Path>,
+) -> io::Result<Authentication> {
+    let invalid_token = match verify_credentials(req, htpasswd, tokens)? {
+        Credentials::Valid(identity) => return Ok(Authentication::Valid(identity)),
+        Credentials::Missing => false,
+        Credentials::Invalid { bearer } => bearer,
+    };
+
+    let mut challenges = vec![];
+    if htpasswd.is_some() {
+        challenges.push(format!("Basic realm=\"{realm}\", charset=\"UTF-8\""));
+    }
+    if tokens.is_some() {
+        if invalid_token {
+            challenges.push(format!("Bearer realm=\"{realm}\", error=\"invalid_token\""));
+        } else {
+            challenges.push(format!("Bearer realm=\"{realm}\""));
+        }
+    }
+    Ok(Authentication::Challenge(challenges.join(", ")))
+}
+
+/// Verifies the `Authorization` header of a request against credential files
+///
+/// Returns the outcome, or an error if a credential file can't be read
+fn verify_credentials(
+    req: &Request,
+    htpasswd: Option<&Path>,
+    tokens: Option<&Path>,
+) -> io::Result<Credentials> {
+    let Some(header) = req.get_headers().get("authorization") else {
+        return Ok(Credentials::Missing);
+    };
+    let (scheme, value) = header.split_once(' ').unwrap_or((header, ""));
+    let value = value.trim();
+
+    match (scheme.to_lowercase().as_str(), htpasswd, tokens) {
+        ("basic", Some(htpasswd), _) => {
+            let decoded = STANDARD
+                .decode(value)
+                .ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok());
+            let Some((user, password)) = decoded.as_deref().and_then(|d| d.split_once(':')) else {
+                return Ok(Credentials::Invalid { bearer: false });
+            };
+
+            if verify_basic(htpasswd, user, password)? {
+                Ok(Credentials::Valid(user.to_owned()))
+            } else {
+                Ok(Credentials::Invalid { bearer: false })
+            }
+        }
+        ("bearer", _, Some(tokens)) => match find_token(tokens, value)? {
+            Some(identity) => Ok(Credentials::Valid(identity)),
+            None => Ok(Credentials::Invalid { bearer: true }),
+        },
+        _ => Ok(Credentials::Missing),
+    }
+}
+
+/// Checks a user's password against an htpasswd-style file
+///
+/// The `htpasswd` is the file of `user:hash` lines, and `user` and `password` are the decoded Basic credentials
+pub(crate) fn verify_basic(htpasswd: &Path, user: &str, password: &str) -> io::Result<bool> {
+    let contents = fs::read_to_string(htpasswd)?;
+
+    let hash = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| *name == user)
+        .map(|(_, hash)| hash);
+
+    Ok(hash.is_some_and(|hash| verify_password(password, hash)))
+}
+
+/// Verifies a password against a bcrypt or argon2 hash
+fn verify_password(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else if hash.starts_with("$argon2") {
+        Argon2::default()
+            .verify_password(password.as_bytes(), hash)
+            .is_ok()
+    } else {
+        log_error!("Unsupported password hash format; use bcrypt or argon2");
+        false
+    }
+}
+
+/// Looks up a bearer token in a tokens file
+///
+/// The `tokens` is the file of `identity:token` lines and `token` is the token sent by the client
+///
+/// Returns the identity the token belongs to, if any
+fn find_token(tokens: &Path, token: &str) -> io::Result<Option<String>> {
+    let contents = fs::read_to_string(tokens)?;
+
+    let identity = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(':'))
+        .find(|(_, known)| {
+            !token.is_empty() && constant_time_eq(known.as_bytes(), token.as_bytes())
+        })
+        .map(|(identity, _)| identity.to_owned());
+
+    Ok(identity)
+}
+
+/// Compares two byte strings without short-circuiting on the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::PasswordHasher;
+    use tempfile::{TempDir, tempdir};
+
+    /// Helper function to build a site whose writes need credentials and reads are public
+    ///
+    /// Returns the site and the directory holding its credential files
+    fn locked_site() -> (SiteConfig, TempDir) {
+        let temp_dir = tempdir().unwrap();
+        let htpasswd = temp_dir.path().join("users.htpasswd");
+        let tokens = temp_dir.path().join("tokens.txt");
+
+        let bcrypt_hash = bcrypt::hash("secret", 4).unwrap();
+        let argon2_hash = Argon2::default()
+            .hash_password(b"hunter2")
+            .unwrap()
+            .to_string();
+        fs::write(
+            &htpasswd,
+            format!("# users\nalice:{bcrypt_hash}\nbob:{argon2_hash}\nmallory:{bcrypt_hash}\n"),
+        )
+        .unwrap();
+        fs::write(&tokens, "deploy-bot:s3cr3t-token\n").unwrap();
+
+        let site = toml::from_str(&format!(
+            r#"
+            [[access]]
+            prefix = "/"
+            methods = ["PUT", "DELETE", "POST"]
+            policy = "authenticate"
+            realm = "uploads"
+            htpasswd = {htpasswd:?}
+            tokens = {tokens:?}
+            users = ["alice", "bob", "deploy-bot"]
+
+            [[access]]
+            prefix = "/private/"
+            policy = "deny"
+            "#
+        ))
+        .unwrap();
+
+        (site, temp_dir)
+    }
+
+    /// Helper function to build a request with an optional `Authorization` header
+    fn request(status_line: &str, authorization: Option<String>) -> Request {
+        let mut req = Request::default();
+        req.parse_status_line(status_line.to_owned());
+        if let Some(value) = authorization {
+            req.append_header(format!("Authorization: {value}"));
+        }
+        req
+    }
+
+    /// Helper function to encode Basic credentials
+    fn basic(user: &str, password: &str) -> Option<String> {
+        Some(format!(
+            "Basic {}",
+            STANDARD.encode(format!("{user}:{password}"))
+        ))
+    }
+
+    #[test]
+    fn test_reads_are_public_and_writes_are_challenged() {
+        let (site, _dir) = locked_site();
+
+        assert!(check_access(&mut request("GET /index.html HTTP/1.1", None), &site).is_none());
+
+        let res = check_access(&mut request("PUT /index.html HTTP/1.1", None), &site).unwrap();
+        assert_eq!(res.get_status(), Some(401));
+        assert_eq!(
+            res.get_headers()["WWW-Authenticate"],
+            "Basic realm=\"uploads\", charset=\"UTF-8\", Bearer realm=\"uploads\""
+        );
+    }
+
+    #[test]
+    fn test_basic_auth_with_bcrypt_and_argon2() {
+        let (site, _dir) = locked_site();
+
+        let mut req = request("PUT /a.html HTTP/1.1", basic("alice", "secret"));
+        assert!(check_access(&mut req, &site).is_none());
+        assert_eq!(req.get_identity(), Some("alice"));
+
+        let mut req = request("DELETE /a.html HTTP/1.1", basic("bob", "hunter2"));
+        assert!(check_access(&mut req, &site).is_none());
+
+        let mut req = request("PUT /a.html HTTP/1.1", basic("alice", "wrong"));
+        assert_eq!(
+            check_access(&mut req, &site).unwrap().get_status(),
+            Some(401)
+        );
+
+        // Valid credentials outside the allow list
+        let mut req = request("PUT /a.html HTTP/1.1", basic("mallory", "secret"));
+        assert_eq!(
+            check_access(&mut req, &site).unwrap().get_status(),
+            Some(403)
+        );
+    }
+
+    #[test]
+    fn test_bearer_tokens() {
+        let (site, _dir) = locked_site();
+
+        let mut req = request(
+            "POST /a.html HTTP/1.1",
+            Some("Bearer s3cr3t-token".to_owned()),
+        );
+        assert!(check_access(&mut req, &site).is_none());
+        assert_eq!(req.get_identity(), Some("deploy-bot"));
+
+        let mut req = request("POST /a.html HTTP/1.1", Some("Bearer nope".to_owned()));
+        let res = check_access(&mut req, &site).unwrap();
+        assert_eq!(res.get_status(), Some(401));
+        assert!(res.get_headers()["WWW-Authenticate"].contains("error=\"invalid_token\""));
+    }
+
+    #[test]
+    fn test_webdav_writes_need_write_access() {
+        let (mut site, _dir) = locked_site();
+        site.webdav = true;
+
+        // Each would write or delete files under a rule that only names PUT and DELETE
+        for (status_line, destination) in [
+            ("COPY /notes.txt HTTP/1.1", Some("/index.html")),
+            ("MOVE /notes.txt HTTP/1.1", Some("/moved.txt")),
+            ("MOVE /notes.txt HTTP/1.1", None),
+            ("MKCOL /docs HTTP/1.1", None),
+        ] {
+            let mut req = request(status_line, None);
+            if let Some(destination) = destination {
+                req.append_header(format!("Destination: {destination}"));
+            }
+            assert_eq!(
+                check_access(&mut req, &site).unwrap().get_status(),
+                Some(401),
+                "{status_line}"
+            );
+        }
+
+        // Copying out of a private area is still forbidden by its own rule
+        let mut req = request("COPY /private/notes.txt HTTP/1.1", basic("alice", "secret"));
+        req.append_header("Destination: /notes.txt".to_owned());
+        assert_eq!(
+            check_access(&mut req, &site).unwrap().get_status(),
+            Some(403)
+        );
+
+        let mut req = request("MOVE /notes.txt HTTP/1.1", basic("alice", "secret"));
+        req.append_header("Destination: /moved.txt".to_owned());
+        assert!(check_access(&mut req, &site).is_none());
+        assert_eq!(req.get_identity(), Some("alice"));
+    }
+
+    #[test]
+    fn test_deny_rule_is_forbidden() {
+        let (site, _dir) = locked_site();
+
+        let mut req = request("GET /private/notes.txt HTTP/1.1", basic("alice", "secret"));
+        assert_eq!(
+            check_access(&mut req, &site).unwrap().get_status(),
+            Some(403)
+        );
+    }
+
+    #[test]
+    fn test_rules_match_every_spelling_of_a_path() {
+        let (site, _dir) = locked_site();
+
+        for target in [
+            "/private/x.txt",
+            "//private/x.txt",
+            "/./private/x.txt",
+            "/%70rivate/x.txt",
+        ] {
+            let mut req = request(&format!("GET {target} HTTP/1.1"), None);
+            assert_eq!(req.get_path(), "/private/x.txt");
+            assert_eq!(req.get_resource(), Path::new("public/private/x.txt"));
+            assert_eq!(
+                check_access(&mut req, &site).unwrap().get_status(),
+                Some(403),
+                "{target}"
+            );
+        }
+
+        // Climbing out of a directory isn't one of them
+        let req = request("GET /public/.%2E/private/x.txt HTTP/1.1", None);
+        assert!(!req.has_valid_path());
+
+        // Write locks on a prefix can't be skipped with an extra slash either
+        let site: SiteConfig = toml::from_str(
+            r#"
+            [[access]]
+            prefix = "/uploads/"
+            methods = ["PUT"]
+            policy = "deny"
+            "#,
+        )
+        .unwrap();
+        for target in ["/uploads/f", "//uploads/f", "/uploads/./f", "/%75ploads/f"] {
+            let mut req = request(&format!("PUT {target} HTTP/1.1"), None);
+            assert_eq!(
+                check_access(&mut req, &site).unwrap().get_status(),
+                Some(403),
+                "{target}"
+            );
+        }
+    }
+}
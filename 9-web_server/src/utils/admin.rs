@@ -0,0 +1,192 @@
+use httpdate::fmt_http_date;
+use serde_json::{Value, json};
+
+use crate::{
+    log_error,
+    models::{AdminConfig, HttpMethod, LogLevel, Request, Response, ServerState},
+    utils::{Authentication, authenticate, healthz, json_error, json_response, readyz},
+};
+
+/// Handles requests to the admin API
+///
+/// The `req` is the Request with its body read, `admin` holds the credentials it must carry, and `state` is the shared server state
+///
+/// Returns a `Response` containing the JSON result of the admin operation
+pub fn admin(req: &Request, admin: &AdminConfig, state: &ServerState) -> Response {
+    // Every admin operation needs credentials
+    let authentication = authenticate(
+        req,
+        &admin.realm,
+        admin.htpasswd.as_deref(),
+        admin.tokens.as_deref(),
+    );
+    let identity = match authentication {
+        Ok(Authentication::Valid(identity)) => identity,
+        Ok(Authentication::Challenge(challenge)) => {
+            let mut res = json_error(401, "Admin credentials are required");
+            res.add_header(("WWW-Authenticate".to_owned(), challenge));
+            return res;
+        }
+        Err(err) => {
+            log_error!("Error reading admin credentials: {err}");
+            return json_error(500, "Admin credentials are unavailable");
+        }
+    };
+
+    match (req.get_method(), req.get_path()) {
+        (HttpMethod::Get, "/healthz") => healthz(),
+        (HttpMethod::Get, "/readyz") => readyz(state),
+        (HttpMethod::Get, "/connections") => connections(state),
+        (HttpMethod::Post, "/reload") => {
+            log_error!("Configuration reload requested by {identity}");
+            reload(state)
+        }
+        (HttpMethod::Get, "/log-level") => {
+            json_response(200, &json!({ "level": LogLevel::current().as_str() }))
+        }
+        (HttpMethod::Put, "/log-level") => set_log_level(req),
+        (HttpMethod::Post, "/drain") => {
+            log_error!("Drain requested by {identity}");
+            state.start_drain();
+            json_response(
+                202,
+                &json!({ "draining": true, "open_connections": state.connections.len() }),
+            )
+        }
+        _ => json_error(404, "Unknown admin operation"),
+    }
+}
+
+/// Lists the connections currently being handled
+fn connections(state: &ServerState) -> Response {
+    let connections: Vec<Value> = state
+        .connections
+        .list()
+        .into_iter()
+        .map(|connection| {
+            json!({
+                "id": connection.id,
+                "client": connection.client.map(|addr| addr.to_string()),
+                "opened": fmt_http_date(connection.opened),
+                "request": connection.request,
+            })
+        })
+        .collect();
+
+    json_response(
+        200,
+        &json!({ "count": connections.len(), "connections": connections }),
+    )
+}
+
+/// Reloads the configuration file
+fn reload(state: &ServerState) -> Response {
+    match state.reload_config() {
+        Ok(restart_required) => json_response(
+            200,
+            &json!({ "reloaded": true, "restart_required": restart_required }),
+        ),
+        Err(err) => json_error(400, &format!("Configuration not reloaded: {err}")),
+    }
+}
+
+/// Changes the log level to the one named in the body, as plain text or `{"level": "debug"}`
+fn set_log_level(req: &Request) -> Response {
+    let body = String::from_utf8_lossy(req.get_body());
+    let name = match serde_json::from_str::<Value>(&body) {
+        Ok(value) => value["level"].as_str().unwrap_or_default().to_owned(),
+        Err(_) => body.into_owned(),
+    };
+
+    match LogLevel::parse(&name) {
+        Some(level) => {
+            LogLevel::set_current(level);
+            json_response(200, &json!({ "level": level.as_str() }))
+        }
+        None => json_error(400, "Log level must be error, info, or debug"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{PoolLoad, ServerConfig};
+    use std::{fs, sync::Arc};
+    use tempfile::tempdir;
+
+    /// Helper function to send a request with the admin token
+    fn send(state: &ServerState, status_line: &str, body: &str) -> Response {
+        let mut req = Request::default();
+        req.parse_status_line(status_line.to_owned());
+        req.append_header("Authorization: Bearer let-me-in".to_owned());
+        req.set_body(body.as_bytes());
+        admin(&req, state.config().admin.as_ref().unwrap(), state)
+    }
+
+    #[test]
+    fn test_admin_operations() {
+        let temp_dir = tempdir().unwrap();
+        let tokens = temp_dir.path().join("tokens.txt");
+        let config_path = temp_dir.path().join("server.toml");
+        fs::write(&tokens, "ops:let-me-in\n").unwrap();
+        let contents = format!(
+            "root = {:?}\n[admin]\naddress = \"127.0.0.1:0\"\ntokens = {tokens:?}\n",
+            temp_dir.path()
+        );
+        fs::write(&config_path, &contents).unwrap();
+        let state = ServerState::new(
+            ServerConfig::load(&config_path).unwrap(),
+            Arc::new(PoolLoad::default()),
+        );
+
+        // Credentials are required
+        let mut req = Request::default();
+        req.parse_status_line("GET /connections HTTP/1.1".to_owned());
+        let res = admin(&req, state.config().admin.as_ref().unwrap(), &state);
+        assert_eq!(res.get_status(), Some(401));
+
+        let _open = state.connections.open(None);
+        assert_eq!(
+            send(&state, "GET /connections HTTP/1.1", "").get_status(),
+            Some(200)
+        );
+        assert_eq!(
+            send(&state, "GET /readyz HTTP/1.1", "").get_status(),
+            Some(200)
+        );
+
+        // Reloading picks up edits and reports settings needing a restart
+        fs::write(
+            &config_path,
+            format!("threads = 4\nindex = \"home.html\"\n{contents}"),
+        )
+        .unwrap();
+        assert_eq!(
+            send(&state, "POST /reload HTTP/1.1", "").get_status(),
+            Some(200)
+        );
+        assert_eq!(state.config().default_site.index, "home.html");
+        fs::write(&config_path, "threads = 0").unwrap();
+        assert_eq!(
+            send(&state, "POST /reload HTTP/1.1", "").get_status(),
+            Some(400)
+        );
+        assert_eq!(state.config().default_site.index, "home.html");
+
+        assert_eq!(
+            send(&state, "PUT /log-level HTTP/1.1", "loud").get_status(),
+            Some(400)
+        );
+
+        // Draining makes the server unready
+        assert_eq!(
+            send(&state, "POST /drain HTTP/1.1", "").get_status(),
+            Some(202)
+        );
+        assert!(state.is_draining());
+        assert_eq!(
+            send(&state, "GET /readyz HTTP/1.1", "").get_status(),
+            Some(503)
+        );
+    }
+}
@@ -0,0 +1,408 @@
+use serde_json::{Value, json};
+
+use crate::{
+    log_error,
+    models::{
+        ApiConfig, Document, HttpMethod, Precondition, Request, Response, SiteConfig, StoreError,
+        is_valid_name,
+    },
+    utils::{etag_matches, set_date_header},
+};
+
+/// Methods supported on a collection, e.g. `/api/notes`
+const COLLECTION_METHODS: &[&str] = &["GET", "POST", "OPTIONS"];
+/// Methods supported on a document, e.g. `/api/notes/42`
+const DOCUMENT_METHODS: &[&str] = &["GET", "PUT", "PATCH", "DELETE", "OPTIONS"];
+
+/// Returns the methods supported by a path under a document store
+///
+/// The `path` is the request path without its query string and `api` is the store it falls under
+pub fn api_methods(path: &str, api: &ApiConfig) -> &'static [&'static str] {
+    match parse_api_path(path, api) {
+        Some((_, Some(_))) => DOCUMENT_METHODS,
+        _ => COLLECTION_METHODS,
+    }
+}
+
+/// Handles requests to the JSON document store
+///
+/// The `req` is the Request with its body read, `api` is the store the path falls under, and `site` is the virtual host it was addressed to
+///
+/// Returns a `Response` containing a JSON document, a page of documents, or a JSON error
+pub fn api(req: &Request, api: &ApiConfig, site: &SiteConfig) -> Response {
+    let Some((collection, id)) = parse_api_path(req.get_path(), api) else {
+        return json_error(404, "Documents are served as /<collection>/<id>");
+    };
+    if !is_valid_name(collection) || id.is_some_and(|id| !is_valid_name(id)) {
+        return json_error(
+            400,
+            "Collection names and ids may only use letters, digits, '-' and '_'",
+        );
+    }
+
+    let methods = api_methods(req.get_path(), api);
+    let method = req.get_method().as_str();
+    if !methods.contains(&method) || !site.allows_method(method) {
+        let mut res = json_error(405, &format!("{method} is not supported here"));
+        res.add_header(("Allow".to_owned(), methods.join(", ")));
+        return res;
+    }
+
+    let result = match (req.get_method(), id) {
+        (HttpMethod::Options, _) => {
+            let mut res = Response::default();
+            res.set_status(204);
+            res.add_header(set_date_header());
+            res.add_header(("Allow".to_owned(), methods.join(", ")));
+            return res;
+        }
+        (HttpMethod::Get, None) => list(req, api, collection),
+        (HttpMethod::Post, None) => create(req, api, collection),
+        (HttpMethod::Get, Some(id)) => get(req, api, collection, id),
+        (HttpMethod::Put, Some(id)) => replace(req, api, collection, id),
+        (HttpMethod::Patch, Some(id)) => patch(req, api, collection, id),
+        (HttpMethod::Delete, Some(id)) => delete(req, api, collection, id),
+        _ => Ok(json_error(405, &format!("{method} is not supported here"))),
+    };
+
+    result.unwrap_or_else(|err| match err {
+        StoreError::NotFound => json_error(404, "Document not found"),
+        StoreError::PreconditionFailed => {
+            json_error(412, "Document has changed; fetch it again to get its ETag")
+        }
+        StoreError::NotAnObject => json_error(400, "Documents must be JSON objects"),
+        StoreError::Io(err) => {
+            log_error!("Error accessing document store: {err}");
+            json_error(500, "Document store is unavailable")
+        }
+    })
+}
+
+/// Splits a request path into its collection name and optional document id
+fn parse_api_path<'a>(path: &'a str, api: &ApiConfig) -> Option<(&'a str, Option<&'a str>)> {
+    let rest = path.strip_prefix(&api.prefix)?.trim_end_matches('/');
+    let mut segments = rest.split('/');
+
+    match (segments.next(), segments.next(), segments.next()) {
+        (Some(collection), None, None) if !collection.is_empty() => Some((collection, None)),
+        (Some(collection), Some(id), None) => Some((collection, Some(id))),
+        _ => None,
+    }
+}
+
+/// Handles `GET` on a collection: a page of documents matching the query's field filters
+fn list(req: &Request, api: &ApiConfig, collection: &str) -> Result<Response, StoreError> {
+    let queries = req.get_queries();
+    let number = |name: &str| {
+        queries
+            .get(name)
+            .and_then(|value| value.parse::<usize>().ok())
+    };
+    let limit = number("limit")
+        .unwrap_or(api.page_size)
+        .min(api.max_page_size);
+    let offset = number("offset").unwrap_or(0);
+
+    // Every other query parameter filters on a field
+    let mut filters: Vec<(String, String)> = queries
+        .iter()
+        .filter(|(name, _)| *name != "limit" && *name != "offset")
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    filters.sort();
+
+    let (page, total) = api.store().list(collection, &filters, offset, limit)?;
+    let items: Vec<Value> = page.iter().map(Document::to_json).collect();
+    Ok(json_response(
+        200,
+        &json!({ "items": items, "total": total, "offset": offset, "limit": limit }),
+    ))
+}
+
+/// Handles `POST` on a collection: stores a new document under a server-assigned id
+fn create(req: &Request, api: &ApiConfig, collection: &str) -> Result<Response, StoreError> {
+    let data = match json_body(req, &["application/json"]) {
+        Ok(Value::Object(data)) => data,
+        Ok(_) => return Err(StoreError::NotAnObject),
+        Err(res) => return Ok(*res),
+    };
+
+    let doc = api.store().create(collection, data)?;
+    let mut res = document_response(201, &doc);
+    res.add_header((
+        "Location".to_owned(),
+        format!("{}{collection}/{}", api.prefix, doc.id),
+    ));
+    Ok(res)
+}
+
+/// Handles `GET` on a document
+fn get(req: &Request, api: &ApiConfig, collection: &str, id: &str) -> Result<Response, StoreError> {
+    let doc = api
+        .store()
+        .get(collection, id)?
+        .ok_or(StoreError::NotFound)?;
+
+    // Answer conditional requests for unchanged documents without a body
+    let unchanged = req
+        .get_headers()
+        .get("if-none-match")
+        .is_some_and(|tags| etag_matches(tags, &doc.etag()));
+    if unchanged {
+        let mut res = Response::default();
+        res.set_status(304);
+        res.add_header(set_date_header());
+        res.add_header(("ETag".to_owned(), doc.etag()));
+        return Ok(res);
+    }
+
+    Ok(document_response(200, &doc))
+}
+
+/// Handles `PUT` on a document: replaces it, or creates it under the client's id
+fn replace(
+    req: &Request,
+    api: &ApiConfig,
+    collection: &str,
+    id: &str,
+) -> Result<Response, StoreError> {
+    let data = match json_body(req, &["application/json"]) {
+        Ok(Value::Object(data)) => data,
+        Ok(_) => return Err(StoreError::NotAnObject),
+        Err(res) => return Ok(*res),
+    };
+
+    let (doc, created) = api
+        .store()
+        .replace(collection, id, data, precondition(req))?;
+    Ok(document_response(if created { 201 } else { 200 }, &doc))
+}
+
+/// Handles `PATCH` on a document with a JSON Merge Patch
+fn patch(
+    req: &Request,
+    api: &ApiConfig,
+    collection: &str,
+    id: &str,
+) -> Result<Response, StoreError> {
+    let patch = match json_body(req, &["application/merge-patch+json", "application/json"]) {
+        Ok(patch) => patch,
+        Err(res) => return Ok(*res),
+    };
+
+    let doc = api
+        .store()
+        .patch(collection, id, &patch, precondition(req))?;
+    Ok(document_response(200, &doc))
+}
+
+/// Handles `DELETE` on a document
+fn delete(
+    req: &Request,
+    api: &ApiConfig,
+    collection: &str,
+    id: &str,
+) -> Result<Response, StoreError> {
+    api.store().delete(collection, id, precondition(req))?;
+
+    let mut res = Response::default();
+    res.set_status(204);
+    res.add_header(set_date_header());
+    Ok(res)
+}
+
+/// Reads the client's precondition from `If-Match` or `If-None-Match: *`
+fn precondition(req: &Request) -> Precondition<'_> {
+    let headers = req.get_headers();
+    match (headers.get("if-match"), headers.get("if-none-match")) {
+        (Some(tags), _) => Precondition::Match(tags),
+        (None, Some(tags)) if tags.trim() == "*" => Precondition::Absent,
+        _ => Precondition::None,
+    }
+}
+
+/// Parses the JSON body of a request
+///
+/// The `media_types` are the accepted `Content-Type`s, ignoring parameters such as `charset`
+///
+/// Returns the parsed Value, or a `415`/`400` Response
+fn json_body(req: &Request, media_types: &[&str]) -> Result<Value, Box<Response>> {
+    let content_type = req
+        .get_headers()
+        .get("content-type")
+        .map(|value| value.split(';').next().unwrap_or("").trim().to_lowercase())
+        .unwrap_or_default();
+    if !media_types.contains(&content_type.as_str()) {
+        return Err(Box::new(json_error(
+            415,
+            &format!("Content-Type must be {}", media_types.join(" or ")),
+        )));
+    }
+
+    serde_json::from_slice(req.get_body())
+        .map_err(|err| Box::new(json_error(400, &format!("Invalid JSON: {err}"))))
+}
+
+/// Builds a Response containing a document and its ETag
+fn document_response(code: usize, doc: &Document) -> Response {
+    let mut res = json_response(code, &doc.to_json());
+    res.add_header(("ETag".to_owned(), doc.etag()));
+    res
+}
+
+/// Builds a Response with a JSON body
+pub(crate) fn json_response(code: usize, body: &Value) -> Response {
+    let mut res = Response::default();
+    res.set_status(code);
+    set_json_body(&mut res, body);
+    res
+}
+
+/// Builds a JSON error Response
+///
+/// The `code` is the HTTP status code and `message` explains the error to the client
+pub(crate) fn json_error(code: usize, message: &str) -> Response {
+    let mut res = Response::default();
+    res.set_status(code);
+    let reason = res.get_description().unwrap_or("Error").to_owned();
+    set_json_body(&mut res, &json!({ "error": reason, "message": message }));
+    res
+}
+
+/// Sets the Date, Content-Type, and Content-Length headers and the body of a JSON Response
+fn set_json_body(res: &mut Response, body: &Value) {
+    let contents = body.to_string();
+    res.add_header(set_date_header());
+    res.add_header(("Content-Type".to_owned(), "application/json".to_owned()));
+    res.add_header(("Content-Length".to_owned(), contents.len().to_string()));
+    res.set_body(Some(contents));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    /// Helper function to build a site with a document store in a directory
+    fn api_site(dir: &std::path::Path) -> SiteConfig {
+        toml::from_str(&format!("[api]\ndir = {dir:?}")).unwrap()
+    }
+
+    /// Helper function to send a request to a site's document store
+    fn send(site: &SiteConfig, status_line: &str, headers: &[&str], body: &str) -> Response {
+        let mut req = Request::default();
+        req.parse_status_line(status_line.to_owned());
+        for header in headers {
+            req.append_header(header.to_string());
+        }
+        req.set_body(body.as_bytes());
+        api(&req, site.api.as_ref().unwrap(), site)
+    }
+
+    /// Helper function to parse the JSON body of a Response
+    fn body(res: Response) -> Value {
+        let mut out = vec![];
+        res.send(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        serde_json::from_str(out.split("\r\n\r\n").nth(1).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_create_update_and_list() {
+        let temp_dir = tempdir().unwrap();
+        let site = api_site(temp_dir.path());
+        let json = "Content-Type: application/json";
+
+        let res = send(
+            &site,
+            "POST /api/notes HTTP/1.1",
+            &[json],
+            r#"{"title": "a"}"#,
+        );
+        assert_eq!(res.get_status(), Some(201));
+        let location = res.get_headers()["Location"].clone();
+        let first = res.get_headers()["ETag"].clone();
+        let id = body(res)["id"].as_str().unwrap().to_owned();
+        assert_eq!(location, format!("/api/notes/{id}"));
+
+        // Merge patch against the current ETag, then a stale write
+        let target = format!("PATCH /api/notes/{id} HTTP/1.1");
+        let res = send(
+            &site,
+            &target,
+            &[
+                "Content-Type: application/merge-patch+json",
+                &format!("If-Match: {first}"),
+            ],
+            r#"{"done": true}"#,
+        );
+        assert_eq!(res.get_status(), Some(200));
+        assert!(res.get_headers()["ETag"].ends_with("-v2\""));
+        let target = format!("PUT /api/notes/{id} HTTP/1.1");
+        let res = send(&site, &target, &[json, &format!("If-Match: {first}")], "{}");
+        assert_eq!(res.get_status(), Some(412));
+
+        send(
+            &site,
+            "PUT /api/notes/b HTTP/1.1",
+            &[json],
+            r#"{"done": false}"#,
+        );
+        let list = body(send(
+            &site,
+            "GET /api/notes?done=true&limit=5 HTTP/1.1",
+            &[],
+            "",
+        ));
+        assert_eq!(list["total"], 1);
+        assert_eq!(list["limit"], 5);
+        assert_eq!(
+            list["items"][0],
+            serde_json::json!({"id": id, "title": "a", "done": true})
+        );
+    }
+
+    #[test]
+    fn test_stale_etags_miss_recreated_documents() {
+        let temp_dir = tempdir().unwrap();
+        let site = api_site(temp_dir.path());
+        let json = "Content-Type: application/json";
+
+        let res = send(&site, "PUT /api/notes/a HTTP/1.1", &[json], r#"{"v": 1}"#);
+        assert_eq!(res.get_status(), Some(201));
+        let stale = res.get_headers()["ETag"].clone();
+        let res = send(&site, "DELETE /api/notes/a HTTP/1.1", &[], "");
+        assert_eq!(res.get_status(), Some(204));
+        let res = send(&site, "PUT /api/notes/a HTTP/1.1", &[json], r#"{"v": 2}"#);
+        assert_eq!(res.get_status(), Some(201));
+
+        let if_match = format!("If-Match: {stale}");
+        let res = send(&site, "PUT /api/notes/a HTTP/1.1", &[json, &if_match], "{}");
+        assert_eq!(res.get_status(), Some(412));
+        let res = send(&site, "DELETE /api/notes/a HTTP/1.1", &[&if_match], "");
+        assert_eq!(res.get_status(), Some(412));
+    }
+
+    #[test]
+    fn test_errors_are_json() {
+        let temp_dir = tempdir().unwrap();
+        let site = api_site(temp_dir.path());
+
+        let res = send(&site, "GET /api/notes/missing HTTP/1.1", &[], "");
+        assert_eq!(res.get_status(), Some(404));
+        assert_eq!(body(res)["error"], "Not Found");
+
+        let res = send(
+            &site,
+            "POST /api/notes HTTP/1.1",
+            &["Content-Type: text/plain"],
+            "{}",
+        );
+        assert_eq!(res.get_status(), Some(415));
+        let res = send(&site, "DELETE /api/notes HTTP/1.1", &[], "");
+        assert_eq!(res.get_status(), Some(405));
+        assert_eq!(res.get_headers()["Allow"], "GET, POST, OPTIONS");
+        let res = send(&site, "GET /api/no.tes/1 HTTP/1.1", &[], "");
+        assert_eq!(res.get_status(), Some(400));
+    }
+}
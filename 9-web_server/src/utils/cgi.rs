@@ -0,0 +1,433 @@
+use std::{
+    env,
+    io::{self, Read, Write},
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    log_error,
+    models::{CgiConfig, Request, Response, SiteConfig},
+    utils::{error_response, set_date_header},
+};
+
+/// Request headers that are never passed to scripts as `HTTP_*` variables
+const HIDDEN_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "content-type",
+    "content-length",
+    "proxy",
+];
+
+/// Request headers replaced by the request id and trace context of this server
+const TRACE_HEADERS: &[&str] = &["x-request-id", "traceparent", "tracestate"];
+
+/// Runs a CGI/1.1 script and converts its output into a Response
+///
+/// The `req` is the Request with its body already read, `cgi` is the matched CGI configuration, and `site` is the virtual host it was addressed to
+///
+/// Returns a `Response` built from the script's header block and body, or an error response
+pub fn cgi(req: &Request, cgi: &CgiConfig, site: &SiteConfig) -> Response {
+    // Split `/cgi-bin/<script>/<path info>`
+    let Some(rest) = req.get_path().strip_prefix(cgi.prefix.as_str()) else {
+        return error_response(404, req, site);
+    };
+    let (script_name, path_info) = match rest.split_once('/') {
+        Some((script, info)) => (script, format!("/{info}")),
+        None => (rest, String::new()),
+    };
+
+    // Only run regular files directly inside the CGI directory
+    if script_name.is_empty() || script_name.starts_with('.') {
+        return error_response(404, req, site);
+    }
+    let script = match cgi.dir.join(script_name).canonicalize() {
+        Ok(script) if script.is_file() => script,
+        _ => return error_response(404, req, site),
+    };
+
+    // Use a configured interpreter or execute the script itself
+    let interpreter = script
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(|ext| cgi.interpreters.get(ext));
+    let mut command = match interpreter {
+        Some(interpreter) => {
+            let mut command = Command::new(interpreter);
+            command.arg(&script);
+            command
+        }
+        None => Command::new(&script),
+    };
+    if let Some(dir) = script.parent() {
+        command.current_dir(dir);
+    }
+
+    // Build the CGI environment from scratch
+    command.env_clear();
+    if let Ok(path) = env::var("PATH") {
+        command.env("PATH", path);
+    }
+    let script_uri = format!("{}{script_name}", cgi.prefix);
+    for (key, value) in cgi_environment(req, &script_uri, &path_info, site) {
+        command.env(key, value);
+    }
+
+    let child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn();
+    let child = match child {
+        Ok(child) => child,
+        Err(err) => {
+            log_error!("Error starting CGI script {}: {err}", script.display());
+            let code = if err.kind() == io::ErrorKind::PermissionDenied {
+                403
+            } else {
+                500
+            };
+            return error_response(code, req, site);
+        }
+    };
+
+    match run_script(child, req.get_body(), cgi.timeout()) {
+        Ok(Some(output)) => parse_cgi_output(&output).unwrap_or_else(|message| {
+            log_error!(
+                "Invalid output from CGI script {}: {message}",
+                script.display()
+            );
+            error_response(500, req, site)
+        }),
+        Ok(None) => {
+            log_error!("CGI script {} timed out", script.display());
+            error_response(504, req, site)
+        }
+        Err(err) => {
+            log_error!("Error running CGI script {}: {err}", script.display());
+            error_response(500, req, site)
+        }
+    }
+}
+
+/// Builds the CGI/1.1 meta-variables for a request
+///
+/// The `script_uri` is the URI path of the script and `path_info` is the extra path after it
+fn cgi_environment(
+    req: &Request,
+    script_uri: &str,
+    path_info: &str,
+    site: &SiteConfig,
+) -> Vec<(String, String)> {
+    let headers = req.get_headers();
+    let query = req
+        .get_target()
+        .split_once('?')
+        .map_or("", |(_, query)| query);
+    let host = headers.get("host").map(String::as_str).unwrap_or("");
+    let server_name = host.rsplit_once(':').map_or(host, |(name, _)| name);
+    let server_port = req
+        .get_server_addr()
+        .map(|addr| addr.port().to_string())
+        .unwrap_or_else(|| "80".to_owned());
+
+    let mut vars = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_owned()),
+        (
+            "SERVER_SOFTWARE",
+            format!("web_server/{}", env!("CARGO_PKG_VERSION")),
+        ),
+        ("SERVER_PROTOCOL", req.get_protocol().to_owned()),
+        ("SERVER_NAME", server_name.to_owned()),
+        ("SERVER_PORT", server_port),
+        ("REQUEST_METHOD", req.get_method().as_str().to_owned()),
+        ("REQUEST_URI", req.get_target().to_owned()),
+        ("SCRIPT_NAME", script_uri.to_owned()),
+        ("QUERY_STRING", query.to_owned()),
+    ];
+
+    if !path_info.is_empty() {
+        vars.push(("PATH_INFO", path_info.to_owned()));
+        vars.push((
+            "PATH_TRANSLATED",
+            site.root
+                .join(path_info.trim_start_matches('/'))
+                .display()
+                .to_string(),
+        ));
+    }
+    if let Some(addr) = req.get_client_addr() {
+        vars.push(("REMOTE_ADDR", addr.ip().to_string()));
+        // Addresses named by a front proxy come without a port
+        if addr.port() != 0 {
+            vars.push(("REMOTE_PORT", addr.port().to_string()));
+        }
+    }
+    if let Some(content_type) = headers.get("content-type") {
+        vars.push(("CONTENT_TYPE", content_type.clone()));
+    }
+    if !req.get_body().is_empty() {
+        vars.push(("CONTENT_LENGTH", req.get_body().len().to_string()));
+    }
+
+    let mut vars: Vec<(String, String)> = vars
+        .into_iter()
+        .map(|(key, value)| (key.to_owned(), value))
+        .collect();
+
+    // Pass remaining headers as HTTP_* variables
+    for (key, value) in headers {
+        if HIDDEN_HEADERS.contains(&key.as_str()) || TRACE_HEADERS.contains(&key.as_str()) {
+            continue;
+        }
+        vars.push((
+            format!("HTTP_{}", key.to_uppercase().replace('-', "_")),
+            value.clone(),
+        ));
+    }
+
+    // Pass the request id and trace as if the script had been called with them
+    vars.push((
+        "HTTP_X_REQUEST_ID".to_owned(),
+        req.get_request_id().to_owned(),
+    ));
+    for (key, value) in req.get_trace().headers() {
+        vars.push((format!("HTTP_{}", key.to_uppercase()), value));
+    }
+
+    vars
+}
+
+/// Feeds the request body to a running script and collects its output
+///
+/// The `child` is the spawned script, `body` is piped to its stdin, and `timeout` bounds its run time
+///
+/// Returns the script's stdout, or None if it was killed after timing out
+fn run_script(mut child: Child, body: &[u8], timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+    // Write stdin and read stdout on separate threads so large payloads can't deadlock
+    let mut stdin = child.stdin.take();
+    let body = body.to_vec();
+    let writer = thread::spawn(move || {
+        if let Some(stdin) = stdin.as_mut() {
+            // Scripts may exit without reading their input
+            stdin.write_all(&body).unwrap_or(());
+        }
+    });
+
+    let mut stdout = child.stdout.take();
+    let reader = thread::spawn(move || {
+        let mut output = vec![];
+        if let Some(stdout) = stdout.as_mut() {
+            stdout.read_to_end(&mut output).map(|_| output)
+        } else {
+            Ok(output)
+        }
+    });
+
+    // Wait for the script to exit or the deadline to pass
+    let deadline = Instant::now() + timeout;
+    loop {
+        if child.try_wait()?.is_some() {
+            break;
+        }
+        if Instant::now() >= deadline {
+            child.kill().unwrap_or(());
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    writer.join().unwrap_or(());
+    reader
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("CGI output reader panicked")))
+        .map(Some)
+}
+
+/// Converts the header block and body written by a script into a Response
+///
+/// The `output` is everything the script wrote to stdout
+///
+/// Returns the Response or a message describing why the output is invalid
+fn parse_cgi_output(output: &[u8]) -> Result<Response, String> {
+    // Find the blank line ending the header block, accepting bare LF line endings
+    let (head_len, separator_len) = [b"\r\n\r\n".as_slice(), b"\n\n".as_slice()]
+        .iter()
+        .filter_map(|sep| {
+            output
+                .windows(sep.len())
+                .position(|window| window == *sep)
+                .map(|pos| (pos, sep.len()))
+        })
+        .min()
+        .ok_or("missing blank line after headers")?;
+
+    let head = String::from_utf8_lossy(&output[..head_len]);
+    let body = output[head_len + separator_len..].to_vec();
+
+    let mut res = Response::default();
+    let mut status = None;
+    let mut has_location = false;
+    let mut has_content_length = false;
+
+    for line in head.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            return Err(format!("malformed header line {line:?}"));
+        };
+        let (key, value) = (key.trim(), value.trim());
+
+        match key.to_lowercase().as_str() {
+            "status" => {
+                let (code, reason) = value.split_once(' ').unwrap_or((value, ""));
+                let code = code
+                    .parse::<usize>()
+                    .map_err(|_| format!("invalid status {value:?}"))?;
+                status = Some((code, reason.to_owned()));
+            }
+            "location" => {
+                has_location = true;
+                res.add_header(("Location".to_owned(), value.to_owned()));
+            }
+            "content-length" => {
+                has_content_length = true;
+                res.add_header(("Content-Length".to_owned(), value.to_owned()));
+            }
+            // Scripts may set several cookies, whose attributes contain commas
+            "set-cookie" => res.add_set_cookie_header(value.to_owned()),
+            // Framing is decided by the server
+            "connection" | "transfer-encoding" => {}
+            _ => res.add_header((key.to_owned(), value.to_owned())),
+        }
+    }
+
+    // Scripts that only send a Location header are redirects
+    match status {
+        Some((code, reason)) => res.set_custom_status(code, &reason),
+        None if has_location => res.set_custom_status(302, "Found"),
+        None => res.set_status(200),
+    }
+
+    res.add_header(set_date_header());
+    if !has_content_length {
+        res.add_header(("Content-Length".to_owned(), body.len().to_string()));
+    }
+    res.set_body_bytes(body);
+
+    Ok(res)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+    use tempfile::tempdir;
+
+    /// Helper function to create an executable script
+    ///
+    /// `dir` is the CGI directory, `name` is the script name, and `contents` is the script source
+    fn create_script(dir: &Path, name: &str, contents: &str) {
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    /// Helper function to build a request as the server would parse it
+    fn request(lines: &[&str], body: &[u8]) -> Request {
+        let mut req = Request::default();
+        req.parse_status_line(lines[0].to_owned());
+        for line in &lines[1..] {
+            req.append_header(line.to_string());
+        }
+        req.set_body(body);
+        req
+    }
+
+    /// Helper function to send a Response into a String
+    fn sent(res: Response) -> String {
+        let mut out = vec![];
+        res.send(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Helper function to build a CGI configuration for a directory
+    fn config(dir: &Path) -> CgiConfig {
+        CgiConfig {
+            dir: dir.to_path_buf(),
+            ..CgiConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_script_receives_environment_and_body() {
+        let temp_dir = tempdir().unwrap();
+        create_script(
+            temp_dir.path(),
+            "echo.sh",
+            "#!/bin/sh\nprintf 'Content-Type: text/plain\\r\\n\\r\\n'\nprintf '%s|%s|%s|%s|' \"$REQUEST_METHOD\" \"$QUERY_STRING\" \"$PATH_INFO\" \"$CONTENT_TYPE\"\ncat\n",
+        );
+        let req = request(
+            &[
+                "POST /cgi-bin/echo.sh/extra/path?a=1 HTTP/1.1",
+                "Host: localhost",
+                "Content-Type: text/plain",
+            ],
+            b"payload",
+        );
+
+        let raw = sent(cgi(&req, &config(temp_dir.path()), &SiteConfig::default()));
+
+        assert!(raw.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(raw.contains("Content-Type: text/plain\r\n"));
+        assert!(raw.ends_with("\r\n\r\nPOST|a=1|/extra/path|text/plain|payload"));
+    }
+
+    #[test]
+    fn test_status_and_location_headers() {
+        let temp_dir = tempdir().unwrap();
+        create_script(
+            temp_dir.path(),
+            "missing.sh",
+            "#!/bin/sh\necho 'Status: 404 Not Found'\necho\necho gone\n",
+        );
+        create_script(
+            temp_dir.path(),
+            "redirect.sh",
+            "#!/bin/sh\necho 'Location: /index.html'\necho 'Set-Cookie: a=1'\necho 'Set-Cookie: b=2'\necho\n",
+        );
+        let cgi_config = config(temp_dir.path());
+
+        let missing = request(&["GET /cgi-bin/missing.sh HTTP/1.1"], b"");
+        let raw = sent(cgi(&missing, &cgi_config, &SiteConfig::default()));
+        assert!(raw.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(raw.ends_with("gone\n"));
+
+        let redirect = request(&["GET /cgi-bin/redirect.sh HTTP/1.1"], b"");
+        let raw = sent(cgi(&redirect, &cgi_config, &SiteConfig::default()));
+        assert!(raw.starts_with("HTTP/1.1 302 Found\r\n"));
+        assert!(raw.contains("Location: /index.html\r\n"));
+        assert!(raw.contains("Set-Cookie: a=1\r\nSet-Cookie: b=2\r\n"));
+    }
+
+    #[test]
+    fn test_slow_script_times_out() {
+        let temp_dir = tempdir().unwrap();
+        create_script(temp_dir.path(), "slow.sh", "#!/bin/sh\nexec sleep 5\n");
+        let mut cgi_config = config(temp_dir.path());
+        cgi_config.timeout_secs = 1;
+
+        let req = request(&["GET /cgi-bin/slow.sh HTTP/1.1"], b"");
+        let res = cgi(&req, &cgi_config, &SiteConfig::default());
+        assert_eq!(res.get_status(), Some(504));
+    }
+
+    #[test]
+    fn test_unknown_script_is_not_found() {
+        let temp_dir = tempdir().unwrap();
+        let req = request(&["GET /cgi-bin/../secret.sh HTTP/1.1"], b"");
+        let res = cgi(&req, &config(temp_dir.path()), &SiteConfig::default());
+        assert_eq!(res.get_status(), Some(404));
+    }
+}
@@ -0,0 +1,112 @@
+use std::io::{self, BufRead, Read, Write};
+
+/// Decodes a body sent with `Transfer-Encoding: chunked`
+///
+/// Reads from `inner` until the terminating zero-sized chunk. Trailers are discarded
+pub struct ChunkedReader<R> {
+    inner: R,
+    remaining: usize,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    /// Creates a new ChunkedReader
+    ///
+    /// The `inner` is the reader positioned at the start of the chunked body
+    pub fn new(inner: R) -> ChunkedReader<R> {
+        ChunkedReader {
+            inner,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    /// Reads the next chunk-size line and returns its value
+    fn read_chunk_size(&mut self) -> io::Result<usize> {
+        let mut line = String::new();
+        if self.inner.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Chunked body ended early",
+            ));
+        }
+
+        // Ignore chunk extensions
+        let size = line.trim().split(';').next().unwrap_or("");
+        usize::from_str_radix(size.trim(), 16)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid chunk size"))
+    }
+
+    /// Consumes the line ending that follows each chunk
+    fn read_crlf(&mut self) -> io::Result<()> {
+        let mut line = String::new();
+        self.inner.read_line(&mut line)?;
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.remaining == 0 {
+            self.remaining = self.read_chunk_size()?;
+
+            if self.remaining == 0 {
+                // Skip trailers up to the final empty line
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    if self.inner.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                        break;
+                    }
+                }
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let limit = buf.len().min(self.remaining);
+        let n = self.inner.read(&mut buf[..limit])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Chunked body ended early",
+            ));
+        }
+
+        self.remaining -= n;
+        if self.remaining == 0 {
+            self.read_crlf()?;
+        }
+
+        Ok(n)
+    }
+}
+
+/// Copies everything from `reader` to `writer` using chunked transfer encoding
+///
+/// Returns the number of body bytes written, excluding chunk framing
+pub fn write_chunked(reader: &mut impl Read, writer: &mut impl Write) -> io::Result<u64> {
+    let mut buf = [0; 8192];
+    let mut total = 0;
+
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+
+        write!(writer, "{n:x}\r\n")?;
+        writer.write_all(&buf[..n])?;
+        writer.write_all(b"\r\n")?;
+        total += n as u64;
+    }
+
+    writer.write_all(b"0\r\n\r\n")?;
+    Ok(total)
+}
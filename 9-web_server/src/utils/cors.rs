@@ -0,0 +1,287 @@
+use crate::{
+    models::{HttpMethod, Request, Response, SiteConfig},
+    utils::{allowed_methods, set_date_header},
+};
+
+/// `Vary` header of preflight responses, which depend on every preflight request header
+const PREFLIGHT_VARY: &str =
+    "Origin, Access-Control-Request-Method, Access-Control-Request-Headers";
+
+/// Checks whether a request is a CORS preflight the site should answer
+///
+/// The `req` is the Request to check and `site` is the virtual host it was addressed to
+pub fn is_preflight(req: &Request, site: &SiteConfig) -> bool {
+    site.cors.is_some()
+        && matches!(req.get_method(), HttpMethod::Options)
+        && req.get_headers().contains_key("origin")
+        && req
+            .get_headers()
+            .contains_key("access-control-request-method")
+}
+
+/// Answers a CORS preflight request
+///
+/// The `req` is the preflight `OPTIONS` Request and `site` is the virtual host it was addressed to. Preflights the policy doesn't allow are answered without `Access-Control-*` headers, which makes the browser block the actual request
+///
+/// Returns a `204` Response
+pub fn preflight(req: &Request, site: &SiteConfig) -> Response {
+    let mut res = Response::default();
+    res.set_status(204);
+    res.add_header(set_date_header());
+
+    let methods = allowed_methods(req, site);
+    res.add_header(("Allow".to_owned(), methods.join(", ")));
+
+    let Some(cors) = &site.cors else {
+        return res;
+    };
+    let headers = req.get_headers();
+    let origin = headers.get("origin").map(String::as_str).unwrap_or("");
+    let method = headers
+        .get("access-control-request-method")
+        .map(String::as_str)
+        .unwrap_or("");
+    let requested_headers: Vec<&str> = headers
+        .get("access-control-request-headers")
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|h| !h.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    // Check the origin, method, and headers against the policy
+    let methods = if cors.methods.is_empty() {
+        methods
+    } else {
+        cors.methods.clone()
+    };
+    let method_allowed = methods.iter().any(|m| m.eq_ignore_ascii_case(method));
+    let headers_allowed = cors.headers.is_empty()
+        || requested_headers.iter().all(|h| {
+            cors.headers
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(h))
+        });
+    res.add_header(("Vary".to_owned(), PREFLIGHT_VARY.to_owned()));
+    if !cors.allows_origin(origin) || !method_allowed || !headers_allowed {
+        return res;
+    }
+
+    add_origin_headers(&mut res, site, origin);
+    res.add_header((
+        "Access-Control-Allow-Methods".to_owned(),
+        methods.join(", "),
+    ));
+    if !requested_headers.is_empty() {
+        let allowed = if cors.headers.is_empty() {
+            requested_headers.join(", ")
+        } else {
+            cors.headers.join(", ")
+        };
+        res.add_header(("Access-Control-Allow-Headers".to_owned(), allowed));
+    }
+    if let Some(max_age) = cors.max_age_secs {
+        res.add_header(("Access-Control-Max-Age".to_owned(), max_age.to_string()));
+    }
+
+    res
+}
+
+/// Adds CORS headers to the response to an actual (non-preflight) request
+///
+/// The `origin` is the request's `Origin` header, `site` is the virtual host it was addressed to, and `res` is the Response to be sent
+pub fn apply_cors(origin: Option<&str>, site: &SiteConfig, res: &mut Response) {
+    let Some(cors) = &site.cors else {
+        return;
+    };
+    let vary = match res.get_headers().get("Vary") {
+        Some(vary) => format!("{vary}, Origin"),
+        None => "Origin".to_owned(),
+    };
+    res.add_header(("Vary".to_owned(), vary));
+
+    let Some(origin) = origin.filter(|origin| cors.allows_origin(origin)) else {
+        return;
+    };
+    add_origin_headers(res, site, origin);
+    if !cors.expose_headers.is_empty() {
+        res.add_header((
+            "Access-Control-Expose-Headers".to_owned(),
+            cors.expose_headers.join(", "),
+        ));
+    }
+}
+
+/// Adds `Access-Control-Allow-Origin` and, if enabled, `Access-Control-Allow-Credentials`
+///
+/// The wildcard origin is only sent for policies allowing every origin without credentials, since browsers reject it otherwise
+fn add_origin_headers(res: &mut Response, site: &SiteConfig, origin: &str) {
+    let Some(cors) = &site.cors else {
+        return;
+    };
+
+    let allowed = if cors.allows_any_origin() && !cors.credentials {
+        "*"
+    } else {
+        origin
+    };
+    res.add_header(("Access-Control-Allow-Origin".to_owned(), allowed.to_owned()));
+    if cors.credentials {
+        res.add_header((
+            "Access-Control-Allow-Credentials".to_owned(),
+            "true".to_owned(),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    /// Helper function to build a site with a CORS policy rooted at a directory
+    fn cors_site(root: &std::path::Path, extra: &str) -> SiteConfig {
+        let mut site: SiteConfig = toml::from_str(&format!(
+            r#"
+            methods = ["GET", "PUT", "DELETE", "OPTIONS"]
+
+            [cors]
+            origins = ["http://localhost:*"]
+            {extra}
+            "#
+        ))
+        .unwrap();
+        site.root = root.to_path_buf();
+        site
+    }
+
+    /// Helper function to build a request with headers
+    fn request(site: &SiteConfig, status_line: &str, headers: &[&str]) -> Request {
+        let mut req = Request::default();
+        req.parse_status_line(status_line.to_owned());
+        for header in headers {
+            req.append_header(header.to_string());
+        }
+        req.set_document_root(&site.root, &site.index);
+        req
+    }
+
+    #[test]
+    fn test_preflight_reflects_target_methods() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("data.json"), "{}").unwrap();
+        let site = cors_site(temp_dir.path(), "max_age_secs = 60");
+
+        let req = request(
+            &site,
+            "OPTIONS /data.json HTTP/1.1",
+            &[
+                "Origin: http://localhost:5173",
+                "Access-Control-Request-Method: PUT",
+                "Access-Control-Request-Headers: content-type, x-token",
+            ],
+        );
+        assert!(is_preflight(&req, &site));
+
+        let res = preflight(&req, &site);
+        let headers = res.get_headers();
+        assert_eq!(res.get_status(), Some(204));
+        assert_eq!(headers["Allow"], "GET, PUT, DELETE, OPTIONS");
+        assert_eq!(
+            headers["Access-Control-Allow-Origin"],
+            "http://localhost:5173"
+        );
+        assert_eq!(
+            headers["Access-Control-Allow-Methods"],
+            "GET, PUT, DELETE, OPTIONS"
+        );
+        assert_eq!(
+            headers["Access-Control-Allow-Headers"],
+            "content-type, x-token"
+        );
+        assert_eq!(headers["Access-Control-Max-Age"], "60");
+
+        // Missing files can't be read or deleted
+        let req = request(
+            &site,
+            "OPTIONS /new.json HTTP/1.1",
+            &[
+                "Origin: http://localhost:5173",
+                "Access-Control-Request-Method: DELETE",
+            ],
+        );
+        let res = preflight(&req, &site);
+        assert_eq!(res.get_headers()["Allow"], "PUT, OPTIONS");
+        assert!(
+            !res.get_headers()
+                .contains_key("Access-Control-Allow-Origin")
+        );
+    }
+
+    #[test]
+    fn test_disallowed_preflights_get_no_cors_headers() {
+        let temp_dir = tempdir().unwrap();
+        let site = cors_site(temp_dir.path(), r#"headers = ["Content-Type"]"#);
+
+        let req = request(
+            &site,
+            "OPTIONS /a.json HTTP/1.1",
+            &[
+                "Origin: https://evil.example",
+                "Access-Control-Request-Method: PUT",
+            ],
+        );
+        assert!(
+            !preflight(&req, &site)
+                .get_headers()
+                .contains_key("Access-Control-Allow-Origin")
+        );
+
+        let req = request(
+            &site,
+            "OPTIONS /a.json HTTP/1.1",
+            &[
+                "Origin: http://localhost:3000",
+                "Access-Control-Request-Method: PUT",
+                "Access-Control-Request-Headers: X-Token",
+            ],
+        );
+        assert!(
+            !preflight(&req, &site)
+                .get_headers()
+                .contains_key("Access-Control-Allow-Origin")
+        );
+    }
+
+    #[test]
+    fn test_actual_responses() {
+        let temp_dir = tempdir().unwrap();
+        let site = cors_site(
+            temp_dir.path(),
+            r#"credentials = true
+            expose_headers = ["ETag"]"#,
+        );
+
+        let mut res = Response::default();
+        apply_cors(Some("http://localhost:8080"), &site, &mut res);
+        let headers = res.get_headers();
+        assert_eq!(
+            headers["Access-Control-Allow-Origin"],
+            "http://localhost:8080"
+        );
+        assert_eq!(headers["Access-Control-Allow-Credentials"], "true");
+        assert_eq!(headers["Access-Control-Expose-Headers"], "ETag");
+        assert_eq!(headers["Vary"], "Origin");
+
+        let mut res = Response::default();
+        apply_cors(Some("https://evil.example"), &site, &mut res);
+        assert!(
+            !res.get_headers()
+                .contains_key("Access-Control-Allow-Origin")
+        );
+    }
+}
@@ -0,0 +1,273 @@
+use std::path::Path;
+
+use minijinja::{Environment, Value, context, path_loader};
+use serde_json::json;
+
+use crate::{
+    log_error,
+    models::{Request, Response, SiteConfig},
+    utils::{is_template, read_file, request_context, set_date_header},
+};
+
+/// HTML page sent when a site has no error page for a status code
+const FALLBACK_HTML: &str = r#"<!DOCTYPE html>
+<html lang="en">
+  <head>
+    <meta charset="utf-8" />
+    <title>{{ status }} {{ reason }}</title>
+  </head>
+  <body>
+    <h1>{{ status }} {{ reason }}</h1>
+    <p>Request ID: <code>{{ request_id }}</code></p>
+    <p>Trace ID: <code>{{ trace_id }}</code></p>
+  </body>
+</html>
+"#;
+
+/// Representations an error can be sent in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Html,
+    Json,
+    Text,
+}
+
+impl ErrorFormat {
+    /// Returns the media type of the format
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            ErrorFormat::Html => "text/html",
+            ErrorFormat::Json => "application/json",
+            ErrorFormat::Text => "text/plain",
+        }
+    }
+}
+
+/// Chooses the format of an error body from an `Accept` header
+///
+/// The `accept` is the header value, if sent, and `default` is the format used when the client accepts every format equally
+///
+/// Returns the acceptable format with the highest quality, or `default` if the client accepts none of them
+pub fn negotiate_error_format(accept: Option<&str>, default: ErrorFormat) -> ErrorFormat {
+    let Some(accept) = accept else {
+        return default;
+    };
+
+    // Parse the media ranges and their quality values
+    let ranges: Vec<(&str, f32)> = accept
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let media_type = params.next()?.trim();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            (!media_type.is_empty()).then_some((media_type, quality))
+        })
+        .collect();
+
+    // The most specific range matching a format decides its quality
+    let quality = |format: ErrorFormat| {
+        let media_type = format.media_type();
+        let (kind, _) = media_type.split_once('/').unwrap_or_default();
+        ranges
+            .iter()
+            .filter_map(|(range, quality)| {
+                let specificity = if range.eq_ignore_ascii_case(media_type) {
+                    2
+                } else if range
+                    .strip_suffix("/*")
+                    .is_some_and(|range_kind| range_kind.eq_ignore_ascii_case(kind))
+                {
+                    1
+                } else if *range == "*/*" {
+                    0
+                } else {
+                    return None;
+                };
+                Some((specificity, *quality))
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map_or(0.0, |(_, quality)| quality)
+    };
+
+    // Prefer the default on ties, then HTML, JSON, and plain text
+    let mut best = (default, quality(default));
+    for format in [ErrorFormat::Html, ErrorFormat::Json, ErrorFormat::Text] {
+        let q = quality(format);
+        if q > best.1 {
+            best = (format, q);
+        }
+    }
+    if best.1 > 0.0 { best.0 } else { default }
+}
+
+/// Builds an error response in the format the client prefers
+///
+/// The `code` is the HTTP status code to send, `req` is the Request being answered, and `site` provides the error pages. HTML clients get the site's page for the code, or a built-in page if it is missing. Requests to the site's API default to JSON
+///
+/// Returns a `Response` whose body carries the request and trace ids, and whose `X-Request-Id` header carries the request id
+pub(crate) fn error_response(code: usize, req: &Request, site: &SiteConfig) -> Response {
+    let mut res = Response::default();
+    res.set_status(code);
+    res.add_header(set_date_header());
+    res.add_header(("X-Request-Id".to_owned(), req.get_request_id().to_owned()));
+
+    let default = if site.find_api(req.get_path()).is_some() {
+        ErrorFormat::Json
+    } else {
+        ErrorFormat::Html
+    };
+    let format =
+        negotiate_error_format(req.get_headers().get("accept").map(String::as_str), default);
+    res.add_header(("Vary".to_owned(), "Accept".to_owned()));
+
+    let reason = res.get_description().unwrap_or("Error").to_owned();
+    let request_id = req.get_request_id();
+    let trace_id = req.get_trace().get_trace_id();
+    let contents = match format {
+        ErrorFormat::Html => html_page(code, &reason, req, site),
+        ErrorFormat::Json => json!({
+            "error": reason,
+            "status": code,
+            "request_id": request_id,
+            "trace_id": trace_id,
+        })
+        .to_string(),
+        ErrorFormat::Text => {
+            format!("{code} {reason}\nRequest ID: {request_id}\nTrace ID: {trace_id}\n")
+        }
+    };
+    res.add_header(("Content-Type".to_owned(), format.media_type().to_owned()));
+    res.add_header(("Content-Length".to_owned(), contents.len().to_string()));
+    res.set_body(Some(contents));
+
+    res
+}
+
+/// Renders the HTML error page for a status code
+///
+/// Pages ending in `.tmpl.html` are rendered with `status`, `reason`, `request_id`, `trace_id`, and `request` in their context. Missing or broken pages fall back to a built-in one
+fn html_page(code: usize, reason: &str, req: &Request, site: &SiteConfig) -> String {
+    let context = context! {
+        status => code,
+        reason => reason,
+        request_id => req.get_request_id(),
+        trace_id => req.get_trace().get_trace_id(),
+        request => request_context(req).get_attr("request").unwrap_or_default(),
+    };
+
+    let page = site.error_page(code);
+    let rendered = if !page.is_file() {
+        None
+    } else if is_template(&page) {
+        render_page(&page, context.clone())
+            .inspect_err(|err| log_error!("Error rendering {}: {err}", page.display()))
+            .ok()
+    } else {
+        read_file(&page)
+    };
+
+    // Named like an HTML file so the request id and reason are escaped
+    rendered.unwrap_or_else(|| {
+        let mut env = Environment::new();
+        env.add_template("fallback.html", FALLBACK_HTML)
+            .and_then(|_| env.get_template("fallback.html")?.render(context))
+            .unwrap_or_default()
+    })
+}
+
+/// Renders an error page template, resolving includes against its directory
+fn render_page(page: &Path, context: Value) -> Result<String, String> {
+    let dir = page.parent().unwrap_or(Path::new("."));
+    let name = page
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or("template name is not valid UTF-8")?;
+
+    let mut env = Environment::new();
+    env.set_loader(path_loader(dir));
+    let template = env.get_template(name).map_err(|err| err.to_string())?;
+    template.render(context).map_err(|err| format!("{err:#}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_negotiate_error_format() {
+        let html = ErrorFormat::Html;
+        assert_eq!(negotiate_error_format(None, html), ErrorFormat::Html);
+        assert_eq!(
+            negotiate_error_format(Some("*/*"), ErrorFormat::Json),
+            ErrorFormat::Json
+        );
+        assert_eq!(
+            negotiate_error_format(Some("application/json"), html),
+            ErrorFormat::Json
+        );
+        assert_eq!(
+            negotiate_error_format(
+                Some("text/html;q=0.5, application/json;q=0.9, */*;q=0.1"),
+                html
+            ),
+            ErrorFormat::Json
+        );
+        assert_eq!(
+            negotiate_error_format(Some("text/*, text/html;q=0"), html),
+            ErrorFormat::Text
+        );
+        assert_eq!(
+            negotiate_error_format(Some("image/png"), html),
+            ErrorFormat::Html
+        );
+    }
+
+    #[test]
+    fn test_error_pages() {
+        let temp_dir = tempdir().unwrap();
+        let page = temp_dir.path().join("missing.tmpl.html");
+        fs::write(
+            &page,
+            "<p>{{ request.method }} was {{ reason }} ({{ request_id }})</p>",
+        )
+        .unwrap();
+        let mut site = SiteConfig {
+            root: temp_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+
+        let mut req = Request::default();
+        req.parse_status_line("GET /nothing.html HTTP/1.1".to_owned());
+        let id = req.get_request_id().to_owned();
+        let trace_id = req.get_trace().get_trace_id().to_owned();
+
+        // Missing pages fall back to the built-in one
+        let res = error_response(404, &req, &site);
+        assert_eq!(res.get_headers()["Content-Type"], "text/html");
+        assert_eq!(res.get_headers()["X-Request-Id"], id);
+        assert!(res.get_headers().contains_key("Content-Length"));
+        let body = String::from_utf8(res.get_body().unwrap().to_vec()).unwrap();
+        assert!(body.contains(&format!("<code>{id}</code>")));
+
+        // Overridden codes render their template
+        site.error_pages.insert("404".to_owned(), page);
+        let mut body = vec![];
+        error_response(404, &req, &site).send(&mut body).unwrap();
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.ends_with(&format!("<p>GET was Not Found ({id})</p>")));
+
+        req.append_header("Accept: application/json".to_owned());
+        let mut body = vec![];
+        error_response(503, &req, &site).send(&mut body).unwrap();
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains("Content-Type: application/json"));
+        assert!(body.ends_with(&format!(
+            r#"{{"error":"Service Unavailable","request_id":"{id}","status":503,"trace_id":"{trace_id}"}}"#
+        )));
+    }
+}
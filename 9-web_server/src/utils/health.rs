@@ -0,0 +1,47 @@
+use std::fs;
+
+use serde_json::json;
+
+use crate::{models::Response, models::ServerState, utils::json_response};
+
+/// Answers a liveness probe
+///
+/// Returns a `200` Response as long as the process can answer at all
+pub fn healthz() -> Response {
+    json_response(200, &json!({ "status": "ok" }))
+}
+
+/// Answers a readiness probe
+///
+/// The `state` is the shared server state. The server is ready when it isn't draining, every site's document root is readable, and the ThreadPool has a worker free or nothing queued
+///
+/// Returns a `200` Response if ready, or `503` listing the failed checks
+pub fn readyz(state: &ServerState) -> Response {
+    let config = state.config();
+    let mut failures = vec![];
+
+    if state.is_draining() {
+        failures.push("server is draining".to_owned());
+    }
+    for site in std::iter::once(&config.default_site).chain(&config.sites) {
+        if let Err(err) = fs::read_dir(&site.root) {
+            failures.push(format!("{} is not readable: {err}", site.root.display()));
+        }
+    }
+    if state.pool.busy() >= state.pool.size() && state.pool.queued() > 0 {
+        failures.push(format!(
+            "all {} workers are busy with {} connections queued",
+            state.pool.size(),
+            state.pool.queued()
+        ));
+    }
+
+    if failures.is_empty() {
+        json_response(200, &json!({ "status": "ready" }))
+    } else {
+        json_response(
+            503,
+            &json!({ "status": "unavailable", "failures": failures }),
+        )
+    }
+}
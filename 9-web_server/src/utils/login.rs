@@ -0,0 +1,186 @@
+use crate::{
+    log_error,
+    models::{HttpMethod, Request, Response, SessionConfig, SiteConfig},
+    utils::{error_response, parse_urlencoded, percent_encode, redirect, verify_basic},
+};
+
+/// Checks whether a request logs in or out of a site's sessions
+///
+/// The `req` is the Request to check and `sessions` is the site's session configuration
+pub fn is_login_request(req: &Request, sessions: &SessionConfig) -> bool {
+    matches!(req.get_method(), HttpMethod::Post)
+        && (req.get_path() == sessions.login_path || req.get_path() == sessions.logout_path)
+}
+
+/// Handles a login or logout form post
+///
+/// The `req` is the Request with its urlencoded body read, `sessions` is the site's session configuration, and `site` provides the error pages. Logins post `user`, `password`, and optionally `next`, the local path to return to
+///
+/// Returns a `303` Response to `next` on login, back to the login form on failure, or to the login form on logout
+pub fn login(req: &Request, sessions: &SessionConfig, site: &SiteConfig) -> Response {
+    let manager = sessions.manager();
+    let mut session = manager.load(req);
+
+    let mut res = if req.get_path() == sessions.logout_path {
+        session.destroy();
+        redirect(303, &sessions.login_path)
+    } else {
+        let form = parse_urlencoded(&String::from_utf8_lossy(req.get_body()));
+        let field = |name: &str| {
+            form.iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        let (user, password) = (field("user").unwrap_or(""), field("password").unwrap_or(""));
+        // Only return to local paths, so the form can't be used to redirect elsewhere
+        let next = field("next")
+            .filter(|next| is_local_path(next))
+            .unwrap_or("/");
+
+        let Some(htpasswd) = &sessions.htpasswd else {
+            return error_response(500, req, site);
+        };
+        match verify_basic(htpasswd, user, password) {
+            Ok(true) => {
+                // A new id stops anyone who knew the old one from sharing the login
+                session.rotate();
+                session.insert("user", user);
+                redirect(303, next)
+            }
+            Ok(false) => {
+                log_error!("Failed login for {user:?}");
+                redirect(
+                    303,
+                    &format!(
+                        "{}?error=invalid&next={}",
+                        sessions.login_path,
+                        percent_encode(next)
+                    ),
+                )
+            }
+            Err(err) => {
+                log_error!("Error reading {}: {err}", htpasswd.display());
+                return error_response(500, req, site);
+            }
+        }
+    };
+
+    if let Err(err) = manager.save(&session, &mut res) {
+        log_error!("Error saving session: {err}");
+        return error_response(500, req, site);
+    }
+    res
+}
+
+/// Checks whether a path to return to after logging in stays on this site
+///
+/// Browsers treat `//host` and `/\host` as other hosts, and control characters could split the `Location` header
+fn is_local_path(next: &str) -> bool {
+    next.starts_with('/')
+        && !next.starts_with("//")
+        && !next.contains('\\')
+        && !next.chars().any(char::is_control)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::check_access;
+    use std::fs;
+    use tempfile::tempdir;
+
+    /// Helper function to build a request with headers and a body
+    fn request(site: &SiteConfig, status_line: &str, cookie: Option<&str>, body: &str) -> Request {
+        let mut req = Request::default();
+        req.parse_status_line(status_line.to_owned());
+        if let Some(cookie) = cookie {
+            req.append_header(format!("Cookie: {cookie}"));
+        }
+        req.set_body(body.as_bytes());
+        req.set_document_root(&site.root, &site.index);
+        req
+    }
+
+    #[test]
+    fn test_login_protected_pages() {
+        let temp_dir = tempdir().unwrap();
+        let htpasswd = temp_dir.path().join("users.htpasswd");
+        fs::write(
+            &htpasswd,
+            format!("alice:{}\n", bcrypt::hash("secret", 4).unwrap()),
+        )
+        .unwrap();
+        let site: SiteConfig = toml::from_str(&format!(
+            r#"
+            [sessions]
+            htpasswd = {htpasswd:?}
+
+            [[access]]
+            prefix = "/members/"
+            policy = "session"
+            "#
+        ))
+        .unwrap();
+        let sessions = site.sessions.as_ref().unwrap();
+
+        // Visitors without a session are sent to the login form
+        let mut req = request(&site, "GET /members/a.html?x=1 HTTP/1.1", None, "");
+        let res = check_access(&mut req, &site).unwrap();
+        assert_eq!(res.get_status(), Some(303));
+        assert_eq!(
+            res.get_headers()["Location"],
+            "/login.html?next=/members/a.html%3Fx%3D1"
+        );
+
+        let req = request(
+            &site,
+            "POST /login.html HTTP/1.1",
+            None,
+            "user=alice&password=wrong&next=/members/a.html",
+        );
+        assert!(is_login_request(&req, sessions));
+        let res = login(&req, sessions, &site);
+        assert!(res.get_headers()["Location"].contains("error=invalid"));
+        assert!(res.get_cookies().is_empty());
+
+        let req = request(
+            &site,
+            "POST /login.html HTTP/1.1",
+            None,
+            "user=alice&password=secret&next=/members/a.html",
+        );
+        let res = login(&req, sessions, &site);
+        assert_eq!(res.get_headers()["Location"], "/members/a.html");
+
+        // Return paths leading off the site or into the headers go to the home page instead
+        for next in [
+            "//evil.com",
+            "/%5Cevil.com",
+            "/a%0D%0ASet-Cookie:%20x=1",
+            "https://evil.com",
+        ] {
+            let req = request(
+                &site,
+                "POST /login.html HTTP/1.1",
+                None,
+                &format!("user=alice&password=secret&next={next}"),
+            );
+            let res = login(&req, sessions, &site);
+            assert_eq!(res.get_headers()["Location"], "/", "{next}");
+        }
+        let cookie = &res.get_cookies()[0];
+        assert!(cookie.http_only);
+        let cookie = format!("{}={}", cookie.name, cookie.value);
+
+        let mut req = request(&site, "GET /members/a.html HTTP/1.1", Some(&cookie), "");
+        assert!(check_access(&mut req, &site).is_none());
+        assert_eq!(req.get_identity(), Some("alice"));
+
+        // Logging out ends the session
+        let req = request(&site, "POST /logout HTTP/1.1", Some(&cookie), "");
+        let res = login(&req, sessions, &site);
+        assert_eq!(res.get_cookies()[0].max_age, Some(0));
+        let mut req = request(&site, "GET /members/a.html HTTP/1.1", Some(&cookie), "");
+        assert!(check_access(&mut req, &site).is_some());
+    }
+}
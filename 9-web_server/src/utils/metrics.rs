@@ -0,0 +1,27 @@
+use crate::{
+    models::{Response, ServerState},
+    utils::set_date_header,
+};
+
+/// Builds the response to a Prometheus scrape
+///
+/// The `state` is the shared server state whose metrics are reported
+///
+/// Returns a `Response` containing the metrics in the Prometheus text format
+pub fn metrics_response(state: &ServerState) -> Response {
+    let cache = state.config().cache.enabled.then_some(&state.cache);
+    let contents = state
+        .metrics
+        .render(state.connections.len(), &state.pool, cache);
+
+    let mut res = Response::default();
+    res.set_status(200);
+    res.add_header(set_date_header());
+    res.add_header((
+        "Content-Type".to_owned(),
+        "text/plain; version=0.0.4; charset=utf-8".to_owned(),
+    ));
+    res.add_header(("Content-Length".to_owned(), contents.len().to_string()));
+    res.set_body(Some(contents));
+    res
+}
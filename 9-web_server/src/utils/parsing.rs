@@ -1,46 +1,180 @@
-use std::{
-    io::{BufRead, BufReader, Read},
-    net::TcpStream,
-};
+use std::io::{BufRead, Read};
 
-use crate::models::Request;
+use crate::{models::Request, utils::ChunkedReader};
 
 /// Parses HTTP request from client
 ///
 /// The `buf_reader` is a buffered reader containing the `TcpStream` for easier processing
 ///
 /// Returns a processed Request containing all parsed data
-pub fn parse_request(buf_reader: &mut BufReader<&TcpStream>) -> Request {
+pub fn parse_request(buf_reader: &mut impl BufRead) -> Request {
+    let mut req = parse_request_head(buf_reader);
+    read_request_body(buf_reader, &mut req);
+
+    // Return constructed Reqest
+    req
+}
+
+/// Parses the status line and headers of an HTTP request, leaving the body unread
+///
+/// The `buf_reader` is a buffered reader containing the `TcpStream` for easier processing
+///
+/// Returns a Request containing the parsed status line and headers, with the request id and trace context they carry
+pub fn parse_request_head(buf_reader: &mut impl BufRead) -> Request {
     let mut req = Request::default();
     let mut status_line = String::new();
 
     // Read the request line
-    buf_reader.read_line(&mut status_line).unwrap();
+    buf_reader.read_line(&mut status_line).unwrap_or(0);
     req.parse_status_line(status_line);
 
     // Read headers
     let mut line = String::new();
     loop {
         line.clear();
-        buf_reader.read_line(&mut line).unwrap();
-        let trimmed = line.trim_end().to_lowercase();
+        if buf_reader.read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        let trimmed = line.trim_end();
 
         if trimmed.is_empty() {
             break;
         }
-        req.append_header(trimmed);
+        req.append_header(trimmed.to_owned());
+    }
+    req.read_trace_headers();
+
+    req
+}
+
+/// Reads the request body, if present, into the Request
+///
+/// The `buf_reader` is positioned right after the request headers. Both `Content-Length` and chunked bodies are supported
+pub fn read_request_body(buf_reader: &mut impl BufRead, req: &mut Request) {
+    let headers = req.get_headers();
+    let chunked = headers
+        .get("transfer-encoding")
+        .is_some_and(|te| te.to_lowercase().contains("chunked"));
+
+    if chunked {
+        let mut body_buf = vec![];
+        ChunkedReader::new(buf_reader)
+            .read_to_end(&mut body_buf)
+            .unwrap_or(0);
+        req.set_body(&body_buf);
+    } else if let Some(cl) = headers.get("content-length")
+        && let Ok(content_length) = cl.parse::<usize>()
+    {
+        let mut body_buf = vec![0; content_length];
+        buf_reader.read_exact(&mut body_buf).unwrap_or(());
+        req.set_body(&body_buf);
     }
+}
 
-    // Read request body if present
-    if let Some(cl) = req.get_headers().get("content-length") {
-        if let Ok(content_length) = cl.parse::<usize>() {
-            let mut body_buf = vec![0; content_length];
-            buf_reader.read_exact(&mut body_buf).unwrap_or(());
-            // body = body_buf;
-            req.set_body(&body_buf);
+/// Decodes a percent-encoded URL component, treating `+` as a space
+///
+/// The `input` is the encoded string. Invalid escapes are kept as-is
+///
+/// Returns the decoded String, replacing invalid UTF-8 sequences
+pub fn percent_decode(input: &str) -> String {
+    decode(input, true)
+}
+
+/// Decodes a percent-encoded URL path, where `+` is a literal plus sign
+///
+/// The `input` is the encoded path. Invalid escapes are kept as-is
+///
+/// Returns the decoded String, replacing invalid UTF-8 sequences
+pub fn percent_decode_path(input: &str) -> String {
+    decode(input, false)
+}
+
+/// Canonicalizes a request path so that equivalent spellings of it compare equal
+///
+/// The `path` is the percent-encoded request path. Escapes are decoded, repeated slashes collapsed, and `.` segments dropped, keeping a trailing slash
+///
+/// Returns the decoded path starting with `/`, or None if it has a `..` segment
+pub fn canonical_path(path: &str) -> Option<String> {
+    let decoded = percent_decode_path(path);
+    let mut canonical = String::with_capacity(decoded.len() + 1);
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            segment => {
+                canonical.push('/');
+                canonical.push_str(segment);
+            }
         }
     }
 
-    // Return constructed Reqest
-    req
+    // Directory paths keep addressing the directory
+    if canonical.is_empty() || decoded.ends_with('/') || decoded.ends_with("/.") {
+        canonical.push('/');
+    }
+    Some(canonical)
+}
+
+/// Decodes percent escapes, and `+` as a space if `plus_as_space` is set
+fn decode(input: &str, plus_as_space: bool) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match (bytes[i], escaped) {
+            (_, Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', None) if plus_as_space => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, None) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Percent-encodes a URL component
+///
+/// The `input` is the raw string. Everything except unreserved characters (letters, digits, `-`, `.`, `_`, `~`) and `/` is escaped
+///
+/// Returns the encoded String
+pub fn percent_encode(input: &str) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+/// Parses an `application/x-www-form-urlencoded` string into decoded key-value pairs
+///
+/// The `input` is a query string or form body such as `a=1&b=two`
+pub fn parse_urlencoded(input: &str) -> Vec<(String, String)> {
+    input
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
 }
@@ -0,0 +1,594 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+use crate::{
+    log_error,
+    models::{ProxyRoute, Request, Response, SiteConfig},
+    utils::{ChunkedReader, error_response, percent_encode, write_chunked},
+};
+
+/// Headers that only apply to a single connection and must not be forwarded
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Headers identifying the request and its trace, replaced with this server's own on the way through
+const TRACE_HEADERS: &[&str] = &["x-request-id", "traceparent", "tracestate"];
+
+/// Forwards a request to one of the upstream servers of a proxy route
+///
+/// The `req` is the parsed request head, `body` is the client reader positioned at the start of the request body, `route` is the matched ProxyRoute, and `site` provides the error pages
+///
+/// Returns a `Response` whose body is streamed from the upstream server, or a `502`/`504` error response
+pub fn proxy(
+    req: &Request,
+    body: &mut impl BufRead,
+    route: &ProxyRoute,
+    site: &SiteConfig,
+) -> Response {
+    // Connect to the first reachable upstream in round-robin order
+    let Some((mut upstream, authority)) = connect_upstream(route) else {
+        return error_response(502, req, site);
+    };
+
+    // Forward request head and body
+    if let Err(err) = send_upstream_request(req, body, route, &authority, &mut upstream) {
+        log_error!("Error forwarding request to {authority}: {err}");
+        return error_response(if is_timeout(&err) { 504 } else { 502 }, req, site);
+    }
+
+    // Relay upstream response. Responses to HEAD have headers but no body
+    let head = req.get_method_name().eq_ignore_ascii_case("HEAD");
+    match read_upstream_response(BufReader::new(upstream), head) {
+        Ok(res) => res,
+        Err(err) => {
+            log_error!("Error reading response from {authority}: {err}");
+            error_response(if is_timeout(&err) { 504 } else { 502 }, req, site)
+        }
+    }
+}
+
+/// Opens a connection to the next available upstream of `route`
+///
+/// Returns the connected stream and the authority it was opened to, or None if every upstream failed
+fn connect_upstream(route: &ProxyRoute) -> Option<(TcpStream, String)> {
+    let timeout = route.timeout();
+
+    for authority in route.upstream_order() {
+        let addrs = match authority.to_socket_addrs() {
+            Ok(addrs) => addrs,
+            Err(err) => {
+                log_error!("Error resolving upstream {authority}: {err}");
+                continue;
+            }
+        };
+
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(timeout)).ok()?;
+                    stream.set_write_timeout(Some(timeout)).ok()?;
+                    return Some((stream, authority.to_owned()));
+                }
+                Err(err) => log_error!("Error connecting to upstream {authority}: {err}"),
+            }
+        }
+    }
+
+    None
+}
+
+/// Writes the request head and streams the request body to the upstream server
+///
+/// The `authority` is the `host:port` of the connected upstream
+fn send_upstream_request(
+    req: &Request,
+    body: &mut impl BufRead,
+    route: &ProxyRoute,
+    authority: &str,
+    upstream: &mut TcpStream,
+) -> io::Result<()> {
+    let headers = req.get_headers();
+
+    // Rewrite the target when the prefix is stripped, from the same canonical path the route matched
+    let target = if route.strip_prefix {
+        let path = req.get_path();
+        let rest = path
+            .strip_prefix(route.prefix.trim_end_matches('/'))
+            .unwrap_or(path);
+        let mut target = percent_encode(rest);
+        if !target.starts_with('/') {
+            target.insert(0, '/');
+        }
+        if let Some((_, query)) = req.get_target().split_once('?') {
+            target.push_str(&format!("?{query}"));
+        }
+        target
+    } else {
+        req.get_target().to_owned()
+    };
+
+    let mut head = format!("{} {target} HTTP/1.1\r\n", req.get_method_name());
+
+    // Copy end-to-end headers
+    let connection_tokens = connection_tokens(headers.get("connection"));
+    for (key, value) in headers {
+        if is_hop_by_hop(key, &connection_tokens)
+            || matches!(key.as_str(), "host" | "content-length")
+            || TRACE_HEADERS.contains(&key.as_str())
+        {
+            continue;
+        }
+        head.push_str(&format!("{key}: {value}\r\n"));
+    }
+
+    // Pass on the request id, and continue the trace with this request's span as the parent
+    head.push_str(&format!("X-Request-Id: {}\r\n", req.get_request_id()));
+    for (key, value) in req.get_trace().headers() {
+        head.push_str(&format!("{key}: {value}\r\n"));
+    }
+
+    // Rewrite Host
+    let client_host = headers.get("host").cloned().unwrap_or_default();
+    if route.preserve_host && !client_host.is_empty() {
+        head.push_str(&format!("Host: {client_host}\r\n"));
+    } else {
+        head.push_str(&format!("Host: {authority}\r\n"));
+    }
+
+    // Record the original client
+    if let Some(client_ip) = req.get_client_addr().map(|addr| addr.ip()) {
+        let forwarded_for = match headers.get("x-forwarded-for") {
+            Some(existing) => format!("{existing}, {client_ip}"),
+            None => client_ip.to_string(),
+        };
+        head.push_str(&format!("X-Forwarded-For: {forwarded_for}\r\n"));
+
+        // IPv6 addresses must be quoted and bracketed in Forwarded
+        let node = if client_ip.is_ipv6() {
+            format!("\"[{client_ip}]\"")
+        } else {
+            client_ip.to_string()
+        };
+        let mut element = format!("for={node};proto=http");
+        if !client_host.is_empty() {
+            element.push_str(&format!(";host=\"{client_host}\""));
+        }
+        let forwarded = match headers.get("forwarded") {
+            Some(existing) => format!("{existing}, {element}"),
+            None => element,
+        };
+        head.push_str(&format!("Forwarded: {forwarded}\r\n"));
+    }
+    if !client_host.is_empty() && !headers.contains_key("x-forwarded-host") {
+        head.push_str(&format!("X-Forwarded-Host: {client_host}\r\n"));
+    }
+    if !headers.contains_key("x-forwarded-proto") {
+        head.push_str("X-Forwarded-Proto: http\r\n");
+    }
+
+    // Only one request is sent per upstream connection
+    head.push_str("Connection: close\r\n");
+
+    let chunked = headers
+        .get("transfer-encoding")
+        .is_some_and(|te| te.to_lowercase().contains("chunked"));
+    let content_length = headers
+        .get("content-length")
+        .and_then(|cl| cl.parse::<u64>().ok());
+
+    // Stream the request body
+    if chunked {
+        head.push_str("Transfer-Encoding: chunked\r\n\r\n");
+        upstream.write_all(head.as_bytes())?;
+        write_chunked(&mut ChunkedReader::new(body), upstream)?;
+    } else if let Some(length) = content_length {
+        head.push_str(&format!("Content-Length: {length}\r\n\r\n"));
+        upstream.write_all(head.as_bytes())?;
+        let copied = io::copy(&mut body.take(length), upstream)?;
+        if copied < length {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Client closed connection before sending full body",
+            ));
+        }
+    } else {
+        head.push_str("\r\n");
+        upstream.write_all(head.as_bytes())?;
+    }
+
+    upstream.flush()
+}
+
+/// Reads the upstream status line and headers, and streams the remaining body
+///
+/// The `upstream` is the buffered upstream connection after the request was sent and `head` is set if it was a `HEAD` request
+///
+/// Returns a Response relaying the upstream status, end-to-end headers, and body
+fn read_upstream_response(mut upstream: BufReader<TcpStream>, head: bool) -> io::Result<Response> {
+    let mut res = Response::default();
+
+    // Parse status line
+    let mut status_line = String::new();
+    if upstream.read_line(&mut status_line)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Upstream closed connection without a response",
+        ));
+    }
+    let mut parts = status_line.trim_end().splitn(3, ' ');
+    let (Some(_), Some(code)) = (parts.next(), parts.next()) else {
+        return Err(invalid_data("Malformed upstream status line"));
+    };
+    let code = code
+        .parse::<usize>()
+        .map_err(|_| invalid_data("Malformed upstream status code"))?;
+    res.set_custom_status(code, parts.next().unwrap_or(""));
+
+    // Parse headers
+    let mut headers: Vec<(String, String)> = vec![];
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if upstream.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Upstream closed connection in headers",
+            ));
+        }
+        let trimmed = line.trim_end();
+        if trimmed.is_empty() {
+            break;
+        }
+        if let Some((key, value)) = trimmed.split_once(':') {
+            headers.push((key.trim().to_owned(), value.trim().to_owned()));
+        }
+    }
+
+    let header_value = |name: &str| {
+        headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.clone())
+    };
+    let connection_tokens = connection_tokens(header_value("connection").as_ref());
+    let chunked =
+        header_value("transfer-encoding").is_some_and(|te| te.to_lowercase().contains("chunked"));
+    let content_length = header_value("content-length").and_then(|cl| cl.parse::<u64>().ok());
+
+    // Copy end-to-end headers, joining repeated ones except cookies. The server sends its own request id and trace headers
+    for (key, value) in &headers {
+        let lowercase = key.to_lowercase();
+        if is_hop_by_hop(&lowercase, &connection_tokens)
+            || TRACE_HEADERS.contains(&lowercase.as_str())
+        {
+            continue;
+        }
+        if lowercase == "set-cookie" {
+            res.add_set_cookie_header(value.clone());
+            continue;
+        }
+        let value = match res.get_headers().get(key) {
+            Some(existing) => format!("{existing}, {value}"),
+            None => value.clone(),
+        };
+        res.add_header((key.clone(), value));
+    }
+
+    // Stream body using the upstream framing
+    let bodyless = head || (100..200).contains(&code) || code == 204 || code == 304;
+    if bodyless {
+        return Ok(res);
+    }
+    if chunked {
+        res.set_body_stream(Box::new(ChunkedReader::new(upstream)));
+    } else if let Some(length) = content_length {
+        res.set_body_stream(Box::new(upstream.take(length)));
+    } else {
+        res.set_body_stream(Box::new(upstream));
+    }
+
+    Ok(res)
+}
+
+/// Returns the lowercased header names listed in a `Connection` header
+fn connection_tokens(connection: Option<&String>) -> Vec<String> {
+    connection
+        .map(|value| {
+            value
+                .split(',')
+                .map(|token| token.trim().to_lowercase())
+                .filter(|token| !token.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Checks whether a lowercased header name must not be forwarded
+fn is_hop_by_hop(key: &str, connection_tokens: &[String]) -> bool {
+    HOP_BY_HOP_HEADERS.contains(&key) || connection_tokens.iter().any(|token| token == key)
+}
+
+/// Checks whether an I/O error was caused by a socket timeout
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    /// Helper function to start a stub upstream that answers a single connection
+    ///
+    /// `response` is the raw HTTP response to send back
+    ///
+    /// Returns the stub's address and a handle yielding the raw request it received
+    fn stub_upstream(response: &'static str) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+
+            // Read request head and Content-Length body
+            let mut request = String::new();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(cl) = line.to_lowercase().strip_prefix("content-length:") {
+                    length = cl.trim().parse().unwrap();
+                }
+                request.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            request.push_str(&String::from_utf8(body).unwrap());
+
+            (&stream).write_all(response.as_bytes()).unwrap();
+            request
+        });
+
+        (addr, handle)
+    }
+
+    /// Helper function to build a request head as the server would parse it
+    fn request(lines: &[&str]) -> Request {
+        let mut req = Request::default();
+        req.parse_status_line(lines[0].to_owned());
+        for line in &lines[1..] {
+            req.append_header(line.to_string());
+        }
+        req.set_client_addr(Some("10.0.0.7:5000".parse().unwrap()));
+        req
+    }
+
+    /// Helper function to send a Response into a String
+    fn sent(res: Response) -> String {
+        let mut out = vec![];
+        res.send(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_forwards_request_and_streams_response() {
+        let (addr, upstream) = stub_upstream(
+            "HTTP/1.1 201 Created\r\nContent-Length: 5\r\nKeep-Alive: timeout=5\r\nX-Upstream: yes\r\nSet-Cookie: a=1; Expires=Wed, 21 Oct 2037 07:28:00 GMT\r\nSet-Cookie: b=2\r\n\r\nhello",
+        );
+        let route = ProxyRoute::new("/api/", vec![addr.clone()]);
+        let req = request(&[
+            "POST /api/items?x=1 HTTP/1.1",
+            "Host: example.com",
+            "Connection: keep-alive, X-Secret",
+            "X-Secret: hidden",
+            "Content-Length: 4",
+        ]);
+
+        let res = proxy(&req, &mut "ping".as_bytes(), &route, &SiteConfig::default());
+        let raw = sent(res);
+        let forwarded = upstream.join().unwrap();
+
+        assert!(forwarded.starts_with("POST /api/items?x=1 HTTP/1.1\r\n"));
+        assert!(forwarded.contains(&format!("Host: {addr}\r\n")));
+        assert!(forwarded.contains("X-Forwarded-For: 10.0.0.7\r\n"));
+        assert!(forwarded.contains("Forwarded: for=10.0.0.7;proto=http;host=\"example.com\"\r\n"));
+        assert!(!forwarded.to_lowercase().contains("x-secret"));
+        assert!(forwarded.ends_with("\r\n\r\nping"));
+
+        assert!(raw.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(raw.contains("X-Upstream: yes\r\n"));
+        assert!(raw.contains("Set-Cookie: a=1; Expires=Wed, 21 Oct 2037 07:28:00 GMT\r\n"));
+        assert!(raw.contains("Set-Cookie: b=2\r\n"));
+        assert!(!raw.contains("Keep-Alive"));
+        assert!(raw.ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn test_propagates_request_id_and_trace() {
+        let (addr, upstream) = stub_upstream(
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nX-Request-Id: upstream\r\ntraceparent: 00-upstream\r\n\r\n",
+        );
+        let route = ProxyRoute::new("/", vec![addr]);
+        let mut req = request(&[
+            "GET / HTTP/1.1",
+            "Host: example.com",
+            "X-Request-Id: abc-123",
+            "traceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "tracestate: vendor=abc",
+        ]);
+        req.read_trace_headers();
+
+        let raw = sent(proxy(
+            &req,
+            &mut "".as_bytes(),
+            &route,
+            &SiteConfig::default(),
+        ));
+        let forwarded = upstream.join().unwrap();
+
+        // The upstream sees this server's span as the parent of its own
+        let span_id = req.get_trace().get_span_id();
+        assert!(forwarded.contains("X-Request-Id: abc-123\r\n"));
+        assert!(forwarded.contains(&format!(
+            "traceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-{span_id}-01\r\n"
+        )));
+        assert!(forwarded.contains("tracestate: vendor=abc\r\n"));
+        assert!(!forwarded.contains("00f067aa0ba902b7"));
+        assert!(!raw.contains("upstream"));
+    }
+
+    #[test]
+    fn test_strips_prefix_and_rechunks_body() {
+        let (addr, upstream) = stub_upstream(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n",
+        );
+        let mut route = ProxyRoute::new("/api/", vec![addr]);
+        route.strip_prefix = true;
+        let req = request(&["GET /api/users HTTP/1.1", "Host: example.com"]);
+
+        let raw = sent(proxy(
+            &req,
+            &mut "".as_bytes(),
+            &route,
+            &SiteConfig::default(),
+        ));
+
+        assert!(
+            upstream
+                .join()
+                .unwrap()
+                .starts_with("GET /users HTTP/1.1\r\n")
+        );
+        assert!(raw.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(raw.ends_with("\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_strips_prefix_from_canonical_path() {
+        let (addr, upstream) = stub_upstream("HTTP/1.1 204 No Content\r\n\r\n");
+        let mut route = ProxyRoute::new("/api/", vec![addr]);
+        route.strip_prefix = true;
+        let req = request(&[
+            "GET //api/./users%20list?q=a%20b HTTP/1.1",
+            "Host: example.com",
+        ]);
+
+        proxy(&req, &mut "".as_bytes(), &route, &SiteConfig::default());
+        assert!(
+            upstream
+                .join()
+                .unwrap()
+                .starts_with("GET /users%20list?q=a%20b HTTP/1.1\r\n")
+        );
+    }
+
+    #[test]
+    fn test_forwards_head_and_methods_without_variant() {
+        let (addr, upstream) =
+            stub_upstream("HTTP/1.1 200 OK\r\nContent-Length: 5\r\nX-Upstream: yes\r\n\r\n");
+        let route = ProxyRoute::new("/up/", vec![addr]);
+        let req = request(&["HEAD /up/x HTTP/1.1", "Host: example.com"]);
+
+        let raw = sent(proxy(
+            &req,
+            &mut "".as_bytes(),
+            &route,
+            &SiteConfig::default(),
+        ));
+        assert!(
+            upstream
+                .join()
+                .unwrap()
+                .starts_with("HEAD /up/x HTTP/1.1\r\n")
+        );
+        assert!(raw.contains("X-Upstream: yes\r\n"));
+        assert!(raw.ends_with("\r\n\r\n"));
+
+        let (addr, upstream) = stub_upstream("HTTP/1.1 204 No Content\r\n\r\n");
+        let route = ProxyRoute::new("/up/", vec![addr]);
+        let req = request(&["REPORT /up/x HTTP/1.1", "Host: example.com"]);
+        proxy(&req, &mut "".as_bytes(), &route, &SiteConfig::default());
+        assert!(
+            upstream
+                .join()
+                .unwrap()
+                .starts_with("REPORT /up/x HTTP/1.1\r\n")
+        );
+    }
+
+    #[test]
+    fn test_round_robin_over_upstreams() {
+        let (first, first_upstream) =
+            stub_upstream("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n1");
+        let (second, second_upstream) =
+            stub_upstream("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n2");
+        let route = ProxyRoute::new("/api/", vec![first, second]);
+        let req = request(&["GET /api/ HTTP/1.1", "Host: example.com"]);
+
+        let bodies: Vec<String> = (0..2)
+            .map(|_| {
+                sent(proxy(
+                    &req,
+                    &mut "".as_bytes(),
+                    &route,
+                    &SiteConfig::default(),
+                ))
+            })
+            .collect();
+
+        first_upstream.join().unwrap();
+        second_upstream.join().unwrap();
+        assert!(bodies[0].ends_with("\r\n\r\n1"));
+        assert!(bodies[1].ends_with("\r\n\r\n2"));
+    }
+
+    #[test]
+    fn test_unreachable_upstream_is_bad_gateway() {
+        // Reserve a port, then close it so connections are refused
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let route = ProxyRoute::new("/api/", vec![addr]);
+        let req = request(&["GET /api/ HTTP/1.1", "Host: example.com"]);
+
+        let res = proxy(&req, &mut "".as_bytes(), &route, &SiteConfig::default());
+        assert_eq!(res.get_status(), Some(502));
+    }
+
+    #[test]
+    fn test_silent_upstream_is_gateway_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut route = ProxyRoute::new("/api/", vec![listener.local_addr().unwrap().to_string()]);
+        route.timeout_secs = 1;
+        let req = request(&["GET /api/ HTTP/1.1", "Host: example.com"]);
+
+        // The listener accepts through its backlog but never answers
+        let res = proxy(&req, &mut "".as_bytes(), &route, &SiteConfig::default());
+        assert_eq!(res.get_status(), Some(504));
+        drop(listener);
+    }
+}
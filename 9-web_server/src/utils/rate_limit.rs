@@ -0,0 +1,145 @@
+use crate::{
+    models::{RateDecision, RateLimitRule, Request, Response, SiteConfig},
+    utils::error_response,
+};
+
+/// Applies a site's rate limits to a request
+///
+/// The `req` is the Request to check and `site` is the virtual host it was addressed to. Clients are keyed by the identity set by `check_access`, or else by IP address
+///
+/// Returns the `RateLimit-*` headers to add to the response, or a `429` Response if the client is over its limit
+pub fn check_rate_limit(
+    req: &Request,
+    site: &SiteConfig,
+) -> Result<Vec<(String, String)>, Box<Response>> {
+    let Some(limit) = site.find_rate_limit(req.get_path(), req.get_method().as_str()) else {
+        return Ok(vec![]);
+    };
+
+    let client = match (req.get_identity(), req.get_client_addr()) {
+        (Some(identity), _) => format!("user:{identity}"),
+        (None, Some(addr)) => format!("ip:{}", addr.ip()),
+        (None, None) => "unknown".to_owned(),
+    };
+
+    let decision = limit.check(&client);
+    let headers = rate_limit_headers(limit, &decision);
+    if decision.allowed {
+        return Ok(headers);
+    }
+
+    let mut res = error_response(429, req, site);
+    res.add_header((
+        "Retry-After".to_owned(),
+        decision.retry_after_secs.to_string(),
+    ));
+    for header in headers {
+        res.add_header(header);
+    }
+    Err(Box::new(res))
+}
+
+/// Builds the `RateLimit-*` headers describing a client's bucket
+fn rate_limit_headers(limit: &RateLimitRule, decision: &RateDecision) -> Vec<(String, String)> {
+    vec![
+        ("RateLimit-Limit".to_owned(), limit.requests.to_string()),
+        (
+            "RateLimit-Remaining".to_owned(),
+            decision.remaining.to_string(),
+        ),
+        (
+            "RateLimit-Reset".to_owned(),
+            decision.reset_secs.to_string(),
+        ),
+        (
+            "RateLimit-Policy".to_owned(),
+            format!("{};w={}", limit.requests, limit.per_secs),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    /// Helper function to build a site allowing two `POST`s per minute
+    fn limited_site() -> SiteConfig {
+        toml::from_str(
+            r#"
+            [[rate_limit]]
+            prefix = "/"
+            methods = ["POST"]
+            requests = 2
+            per_secs = 60
+            "#,
+        )
+        .unwrap()
+    }
+
+    /// Helper function to build a request from a client address
+    fn request(status_line: &str, client: &str) -> Request {
+        let mut req = Request::default();
+        req.parse_status_line(status_line.to_owned());
+        req.set_client_addr(client.parse().ok());
+        req
+    }
+
+    #[test]
+    fn test_posts_are_limited_per_client() {
+        let site = limited_site();
+
+        let headers = check_rate_limit(&request("POST / HTTP/1.1", "10.0.0.1:5000"), &site)
+            .ok()
+            .unwrap();
+        assert!(headers.contains(&("RateLimit-Remaining".to_owned(), "1".to_owned())));
+        assert!(headers.contains(&("RateLimit-Policy".to_owned(), "2;w=60".to_owned())));
+        check_rate_limit(&request("POST / HTTP/1.1", "10.0.0.1:5001"), &site)
+            .ok()
+            .unwrap();
+
+        let res =
+            check_rate_limit(&request("POST / HTTP/1.1", "10.0.0.1:5002"), &site).unwrap_err();
+        assert_eq!(res.get_status(), Some(429));
+        assert_eq!(res.get_headers()["Retry-After"], "30");
+        assert_eq!(res.get_headers()["RateLimit-Remaining"], "0");
+
+        // Other clients, identities, and methods are unaffected
+        assert!(check_rate_limit(&request("POST / HTTP/1.1", "10.0.0.2:5000"), &site).is_ok());
+        let mut req = request("POST / HTTP/1.1", "10.0.0.1:5003");
+        req.set_identity(Some("alice".to_owned()));
+        assert!(check_rate_limit(&req, &site).is_ok());
+        let headers = check_rate_limit(&request("GET / HTTP/1.1", "10.0.0.1:5004"), &site)
+            .ok()
+            .unwrap();
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn test_clients_behind_a_front_proxy_are_limited_apart() {
+        let site = limited_site();
+        let forwarded = |forwarded_for: &str| {
+            let mut req = Request::default();
+            req.parse_status_line("POST / HTTP/1.1".to_owned());
+            req.append_header(format!("X-Forwarded-For: {forwarded_for}"));
+            let client = req.take_forwarded_for();
+            req.set_client_addr(client.map(|ip| SocketAddr::new(ip, 0)));
+            req
+        };
+
+        // The proxy's own entry names the client, whatever the client claimed before it
+        let req = forwarded("203.0.113.9, 10.0.0.1");
+        assert_eq!(req.get_headers()["x-forwarded-for"], "203.0.113.9");
+        assert!(check_rate_limit(&req, &site).is_ok());
+        assert!(check_rate_limit(&forwarded("198.51.100.7, 10.0.0.1"), &site).is_ok());
+        assert!(check_rate_limit(&forwarded("10.0.0.1"), &site).is_err());
+        assert!(check_rate_limit(&forwarded("2001:db8::1"), &site).is_ok());
+
+        let req = forwarded("10.0.0.1, not-an-address");
+        assert_eq!(req.get_client_addr(), None);
+        assert_eq!(
+            req.get_headers()["x-forwarded-for"],
+            "10.0.0.1, not-an-address"
+        );
+    }
+}
@@ -0,0 +1,115 @@
+use crate::{
+    models::{Request, Response, SiteConfig},
+    utils::{percent_encode, redirect},
+};
+
+/// Applies the first of a site's rewrite rules that matches a request
+///
+/// The `req` is the Request, whose target is replaced by internal rewrites, and `site` is the virtual host it was addressed to. The query string is kept unless the replacement has its own
+///
+/// Returns a redirect Response if the matching rule redirects, or None to continue routing the request
+pub fn apply_rewrites(req: &mut Request, site: &SiteConfig) -> Option<Response> {
+    // Rules see the canonical path, encoded again since the replacement becomes a new target
+    let encoded = percent_encode(req.get_path());
+    let (rule, path) = site.rewrites.iter().find_map(|rule| {
+        rule.applies_to(req)
+            .then(|| rule.rewrite(&encoded))
+            .flatten()
+            .map(|path| (rule, path))
+    })?;
+
+    let target = match req.get_target().split_once('?') {
+        Some((_, query)) if !path.contains('?') => format!("{path}?{query}"),
+        _ => path,
+    };
+    match rule.redirect {
+        Some(code) => Some(redirect(code, &target)),
+        None => {
+            req.set_target(&target);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ServerConfig;
+    use std::fs;
+    use tempfile::tempdir;
+
+    /// Helper function to build a request with a Host header
+    fn request(status_line: &str, host: &str) -> Request {
+        let mut req = Request::default();
+        req.parse_status_line(status_line.to_owned());
+        req.append_header(format!("Host: {host}"));
+        req
+    }
+
+    #[test]
+    fn test_redirects_and_rewrites() {
+        let site: SiteConfig = toml::from_str(
+            r#"
+            [[rewrite]]
+            pattern = '^/blog/(?P<year>\d{4})/(.+)\.php$'
+            to = '/posts/${year}-$2.html'
+            redirect = 301
+
+            [[rewrite]]
+            prefix = "/old/"
+            to = "https://example.com/new/"
+            redirect = 308
+            hosts = ["*.example.org"]
+
+            [[rewrite]]
+            prefix = "/app/"
+            to = "/mobile/"
+            methods = ["GET"]
+            headers = { User-Agent = "(?i)mobile" }
+            "#,
+        )
+        .unwrap();
+
+        let mut req = request("GET /blog/2023/hello.php?ref=rss HTTP/1.1", "example.org");
+        let res = apply_rewrites(&mut req, &site).unwrap();
+        assert_eq!(res.get_status(), Some(301));
+        assert_eq!(
+            res.get_headers()["Location"],
+            "/posts/2023-hello.html?ref=rss"
+        );
+
+        // Conditions on the host have to hold
+        let mut req = request("POST /old/a.html HTTP/1.1", "www.example.org:7878");
+        let res = apply_rewrites(&mut req, &site).unwrap();
+        assert_eq!(res.get_status(), Some(308));
+        assert_eq!(
+            res.get_headers()["Location"],
+            "https://example.com/new/a.html"
+        );
+        let mut req = request("GET /old/a.html HTTP/1.1", "example.com");
+        assert!(apply_rewrites(&mut req, &site).is_none());
+        assert_eq!(req.get_target(), "/old/a.html");
+
+        // Internal rewrites change the target the request is routed by
+        let mut req = request("GET /app/index.html?x=1 HTTP/1.1", "example.com");
+        req.append_header("User-Agent: Some Mobile Browser".to_owned());
+        assert!(apply_rewrites(&mut req, &site).is_none());
+        assert_eq!(req.get_target(), "/mobile/index.html?x=1");
+        assert_eq!(req.get_queries()["x"], "1");
+    }
+
+    #[test]
+    fn test_invalid_rules_are_rejected() {
+        let temp_dir = tempdir().unwrap();
+        for rule in [
+            "pattern = '(' \n to = '/'",
+            "prefix = '/a/' \n to = '/b/' \n redirect = 303",
+            "prefix = '/a/' \n to = 'b/'",
+            "to = '/b/'",
+        ] {
+            let path = temp_dir.path().join("server.toml");
+            fs::write(&path, format!("[[rewrite]]\n{rule}")).unwrap();
+            assert!(ServerConfig::load(&path).is_err(), "{rule}");
+        }
+    }
+}
@@ -1,73 +1,92 @@
-use std::{fs, path::Path, time::SystemTime};
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Component, Path},
+    process,
+    time::SystemTime,
+};
 
 use httpdate::fmt_http_date;
 
-use crate::models::{HttpMethod, Request, Response};
+use crate::{
+    log_error,
+    models::{AccessPolicy, FileCache, HttpMethod, Request, Response, ServerState, SiteConfig},
+    utils::{
+        api_methods, error_response, is_template, is_webdav_request, percent_encode,
+        template_response, webdav, webdav_methods,
+    },
+};
 
 /// Handles routing based on HTTP method and requested path
 ///
-/// The `method` is the HTTP request method, `path` is the requested path, and `body` is the request body (may be empty)
-pub fn route(req: Request) -> Response {
+/// The `req` is the parsed Request, `site` is the virtual host it was addressed to, and `state` holds the shared file cache
+pub fn route(req: Request, site: &SiteConfig, state: &ServerState) -> Response {
+    // Check if the site handles the method
+    if !site.allows_method(req.get_method().as_str()) {
+        return invalid_request(&req, site);
+    }
+
+    // Keep requests inside the site's document root
+    if req
+        .get_resource()
+        .components()
+        .any(|c| c == Component::ParentDir)
+    {
+        return handle_bad_request("Request target must not leave the document root");
+    }
+
+    // WebDAV methods address directories as well as files
+    if is_webdav_request(&req, site) {
+        return webdav(&req, site, state);
+    }
+
     // Check if request path is valid
-    if req.get_resource().extension().is_none() {
+    if req.get_resource().is_dir() && !matches!(req.get_method(), HttpMethod::Options) {
         return handle_bad_request("Request target must be a file");
     }
 
     match req.get_method() {
         HttpMethod::Options => {
             // Return allowed HTTP request methods
-            options()
+            options(&req, site)
             // TODO: Test for queries in options()
         }
         HttpMethod::Get => {
             // Return requested resource/data if it exists or return error page
-            get(req)
+            get(req, site, state)
             // TODO: Test for queries in get()
         }
         HttpMethod::Post => {
-            post(req)
+            post(req, site, state)
             // TODO: Test for queries in post()
         }
         HttpMethod::Put => {
             // Create new resource or modify existing resource SAFELY (Idempotent)
-            put(req)
+            put(req, site, state)
             // TODO: Test for queries in put()
         }
         HttpMethod::Delete => {
             // Delete a resource SAFELY (Idempotent)
-            delete(req)
+            delete(req, site, state)
             // TODO: Test for queries in delete()
         }
-        HttpMethod::None => invalid_request(),
+        HttpMethod::Patch
+        | HttpMethod::Propfind
+        | HttpMethod::Mkcol
+        | HttpMethod::Copy
+        | HttpMethod::Move
+        | HttpMethod::None => invalid_request(&req, site),
     }
 }
 
 /// Handles requests with unsupported HTTP methods
 ///
-/// Returns a `Response` containing containing the file path of the 405 error page
-fn invalid_request() -> Response {
-    // Initialize response
-    let mut res = Response::default();
-
-    // Set Date header
-    res.add_header(set_date_header());
-
-    // Set status line
-    res.set_status(405);
-
-    // Get contents
-    let contents = read_file("public/error/405.html");
-
-    // Set headers
-    if let Some(content) = &contents {
-        res.add_header(("Content-Type".to_owned(), "text/html".to_owned()));
-        res.add_header(("Content-Length".to_owned(), content.len().to_string()));
-    }
-
-    // Set body
-    res.set_body(contents);
-
-    // Return response
+/// The `req` is the Request being answered and `site` is the virtual host whose error page is sent
+///
+/// Returns a `405` Response listing the methods the target supports in its `Allow` header
+fn invalid_request(req: &Request, site: &SiteConfig) -> Response {
+    let mut res = error_response(405, req, site);
+    res.add_header(("Allow".to_owned(), allowed_methods(req, site).join(", ")));
     res
 }
 
@@ -76,7 +95,7 @@ fn invalid_request() -> Response {
 /// The `message` is the error message to be sent
 ///
 /// Returns a `Response` containing an informational error message in JSON
-fn handle_bad_request(message: &str) -> Response {
+pub fn handle_bad_request(message: &str) -> Response {
     // Initialize response
     let mut res = Response::default();
 
@@ -99,26 +118,61 @@ fn handle_bad_request(message: &str) -> Response {
 
 /// Handles `OPTIONS` request
 ///
+/// The `req` is the Request struct containing request data and `site` is the virtual host it was addressed to
+///
 /// Returns a `Response` containing `Allow` header detailing permitted HTTP request methods
-fn options() -> Response {
+fn options(req: &Request, site: &SiteConfig) -> Response {
     // Construct response
     let mut res = Response::default();
     res.set_status(204);
-    res.add_header((
-        "Allow".to_owned(),
-        "GET, POST, PUT, DELETE, OPTIONS".to_owned(),
-    ));
+    res.add_header(set_date_header());
+    res.add_header(("Allow".to_owned(), allowed_methods(req, site).join(", ")));
+    if site.webdav {
+        // Advertise WebDAV class 1 compliance to clients mounting the site
+        res.add_header(("DAV".to_owned(), "1".to_owned()));
+    }
 
     // Return response
     res
 }
 
+/// Determines the methods a request target supports
+///
+/// The `req` is the Request struct containing request data and `site` is the virtual host it was addressed to
+///
+/// Returns the method names the site handles for the target and doesn't deny through access rules
+pub(crate) fn allowed_methods(req: &Request, site: &SiteConfig) -> Vec<String> {
+    let path = req.get_resource();
+    let supported: &[&str] = if let Some(api) = site.find_api(req.get_path()) {
+        api_methods(req.get_path(), api)
+    } else if site.find_cgi(req.get_path()).is_some() || is_template(path) {
+        &["GET", "POST", "OPTIONS"]
+    } else if let Some(methods) = webdav_methods(req, site) {
+        methods
+    } else if path.is_file() {
+        &["GET", "POST", "PUT", "DELETE", "OPTIONS"]
+    } else {
+        // Missing files can only be created
+        &["POST", "PUT", "OPTIONS"]
+    };
+
+    supported
+        .iter()
+        .filter(|method| site.allows_method(method))
+        .filter(|method| {
+            site.find_access_rule(req.get_path(), method)
+                .is_none_or(|rule| rule.policy != AccessPolicy::Deny)
+        })
+        .map(|method| method.to_string())
+        .collect()
+}
+
 /// Handles `GET` requests
 ///
-/// The `req` is the Request struct containing request data
+/// The `req` is the Request struct containing request data, `site` is the virtual host it was addressed to, and `state` holds the shared file cache and media types
 ///
 /// Returns a `Response` containing the file path of the requested resource
-fn get(req: Request) -> Response {
+fn get(req: Request, site: &SiteConfig, state: &ServerState) -> Response {
     // Initialize response
     let mut res = Response::default();
 
@@ -128,35 +182,37 @@ fn get(req: Request) -> Response {
     // Extract path from request
     let path = req.get_resource();
 
-    // Return requested resource/data if it exists or return error page
-    if path.exists() {
-        // Get file contents
-        let contents = read_file(path.to_str().unwrap_or("error"));
-
-        // Set status line
-        res.set_status(200);
+    // Render templates instead of serving their source
+    if path.exists() && is_template(path) {
+        return template_response(&req, site);
+    }
 
+    // Return requested resource/data if it exists or return error page
+    let config = state.config();
+    if let Ok(file) = state.cache.get(path, config.mime.types()) {
         // Set headers
-        if let Some(content) = &contents {
-            res.add_header(get_content_type(path));
-            res.add_header(("Content-Length".to_owned(), content.len().to_string()));
+        res.add_header(("Content-Type".to_owned(), file.content_type));
+        res.add_header(("ETag".to_owned(), file.etag.clone()));
+        res.add_header(("Last-Modified".to_owned(), fmt_http_date(file.modified)));
+
+        // Answer conditional requests for unchanged files without a body
+        let unchanged = req
+            .get_headers()
+            .get("if-none-match")
+            .is_some_and(|tags| etag_matches(tags, &file.etag));
+        if unchanged {
+            res.set_status(304);
+            return res;
         }
 
-        // Set body
-        res.set_body(contents);
-    } else {
-        let contents = read_file("public/error/404.html");
         // Set status line
-        res.set_status(404);
-
-        // Set headers
-        if let Some(content) = &contents {
-            res.add_header(get_content_type(Path::new("public/error/404.html")));
-            res.add_header(("Content-Length".to_owned(), content.len().to_string()));
-        }
+        res.set_status(200);
 
         // Set body
-        res.set_body(contents);
+        res.add_header(("Content-Length".to_owned(), file.contents.len().to_string()));
+        res.set_body_shared(file.contents);
+    } else {
+        return error_response(404, &req, site);
     }
 
     // Return response
@@ -165,10 +221,15 @@ fn get(req: Request) -> Response {
 
 /// Handles `POST` requests
 ///
-/// The `req` is the Request struct containing request data
+/// The `req` is the Request struct containing request data, `site` is the virtual host it was addressed to, and `state` holds the shared file cache and write locks
 ///
 /// Returns a `Response` redirecting to success file path
-fn post(req: Request) -> Response {
+fn post(req: Request, site: &SiteConfig, state: &ServerState) -> Response {
+    // Render templates with the posted form fields
+    if req.get_resource().exists() && is_template(req.get_resource()) {
+        return template_response(&req, site);
+    }
+
     // Initialize response
     let mut res = Response::default();
 
@@ -177,7 +238,9 @@ fn post(req: Request) -> Response {
 
     // Process request body
     if let Some(value) = req.get_headers().get("content-type") {
-        match value.as_str() {
+        // Match the media type alone, since clients add parameters like `charset=UTF-8`
+        let media_type = value.split(';').next().unwrap_or_default().trim();
+        match media_type.to_lowercase().as_str() {
             "application/x-www-form-urlencoded" => {
                 let body_str = String::from_utf8_lossy(req.get_body());
                 let params: Vec<(&str, &str)> = body_str
@@ -197,19 +260,11 @@ fn post(req: Request) -> Response {
                     .join(", ");
 
                 // Write processed data to file
-                if let Some(error_file) =
-                    write_to_file(Path::new("public/post-success.txt"), res_body.as_bytes())
-                {
+                let path = site.root.join("post-success.txt");
+                let _lock = state.locks.lock(&path);
+                if let Err(err) = write_to_file(&path, res_body.as_bytes(), false, &state.cache) {
                     // Handle file writing error
-                    // Set status line
-                    res.set_status(500);
-
-                    // Set headers
-                    res.add_header(get_content_type(Path::new("public/error/500.html")));
-                    res.add_header(("Content-Length".to_owned(), error_file.len().to_string()));
-
-                    // Set body
-                    res.set_body(Some(error_file));
+                    return write_error(&err, &req, site);
                 }
 
                 // Redirect on success
@@ -218,32 +273,23 @@ fn post(req: Request) -> Response {
 
                 // Set headers
                 let redirect = Path::new("/post-success.txt");
-                res.add_header(get_content_type(redirect));
+                res.add_header((
+                    "Content-Type".to_owned(),
+                    state.config().mime.types().content_type(redirect, b""),
+                ));
                 res.add_header((
                     "Location".to_owned(),
                     redirect.to_str().map(|val| val.to_owned()).unwrap(),
                 ));
             }
-            "multiplart/form-data" => {
-                // TODO: Used for file uploads. Implement later
-                todo!("miltipart/form-data not implemented yet")
-            }
             "text/plain" | "application/octet-stream" => {
                 // NOTE: Do something with data
                 // Write data to file
-                if let Some(error_file) =
-                    write_to_file(Path::new("public/post-success.txt"), req.get_body())
-                {
+                let path = site.root.join("post-success.txt");
+                let _lock = state.locks.lock(&path);
+                if let Err(err) = write_to_file(&path, req.get_body(), false, &state.cache) {
                     // Handle file writing error
-                    // Set status line
-                    res.set_status(500);
-
-                    // Set headers
-                    res.add_header(get_content_type(Path::new("public/error/500.html")));
-                    res.add_header(("Content-Length".to_owned(), error_file.len().to_string()));
-
-                    // Set body
-                    res.set_body(Some(error_file));
+                    return write_error(&err, &req, site);
                 }
 
                 // Redirect on success
@@ -252,15 +298,18 @@ fn post(req: Request) -> Response {
 
                 // Set headers
                 let redirect = Path::new("/post-success.txt");
-                res.add_header(get_content_type(redirect));
+                res.add_header((
+                    "Content-Type".to_owned(),
+                    state.config().mime.types().content_type(redirect, b""),
+                ));
                 res.add_header((
                     "Location".to_owned(),
                     redirect.to_str().map(|val| val.to_owned()).unwrap(),
                 ));
             }
             _ => {
-                // NOTE: Content-Type can be anything if POST is not sent by a form
-                todo!("Unimplemented Content-Type on POST")
+                // TODO: Accept multipart/form-data file uploads. Other bodies have nowhere to go
+                return error_response(415, &req, site);
             }
         }
     }
@@ -270,10 +319,10 @@ fn post(req: Request) -> Response {
 
 /// Handles `PUT` requests
 ///
-/// The `req` is the Request struct containing request data
+/// The `req` is the Request struct containing request data, `site` is the virtual host it was addressed to, and `state` holds the shared file cache and write locks
 ///
 /// Returns a `Response` containing the file path of created/modified resource
-fn put(req: Request) -> Response {
+fn put(req: Request, site: &SiteConfig, state: &ServerState) -> Response {
     // Initialize response
     let mut res = Response::default();
 
@@ -284,60 +333,39 @@ fn put(req: Request) -> Response {
     let path = req.get_resource();
     let body = req.get_body();
 
+    // Hold the path until written so concurrent writes don't interleave
+    let _lock = state.locks.lock(path);
+
     // Check if resource exists
     if path.exists() {
         // File exists so modify it. Handle error if it occurs
-        if let Some(error_file) = write_to_file(path, body) {
-            // Set status line
-            res.set_status(500);
-
-            // Set headers
-            res.add_header(get_content_type(Path::new("public/error/500.html")));
-            res.add_header(("Content-Length".to_owned(), error_file.len().to_string()));
-
-            // Set body
-            res.set_body(Some(error_file));
+        if let Err(err) = write_to_file(path, body, site.create_dirs, &state.cache) {
+            return write_error(&err, &req, site);
         }
 
         // Successfully modified
         // Set status line
         res.set_status(204);
 
-        // Set headers
+        // Set headers, naming the resource by its URL rather than its place on disk
         res.add_header((
             "Content-Location".to_owned(),
-            path.to_str().map(|v| v.to_owned()).unwrap(),
+            percent_encode(req.get_path()),
         ));
     }
     // File doesn't exist so create it
     else {
         // Write to file and handle error if it occurs
-        if let Some(error_file) = write_to_file(path, body) {
-            // Set status line
-            res.set_status(500);
-
-            // Set headers
-            res.add_header(get_content_type(Path::new("public/error/500.html")));
-            res.add_header(("Content-Length".to_owned(), error_file.len().to_string()));
-
-            // Set body
-            res.set_body(Some(error_file));
+        if let Err(err) = write_to_file(path, body, site.create_dirs, &state.cache) {
+            return write_error(&err, &req, site);
         }
 
         // Successfully created
         // Set status line
         res.set_status(201);
 
-        // Get path of new resource for client
-        let new_path = path
-            .file_name()
-            .map(|v| v.to_str())
-            .unwrap()
-            .unwrap()
-            .to_owned();
-
-        // Set headers
-        res.add_header(("Location".to_owned(), format!("/{new_path}")));
+        // Set headers, with the full path since the resource may be nested
+        res.add_header(("Location".to_owned(), percent_encode(req.get_path())));
     }
 
     // Return response
@@ -346,10 +374,10 @@ fn put(req: Request) -> Response {
 
 /// Handles `DELETE` requests
 ///
-/// The `req` is the Request struct containing request data
+/// The `req` is the Request struct containing request data, `site` is the virtual host it was addressed to, and `state` holds the shared file cache and write locks
 ///
 /// Returns a `Response` containing the redirection file path (empty `String` if successful)
-fn delete(req: Request) -> Response {
+fn delete(req: Request, site: &SiteConfig, state: &ServerState) -> Response {
     // Initialize response
     let mut res = Response::default();
 
@@ -360,78 +388,58 @@ fn delete(req: Request) -> Response {
     let path = req.get_resource();
 
     // Check if file-to-delete exists
+    let _lock = state.locks.lock(path);
     if path.exists() {
-        fs::remove_file(path).unwrap_or_else(|e| {
-            eprintln!("File deletion error: {e}");
+        let result = fs::remove_file(path);
+        state.cache.invalidate(path);
+        if let Err(e) = result {
+            log_error!("File deletion error: {e}");
 
             // Send error page
-            let contents = read_file("public/error/500.html");
-
-            // Set status line
-            res.set_status(500);
-
-            // Set headers
-            if let Some(content) = &contents {
-                res.add_header(get_content_type(Path::new("public/error/500.html")));
-                res.add_header(("Content-Length".to_owned(), content.len().to_string()));
-            }
-
-            // Set body
-            res.set_body(contents);
-        });
+            return error_response(500, &req, site);
+        }
         // File successfully deleted
         res.set_status(204);
         // NOTE: In calling code check path and refresh page on successful deletion
     } else {
         // File-to-delete not found
-        let contents = read_file("public/error/404.html");
-
-        // Set status line
-        res.set_status(404);
-
-        // Set headers
-        if let Some(content) = &contents {
-            res.add_header(get_content_type(Path::new("public/error/404.html")));
-            res.add_header(("Content-Length".to_owned(), content.len().to_string()));
-        }
-
-        // Set body
-        res.set_body(contents);
+        return error_response(404, &req, site);
     }
 
     // Return response
     res
 }
 
+/// Builds a redirect response
+///
+/// The `code` is the redirect status code and `location` is the URL the client is sent to
+///
+/// Returns a `Response` with a `Location` header and an empty body
+pub(crate) fn redirect(code: usize, location: &str) -> Response {
+    let mut res = Response::default();
+    res.set_status(code);
+    res.add_header(set_date_header());
+    res.add_header(("Location".to_owned(), location.to_owned()));
+    res.add_header(("Content-Length".to_owned(), "0".to_owned()));
+    res
+}
+
 /// Determines Date header
 ///
 /// Returns a tuple of two Strings containing the header name and computed value
-fn set_date_header() -> (String, String) {
+pub(crate) fn set_date_header() -> (String, String) {
     let now = SystemTime::now();
     ("Date".to_owned(), fmt_http_date(now))
 }
 
-/// Determines the correct `Content-Type` for a given file path
+/// Checks whether an `If-None-Match` header matches an ETag
 ///
-/// The `path` is the file path to be analyzed
-///
-/// Returns a tuple of two Strings containing the header name and computed value
-fn get_content_type(path: &Path) -> (String, String) {
-    let content_type = match path.extension().and_then(|p| p.to_str()).unwrap() {
-        "html" => "text/html",
-        "pdf" => "application/pdf",
-        "json" => "application/json",
-        "js" | "mjs" => "text/javascript",
-        "css" => "text/css",
-        "jpeg" | "jpg" => "image/jpeg",
-        "png" => "image/png",
-        "txt" => "text/plain",
-        "md" => "text/markdown",
-        _ => "application/octet-stream",
-    }
-    .to_owned();
-
-    ("Content-Type".to_owned(), content_type)
+/// The `tags` is the header value, a list of ETags or `*`, and `etag` is the current ETag of the resource
+pub(crate) fn etag_matches(tags: &str, etag: &str) -> bool {
+    tags.split(',').map(str::trim).any(|tag| {
+        // Weak comparison ignores the `W/` prefix
+        tag == "*" || tag.trim_start_matches("W/") == etag.trim_start_matches("W/")
+    })
 }
 
 /// Read and return file contents
@@ -439,11 +447,11 @@ fn get_content_type(path: &Path) -> (String, String) {
 /// The `file_path` is the file path to read from
 ///
 /// Returns an Option containing the file contents or None if an error occurs
-fn read_file(file_path: &str) -> Option<String> {
+pub(crate) fn read_file(file_path: &Path) -> Option<String> {
     match fs::read_to_string(file_path) {
         Ok(contents) => Some(contents),
         Err(err) => {
-            eprintln!("Error reading {file_path}: {err}");
+            log_error!("Error reading {}: {err}", file_path.display());
             None
         }
     }
@@ -451,15 +459,145 @@ fn read_file(file_path: &str) -> Option<String> {
 
 /// Write to a file, overwriting an existing file or creating a new one
 ///
-/// The `file_path` is the target file path and `contents` is the payload (in bytes)
+/// The contents are written to a temporary file next to the target, which is then renamed over it, so a crash or a concurrent reader never sees a partly written file
 ///
-/// Returns an Option containing the contents of an error file or None on success
-fn write_to_file(file_path: &Path, contents: &[u8]) -> Option<String> {
-    match fs::write(file_path, contents) {
-        Ok(_) => None,
-        Err(err) => {
-            eprintln!("Error writing to file: {err}");
-            read_file("public/error/500.html")
+/// The `file_path` is the target file path, `contents` is the payload (in bytes), `create_dirs` creates missing parent directories, and `cache` has its entry for the file invalidated
+///
+/// Returns an error if the file couldn't be written, leaving any existing file untouched
+fn write_to_file(
+    file_path: &Path,
+    contents: &[u8],
+    create_dirs: bool,
+    cache: &FileCache,
+) -> io::Result<()> {
+    let dir = file_path.parent().unwrap_or(Path::new("."));
+    if create_dirs {
+        fs::create_dir_all(dir)?;
+    }
+
+    let name = file_path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let temp_path = dir.join(format!(".{name}.{}.tmp", process::id()));
+    let result = (|| {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+
+        // Keep the permissions of a file being replaced
+        if let Ok(metadata) = fs::metadata(file_path) {
+            fs::set_permissions(&temp_path, metadata.permissions())?;
         }
+        fs::rename(&temp_path, file_path)
+    })();
+    cache.invalidate(file_path);
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Builds the response to a failed write
+///
+/// The `err` is the write error, `req` is the Request being answered, and `site` provides the error pages
+///
+/// Returns a `409` Response if the file's directory is missing, or `500` for other errors
+fn write_error(err: &io::Error, req: &Request, site: &SiteConfig) -> Response {
+    if err.kind() == io::ErrorKind::NotFound {
+        return error_response(409, req, site);
+    }
+
+    log_error!("Error writing to file: {err}");
+    error_response(500, req, site)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{PoolLoad, ServerConfig};
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    /// Helper function to send a `PUT` request with a body
+    fn put_file(site: &SiteConfig, state: &ServerState, target: &str, body: &str) -> Response {
+        let mut req = Request::default();
+        req.parse_status_line(format!("PUT {target} HTTP/1.1"));
+        req.set_body(body.as_bytes());
+        req.set_document_root(&site.root, &site.index);
+        route(req, site, state)
+    }
+
+    #[test]
+    fn test_put_writes_atomically() {
+        let temp_dir = tempdir().unwrap();
+        let state = ServerState::new(ServerConfig::default(), Arc::new(PoolLoad::default()));
+        let mut site = SiteConfig {
+            root: temp_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+
+        let res = put_file(&site, &state, "/notes.txt", "first");
+        assert_eq!(res.get_status(), Some(201));
+        let res = put_file(&site, &state, "/notes.txt", "second");
+        assert_eq!(res.get_status(), Some(204));
+        assert_eq!(res.get_headers()["Content-Location"], "/notes.txt");
+        assert_eq!(
+            fs::read_to_string(temp_dir.path().join("notes.txt")).unwrap(),
+            "second"
+        );
+
+        // Missing directories are a conflict unless the site creates them
+        let res = put_file(&site, &state, "/a/b/notes.txt", "nested");
+        assert_eq!(res.get_status(), Some(409));
+        site.create_dirs = true;
+        let res = put_file(&site, &state, "/a/b/my%20notes.txt", "nested");
+        assert_eq!(res.get_status(), Some(201));
+        assert_eq!(res.get_headers()["Location"], "/a/b/my%20notes.txt");
+
+        // Failed writes report the error instead of success
+        let res = put_file(&site, &state, "/notes.txt/inside.txt", "oops");
+        assert_eq!(res.get_status(), Some(500));
+
+        // No temporary files are left behind
+        let leftovers: Vec<_> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().ends_with(".tmp"))
+            .collect();
+        assert!(leftovers.is_empty());
+        assert!(state.locks.is_empty());
+    }
+
+    #[test]
+    fn test_post_matches_media_type_without_parameters() {
+        let temp_dir = tempdir().unwrap();
+        let state = ServerState::new(ServerConfig::default(), Arc::new(PoolLoad::default()));
+        let site = SiteConfig {
+            root: temp_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let post = |content_type: &str| {
+            let mut req = Request::default();
+            req.parse_status_line("POST /form HTTP/1.1".to_owned());
+            req.append_header(format!("Content-Type: {content_type}"));
+            req.set_body(b"hello");
+            req.set_document_root(&site.root, &site.index);
+            route(req, &site, &state)
+        };
+
+        assert_eq!(post("text/plain;charset=UTF-8").get_status(), Some(303));
+        assert_eq!(
+            fs::read_to_string(temp_dir.path().join("post-success.txt")).unwrap(),
+            "hello"
+        );
+
+        // Bodies with nowhere to go are refused rather than crashing the worker
+        assert_eq!(post("application/json").get_status(), Some(415));
+        assert_eq!(
+            post("multipart/form-data; boundary=x").get_status(),
+            Some(415)
+        );
     }
 }
@@ -0,0 +1,189 @@
+use std::{collections::BTreeMap, path::Path};
+
+use minijinja::{Environment, Value, path_loader};
+
+use crate::{
+    log_error,
+    models::{Request, Response, SiteConfig},
+    utils::{error_response, parse_urlencoded, set_date_header},
+};
+
+/// File name suffix of pages rendered as templates instead of served verbatim
+pub const TEMPLATE_SUFFIX: &str = ".tmpl.html";
+
+/// Checks whether a file is a template
+///
+/// The `path` is the file path to be analyzed
+pub fn is_template(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(TEMPLATE_SUFFIX))
+}
+
+/// Renders a template from a site's document root
+///
+/// The `site` provides the document root that template names, includes, and `extends` are resolved against, `name` is the template path relative to it, and `context` is the data available to the template
+///
+/// Returns the rendered HTML, with variables auto-escaped, or the template error
+pub fn render_template(site: &SiteConfig, name: &str, context: Value) -> Result<String, String> {
+    let mut env = Environment::new();
+    env.set_loader(path_loader(&site.root));
+
+    let template = env.get_template(name).map_err(|err| err.to_string())?;
+    template.render(context).map_err(|err| format!("{err:#}"))
+}
+
+/// Builds the template context for a request
+///
+/// The `req` is the Request being answered
+///
+/// Returns a Value with `request` (method, path, headers, and request and trace ids), `query` (decoded query parameters), and `form` (decoded form fields of a urlencoded body)
+pub fn request_context(req: &Request) -> Value {
+    let request = Value::from_pairs([
+        ("method", Value::from(req.get_method().as_str())),
+        ("path", Value::from(req.get_path())),
+        ("target", Value::from(req.get_target())),
+        ("headers", Value::from(req.get_headers().clone())),
+        ("id", Value::from(req.get_request_id())),
+        ("trace_id", Value::from(req.get_trace().get_trace_id())),
+    ]);
+
+    // Decode urlencoded form posts
+    let is_form = req
+        .get_headers()
+        .get("content-type")
+        .is_some_and(|ct| ct.to_lowercase() == "application/x-www-form-urlencoded");
+    let form: BTreeMap<String, String> = if is_form {
+        parse_urlencoded(&String::from_utf8_lossy(req.get_body()))
+            .into_iter()
+            .collect()
+    } else {
+        BTreeMap::new()
+    };
+
+    Value::from_pairs([
+        ("request", request),
+        ("query", Value::from(req.get_queries().clone())),
+        ("form", Value::from(form)),
+    ])
+}
+
+/// Renders the template targeted by a request into a Response
+///
+/// The `req` is the Request targeting a template and `site` is the virtual host it was addressed to
+///
+/// Returns a `Response` containing the rendered page, or an error page
+pub fn template_response(req: &Request, site: &SiteConfig) -> Response {
+    let path = req.get_resource();
+
+    // Partials such as `_layout.tmpl.html` can only be included
+    let is_partial = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('_'));
+    let name = path
+        .strip_prefix(&site.root)
+        .ok()
+        .and_then(|name| name.to_str())
+        .map(|name| name.replace('\\', "/"));
+    let Some(name) = name.filter(|_| !is_partial) else {
+        return error_response(404, req, site);
+    };
+
+    match render_template(site, &name, request_context(req)) {
+        Ok(contents) => {
+            let mut res = Response::default();
+            res.set_status(200);
+            res.add_header(set_date_header());
+            res.add_header(("Content-Type".to_owned(), "text/html".to_owned()));
+            res.add_header(("Content-Length".to_owned(), contents.len().to_string()));
+            res.set_body(Some(contents));
+            res
+        }
+        Err(err) => {
+            log_error!("Error rendering template {name}: {err}");
+            error_response(500, req, site)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    /// Helper function to build a site rooted at a directory
+    fn site(root: &Path) -> SiteConfig {
+        SiteConfig {
+            root: root.to_path_buf(),
+            ..SiteConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_layout_inheritance_and_escaping() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(
+            temp_dir.path().join("_layout.tmpl.html"),
+            "<header>{% include '_nav.tmpl.html' %}</header>{% block body %}{% endblock %}",
+        )
+        .unwrap();
+        fs::write(temp_dir.path().join("_nav.tmpl.html"), "nav").unwrap();
+        fs::write(
+            temp_dir.path().join("hello.tmpl.html"),
+            "{% extends '_layout.tmpl.html' %}{% block body %}\
+             {% if query.name %}Hi {{ query.name }}{% endif %}\
+             {% for item in ['a', 'b'] %}[{{ item }}]{% endfor %}{% endblock %}",
+        )
+        .unwrap();
+
+        let mut req = Request::default();
+        req.parse_status_line("GET /hello.tmpl.html?name=%3Cb%3EBo+b HTTP/1.1".to_owned());
+        req.set_document_root(temp_dir.path(), "index.html");
+
+        let res = template_response(&req, &site(temp_dir.path()));
+        let mut out = vec![];
+        res.send(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(out.ends_with("<header>nav</header>Hi &lt;b&gt;Bo b[a][b]"));
+    }
+
+    #[test]
+    fn test_form_posts_are_in_context() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(
+            temp_dir.path().join("thanks.tmpl.html"),
+            "Thanks {{ form.username }} via {{ request.method }}",
+        )
+        .unwrap();
+
+        let mut req = Request::default();
+        req.parse_status_line("POST /thanks.tmpl.html HTTP/1.1".to_owned());
+        req.append_header("Content-Type: application/x-www-form-urlencoded".to_owned());
+        req.set_body(b"username=ada+lovelace&age=36");
+
+        let rendered = render_template(
+            &site(temp_dir.path()),
+            "thanks.tmpl.html",
+            request_context(&req),
+        )
+        .unwrap();
+        assert_eq!(rendered, "Thanks ada lovelace via POST");
+    }
+
+    #[test]
+    fn test_partials_are_not_served_directly() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("_layout.tmpl.html"), "layout").unwrap();
+
+        let mut req = Request::default();
+        req.parse_status_line("GET /_layout.tmpl.html HTTP/1.1".to_owned());
+        req.set_document_root(temp_dir.path(), "index.html");
+
+        let res = template_response(&req, &site(temp_dir.path()));
+        assert_eq!(res.get_status(), Some(404));
+    }
+}
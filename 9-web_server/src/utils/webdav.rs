@@ -0,0 +1,579 @@
+use std::{
+    fs::{self, Metadata},
+    io,
+    path::{Component, Path, PathBuf},
+    ptr,
+    time::UNIX_EPOCH,
+};
+
+use httpdate::fmt_http_date;
+
+use crate::{
+    log_error,
+    models::{HttpMethod, MimeTypes, Request, Response, ServerState, SiteConfig, make_etag},
+    utils::{
+        allowed_methods, canonical_path, error_response, handle_bad_request, percent_encode,
+        set_date_header,
+    },
+};
+
+/// Methods a site handles in addition to its `methods` when WebDAV is enabled
+pub const WEBDAV_METHODS: &[&str] = &["PROPFIND", "MKCOL", "COPY", "MOVE"];
+
+/// Checks whether a request is answered by the WebDAV handler
+///
+/// The `req` is the Request to check and `site` is the virtual host it was addressed to. Besides the WebDAV methods, this covers `DELETE` of a directory
+pub fn is_webdav_request(req: &Request, site: &SiteConfig) -> bool {
+    site.webdav
+        && match req.get_method() {
+            HttpMethod::Propfind | HttpMethod::Mkcol | HttpMethod::Copy | HttpMethod::Move => true,
+            HttpMethod::Delete => dav_path(req.get_path(), site).is_some_and(|path| path.is_dir()),
+            _ => false,
+        }
+}
+
+/// Determines the methods a WebDAV site supports for a request target
+///
+/// The `req` is the Request struct containing request data and `site` is the virtual host it was addressed to
+///
+/// Returns None if the site doesn't serve WebDAV
+pub(crate) fn webdav_methods(req: &Request, site: &SiteConfig) -> Option<&'static [&'static str]> {
+    if !site.webdav {
+        return None;
+    }
+
+    let path = dav_path(req.get_path(), site)?;
+    Some(if path.is_dir() && req.get_resource().is_file() {
+        // Directories with an index file serve it
+        &["GET", "OPTIONS", "PROPFIND", "DELETE", "COPY", "MOVE"]
+    } else if path.is_dir() {
+        &["OPTIONS", "PROPFIND", "DELETE", "COPY", "MOVE"]
+    } else if path.is_file() {
+        &[
+            "GET", "POST", "PUT", "DELETE", "OPTIONS", "PROPFIND", "COPY", "MOVE",
+        ]
+    } else {
+        &["POST", "PUT", "MKCOL", "OPTIONS"]
+    })
+}
+
+/// Handles a WebDAV request on a file or directory under the document root
+///
+/// The `req` is the Request with its body read, `site` is the virtual host it was addressed to, and `state` holds the shared file cache, media types, and write locks
+///
+/// Returns a `207` Multi-Status Response to `PROPFIND`, or the status of the change made by the other methods
+pub fn webdav(req: &Request, site: &SiteConfig, state: &ServerState) -> Response {
+    let Some(path) = dav_path(req.get_path(), site) else {
+        return handle_bad_request("Request target must not leave the document root");
+    };
+
+    match req.get_method() {
+        HttpMethod::Propfind => propfind(req, &path, site, state),
+        HttpMethod::Mkcol => mkcol(req, &path, site, state),
+        HttpMethod::Copy => copy_or_move(req, &path, false, site, state),
+        HttpMethod::Move => copy_or_move(req, &path, true, site, state),
+        HttpMethod::Delete => delete_collection(req, &path, site, state),
+        _ => error_response(405, req, site),
+    }
+}
+
+/// Handles `PROPFIND` requests
+///
+/// The target's properties are listed for `Depth: 0`, along with those of a directory's entries for `Depth: 1`. Every live property is returned whichever were asked for
+///
+/// Returns a `207` Response listing the size, modification time, and resource type of each resource
+fn propfind(req: &Request, path: &Path, site: &SiteConfig, state: &ServerState) -> Response {
+    // Infinite depth, the default, may be refused to keep listings bounded
+    let depth = req.get_headers().get("depth").map(String::as_str);
+    let recurse = match depth {
+        Some("0") => false,
+        Some("1") => true,
+        _ => return error_response(403, req, site),
+    };
+    let Ok(metadata) = fs::metadata(path) else {
+        return error_response(404, req, site);
+    };
+
+    let config = state.config();
+    let mime = config.mime.types();
+    let relative = path.strip_prefix(&site.root).unwrap_or(path);
+    let mut body = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+    );
+    push_properties(&mut body, relative, &metadata, mime);
+
+    if recurse && metadata.is_dir() {
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(err) => {
+                log_error!("Error listing {}: {err}", path.display());
+                return error_response(500, req, site);
+            }
+        };
+        let mut children: Vec<_> = entries
+            .filter_map(Result::ok)
+            .filter(|entry| !is_temp_file(&entry.file_name().to_string_lossy()))
+            .filter_map(|entry| Some((entry.file_name(), fs::metadata(entry.path()).ok()?)))
+            .collect();
+        children.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (name, metadata) in children {
+            push_properties(&mut body, &relative.join(name), &metadata, mime);
+        }
+    }
+    body.push_str("</D:multistatus>\n");
+
+    let mut res = Response::default();
+    res.set_status(207);
+    res.add_header(set_date_header());
+    res.add_header((
+        "Content-Type".to_owned(),
+        "application/xml; charset=utf-8".to_owned(),
+    ));
+    res.add_header(("Content-Length".to_owned(), body.len().to_string()));
+    res.set_body(Some(body));
+    res
+}
+
+/// Appends the `<D:response>` element of one resource to a Multi-Status body
+///
+/// The `relative` path is the resource's path under the document root and `metadata` describes it
+fn push_properties(body: &mut String, relative: &Path, metadata: &Metadata, mime: &MimeTypes) {
+    let relative = relative.to_string_lossy();
+    let mut href = format!("/{}", percent_encode(&relative));
+    if metadata.is_dir() && !href.ends_with('/') {
+        href.push('/');
+    }
+    let name = relative.rsplit('/').next().unwrap_or_default();
+    let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+
+    body.push_str(&format!(
+        "<D:response>\n<D:href>{href}</D:href>\n<D:propstat>\n<D:prop>\n<D:displayname>{}</D:displayname>\n<D:getlastmodified>{}</D:getlastmodified>\n",
+        xml_escape(name),
+        fmt_http_date(modified)
+    ));
+    if metadata.is_dir() {
+        body.push_str("<D:resourcetype><D:collection/></D:resourcetype>\n");
+    } else {
+        body.push_str(&format!(
+            "<D:resourcetype/>\n<D:getcontentlength>{}</D:getcontentlength>\n<D:getcontenttype>{}</D:getcontenttype>\n<D:getetag>{}</D:getetag>\n",
+            metadata.len(),
+            xml_escape(&mime.content_type(Path::new(name), b"")),
+            xml_escape(&make_etag(metadata.len(), modified))
+        ));
+    }
+    body.push_str(
+        "</D:prop>\n<D:status>HTTP/1.1 200 OK</D:status>\n</D:propstat>\n</D:response>\n",
+    );
+}
+
+/// Lists the writes a WebDAV request makes besides the one its own method names
+///
+/// The `req` is the Request to check and `site` is the virtual host it was addressed to. `COPY` writes its destination, `MOVE` also deletes its source, and `MKCOL` writes its target, so access rules guarding `PUT` and `DELETE` are applied to them too
+///
+/// Returns the request path and method of each write, which is empty for other requests
+pub(crate) fn implied_writes(req: &Request, site: &SiteConfig) -> Vec<(String, &'static str)> {
+    if !site.webdav {
+        return vec![];
+    }
+
+    let path = req.get_path().to_owned();
+    let destination = || destination(req).ok().map(|target| (target, "PUT"));
+    match req.get_method() {
+        HttpMethod::Mkcol => vec![(path, "PUT")],
+        HttpMethod::Copy => destination().into_iter().collect(),
+        HttpMethod::Move => [Some((path, "DELETE")), destination()]
+            .into_iter()
+            .flatten()
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Handles `MKCOL` requests
+///
+/// Returns a `201` Response once the directory was created, `405` if the target exists, or `409` if its parent is missing
+fn mkcol(req: &Request, path: &Path, site: &SiteConfig, state: &ServerState) -> Response {
+    // Bodies would describe the new collection's contents, which isn't supported
+    if !req.get_body().is_empty() {
+        return error_response(415, req, site);
+    }
+
+    let _lock = state.locks.lock(path);
+    if path.exists() {
+        let mut res = error_response(405, req, site);
+        res.add_header(("Allow".to_owned(), allowed_methods(req, site).join(", ")));
+        return res;
+    }
+
+    match fs::create_dir(path) {
+        Ok(()) => created_or_replaced(201),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => error_response(409, req, site),
+        Err(err) => {
+            log_error!("Error creating {}: {err}", path.display());
+            error_response(500, req, site)
+        }
+    }
+}
+
+/// Handles `COPY` and `MOVE` requests
+///
+/// The `source` is the resolved request target and `remove_source` is set for `MOVE`. The `Destination` header names the new path on this server and `Overwrite: F` refuses to replace an existing resource. Directories are copied with their contents unless `Depth: 0` is sent
+///
+/// Returns a `201` Response if the destination was created, `204` if it was replaced, or `412` if it exists and may not be overwritten
+fn copy_or_move(
+    req: &Request,
+    source: &Path,
+    remove_source: bool,
+    site: &SiteConfig,
+    state: &ServerState,
+) -> Response {
+    if !source.exists() {
+        return error_response(404, req, site);
+    }
+    let target = match destination(req) {
+        Ok(target) => target,
+        Err(BadDestination::Missing) => {
+            return handle_bad_request("COPY and MOVE require a Destination header");
+        }
+        Err(BadDestination::Remote) => return error_response(502, req, site),
+        Err(BadDestination::Outside) => {
+            return handle_bad_request("Destination must not leave the document root");
+        }
+    };
+    let Some(dest) = dav_path(&target, site) else {
+        return handle_bad_request("Destination must not leave the document root");
+    };
+
+    // Resources can't be copied into themselves or replace a directory containing them
+    if dest.starts_with(source) || source.starts_with(&dest) {
+        return error_response(403, req, site);
+    }
+
+    // Moving into an area covered by another access rule could get around it
+    let method = req.get_method().as_str();
+    let same_rule = match (
+        site.find_access_rule(req.get_path(), method),
+        site.find_access_rule(&target, method),
+    ) {
+        (None, None) => true,
+        (Some(source_rule), Some(dest_rule)) => ptr::eq(source_rule, dest_rule),
+        _ => false,
+    };
+    if !same_rule {
+        return error_response(403, req, site);
+    }
+
+    let overwrite = !req
+        .get_headers()
+        .get("overwrite")
+        .is_some_and(|value| value.eq_ignore_ascii_case("F"));
+    let recursive = req
+        .get_headers()
+        .get("depth")
+        .is_none_or(|depth| depth != "0");
+
+    // Lock both paths in a fixed order so opposite moves can't deadlock
+    let (first, second) = if source < dest.as_path() {
+        (source, dest.as_path())
+    } else {
+        (dest.as_path(), source)
+    };
+    let _locks = (state.locks.lock(first), state.locks.lock(second));
+
+    let replaced = dest.exists();
+    if replaced && !overwrite {
+        return error_response(412, req, site);
+    }
+    if !dest.parent().is_some_and(Path::is_dir) {
+        return error_response(409, req, site);
+    }
+
+    let result = (|| {
+        if replaced {
+            remove_path(&dest)?;
+        }
+        if remove_source {
+            fs::rename(source, &dest)
+        } else {
+            copy_path(source, &dest, recursive)
+        }
+    })();
+    state.cache.invalidate_dir(&dest);
+    if remove_source {
+        state.cache.invalidate_dir(source);
+    }
+
+    match result {
+        Ok(()) if replaced => created_or_replaced(204),
+        Ok(()) => created_or_replaced(201),
+        Err(err) => {
+            log_error!(
+                "Error copying {} to {}: {err}",
+                source.display(),
+                dest.display()
+            );
+            error_response(500, req, site)
+        }
+    }
+}
+
+/// Handles `DELETE` requests for directories, removing them with their contents
+///
+/// Returns a `204` Response, or `403` for the document root itself
+fn delete_collection(
+    req: &Request,
+    path: &Path,
+    site: &SiteConfig,
+    state: &ServerState,
+) -> Response {
+    if path == site.root {
+        return error_response(403, req, site);
+    }
+
+    let _lock = state.locks.lock(path);
+    let result = fs::remove_dir_all(path);
+    state.cache.invalidate_dir(path);
+    if let Err(err) = result {
+        log_error!("Error deleting {}: {err}", path.display());
+        return error_response(500, req, site);
+    }
+    created_or_replaced(204)
+}
+
+/// Builds an empty success response
+fn created_or_replaced(code: usize) -> Response {
+    let mut res = Response::default();
+    res.set_status(code);
+    res.add_header(set_date_header());
+    res.add_header(("Content-Length".to_owned(), "0".to_owned()));
+    res
+}
+
+/// Maps a request path onto a file or directory under the document root
+///
+/// The `path` is the canonical request path. Unlike `Request::get_resource`, directory paths don't resolve to their index file
+///
+/// Returns None if the path would leave the document root
+fn dav_path(path: &str, site: &SiteConfig) -> Option<PathBuf> {
+    let relative = Path::new(path.trim_start_matches('/'));
+    if relative
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return None;
+    }
+    Some(site.root.join(relative))
+}
+
+/// Removes a file, or a directory with its contents
+fn remove_path(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Copies a file, or a directory with its contents if `recursive` is set
+fn copy_path(source: &Path, dest: &Path, recursive: bool) -> io::Result<()> {
+    if !source.is_dir() {
+        return fs::copy(source, dest).map(|_| ());
+    }
+
+    fs::create_dir(dest)?;
+    if recursive {
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            copy_path(&entry.path(), &dest.join(entry.file_name()), true)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reasons a `Destination` header can't be used
+enum BadDestination {
+    Missing,
+    /// Names another server
+    Remote,
+    /// Leaves the document root
+    Outside,
+}
+
+/// Reads the `Destination` header of a `COPY` or `MOVE` request
+///
+/// Returns the request path it names
+fn destination(req: &Request) -> Result<String, BadDestination> {
+    let Some(destination) = req.get_headers().get("destination") else {
+        return Err(BadDestination::Missing);
+    };
+
+    // Absolute URIs must name this server, since resources can't be sent elsewhere
+    let target = match destination.split_once("://") {
+        Some((_, rest)) => {
+            let (authority, path) = rest.find('/').map_or((rest, "/"), |i| rest.split_at(i));
+            let host = req.get_headers().get("host");
+            if host.is_some_and(|host| !host.eq_ignore_ascii_case(authority)) {
+                return Err(BadDestination::Remote);
+            }
+            path
+        }
+        None => destination.as_str(),
+    };
+    let target = target.split(['?', '#']).next().unwrap_or_default();
+    canonical_path(target).ok_or(BadDestination::Outside)
+}
+
+/// Checks whether a file name is a temporary file left by an unfinished write
+fn is_temp_file(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(".tmp")
+}
+
+/// Escapes text for use in XML content
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{PoolLoad, ServerConfig};
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    /// Helper function to send a WebDAV request with headers
+    fn send(
+        site: &SiteConfig,
+        state: &ServerState,
+        status_line: &str,
+        headers: &[&str],
+    ) -> Response {
+        let mut req = Request::default();
+        req.parse_status_line(status_line.to_owned());
+        for header in headers {
+            req.append_header(header.to_string());
+        }
+        req.set_document_root(&site.root, &site.index);
+        assert!(is_webdav_request(&req, site));
+        webdav(&req, site, state)
+    }
+
+    #[test]
+    fn test_manage_files_over_webdav() {
+        let temp_dir = tempdir().unwrap();
+        let state = ServerState::new(ServerConfig::default(), Arc::new(PoolLoad::default()));
+        let site = SiteConfig {
+            root: temp_dir.path().to_path_buf(),
+            webdav: true,
+            ..Default::default()
+        };
+        fs::write(temp_dir.path().join("a & b.txt"), "hello").unwrap();
+
+        let res = send(&site, &state, "MKCOL /docs HTTP/1.1", &[]);
+        assert_eq!(res.get_status(), Some(201));
+        let res = send(&site, &state, "MKCOL /docs HTTP/1.1", &[]);
+        assert_eq!(res.get_status(), Some(405));
+        let res = send(&site, &state, "MKCOL /missing/docs HTTP/1.1", &[]);
+        assert_eq!(res.get_status(), Some(409));
+
+        // Listings include the target and, at depth 1, its entries
+        let res = send(&site, &state, "PROPFIND / HTTP/1.1", &["Depth: 1"]);
+        assert_eq!(res.get_status(), Some(207));
+        let body = String::from_utf8(res.get_body().unwrap().to_vec()).unwrap();
+        assert!(body.contains("<D:href>/a%20%26%20b.txt</D:href>"));
+        assert!(body.contains("<D:displayname>a &amp; b.txt</D:displayname>"));
+        assert!(body.contains("<D:getcontentlength>5</D:getcontentlength>"));
+        assert!(body.contains("<D:href>/docs/</D:href>"));
+        assert!(body.contains("<D:collection/>"));
+        let res = send(&site, &state, "PROPFIND /docs HTTP/1.1", &[]);
+        assert_eq!(res.get_status(), Some(403));
+
+        let res = send(
+            &site,
+            &state,
+            "COPY /a%20%26%20b.txt HTTP/1.1",
+            &[
+                "Host: localhost",
+                "Destination: http://localhost/docs/copy.txt",
+            ],
+        );
+        assert_eq!(res.get_status(), Some(201));
+        assert_eq!(
+            fs::read_to_string(temp_dir.path().join("docs/copy.txt")).unwrap(),
+            "hello"
+        );
+
+        // Existing destinations are only replaced when allowed
+        let res = send(
+            &site,
+            &state,
+            "MOVE /docs/copy.txt HTTP/1.1",
+            &["Destination: /a%20%26%20b.txt", "Overwrite: F"],
+        );
+        assert_eq!(res.get_status(), Some(412));
+        let res = send(
+            &site,
+            &state,
+            "MOVE /docs HTTP/1.1",
+            &["Destination: /archive/"],
+        );
+        assert_eq!(res.get_status(), Some(201));
+        assert!(temp_dir.path().join("archive/copy.txt").exists());
+        assert!(!temp_dir.path().join("docs").exists());
+
+        let res = send(
+            &site,
+            &state,
+            "MOVE /archive HTTP/1.1",
+            &["Destination: /archive/inner"],
+        );
+        assert_eq!(res.get_status(), Some(403));
+        let res = send(
+            &site,
+            &state,
+            "MOVE /archive HTTP/1.1",
+            &["Destination: /../escape"],
+        );
+        assert_eq!(res.get_status(), Some(400));
+
+        let res = send(&site, &state, "DELETE /archive/ HTTP/1.1", &[]);
+        assert_eq!(res.get_status(), Some(204));
+        assert!(!temp_dir.path().join("archive").exists());
+        assert!(state.locks.is_empty());
+    }
+
+    #[test]
+    fn test_moves_stay_under_their_access_rule() {
+        let temp_dir = tempdir().unwrap();
+        let state = ServerState::new(ServerConfig::default(), Arc::new(PoolLoad::default()));
+        let mut site: SiteConfig = toml::from_str(
+            r#"
+            webdav = true
+
+            [[access]]
+            prefix = "/private/"
+            policy = "deny"
+            "#,
+        )
+        .unwrap();
+        site.root = temp_dir.path().to_path_buf();
+        fs::create_dir(temp_dir.path().join("private")).unwrap();
+        fs::write(temp_dir.path().join("notes.txt"), "hello").unwrap();
+
+        for destination in [
+            "//private/notes.txt",
+            "/./private/notes.txt",
+            "/%70rivate/notes.txt",
+        ] {
+            let res = send(
+                &site,
+                &state,
+                "MOVE /notes.txt HTTP/1.1",
+                &[&format!("Destination: {destination}")],
+            );
+            assert_eq!(res.get_status(), Some(403), "{destination}");
+        }
+        assert!(temp_dir.path().join("notes.txt").exists());
+    }
+}